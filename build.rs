@@ -1,20 +1,56 @@
 // build.rs
 
 use bootloader::DiskImageBuilder;
-use std::{env, path::PathBuf};
+use std::{env, path::Path, path::PathBuf};
+
+#[path = "src/initrd.rs"]
+mod initrd;
+#[path = "src/symbols.rs"]
+mod symbols;
 
 fn main() {
     // set by cargo for the kernel artifact dependency
     let kernel_path = env::var("CARGO_BIN_FILE_KERNEL").unwrap();
-    let disk_builder = DiskImageBuilder::new(PathBuf::from(kernel_path));
+    let mut disk_builder = DiskImageBuilder::new(PathBuf::from(&kernel_path));
 
     // specify output paths
     let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
     let uefi_path = out_dir.join("bench-uefi.img");
+    let bios_path = out_dir.join("bench-bios.img");
+    let initrd_path = out_dir.join("initrd.tar");
+
+    // the kernel reads its symbol table and command line from the ramdisk, the command line can be changed at build
+    // time with KERNEL_CMDLINE or when running with --cmdline
+    println!("cargo:rerun-if-env-changed=KERNEL_CMDLINE");
+    let cmdline = env::var("KERNEL_CMDLINE").unwrap_or_default();
+
+    // everything in the initrd directory is packed into the ramdisk too, it can be changed with INITRD_DIR or when
+    // running with --initrd
+    println!("cargo:rerun-if-env-changed=INITRD_DIR");
+    let initrd_dir = env::var("INITRD_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("initrd"));
+    println!("cargo:rerun-if-changed={}", initrd_dir.display());
+
+    initrd::write_kernel_initrd(
+        Path::new(&kernel_path),
+        &cmdline,
+        Some(&initrd_dir)
+            .filter(|dir| dir.exists())
+            .map(PathBuf::as_path),
+        Vec::new(),
+        &initrd_path,
+    );
+    disk_builder.set_ramdisk(initrd_path);
 
     // create the disk images
     disk_builder.create_uefi_image(&uefi_path).unwrap();
+    disk_builder.create_bios_image(&bios_path).unwrap();
 
     // pass the disk image paths via environment variables
     println!("cargo:rustc-env=UEFI_IMAGE={}", uefi_path.display());
+    println!("cargo:rustc-env=BIOS_IMAGE={}", bios_path.display());
+    // the runner rebuilds the images from the kernel when given a different command line or initrd directory
+    println!("cargo:rustc-env=KERNEL_PATH={}", kernel_path);
+    println!("cargo:rustc-env=INITRD_DIR={}", initrd_dir.display());
 }
@@ -0,0 +1,250 @@
+//! Frame pointer based stack unwinding. The kernel is built with `-C force-frame-pointers=yes` so every function
+//! starts by pushing the caller's RBP and pointing RBP at it, which means `[rbp]` is the previous frame's RBP and
+//! `[rbp + 8]` is the return address.
+//!
+//! Function names come from a symbol table extracted from the kernel ELF by the host `build.rs` and passed to the
+//! kernel as `kernel.sym` in the initrd. The kernel is position independent and the bootloader chooses where to load
+//! it, so the table's link time addresses are moved by however far `init` is from where the table says it is.
+
+use crate::io::interrupts::crash_report;
+use crate::memory;
+use conquer_once::spin::OnceCell;
+use core::arch::asm;
+use core::{fmt, str};
+use x86_64::VirtAddr;
+
+/// Stops runaway unwinding if the frame pointer chain is corrupted
+const MAX_FRAMES: usize = 64;
+
+const SYMBOL_TABLE_MAGIC: &[u8; 4] = b"BSYM";
+const SYMBOL_TABLE_HEADER_SIZE: usize = 8;
+const SYMBOL_ENTRY_SIZE: usize = 24;
+
+/// The function whose address gives the load offset, as it appears in the symbol table
+const ANCHOR_SYMBOL: &str = "kernel::backtrace::init";
+
+static SYMBOL_TABLE: OnceCell<SymbolTable> = OnceCell::uninit();
+
+/// See `write_symbol_table` in `build.rs` for the layout
+pub struct SymbolTable {
+    entries: &'static [u8],
+    names: &'static [u8],
+    /// Added to link time addresses to get where the functions are loaded
+    load_offset: u64,
+}
+
+impl SymbolTable {
+    pub fn parse(data: &'static [u8]) -> Option<Self> {
+        if data.get(0..4)? != SYMBOL_TABLE_MAGIC {
+            return None;
+        }
+
+        let count = u32::from_le_bytes(data.get(4..8)?.try_into().ok()?) as usize;
+        let names_start = SYMBOL_TABLE_HEADER_SIZE + count * SYMBOL_ENTRY_SIZE;
+
+        Some(SymbolTable {
+            entries: data.get(SYMBOL_TABLE_HEADER_SIZE..names_start)?,
+            names: data.get(names_start..)?,
+            load_offset: 0,
+        })
+    }
+
+    /// Sets the load offset from the address `name` was loaded at, returning `false` if it isn't in the table
+    pub fn locate(&mut self, name: &str, loaded_address: u64) -> bool {
+        match (0..self.len()).find(|&index| self.name(index) == name) {
+            Some(index) => {
+                self.load_offset = loaded_address.wrapping_sub(self.address(index));
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len() / SYMBOL_ENTRY_SIZE
+    }
+
+    fn address(&self, index: usize) -> u64 {
+        let entry = &self.entries[index * SYMBOL_ENTRY_SIZE..];
+        u64::from_le_bytes(entry[0..8].try_into().unwrap())
+    }
+
+    fn size(&self, index: usize) -> u64 {
+        let entry = &self.entries[index * SYMBOL_ENTRY_SIZE..];
+        u64::from_le_bytes(entry[8..16].try_into().unwrap())
+    }
+
+    fn name(&self, index: usize) -> &'static str {
+        let entry = &self.entries[index * SYMBOL_ENTRY_SIZE..];
+        let offset = u32::from_le_bytes(entry[16..20].try_into().unwrap()) as usize;
+        let len = u32::from_le_bytes(entry[20..24].try_into().unwrap()) as usize;
+
+        self.names
+            .get(offset..offset + len)
+            .and_then(|name| str::from_utf8(name).ok())
+            .unwrap_or("<invalid symbol>")
+    }
+
+    /// Returns the name of the function containing the loaded `address` and how far into it `address` is
+    pub fn resolve(&self, address: u64) -> Option<(&'static str, u64)> {
+        let address = address.wrapping_sub(self.load_offset);
+
+        // Binary search for the first symbol starting after the address, the one before it is the candidate
+        let (mut low, mut high) = (0, self.len());
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if self.address(mid) <= address {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+
+        let index = low.checked_sub(1)?;
+        let offset = address - self.address(index);
+
+        if self.size(index) != 0 && offset >= self.size(index) {
+            return None;
+        }
+
+        Some((self.name(index), offset))
+    }
+}
+
+/// Loads the symbol table, if it is valid. Never inlined, since its own address is used to find the load offset.
+#[inline(never)]
+pub(crate) fn init(symbol_table: &'static [u8]) {
+    let Some(mut symbol_table) = SymbolTable::parse(symbol_table) else {
+        return;
+    };
+
+    let init_address = init as fn(&'static [u8]) as usize as u64;
+    if symbol_table.locate(ANCHOR_SYMBOL, init_address) {
+        SYMBOL_TABLE.init_once(|| symbol_table);
+    }
+}
+
+/// Iterates over the return addresses in the frame pointer chain starting at `rbp`
+pub struct StackWalker {
+    rbp: u64,
+    frames: usize,
+}
+
+impl StackWalker {
+    pub fn new(rbp: u64) -> Self {
+        StackWalker { rbp, frames: 0 }
+    }
+
+    /// Starts at the frame of the caller
+    #[inline(always)]
+    pub fn current() -> Self {
+        let rbp: u64;
+        unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+
+        StackWalker::new(rbp)
+    }
+}
+
+impl Iterator for StackWalker {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        if self.frames >= MAX_FRAMES || self.rbp == 0 || self.rbp % 8 != 0 {
+            return None;
+        }
+
+        // A corrupt frame pointer would otherwise turn a panic into a page fault, and the frame can straddle two pages
+        let frame_mapped = [self.rbp, self.rbp.wrapping_add(8)]
+            .into_iter()
+            .all(|address| {
+                VirtAddr::try_new(address)
+                    .ok()
+                    .and_then(memory::translate)
+                    .is_some()
+            });
+        if !frame_mapped {
+            self.rbp = 0;
+            return None;
+        }
+
+        let frame = self.rbp as *const u64;
+        let (previous_rbp, return_address) = unsafe { (frame.read(), frame.add(1).read()) };
+
+        // Stacks grow downwards so the caller's frame must be above this one, anything else means we have walked off
+        // the end of the chain
+        if return_address == 0 || previous_rbp <= self.rbp {
+            self.rbp = 0;
+        } else {
+            self.rbp = previous_rbp;
+        }
+
+        self.frames += 1;
+
+        if return_address == 0 {
+            None
+        } else {
+            Some(return_address)
+        }
+    }
+}
+
+/// Return addresses point after the call instruction, which may be past the end of the calling function, so they are
+/// resolved using the address of the last byte of the call instead
+fn write_frame(
+    writer: &mut impl fmt::Write,
+    index: usize,
+    address: u64,
+    is_return_address: bool,
+) -> fmt::Result {
+    let lookup_address = if is_return_address {
+        address - 1
+    } else {
+        address
+    };
+
+    match SYMBOL_TABLE
+        .get()
+        .and_then(|symbol_table| symbol_table.resolve(lookup_address))
+    {
+        Some((name, offset)) => writeln!(
+            writer,
+            "  {:>2}: {:#018x} {}+{:#x}",
+            index,
+            address,
+            name,
+            offset + (address - lookup_address)
+        ),
+        None => writeln!(writer, "  {:>2}: {:#018x} <unknown>", index, address),
+    }
+}
+
+/// Writes a backtrace from the last exception if there was one, otherwise from the caller
+#[inline(always)]
+pub fn write_backtrace(writer: &mut impl fmt::Write) -> fmt::Result {
+    writeln!(writer, "Backtrace:")?;
+
+    let mut index = 0;
+
+    let walker = match crash_report::last() {
+        Some(report) => {
+            // The faulting instruction itself is not in the frame pointer chain
+            write_frame(
+                writer,
+                index,
+                report.frame.stack_frame.instruction_pointer.as_u64(),
+                false,
+            )?;
+            index += 1;
+
+            StackWalker::new(report.frame.rbp)
+        }
+        None => StackWalker::current(),
+    };
+
+    for address in walker {
+        write_frame(writer, index, address, true)?;
+        index += 1;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,99 @@
+//! Block devices, which the disk filesystems are built on. Devices are addressed in 512 byte sectors whatever their
+//! physical sector size is, and `read_bytes`/`write_bytes` let filesystems ignore sectors entirely. Partitions and the
+//! buffer cache are block devices themselves, layered on top of a disk.
+
+pub mod cache;
+pub mod partition;
+pub mod ramdisk;
+
+pub const SECTOR_SIZE: usize = 512;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockError {
+    /// The access goes past the end of the device
+    OutOfRange,
+    /// The buffer isn't a whole number of sectors
+    InvalidBuffer,
+    ReadOnly,
+    /// The device reported an error
+    Io,
+    /// The MBR or GPT describes partitions that can't be right, or the GPT and its backup are both damaged
+    InvalidPartitionTable,
+}
+
+pub trait BlockDevice: Send + Sync {
+    fn sector_count(&self) -> u64;
+
+    /// Reads `buf.len() / SECTOR_SIZE` sectors starting at `start`
+    fn read_sectors(&self, start: u64, buf: &mut [u8]) -> Result<(), BlockError>;
+
+    fn write_sectors(&self, start: u64, buf: &[u8]) -> Result<(), BlockError>;
+
+    /// Makes sure every write has reached the device
+    fn flush(&self) -> Result<(), BlockError> {
+        Ok(())
+    }
+}
+
+/// Checks a `read_sectors`/`write_sectors` request, for devices to call before touching anything
+pub fn check_request(
+    device: &dyn BlockDevice,
+    start: u64,
+    buf_len: usize,
+) -> Result<(), BlockError> {
+    if !buf_len.is_multiple_of(SECTOR_SIZE) {
+        return Err(BlockError::InvalidBuffer);
+    }
+
+    let end = start
+        .checked_add((buf_len / SECTOR_SIZE) as u64)
+        .ok_or(BlockError::OutOfRange)?;
+
+    if end > device.sector_count() {
+        return Err(BlockError::OutOfRange);
+    }
+
+    Ok(())
+}
+
+/// Reads `buf.len()` bytes starting at byte `offset`, which don't need to be sector aligned
+pub fn read_bytes(device: &dyn BlockDevice, offset: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+    let mut sector = [0; SECTOR_SIZE];
+    let mut done = 0;
+
+    while done < buf.len() {
+        let position = offset + done as u64;
+        let sector_offset = (position % SECTOR_SIZE as u64) as usize;
+        let length = (SECTOR_SIZE - sector_offset).min(buf.len() - done);
+
+        device.read_sectors(position / SECTOR_SIZE as u64, &mut sector)?;
+        buf[done..done + length].copy_from_slice(&sector[sector_offset..sector_offset + length]);
+
+        done += length;
+    }
+
+    Ok(())
+}
+
+/// Writes `buf` at byte `offset`, reading the sectors at either end first if it only covers part of them
+pub fn write_bytes(device: &dyn BlockDevice, offset: u64, buf: &[u8]) -> Result<(), BlockError> {
+    let mut sector = [0; SECTOR_SIZE];
+    let mut done = 0;
+
+    while done < buf.len() {
+        let position = offset + done as u64;
+        let sector_offset = (position % SECTOR_SIZE as u64) as usize;
+        let length = (SECTOR_SIZE - sector_offset).min(buf.len() - done);
+
+        if length != SECTOR_SIZE {
+            device.read_sectors(position / SECTOR_SIZE as u64, &mut sector)?;
+        }
+
+        sector[sector_offset..sector_offset + length].copy_from_slice(&buf[done..done + length]);
+        device.write_sectors(position / SECTOR_SIZE as u64, &sector)?;
+
+        done += length;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,227 @@
+//! A write-back cache of sectors in front of a block device. Writes stay in the cache until `flush` or until they are
+//! evicted, and once it is full the least recently used sectors make room. It is a block device itself, so a
+//! filesystem is given the cache instead of the device and syncing the filesystem flushes it.
+
+use crate::block::{check_request, BlockDevice, BlockError, SECTOR_SIZE};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Sectors read from the cache
+    pub hits: u64,
+    /// Sectors that had to be read from the device
+    pub misses: u64,
+    /// Dirty sectors written to the device, by a flush or when they were evicted
+    pub write_backs: u64,
+}
+
+pub struct BufferCache {
+    device: Arc<dyn BlockDevice>,
+    /// How many sectors can be kept
+    capacity: usize,
+    state: Mutex<State>,
+}
+
+struct State {
+    buffers: BTreeMap<u64, Buffer>,
+    /// Sectors by when they were last used, the first is the next to evict
+    lru: BTreeMap<u64, u64>,
+    /// Counts every use, to order `lru`
+    clock: u64,
+    stats: CacheStats,
+}
+
+struct Buffer {
+    data: Box<[u8; SECTOR_SIZE]>,
+    dirty: bool,
+    last_used: u64,
+}
+
+impl State {
+    /// Marks a cached sector as just used
+    fn touch(&mut self, sector: u64) {
+        let buffer = self.buffers.get_mut(&sector).unwrap();
+
+        self.lru.remove(&buffer.last_used);
+        self.clock += 1;
+        buffer.last_used = self.clock;
+        self.lru.insert(self.clock, sector);
+    }
+
+    fn insert(&mut self, sector: u64, data: &[u8; SECTOR_SIZE], dirty: bool) {
+        self.clock += 1;
+        self.lru.insert(self.clock, sector);
+
+        let buffer = Buffer {
+            data: Box::new(*data),
+            dirty,
+            last_used: self.clock,
+        };
+        if let Some(old) = self.buffers.insert(sector, buffer) {
+            self.lru.remove(&old.last_used);
+        }
+    }
+}
+
+impl BufferCache {
+    pub fn new(device: Arc<dyn BlockDevice>, capacity: usize) -> Self {
+        assert!(
+            capacity > 0,
+            "a buffer cache needs room for at least one sector"
+        );
+
+        BufferCache {
+            device,
+            capacity,
+            state: Mutex::new(State {
+                buffers: BTreeMap::new(),
+                lru: BTreeMap::new(),
+                clock: 0,
+                stats: CacheStats::default(),
+            }),
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.state.lock().stats
+    }
+
+    /// How many cached sectors haven't been written to the device yet
+    pub fn dirty_sectors(&self) -> usize {
+        self.state
+            .lock()
+            .buffers
+            .values()
+            .filter(|buffer| buffer.dirty)
+            .count()
+    }
+
+    /// Evicts the least recently used sectors until the cache fits, writing them back if they are dirty. A sector that
+    /// can't be written back is kept.
+    fn evict(&self, state: &mut State) -> Result<(), BlockError> {
+        while state.buffers.len() > self.capacity {
+            let (_, sector) = state.lru.pop_first().unwrap();
+            let buffer = state.buffers.remove(&sector).unwrap();
+
+            if buffer.dirty {
+                if let Err(error) = self.device.write_sectors(sector, &*buffer.data) {
+                    state.lru.insert(buffer.last_used, sector);
+                    state.buffers.insert(sector, buffer);
+                    return Err(error);
+                }
+
+                state.stats.write_backs += 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes back every dirty sector, in runs of consecutive sectors
+    fn write_back(&self, state: &mut State) -> Result<(), BlockError> {
+        let dirty: Vec<u64> = state
+            .buffers
+            .iter()
+            .filter(|(_, buffer)| buffer.dirty)
+            .map(|(&sector, _)| sector)
+            .collect();
+
+        for run in dirty.chunk_by(|a, b| a + 1 == *b) {
+            let mut data = Vec::with_capacity(run.len() * SECTOR_SIZE);
+            for sector in run {
+                data.extend_from_slice(&*state.buffers[sector].data);
+            }
+
+            self.device.write_sectors(run[0], &data)?;
+
+            for sector in run {
+                state.buffers.get_mut(sector).unwrap().dirty = false;
+            }
+            state.stats.write_backs += run.len() as u64;
+        }
+
+        Ok(())
+    }
+}
+
+impl BlockDevice for BufferCache {
+    fn sector_count(&self) -> u64 {
+        self.device.sector_count()
+    }
+
+    fn read_sectors(&self, start: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self, start, buf.len())?;
+
+        let mut state = self.state.lock();
+        let chunks = buf.as_chunks_mut::<SECTOR_SIZE>().0;
+        let mut index = 0;
+
+        while index < chunks.len() {
+            let sector = start + index as u64;
+
+            if let Some(buffer) = state.buffers.get(&sector) {
+                chunks[index].copy_from_slice(&*buffer.data);
+                state.touch(sector);
+                state.stats.hits += 1;
+                index += 1;
+                continue;
+            }
+
+            // Read every sector up to the next cached one at once
+            let misses = (index..chunks.len())
+                .take_while(|&index| !state.buffers.contains_key(&(start + index as u64)))
+                .count();
+            let run = &mut chunks[index..index + misses];
+
+            self.device.read_sectors(sector, run.as_flattened_mut())?;
+
+            for (offset, data) in run.iter().enumerate() {
+                state.insert(sector + offset as u64, data, false);
+            }
+            state.stats.misses += misses as u64;
+            index += misses;
+        }
+
+        self.evict(&mut state)
+    }
+
+    fn write_sectors(&self, start: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check_request(self, start, buf.len())?;
+
+        let mut state = self.state.lock();
+
+        for (sector, data) in (start..).zip(buf.as_chunks::<SECTOR_SIZE>().0) {
+            match state.buffers.get_mut(&sector) {
+                Some(buffer) => {
+                    buffer.data.copy_from_slice(data);
+                    buffer.dirty = true;
+                    state.touch(sector);
+                }
+                None => state.insert(sector, data, true),
+            }
+
+            // Evicting as we go keeps a big write from filling the heap
+            self.evict(&mut state)?;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        let mut state = self.state.lock();
+        self.write_back(&mut state)?;
+        self.device.flush()
+    }
+}
+
+impl Drop for BufferCache {
+    fn drop(&mut self) {
+        if let Err(error) = self.flush() {
+            log::error!("Writes were lost flushing a buffer cache: {:?}", error);
+        }
+    }
+}
@@ -0,0 +1,422 @@
+//! Partition tables. `read_partitions` finds the partitions on a disk from its MBR, following the chain of EBRs in an
+//! extended partition, or from its GPT if the MBR is a protective one. Each partition is a block device of its own
+//! which a filesystem can be mounted from.
+
+use crate::block::{check_request, BlockDevice, BlockError, SECTOR_SIZE};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+const MBR_ENTRIES_OFFSET: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+
+const MBR_TYPE_GPT_PROTECTIVE: u8 = 0xee;
+const MBR_TYPES_EXTENDED: [u8; 3] = [0x05, 0x0f, 0x85];
+
+/// Logical partitions are numbered from 5, after the four primary ones
+const FIRST_LOGICAL_NUMBER: usize = 5;
+/// Stops a loop in the EBR chain
+const MAX_LOGICAL_PARTITIONS: usize = 128;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_MIN_HEADER_SIZE: usize = 92;
+const GPT_MIN_ENTRY_SIZE: usize = 128;
+/// Far more than the 128 entries tools create, it only stops a damaged header making us read the whole disk
+const GPT_MAX_ENTRIES_SIZE: usize = 1024 * 1024;
+const GPT_NAME_LENGTH: usize = 36;
+
+/// A GUID in the mixed endian order GPT stores them in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Guid([u8; 16]);
+
+impl Guid {
+    pub const UNUSED: Guid = Guid([0; 16]);
+    pub const EFI_SYSTEM: Guid = Guid::new(
+        0xc12a7328,
+        0xf81f,
+        0x11d2,
+        [0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e, 0xc9, 0x3b],
+    );
+    /// Microsoft basic data, which FAT volumes usually have
+    pub const BASIC_DATA: Guid = Guid::new(
+        0xebd0a0a2,
+        0xb9e5,
+        0x4433,
+        [0x87, 0xc0, 0x68, 0xb6, 0xb7, 0x26, 0x99, 0xc7],
+    );
+    pub const LINUX_FILESYSTEM: Guid = Guid::new(
+        0x0fc63daf,
+        0x8483,
+        0x4772,
+        [0x8e, 0x79, 0x3d, 0x69, 0xd8, 0x47, 0x7d, 0xe4],
+    );
+
+    /// From the fields of its text form, eg. `Guid::new(0x0fc63daf, 0x8483, 0x4772, [0x8e, 0x79, ...])` for
+    /// 0fc63daf-8483-4772-8e79-...
+    pub const fn new(a: u32, b: u16, c: u16, d: [u8; 8]) -> Self {
+        let a = a.to_le_bytes();
+        let b = b.to_le_bytes();
+        let c = c.to_le_bytes();
+
+        Guid([
+            a[0], a[1], a[2], a[3], b[0], b[1], c[0], c[1], d[0], d[1], d[2], d[3], d[4], d[5],
+            d[6], d[7],
+        ])
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        Guid(bytes[..16].try_into().unwrap())
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bytes = &self.0;
+
+        write!(
+            f,
+            "{:08x}-{:04x}-{:04x}-",
+            u32::from_le_bytes(bytes[0..4].try_into().unwrap()),
+            u16::from_le_bytes([bytes[4], bytes[5]]),
+            u16::from_le_bytes([bytes[6], bytes[7]]),
+        )?;
+
+        for (index, byte) in bytes[8..].iter().enumerate() {
+            if index == 2 {
+                write!(f, "-")?;
+            }
+            write!(f, "{:02x}", byte)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PartitionKind {
+    /// The system ID from the MBR entry
+    Mbr(u8),
+    Gpt {
+        type_guid: Guid,
+        name: String,
+    },
+}
+
+/// The sectors of a disk that belong to a partition
+pub struct Partition {
+    device: Arc<dyn BlockDevice>,
+    number: usize,
+    start: u64,
+    sector_count: u64,
+    kind: PartitionKind,
+}
+
+impl Partition {
+    /// The number the partition would have on Linux, eg. 1 for `sda1`
+    pub fn number(&self) -> usize {
+        self.number
+    }
+
+    /// The first sector of the partition on the disk
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    pub fn kind(&self) -> &PartitionKind {
+        &self.kind
+    }
+}
+
+impl BlockDevice for Partition {
+    fn sector_count(&self) -> u64 {
+        self.sector_count
+    }
+
+    fn read_sectors(&self, start: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self, start, buf.len())?;
+        self.device.read_sectors(self.start + start, buf)
+    }
+
+    fn write_sectors(&self, start: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check_request(self, start, buf.len())?;
+        self.device.write_sectors(self.start + start, buf)
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.device.flush()
+    }
+}
+
+/// The partitions on a disk in the order of their numbers, or none if it doesn't have a partition table
+pub fn read_partitions(device: &Arc<dyn BlockDevice>) -> Result<Vec<Partition>, BlockError> {
+    if device.sector_count() == 0 {
+        return Ok(Vec::new());
+    }
+
+    let mut mbr = [0; SECTOR_SIZE];
+    device.read_sectors(0, &mut mbr)?;
+
+    let entries = match mbr_entries(&mbr) {
+        Some(entries) => entries,
+        None => return Ok(Vec::new()),
+    };
+
+    if entries
+        .iter()
+        .any(|entry| entry.system_id == MBR_TYPE_GPT_PROTECTIVE)
+    {
+        return read_gpt(device);
+    }
+
+    let mut partitions = Vec::new();
+
+    for (index, entry) in entries.iter().enumerate() {
+        if entry.system_id == 0 {
+            continue;
+        }
+
+        let partition = new_partition(
+            device,
+            index + 1,
+            entry.start,
+            entry.sector_count,
+            PartitionKind::Mbr(entry.system_id),
+        )?;
+
+        if MBR_TYPES_EXTENDED.contains(&entry.system_id) {
+            read_logical_partitions(device, &partition, &mut partitions)?;
+        } else {
+            partitions.push(partition);
+        }
+    }
+
+    partitions.sort_by_key(|partition| partition.number);
+    Ok(partitions)
+}
+
+fn new_partition(
+    device: &Arc<dyn BlockDevice>,
+    number: usize,
+    start: u64,
+    sector_count: u64,
+    kind: PartitionKind,
+) -> Result<Partition, BlockError> {
+    let inside = start
+        .checked_add(sector_count)
+        .is_some_and(|end| sector_count != 0 && end <= device.sector_count());
+
+    if !inside {
+        return Err(BlockError::InvalidPartitionTable);
+    }
+
+    Ok(Partition {
+        device: device.clone(),
+        number,
+        start,
+        sector_count,
+        kind,
+    })
+}
+
+struct MbrEntry {
+    system_id: u8,
+    /// Relative to the start of the disk for the MBR, but not for an EBR
+    start: u64,
+    sector_count: u64,
+}
+
+/// The four entries of an MBR or EBR, `None` if the sector doesn't hold one
+fn mbr_entries(sector: &[u8; SECTOR_SIZE]) -> Option<[MbrEntry; 4]> {
+    if sector[510..] != MBR_SIGNATURE {
+        return None;
+    }
+
+    let entry =
+        |index: usize| &sector[MBR_ENTRIES_OFFSET + index * MBR_ENTRY_SIZE..][..MBR_ENTRY_SIZE];
+
+    // A filesystem's boot sector has the same signature, but its boot code won't have only 0 or 0x80 where the boot
+    // flags go
+    if (0..4).any(|index| entry(index)[0] & 0x7f != 0) {
+        return None;
+    }
+
+    Some(core::array::from_fn(|index| {
+        let entry = entry(index);
+
+        MbrEntry {
+            system_id: entry[4],
+            start: u32::from_le_bytes(entry[8..12].try_into().unwrap()) as u64,
+            sector_count: u32::from_le_bytes(entry[12..16].try_into().unwrap()) as u64,
+        }
+    }))
+}
+
+/// Follows the chain of EBRs in an extended partition. Each holds a logical partition relative to itself and a link to
+/// the next EBR relative to the start of the extended partition.
+fn read_logical_partitions(
+    device: &Arc<dyn BlockDevice>,
+    extended: &Partition,
+    partitions: &mut Vec<Partition>,
+) -> Result<(), BlockError> {
+    let mut ebr_start = 0;
+
+    for number in FIRST_LOGICAL_NUMBER..FIRST_LOGICAL_NUMBER + MAX_LOGICAL_PARTITIONS {
+        let mut ebr = [0; SECTOR_SIZE];
+        extended.read_sectors(ebr_start, &mut ebr)?;
+
+        let [logical, next, ..] = mbr_entries(&ebr).ok_or(BlockError::InvalidPartitionTable)?;
+
+        if logical.system_id != 0 {
+            let start = extended.start + ebr_start + logical.start;
+            partitions.push(new_partition(
+                device,
+                number,
+                start,
+                logical.sector_count,
+                PartitionKind::Mbr(logical.system_id),
+            )?);
+        }
+
+        // Links only go forwards, so a chain can't go round in a circle
+        if next.system_id == 0 {
+            return Ok(());
+        } else if next.start <= ebr_start || next.start >= extended.sector_count {
+            return Err(BlockError::InvalidPartitionTable);
+        }
+
+        ebr_start = next.start;
+    }
+
+    Err(BlockError::InvalidPartitionTable)
+}
+
+/// Reads the partitions from the GPT, using the backup at the end of the disk if the primary is damaged
+fn read_gpt(device: &Arc<dyn BlockDevice>) -> Result<Vec<Partition>, BlockError> {
+    let (entry_size, entries) = match read_gpt_at(&**device, 1)? {
+        Some(table) => table,
+        None => read_gpt_at(&**device, device.sector_count() - 1)?
+            .ok_or(BlockError::InvalidPartitionTable)?,
+    };
+
+    let mut partitions = Vec::new();
+
+    for (index, entry) in entries.chunks_exact(entry_size).enumerate() {
+        let type_guid = Guid::from_bytes(&entry[0..16]);
+        if type_guid == Guid::UNUSED {
+            continue;
+        }
+
+        // The last sector is inclusive
+        let first = u64::from_le_bytes(entry[32..40].try_into().unwrap());
+        let last = u64::from_le_bytes(entry[40..48].try_into().unwrap());
+        if last < first {
+            return Err(BlockError::InvalidPartitionTable);
+        }
+
+        let name = entry[56..56 + GPT_NAME_LENGTH * 2]
+            .as_chunks::<2>()
+            .0
+            .iter()
+            .map(|&unit| u16::from_le_bytes(unit))
+            .take_while(|&unit| unit != 0);
+        let name = char::decode_utf16(name)
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect();
+
+        partitions.push(new_partition(
+            device,
+            index + 1,
+            first,
+            last - first + 1,
+            PartitionKind::Gpt { type_guid, name },
+        )?);
+    }
+
+    Ok(partitions)
+}
+
+/// The entry size and entries of the GPT header at `lba`, `None` if it or its entries are damaged
+fn read_gpt_at(device: &dyn BlockDevice, lba: u64) -> Result<Option<(usize, Vec<u8>)>, BlockError> {
+    let mut header = [0; SECTOR_SIZE];
+    device.read_sectors(lba, &mut header)?;
+
+    let u32_at = |offset: usize| u32::from_le_bytes(header[offset..offset + 4].try_into().unwrap());
+    let u64_at = |offset: usize| u64::from_le_bytes(header[offset..offset + 8].try_into().unwrap());
+
+    let header_size = u32_at(12) as usize;
+    if &header[..8] != GPT_SIGNATURE || !(GPT_MIN_HEADER_SIZE..=SECTOR_SIZE).contains(&header_size)
+    {
+        return Ok(None);
+    }
+
+    // The header's checksum is calculated with the checksum field zeroed
+    let mut checked = header;
+    checked[16..20].fill(0);
+    if crc32(&checked[..header_size]) != u32_at(16) || u64_at(24) != lba {
+        return Ok(None);
+    }
+
+    let entries_lba = u64_at(72);
+    let entry_count = u32_at(80) as usize;
+    let entry_size = u32_at(84) as usize;
+
+    let entries_size = entry_count
+        .checked_mul(entry_size)
+        .filter(|&size| size <= GPT_MAX_ENTRIES_SIZE);
+    let entries_size = match entries_size {
+        Some(size) if entry_size >= GPT_MIN_ENTRY_SIZE && entry_size.is_multiple_of(8) => size,
+        _ => return Ok(None),
+    };
+
+    let sectors = entries_size.div_ceil(SECTOR_SIZE) as u64;
+    if entries_lba
+        .checked_add(sectors)
+        .is_none_or(|end| end > device.sector_count())
+    {
+        return Ok(None);
+    }
+
+    let mut entries = vec![0; sectors as usize * SECTOR_SIZE];
+    device.read_sectors(entries_lba, &mut entries)?;
+    entries.truncate(entries_size);
+
+    if crc32(&entries) != u32_at(88) {
+        return Ok(None);
+    }
+
+    Ok(Some((entry_size, entries)))
+}
+
+/// The CRC-32 used by GPT, the same as zlib and Ethernet
+fn crc32(data: &[u8]) -> u32 {
+    const TABLE: [u32; 256] = {
+        let mut table = [0; 256];
+        let mut index = 0;
+
+        while index < 256 {
+            let mut crc = index as u32;
+            let mut bit = 0;
+
+            while bit < 8 {
+                crc = if crc & 1 != 0 {
+                    (crc >> 1) ^ 0xedb88320
+                } else {
+                    crc >> 1
+                };
+                bit += 1;
+            }
+
+            table[index] = crc;
+            index += 1;
+        }
+
+        table
+    };
+
+    !data.iter().fold(!0, |crc, &byte| {
+        (crc >> 8) ^ TABLE[((crc ^ byte as u32) & 0xff) as usize]
+    })
+}
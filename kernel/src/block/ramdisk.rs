@@ -0,0 +1,71 @@
+//! A block device in memory. It can start out as a copy of an image (eg. a file in the initrd), in which case only the
+//! sectors that are written are copied to the heap. Images can be shorter than the disk, the rest reads as zeros.
+
+use crate::block::{check_request, BlockDevice, BlockError, SECTOR_SIZE};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use spin::RwLock;
+
+pub struct RamDisk {
+    image: &'static [u8],
+    sector_count: u64,
+    written: RwLock<BTreeMap<u64, Box<[u8; SECTOR_SIZE]>>>,
+}
+
+impl RamDisk {
+    /// An empty disk
+    pub fn new(sector_count: u64) -> Self {
+        RamDisk::from_image(&[], sector_count)
+    }
+
+    pub fn from_image(image: &'static [u8], sector_count: u64) -> Self {
+        RamDisk {
+            image,
+            sector_count,
+            written: RwLock::new(BTreeMap::new()),
+        }
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn sector_count(&self) -> u64 {
+        self.sector_count
+    }
+
+    fn read_sectors(&self, start: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self, start, buf.len())?;
+
+        let written = self.written.read();
+
+        for (sector, chunk) in (start..).zip(buf.as_chunks_mut::<SECTOR_SIZE>().0) {
+            if let Some(data) = written.get(&sector) {
+                chunk.copy_from_slice(&**data);
+                continue;
+            }
+
+            let offset = sector as usize * SECTOR_SIZE;
+            let from_image = self.image.get(offset..).unwrap_or(&[]);
+            let length = from_image.len().min(SECTOR_SIZE);
+
+            chunk[..length].copy_from_slice(&from_image[..length]);
+            chunk[length..].fill(0);
+        }
+
+        Ok(())
+    }
+
+    fn write_sectors(&self, start: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check_request(self, start, buf.len())?;
+
+        let mut written = self.written.write();
+
+        for (sector, chunk) in (start..).zip(buf.as_chunks::<SECTOR_SIZE>().0) {
+            let data = written
+                .entry(sector)
+                .or_insert_with(|| Box::new([0; SECTOR_SIZE]));
+            data.copy_from_slice(chunk);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,169 @@
+//! The kernel command line, read from `cmdline` in the initrd. It is a whitespace separated list of `key=value`
+//! options:
+//!
+//! - `log=<level>` sets the level of every log sink
+//! - `log.debugcon=<level>`, `log.console=<level>` and `log.serial=<level>` set one sink (defaults `trace`, `info`
+//!   and `debug`)
+//! - `timer_hz=<n>` is how many times a second the LAPIC timer fires (default 100)
+//! - `heap_size=<n>[K|M]` is the size of the kernel heap, at least 64K (default 100K)
+//! - `tmpfs_size=<n>[K|M]` is how much file data `/tmp` can hold, which comes out of the heap (default 32K)
+//! - `keyboard=on|off` loads the PS/2 keyboard driver
+//! - `serial=on|off` loads the COM1/COM2 driver
+//! - `gdb=on|off` runs the GDB stub on COM2, which needs `serial=on` (default off)
+//!
+//! Levels are `off`, `error`, `warn`, `info`, `debug` or `trace`. Invalid options are logged and ignored.
+
+use crate::logger::LoggerConfig;
+use crate::memory::virtual_addresses::{HEAP_START, LAPIC_START};
+use conquer_once::spin::OnceCell;
+use core::ops::RangeInclusive;
+use log::LevelFilter;
+
+static BOOT_CONFIG: OnceCell<BootConfig> = OnceCell::uninit();
+
+/// Less than this and the kernel runs out of heap before it has finished booting, more doesn't fit in the heap's
+/// address space
+const HEAP_SIZES: RangeInclusive<usize> = 64 * 1024..=LAPIC_START - HEAP_START;
+
+#[derive(Clone, Copy, Debug)]
+pub struct BootConfig {
+    pub log: LoggerConfig,
+    pub timer_hz: u32,
+    pub heap_size: usize,
+    pub tmpfs_size: usize,
+    pub keyboard: bool,
+    pub serial: bool,
+    pub gdb: bool,
+}
+
+impl Default for BootConfig {
+    fn default() -> Self {
+        BootConfig {
+            log: LoggerConfig::default(),
+            timer_hz: 100,
+            heap_size: 100 * 1024,
+            tmpfs_size: 32 * 1024,
+            keyboard: true,
+            serial: true,
+            gdb: false,
+        }
+    }
+}
+
+impl BootConfig {
+    /// Parses a command line, skipping any invalid options (see `invalid_options`)
+    pub fn parse(cmdline: &str) -> Self {
+        let mut config = BootConfig::default();
+
+        for option in cmdline.split_whitespace() {
+            let _ = config.apply(option);
+        }
+
+        config
+    }
+
+    /// Every option in `cmdline` which `parse` skipped
+    pub fn invalid_options(cmdline: &str) -> impl Iterator<Item = &str> {
+        let mut config = BootConfig::default();
+
+        cmdline
+            .split_whitespace()
+            .filter(move |option| config.apply(option).is_none())
+    }
+
+    fn apply(&mut self, option: &str) -> Option<()> {
+        let (key, value) = option.split_once('=')?;
+
+        match key {
+            "log" => {
+                let level = value.parse().ok()?;
+                self.log = LoggerConfig {
+                    debug_port: level,
+                    console: level,
+                    serial: level,
+                };
+            }
+            "log.debugcon" => self.log.debug_port = value.parse::<LevelFilter>().ok()?,
+            "log.console" => self.log.console = value.parse::<LevelFilter>().ok()?,
+            "log.serial" => self.log.serial = value.parse::<LevelFilter>().ok()?,
+            "timer_hz" => self.timer_hz = value.parse().ok().filter(|&hz| hz > 0)?,
+            "heap_size" => {
+                self.heap_size = parse_size(value).filter(|size| HEAP_SIZES.contains(size))?
+            }
+            "tmpfs_size" => self.tmpfs_size = parse_size(value)?,
+            "keyboard" => self.keyboard = parse_switch(value)?,
+            "serial" => self.serial = parse_switch(value)?,
+            "gdb" => self.gdb = parse_switch(value)?,
+            _ => return None,
+        }
+
+        Some(())
+    }
+}
+
+fn parse_switch(value: &str) -> Option<bool> {
+    match value {
+        "on" | "1" | "true" => Some(true),
+        "off" | "0" | "false" => Some(false),
+        _ => None,
+    }
+}
+
+/// A number of bytes with an optional `K` or `M` suffix
+fn parse_size(value: &str) -> Option<usize> {
+    let (digits, multiplier) = match value.as_bytes().last()? {
+        b'K' | b'k' => (&value[..value.len() - 1], 1024),
+        b'M' | b'm' => (&value[..value.len() - 1], 1024 * 1024),
+        _ => (value, 1),
+    };
+
+    digits.parse::<usize>().ok()?.checked_mul(multiplier)
+}
+
+pub(crate) fn init(cmdline: &str) -> &'static BootConfig {
+    BOOT_CONFIG.init_once(|| BootConfig::parse(cmdline));
+    BOOT_CONFIG.get().unwrap()
+}
+
+/// The configuration the kernel was booted with, the defaults are used until `init` has parsed the command line
+pub fn get() -> BootConfig {
+    BOOT_CONFIG.get().copied().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn parses_options() {
+        let config =
+            BootConfig::parse("log=warn log.serial=trace timer_hz=250 heap_size=2M keyboard=off");
+
+        assert_eq!(config.log.debug_port, LevelFilter::Warn);
+        assert_eq!(config.log.serial, LevelFilter::Trace);
+        assert_eq!(config.timer_hz, 250);
+        assert_eq!(config.heap_size, 2 * 1024 * 1024);
+        assert!(!config.keyboard);
+        assert!(config.serial);
+        assert!(!config.gdb);
+        assert!(BootConfig::parse("gdb=on").gdb);
+    }
+
+    #[test_case]
+    fn skips_invalid_options() {
+        let cmdline = "timer_hz=0 bogus=1 heap_size=128K heap_size=0 heap_size=12K heap_size=1000000M noequals";
+        let config = BootConfig::parse(cmdline);
+
+        assert_eq!(config.timer_hz, BootConfig::default().timer_hz);
+        assert_eq!(config.heap_size, 128 * 1024);
+
+        let mut invalid = BootConfig::invalid_options(cmdline);
+        assert_eq!(invalid.next(), Some("timer_hz=0"));
+        assert_eq!(invalid.next(), Some("bogus=1"));
+        assert_eq!(invalid.next(), Some("heap_size=0"));
+        assert_eq!(invalid.next(), Some("heap_size=12K"));
+        assert_eq!(invalid.next(), Some("heap_size=1000000M"));
+        assert_eq!(invalid.next(), Some("noequals"));
+        assert_eq!(invalid.next(), None);
+    }
+}
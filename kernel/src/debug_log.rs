@@ -1,21 +1,40 @@
 use crate::io::drivers::display::gop_buffer::WRITER;
+use crate::io::drivers::serial::COM1;
+use crate::io::interrupts;
 use core::fmt;
 use core::fmt::Write;
+use core::str;
 use lazy_static::lazy_static;
+use log::{Level, LevelFilter};
 use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
 use x86_64::instructions::port::Port;
 
+/// Number of lines kept in `LOG_BUFFER` before the oldest are overwritten
+pub const LOG_BUFFER_LINES: usize = 256;
+/// Lines longer than this are truncated when stored in `LOG_BUFFER`
+pub const LOG_LINE_LENGTH: usize = 160;
+
+/// Every line printed or logged since boot (up to `LOG_BUFFER_LINES`), so that output from before a sink existed can
+/// be replayed to it and so that it can be read back later (like `dmesg`)
+pub static LOG_BUFFER: Mutex<LogBuffer> = Mutex::new(LogBuffer::new());
+
 pub struct DebugPort {
     port: Port<u8>,
 }
 
 lazy_static! {
-    pub static ref DEBUG_PORT: Mutex<DebugPort> = Mutex::new(DebugPort {
-        port: Port::new(0xe9)
-    });
+    pub static ref DEBUG_PORT: Mutex<DebugPort> = Mutex::new(DebugPort::new());
 }
 
 impl DebugPort {
+    /// Only the panic handler should need a second instance, everything else should go through `DEBUG_PORT`
+    pub fn new() -> Self {
+        DebugPort {
+            port: Port::new(0xe9),
+        }
+    }
+
     pub fn write_byte(&mut self, character: u8) {
         unsafe { self.port.write(character) }
     }
@@ -27,6 +46,12 @@ impl DebugPort {
     }
 }
 
+impl Default for DebugPort {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl fmt::Write for DebugPort {
     fn write_str(&mut self, s: &str) -> fmt::Result {
         self.write_string(s);
@@ -34,6 +59,160 @@ impl fmt::Write for DebugPort {
     }
 }
 
+#[derive(Clone, Copy)]
+pub struct LogLine {
+    ticks: u64,
+    level: Option<Level>,
+    len: usize,
+    text: [u8; LOG_LINE_LENGTH],
+}
+
+impl LogLine {
+    const EMPTY: LogLine = LogLine {
+        ticks: 0,
+        level: None,
+        len: 0,
+        text: [0; LOG_LINE_LENGTH],
+    };
+
+    /// LAPIC timer ticks when the line was started
+    pub fn ticks(&self) -> u64 {
+        self.ticks
+    }
+
+    /// `None` for output from `print!`, otherwise the level of the `log` record
+    pub fn level(&self) -> Option<Level> {
+        self.level
+    }
+
+    pub fn text(&self) -> &str {
+        let text = &self.text[..self.len];
+
+        // Truncation may have split a character
+        str::from_utf8(text).unwrap_or_else(|error| {
+            str::from_utf8(&text[..error.valid_up_to()]).unwrap_or_default()
+        })
+    }
+
+    fn push_str(&mut self, s: &str) {
+        let len = s.len().min(LOG_LINE_LENGTH - self.len);
+        self.text[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+    }
+}
+
+/// A ring buffer of lines. Each line gets a sequence number so readers can keep their place while it wraps.
+pub struct LogBuffer {
+    lines: [LogLine; LOG_BUFFER_LINES],
+    /// Sequence number of the next complete line
+    next_sequence: u64,
+    partial: Option<LogLine>,
+}
+
+impl LogBuffer {
+    const fn new() -> Self {
+        LogBuffer {
+            lines: [LogLine::EMPTY; LOG_BUFFER_LINES],
+            next_sequence: 0,
+            partial: None,
+        }
+    }
+
+    /// Appends `s`, starting a new line after every `\n`. Output without a trailing newline is kept until the line is
+    /// finished.
+    pub fn write(&mut self, level: Option<Level>, s: &str) {
+        let mut lines = s.split('\n').peekable();
+
+        while let Some(text) = lines.next() {
+            let line = self.partial.get_or_insert(LogLine {
+                ticks: interrupts::ticks(),
+                level,
+                ..LogLine::EMPTY
+            });
+            line.push_str(text);
+
+            // Every piece except the last was followed by a newline
+            if lines.peek().is_some() {
+                let line = self.partial.take().unwrap();
+                self.lines[(self.next_sequence % LOG_BUFFER_LINES as u64) as usize] = line;
+                self.next_sequence += 1;
+            }
+        }
+    }
+
+    /// Sequence number of the oldest line still in the buffer
+    pub fn first_sequence(&self) -> u64 {
+        self.next_sequence.saturating_sub(LOG_BUFFER_LINES as u64)
+    }
+
+    /// One past the sequence number of the newest line
+    pub fn end_sequence(&self) -> u64 {
+        self.next_sequence
+    }
+
+    /// Returns the line with the given sequence number, if it hasn't been overwritten yet
+    pub fn get(&self, sequence: u64) -> Option<LogLine> {
+        if sequence < self.first_sequence() || sequence >= self.end_sequence() {
+            return None;
+        }
+
+        Some(self.lines[(sequence % LOG_BUFFER_LINES as u64) as usize])
+    }
+}
+
+struct LogBufferWriter<'a> {
+    log_buffer: &'a mut LogBuffer,
+    level: Option<Level>,
+}
+
+impl fmt::Write for LogBufferWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.log_buffer.write(self.level, s);
+        Ok(())
+    }
+}
+
+/// Interrupt handlers log too, so `LOG_BUFFER` is only held with interrupts off
+pub(crate) fn record(level: Option<Level>, args: fmt::Arguments) {
+    without_interrupts(|| {
+        LogBufferWriter {
+            log_buffer: &mut LOG_BUFFER.lock(),
+            level,
+        }
+        .write_fmt(args)
+        .unwrap()
+    });
+}
+
+/// Writes every line in `LOG_BUFFER` which passes `filter` to a newly registered sink. Lines from `print!` only pass
+/// if `include_print_output` is set, since they bypass the logger.
+///
+/// `LOG_BUFFER` is only locked while copying each line out, so `writer` may take other locks.
+pub fn replay(writer: &mut impl fmt::Write, filter: LevelFilter, include_print_output: bool) {
+    let (mut sequence, end_sequence) = without_interrupts(|| {
+        let log_buffer = LOG_BUFFER.lock();
+        (log_buffer.first_sequence(), log_buffer.end_sequence())
+    });
+
+    while sequence < end_sequence {
+        let line = without_interrupts(|| LOG_BUFFER.lock().get(sequence));
+
+        if let Some(line) = line {
+            let passes = match line.level() {
+                Some(level) => level <= filter,
+                None => include_print_output,
+            };
+
+            if passes {
+                writer.write_str(line.text()).unwrap();
+                writer.write_char('\n').unwrap();
+            }
+        }
+
+        sequence += 1;
+    }
+}
+
 #[macro_export]
 macro_rules! print {
     ($($arg:tt)*) => ($crate::debug_log::_print(format_args!($($arg)*)));
@@ -47,10 +226,16 @@ macro_rules! println {
 
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
-    DEBUG_PORT.lock().write_fmt(args).unwrap();
+    record(None, args);
 
-    let mut writer = WRITER.lock();
-    if let Some(writer) = writer.as_mut() {
-        writer.write_fmt(args).unwrap();
-    }
+    // The same locks as the logger's sinks, which interrupt handlers can take
+    without_interrupts(|| {
+        DEBUG_PORT.lock().write_fmt(args).unwrap();
+        COM1.lock().write_fmt(args).unwrap();
+
+        let mut writer = WRITER.lock();
+        if let Some(writer) = writer.as_mut() {
+            writer.write_fmt(args).unwrap();
+        }
+    });
 }
@@ -0,0 +1,650 @@
+//! The virtual filesystem. Every filesystem implements `Filesystem` and `Inode`, and is mounted on a directory of
+//! another one (or at `/`). Paths are always absolute and are resolved through the mount table, so callers don't need
+//! to know which filesystem a file lives on.
+//!
+//! `.` and `..` are resolved lexically before a path is looked up, so `..` after a symlink to a directory goes back
+//! to the directory the symlink is in.
+
+use crate::block::BlockError;
+use crate::boot_config::BootConfig;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::fmt;
+use spin::RwLock;
+
+pub mod ext2;
+pub mod fat;
+pub mod initrd;
+pub mod tmpfs;
+
+/// How many symlinks a single lookup can follow, to stop loops
+const MAX_SYMLINK_DEPTH: usize = 8;
+
+pub type Result<T> = core::result::Result<T, FsError>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FsError {
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    NotASymlink,
+    AlreadyExists,
+    DirectoryNotEmpty,
+    ReadOnly,
+    /// The file wasn't opened for reading or writing
+    BadHandle,
+    InvalidPath,
+    /// eg. seeking before the start of a file
+    InvalidArgument,
+    /// A rename between two mounts
+    CrossDevice,
+    /// Something is mounted on the path
+    Busy,
+    TooManySymlinks,
+    NoSpace,
+    FileTooLarge,
+    NameTooLong,
+    /// The file was removed after it was looked up
+    StaleHandle,
+    /// The filesystem's on-disk structures are inconsistent
+    Corrupted,
+    /// The block device failed
+    Io,
+    Unsupported,
+}
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            FsError::NotFound => "no such file or directory",
+            FsError::NotADirectory => "not a directory",
+            FsError::IsADirectory => "is a directory",
+            FsError::NotASymlink => "not a symlink",
+            FsError::AlreadyExists => "file exists",
+            FsError::DirectoryNotEmpty => "directory not empty",
+            FsError::ReadOnly => "read-only filesystem",
+            FsError::BadHandle => "bad file handle",
+            FsError::InvalidPath => "invalid path",
+            FsError::InvalidArgument => "invalid argument",
+            FsError::CrossDevice => "cross-device link",
+            FsError::Busy => "device or resource busy",
+            FsError::TooManySymlinks => "too many levels of symbolic links",
+            FsError::NoSpace => "no space left on device",
+            FsError::FileTooLarge => "file too large",
+            FsError::NameTooLong => "file name too long",
+            FsError::StaleHandle => "stale file handle",
+            FsError::Corrupted => "filesystem is corrupted",
+            FsError::Io => "input/output error",
+            FsError::Unsupported => "operation not supported",
+        };
+
+        f.write_str(message)
+    }
+}
+
+impl From<BlockError> for FsError {
+    fn from(error: BlockError) -> Self {
+        match error {
+            // A filesystem only goes past the end of its device if its own structures say to
+            BlockError::OutOfRange => FsError::Corrupted,
+            BlockError::InvalidPartitionTable => FsError::Corrupted,
+            BlockError::ReadOnly => FsError::ReadOnly,
+            BlockError::InvalidBuffer | BlockError::Io => FsError::Io,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Directory,
+    Symlink,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Metadata {
+    pub file_type: FileType,
+    /// In bytes, 0 for directories on filesystems that don't track their size
+    pub size: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub file_type: FileType,
+}
+
+pub trait Filesystem: Send + Sync {
+    /// Shown in the mount table, eg. `tmpfs`
+    fn name(&self) -> &'static str;
+
+    fn root(&self) -> Arc<dyn Inode>;
+
+    /// Writes out anything cached, called before the filesystem is unmounted
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// A file, directory or symlink. Operations which don't apply to the inode's type (eg. `lookup` on a file) return
+/// `NotADirectory` and friends, and the modifying ones default to `ReadOnly`.
+pub trait Inode: Send + Sync {
+    fn metadata(&self) -> Result<Metadata>;
+
+    /// The child called `name`, never `.` or `..`
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>>;
+
+    /// Every child apart from `.` and `..`
+    fn read_dir(&self) -> Result<Vec<DirEntry>>;
+
+    fn open(&self) -> Result<Arc<dyn File>>;
+
+    fn read_link(&self) -> Result<String> {
+        Err(FsError::NotASymlink)
+    }
+
+    /// Creates an empty file or directory called `name`
+    fn create(&self, _name: &str, _file_type: FileType) -> Result<Arc<dyn Inode>> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn symlink(&self, _name: &str, _target: &str) -> Result<Arc<dyn Inode>> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// Removes a file, symlink or empty directory
+    fn unlink(&self, _name: &str) -> Result<()> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// Moves `old_name` to `new_name` in `new_parent`, which is always on the same filesystem, replacing it if it
+    /// exists and is not a non-empty directory
+    fn rename(&self, _old_name: &str, _new_parent: &Arc<dyn Inode>, _new_name: &str) -> Result<()> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// Lets `rename` get back to the concrete type of `new_parent`
+    fn as_any(&self) -> &dyn Any;
+}
+
+/// The contents of an open file, shared by every `FileHandle` to it
+pub trait File: Send + Sync {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize>;
+
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> Result<usize> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn size(&self) -> Result<u64>;
+
+    /// Shrinks or zero extends the file
+    fn truncate(&self, _size: u64) -> Result<()> {
+        Err(FsError::ReadOnly)
+    }
+}
+
+struct Mount {
+    /// Normalised components, empty for `/`
+    path: Vec<String>,
+    filesystem: Arc<dyn Filesystem>,
+}
+
+#[derive(Clone, Debug)]
+pub struct MountInfo {
+    pub path: String,
+    pub filesystem: &'static str,
+}
+
+/// Sorted with the longest paths first so the first match is the most specific
+static MOUNTS: RwLock<Vec<Mount>> = RwLock::new(Vec::new());
+
+/// Mounts `filesystem` on `path`, which has to be an existing directory unless nothing has been mounted at `/` yet
+pub fn mount(path: &str, filesystem: Arc<dyn Filesystem>) -> Result<()> {
+    let components = normalise(path)?;
+
+    let first_mount = MOUNTS.read().is_empty() && components.is_empty();
+
+    if !first_mount && lookup(path)?.metadata()?.file_type != FileType::Directory {
+        return Err(FsError::NotADirectory);
+    }
+
+    let mut mounts = MOUNTS.write();
+
+    if mounts.iter().any(|mount| mount.path == components) {
+        return Err(FsError::Busy);
+    }
+
+    log::debug!("Mounted {} on {}", filesystem.name(), join(&components));
+
+    mounts.push(Mount {
+        path: components,
+        filesystem,
+    });
+    mounts.sort_by_key(|mount| core::cmp::Reverse(mount.path.len()));
+
+    Ok(())
+}
+
+/// Syncs and removes the filesystem mounted on `path`. Fails if another filesystem is mounted inside it.
+pub fn unmount(path: &str) -> Result<()> {
+    let components = normalise(path)?;
+    let mut mounts = MOUNTS.write();
+
+    let index = mounts
+        .iter()
+        .position(|mount| mount.path == components)
+        .ok_or(FsError::InvalidPath)?;
+
+    if mounts
+        .iter()
+        .any(|mount| mount.path.len() > components.len() && mount.path.starts_with(&components))
+    {
+        return Err(FsError::Busy);
+    }
+
+    mounts[index].filesystem.sync()?;
+    mounts.remove(index);
+
+    Ok(())
+}
+
+pub fn mounts() -> Vec<MountInfo> {
+    MOUNTS
+        .read()
+        .iter()
+        .rev()
+        .map(|mount| MountInfo {
+            path: join(&mount.path),
+            filesystem: mount.filesystem.name(),
+        })
+        .collect()
+}
+
+/// Syncs every mounted filesystem
+pub fn sync() -> Result<()> {
+    for mount in MOUNTS.read().iter() {
+        mount.filesystem.sync()?;
+    }
+
+    Ok(())
+}
+
+/// Splits an absolute path into its components, resolving `.` and `..`
+fn normalise(path: &str) -> Result<Vec<String>> {
+    if !path.starts_with('/') {
+        return Err(FsError::InvalidPath);
+    }
+
+    let mut components: Vec<String> = Vec::new();
+
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            name => components.push(name.to_string()),
+        }
+    }
+
+    Ok(components)
+}
+
+fn join(components: &[String]) -> String {
+    if components.is_empty() {
+        return "/".to_string();
+    }
+
+    components
+        .iter()
+        .fold(String::new(), |mut path, component| {
+            path.push('/');
+            path.push_str(component);
+            path
+        })
+}
+
+/// The filesystem mounted closest to a path, and how many of the path's components lead to its mount point
+fn find_mount(components: &[String]) -> Result<(Arc<dyn Filesystem>, usize)> {
+    MOUNTS
+        .read()
+        .iter()
+        .find(|mount| components.starts_with(&mount.path))
+        .map(|mount| (mount.filesystem.clone(), mount.path.len()))
+        .ok_or(FsError::NotFound)
+}
+
+/// Walks `components` from the root of the mount they are on, following symlinks (the last one only if
+/// `follow_last` is set)
+fn resolve(components: Vec<String>, follow_last: bool, depth: usize) -> Result<Arc<dyn Inode>> {
+    let (filesystem, mount_depth) = find_mount(&components)?;
+    let mut inode = filesystem.root();
+
+    for index in mount_depth..components.len() {
+        let child = inode.lookup(&components[index])?;
+        let is_last = index == components.len() - 1;
+
+        if child.metadata()?.file_type == FileType::Symlink && (!is_last || follow_last) {
+            if depth == MAX_SYMLINK_DEPTH {
+                return Err(FsError::TooManySymlinks);
+            }
+
+            // Relative targets are relative to the directory the link is in
+            let target = child.read_link()?;
+            let mut path = if target.starts_with('/') {
+                target
+            } else {
+                let mut path = join(&components[..index]);
+                path.push('/');
+                path.push_str(&target);
+                path
+            };
+
+            for component in &components[index + 1..] {
+                path.push('/');
+                path.push_str(component);
+            }
+
+            return resolve(normalise(&path)?, follow_last, depth + 1);
+        }
+
+        inode = child;
+    }
+
+    Ok(inode)
+}
+
+/// The inode at `path`, following symlinks
+pub fn lookup(path: &str) -> Result<Arc<dyn Inode>> {
+    resolve(normalise(path)?, true, 0)
+}
+
+/// Like `lookup` but returns a symlink itself rather than what it points to
+pub fn lookup_no_follow(path: &str) -> Result<Arc<dyn Inode>> {
+    resolve(normalise(path)?, false, 0)
+}
+
+/// The directory `path` is in (with symlinks followed) and the last component of it
+fn resolve_parent(path: &str) -> Result<(Arc<dyn Inode>, String)> {
+    let mut components = normalise(path)?;
+    let name = components.pop().ok_or(FsError::InvalidPath)?;
+    let parent = resolve(components, true, 0)?;
+
+    if parent.metadata()?.file_type != FileType::Directory {
+        return Err(FsError::NotADirectory);
+    }
+
+    Ok((parent, name))
+}
+
+/// Mount points can't be removed or renamed
+fn check_not_mount_point(path: &str) -> Result<()> {
+    let components = normalise(path)?;
+
+    if MOUNTS.read().iter().any(|mount| mount.path == components) {
+        return Err(FsError::Busy);
+    }
+
+    Ok(())
+}
+
+pub fn metadata(path: &str) -> Result<Metadata> {
+    lookup(path)?.metadata()
+}
+
+pub fn read_dir(path: &str) -> Result<Vec<DirEntry>> {
+    lookup(path)?.read_dir()
+}
+
+pub fn read_link(path: &str) -> Result<String> {
+    lookup_no_follow(path)?.read_link()
+}
+
+pub fn create_dir(path: &str) -> Result<()> {
+    let (parent, name) = resolve_parent(path)?;
+    parent.create(&name, FileType::Directory).map(|_| ())
+}
+
+pub fn symlink(target: &str, path: &str) -> Result<()> {
+    let (parent, name) = resolve_parent(path)?;
+    parent.symlink(&name, target).map(|_| ())
+}
+
+/// Removes a file, symlink or empty directory
+pub fn remove(path: &str) -> Result<()> {
+    check_not_mount_point(path)?;
+
+    let (parent, name) = resolve_parent(path)?;
+    parent.unlink(&name)
+}
+
+pub fn rename(from: &str, to: &str) -> Result<()> {
+    check_not_mount_point(from)?;
+    check_not_mount_point(to)?;
+
+    let (from_filesystem, _) = find_mount(&normalise(from)?)?;
+    let (to_filesystem, _) = find_mount(&normalise(to)?)?;
+
+    if !Arc::ptr_eq(&from_filesystem, &to_filesystem) {
+        return Err(FsError::CrossDevice);
+    }
+
+    let (old_parent, old_name) = resolve_parent(from)?;
+    let (new_parent, new_name) = resolve_parent(to)?;
+
+    old_parent.rename(&old_name, &new_parent, &new_name)
+}
+
+/// How a file is opened, in the style of `std::fs::OpenOptions`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OpenOptions {
+    read: bool,
+    write: bool,
+    append: bool,
+    create: bool,
+    truncate: bool,
+}
+
+impl OpenOptions {
+    pub fn new() -> Self {
+        OpenOptions::default()
+    }
+
+    pub fn read(&mut self, read: bool) -> &mut Self {
+        self.read = read;
+        self
+    }
+
+    pub fn write(&mut self, write: bool) -> &mut Self {
+        self.write = write;
+        self
+    }
+
+    /// Every write goes to the end of the file, implies `write`
+    pub fn append(&mut self, append: bool) -> &mut Self {
+        self.append = append;
+        self
+    }
+
+    /// Create the file if it doesn't exist
+    pub fn create(&mut self, create: bool) -> &mut Self {
+        self.create = create;
+        self
+    }
+
+    /// Empty the file when it is opened
+    pub fn truncate(&mut self, truncate: bool) -> &mut Self {
+        self.truncate = truncate;
+        self
+    }
+
+    pub fn open(&self, path: &str) -> Result<FileHandle> {
+        let writable = self.write || self.append;
+
+        let inode = match lookup(path) {
+            Ok(inode) => inode,
+            Err(FsError::NotFound) if self.create => {
+                let (parent, name) = resolve_parent(path)?;
+                parent.create(&name, FileType::File)?
+            }
+            Err(error) => return Err(error),
+        };
+
+        if inode.metadata()?.file_type == FileType::Directory {
+            return Err(FsError::IsADirectory);
+        }
+
+        let file = inode.open()?;
+
+        if self.truncate && writable {
+            file.truncate(0)?;
+        }
+
+        Ok(FileHandle {
+            file,
+            offset: 0,
+            readable: self.read,
+            writable,
+            append: self.append,
+        })
+    }
+}
+
+/// Opens `path` for reading
+pub fn open(path: &str) -> Result<FileHandle> {
+    OpenOptions::new().read(true).open(path)
+}
+
+/// Opens `path` for writing, creating it or emptying it
+pub fn create(path: &str) -> Result<FileHandle> {
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+}
+
+/// Reads the whole of `path`
+pub fn read(path: &str) -> Result<Vec<u8>> {
+    let mut file = open(path)?;
+    let mut contents = alloc::vec![0; file.size()? as usize];
+    let read = file.read(&mut contents)?;
+    contents.truncate(read);
+
+    Ok(contents)
+}
+
+/// Replaces the contents of `path`, creating it if needed
+pub fn write(path: &str, contents: &[u8]) -> Result<()> {
+    create(path)?.write_all(contents)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(u64),
+    End(i64),
+    Current(i64),
+}
+
+/// An open file with its own offset
+pub struct FileHandle {
+    file: Arc<dyn File>,
+    offset: u64,
+    readable: bool,
+    writable: bool,
+    append: bool,
+}
+
+impl FileHandle {
+    /// Reads from the current offset and advances it, returning 0 at the end of the file
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if !self.readable {
+            return Err(FsError::BadHandle);
+        }
+
+        let mut total = 0;
+
+        // A file may return less than asked for, eg. when a read crosses a block
+        while total < buf.len() {
+            let read = self.file.read_at(self.offset, &mut buf[total..])?;
+            if read == 0 {
+                break;
+            }
+
+            self.offset += read as u64;
+            total += read;
+        }
+
+        Ok(total)
+    }
+
+    pub fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if !self.writable {
+            return Err(FsError::BadHandle);
+        }
+
+        if self.append {
+            self.offset = self.file.size()?;
+        }
+
+        let written = self.file.write_at(self.offset, buf)?;
+        self.offset += written as u64;
+
+        Ok(written)
+    }
+
+    pub fn write_all(&mut self, mut buf: &[u8]) -> Result<()> {
+        while !buf.is_empty() {
+            let written = self.write(buf)?;
+            if written == 0 {
+                return Err(FsError::NoSpace);
+            }
+
+            buf = &buf[written..];
+        }
+
+        Ok(())
+    }
+
+    /// Moves the offset, which can go past the end of the file. Returns the new offset.
+    pub fn seek(&mut self, position: SeekFrom) -> Result<u64> {
+        let (base, delta) = match position {
+            SeekFrom::Start(offset) => (0, offset as i64),
+            SeekFrom::End(delta) => (self.file.size()?, delta),
+            SeekFrom::Current(delta) => (self.offset, delta),
+        };
+
+        self.offset = base
+            .checked_add_signed(delta)
+            .ok_or(FsError::InvalidArgument)?;
+
+        Ok(self.offset)
+    }
+
+    pub fn size(&self) -> Result<u64> {
+        self.file.size()
+    }
+
+    pub fn set_len(&mut self, size: u64) -> Result<()> {
+        if !self.writable {
+            return Err(FsError::BadHandle);
+        }
+
+        self.file.truncate(size)
+    }
+}
+
+/// Mounts the initrd at `/` and a tmpfs on `/tmp`, called once the heap is available
+pub(crate) fn init(config: &BootConfig) {
+    if let Some(initrd) = crate::initrd::get() {
+        mount("/", Arc::new(initrd::InitrdFs::new(initrd))).expect("mounting the initrd failed");
+    }
+
+    // Every inode takes a little heap too, so they are limited to roughly one per 256 bytes of data
+    let tmpfs = tmpfs::Tmpfs::new(config.tmpfs_size, config.tmpfs_size / 256 + 16);
+    if let Err(error) = mount("/tmp", Arc::new(tmpfs)) {
+        log::warn!("Couldn't mount a tmpfs on /tmp: {}", error);
+    }
+}
@@ -0,0 +1,651 @@
+//! ext2, read and write, on any `BlockDevice`. Files can be sparse and use up to triple indirect blocks, and symlinks
+//! are supported. Timestamps are left at 0 as there is no clock yet, and the `dir_index` hash tree of a directory is
+//! dropped when it is changed (its entries are still valid without it).
+//!
+//! Like the FAT driver, nothing is cached apart from the block group descriptors, and every operation reads what it
+//! needs under one lock per volume. Inodes are freed as soon as their last link is removed, so a file which is
+//! removed while open has to be opened again.
+
+mod dir;
+mod inode;
+
+use crate::block::{self, BlockDevice};
+use crate::fs::{Filesystem, FsError, Inode, Result};
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use inode::{DiskInode, Ext2Inode};
+use spin::Mutex;
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const MAGIC: u16 = 0xef53;
+const ROOT_INODE: u32 = 2;
+
+/// The first inode and inode size of revision 0 filesystems, later ones say in the superblock
+const GOOD_OLD_FIRST_INODE: u32 = 11;
+const GOOD_OLD_INODE_SIZE: u64 = 128;
+
+const GROUP_DESCRIPTOR_SIZE: u64 = 32;
+
+/// Directory entries hold the file type
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+/// Backup superblocks are only in some groups and files can be bigger than 4 GiB, neither of which changes how the
+/// volume is read or written
+const RO_COMPAT_SPARSE_SUPER: u32 = 0x0001;
+const RO_COMPAT_LARGE_FILE: u32 = 0x0002;
+
+pub struct Ext2Fs {
+    volume: Arc<Volume>,
+}
+
+impl Ext2Fs {
+    /// Mounts the ext2 volume on `device`. Fails with `Corrupted` if it doesn't have a valid superblock and
+    /// `Unsupported` if it uses features we don't understand, but features which only matter for writing (read-only
+    /// compatible ones in ext2 terms) just make it read only.
+    pub fn new(device: Arc<dyn BlockDevice>) -> Result<Self> {
+        Ok(Ext2Fs {
+            volume: Arc::new(Volume::new(device)?),
+        })
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.volume.block_size as usize
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.volume.read_only
+    }
+
+    pub fn free_blocks(&self) -> u32 {
+        self.volume.state.lock().free_blocks
+    }
+
+    pub fn free_inodes(&self) -> u32 {
+        self.volume.state.lock().free_inodes
+    }
+}
+
+impl Filesystem for Ext2Fs {
+    fn name(&self) -> &'static str {
+        "ext2"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(Ext2Inode::new(self.volume.clone(), ROOT_INODE))
+    }
+
+    fn sync(&self) -> Result<()> {
+        let mut state = self.volume.state.lock();
+        self.volume.write_superblock(&mut state)?;
+        Ok(self.volume.device.flush()?)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct GroupDescriptor {
+    block_bitmap: u32,
+    inode_bitmap: u32,
+    inode_table: u32,
+    free_blocks: u16,
+    free_inodes: u16,
+    used_directories: u16,
+}
+
+/// The group descriptors and the superblock's free counts. Inodes and bitmaps are read from the disk as they're needed
+/// rather than cached, so an operation holds this lock from loading an inode until its changes are on the disk, and two
+/// can't take the same free block or inode.
+struct State {
+    groups: Vec<GroupDescriptor>,
+    free_blocks: u32,
+    free_inodes: u32,
+    /// The free counts in the superblock need writing
+    superblock_dirty: bool,
+}
+
+struct Volume {
+    device: Arc<dyn BlockDevice>,
+    block_size: u64,
+    /// Block numbers in an indirect block
+    pointers_per_block: u64,
+    blocks_count: u32,
+    first_data_block: u32,
+    blocks_per_group: u32,
+    inodes_per_group: u32,
+    inode_size: u64,
+    first_inode: u32,
+    filetype: bool,
+    large_file: bool,
+    read_only: bool,
+    state: Mutex<State>,
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+impl Volume {
+    fn new(device: Arc<dyn BlockDevice>) -> Result<Self> {
+        let mut superblock = [0; 1024];
+        block::read_bytes(&*device, SUPERBLOCK_OFFSET, &mut superblock)?;
+
+        if u16_at(&superblock, 56) != MAGIC {
+            return Err(FsError::Corrupted);
+        }
+
+        let inodes_count = u32_at(&superblock, 0);
+        let blocks_count = u32_at(&superblock, 4);
+        let first_data_block = u32_at(&superblock, 20);
+        let log_block_size = u32_at(&superblock, 24);
+        let blocks_per_group = u32_at(&superblock, 32);
+        let inodes_per_group = u32_at(&superblock, 40);
+        let revision = u32_at(&superblock, 76);
+
+        let (first_inode, inode_size, incompat, ro_compat) = match revision {
+            0 => (GOOD_OLD_FIRST_INODE, GOOD_OLD_INODE_SIZE, 0, 0),
+            _ => (
+                u32_at(&superblock, 84),
+                u16_at(&superblock, 88) as u64,
+                u32_at(&superblock, 96),
+                u32_at(&superblock, 100),
+            ),
+        };
+
+        if incompat & !INCOMPAT_FILETYPE != 0 {
+            return Err(FsError::Unsupported);
+        }
+
+        if log_block_size > 6 {
+            return Err(FsError::Corrupted);
+        }
+        let block_size = 1024 << log_block_size;
+
+        let valid = blocks_per_group != 0
+            && blocks_per_group as u64 <= block_size * 8
+            && inodes_per_group != 0
+            && inodes_per_group as u64 <= block_size * 8
+            && blocks_count > first_data_block
+            && (GOOD_OLD_INODE_SIZE..=block_size).contains(&inode_size)
+            && inode_size.is_power_of_two()
+            && first_inode > ROOT_INODE
+            && blocks_count as u64 * block_size
+                <= device.sector_count() * block::SECTOR_SIZE as u64;
+
+        if !valid {
+            return Err(FsError::Corrupted);
+        }
+
+        let group_count = (blocks_count - first_data_block).div_ceil(blocks_per_group);
+        if group_count as u64 * inodes_per_group as u64 != inodes_count as u64 {
+            return Err(FsError::Corrupted);
+        }
+
+        // The descriptor table is in the block after the superblock, and has to be inside the volume before it is
+        // worth allocating
+        let table_offset = (first_data_block as u64 + 1) * block_size;
+        let table_size = group_count as u64 * GROUP_DESCRIPTOR_SIZE;
+        if table_offset + table_size > blocks_count as u64 * block_size {
+            return Err(FsError::Corrupted);
+        }
+
+        let mut table = vec![0; table_size as usize];
+        block::read_bytes(&*device, table_offset, &mut table)?;
+
+        let groups: Vec<GroupDescriptor> = table
+            .as_chunks::<{ GROUP_DESCRIPTOR_SIZE as usize }>()
+            .0
+            .iter()
+            .map(|descriptor| GroupDescriptor {
+                block_bitmap: u32_at(descriptor, 0),
+                inode_bitmap: u32_at(descriptor, 4),
+                inode_table: u32_at(descriptor, 8),
+                free_blocks: u16_at(descriptor, 12),
+                free_inodes: u16_at(descriptor, 14),
+                used_directories: u16_at(descriptor, 16),
+            })
+            .collect();
+
+        let inode_table_blocks = (inodes_per_group as u64 * inode_size).div_ceil(block_size);
+        let in_volume = |block: u32, length: u64| {
+            block >= first_data_block && block as u64 + length <= blocks_count as u64
+        };
+        if !groups.iter().all(|group| {
+            in_volume(group.block_bitmap, 1)
+                && in_volume(group.inode_bitmap, 1)
+                && in_volume(group.inode_table, inode_table_blocks)
+        }) {
+            return Err(FsError::Corrupted);
+        }
+
+        Ok(Volume {
+            device,
+            block_size,
+            pointers_per_block: block_size / 4,
+            blocks_count,
+            first_data_block,
+            blocks_per_group,
+            inodes_per_group,
+            inode_size,
+            first_inode,
+            filetype: incompat & INCOMPAT_FILETYPE != 0,
+            large_file: ro_compat & RO_COMPAT_LARGE_FILE != 0,
+            read_only: ro_compat & !(RO_COMPAT_SPARSE_SUPER | RO_COMPAT_LARGE_FILE) != 0,
+            state: Mutex::new(State {
+                groups,
+                free_blocks: u32_at(&superblock, 12),
+                free_inodes: u32_at(&superblock, 16),
+                superblock_dirty: false,
+            }),
+        })
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only {
+            Err(FsError::ReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn write_superblock(&self, state: &mut State) -> Result<()> {
+        if !state.superblock_dirty {
+            return Ok(());
+        }
+
+        let mut counts = [0; 8];
+        counts[..4].copy_from_slice(&state.free_blocks.to_le_bytes());
+        counts[4..].copy_from_slice(&state.free_inodes.to_le_bytes());
+        block::write_bytes(&*self.device, SUPERBLOCK_OFFSET + 12, &counts)?;
+
+        state.superblock_dirty = false;
+        Ok(())
+    }
+
+    /// Writes the free and directory counts of a group descriptor
+    fn write_group(&self, state: &State, group: usize) -> Result<()> {
+        let descriptor = &state.groups[group];
+
+        let mut counts = [0; 6];
+        counts[..2].copy_from_slice(&descriptor.free_blocks.to_le_bytes());
+        counts[2..4].copy_from_slice(&descriptor.free_inodes.to_le_bytes());
+        counts[4..].copy_from_slice(&descriptor.used_directories.to_le_bytes());
+
+        let table = (self.first_data_block as u64 + 1) * self.block_size;
+        Ok(block::write_bytes(
+            &*self.device,
+            table + group as u64 * GROUP_DESCRIPTOR_SIZE + 12,
+            &counts,
+        )?)
+    }
+
+    fn read_block(&self, block: u32, buf: &mut [u8]) -> Result<()> {
+        if block < self.first_data_block || block >= self.blocks_count {
+            return Err(FsError::Corrupted);
+        }
+
+        Ok(block::read_bytes(
+            &*self.device,
+            block as u64 * self.block_size,
+            buf,
+        )?)
+    }
+
+    fn write_block(&self, block: u32, buf: &[u8]) -> Result<()> {
+        if block < self.first_data_block || block >= self.blocks_count {
+            return Err(FsError::Corrupted);
+        }
+
+        Ok(block::write_bytes(
+            &*self.device,
+            block as u64 * self.block_size,
+            buf,
+        )?)
+    }
+
+    fn inode_offset(&self, state: &State, number: u32) -> Result<u64> {
+        let index = number.checked_sub(1).ok_or(FsError::Corrupted)?;
+        let group = state
+            .groups
+            .get((index / self.inodes_per_group) as usize)
+            .ok_or(FsError::Corrupted)?;
+
+        Ok(group.inode_table as u64 * self.block_size
+            + (index % self.inodes_per_group) as u64 * self.inode_size)
+    }
+
+    fn read_inode(&self, state: &State, number: u32) -> Result<DiskInode> {
+        let mut raw = [0; inode::DISK_INODE_SIZE];
+        block::read_bytes(&*self.device, self.inode_offset(state, number)?, &mut raw)?;
+        Ok(DiskInode::new(raw))
+    }
+
+    /// Writes the part of the inode every revision has, anything after it is left alone
+    fn write_inode(&self, state: &State, number: u32, inode: &DiskInode) -> Result<()> {
+        Ok(block::write_bytes(
+            &*self.device,
+            self.inode_offset(state, number)?,
+            &inode.to_raw(),
+        )?)
+    }
+
+    /// Writes a newly allocated inode, clearing anything a previous one left after the part `write_inode` writes
+    fn write_new_inode(&self, state: &State, number: u32, inode: &DiskInode) -> Result<()> {
+        let offset = self.inode_offset(state, number)?;
+        block::write_bytes(&*self.device, offset, &vec![0; self.inode_size as usize])?;
+        self.write_inode(state, number, inode)
+    }
+
+    /// The biggest a file can be, limited by the block map and, without the `large_file` feature, to 2 GiB
+    fn max_file_size(&self) -> u64 {
+        let pointers = self.pointers_per_block;
+        let blocks = inode::DIRECT_BLOCKS as u64 + pointers + pointers.pow(2) + pointers.pow(3);
+        let size = blocks * self.block_size;
+
+        if self.large_file {
+            size
+        } else {
+            size.min(i32::MAX as u64)
+        }
+    }
+
+    fn group_of_inode(&self, number: u32) -> usize {
+        ((number - 1) / self.inodes_per_group) as usize
+    }
+
+    /// Finds a clear bit in a group's bitmap below `limit` and sets it
+    fn allocate_bit(&self, bitmap_block: u32, limit: u32) -> Result<Option<u32>> {
+        let mut bitmap = vec![0; self.block_size as usize];
+        self.read_block(bitmap_block, &mut bitmap)?;
+
+        let Some(bit) = (0..limit).find(|&bit| bitmap[bit as usize / 8] & (1 << (bit % 8)) == 0)
+        else {
+            return Ok(None);
+        };
+
+        bitmap[bit as usize / 8] |= 1 << (bit % 8);
+        self.write_block(bitmap_block, &bitmap)?;
+
+        Ok(Some(bit))
+    }
+
+    fn free_bit(&self, bitmap_block: u32, bit: u32) -> Result<()> {
+        let mut bitmap = vec![0; self.block_size as usize];
+        self.read_block(bitmap_block, &mut bitmap)?;
+
+        // Freeing something that is already free means the bitmaps don't match the inodes
+        if bitmap[bit as usize / 8] & (1 << (bit % 8)) == 0 {
+            return Err(FsError::Corrupted);
+        }
+
+        bitmap[bit as usize / 8] &= !(1 << (bit % 8));
+        self.write_block(bitmap_block, &bitmap)
+    }
+
+    /// Allocates a zeroed block, preferring the group `goal`
+    fn allocate_block(&self, state: &mut State, goal: usize) -> Result<u32> {
+        let group_count = state.groups.len();
+
+        for group in (goal..group_count).chain(0..goal) {
+            if state.groups[group].free_blocks == 0 {
+                continue;
+            }
+
+            // The last group can be shorter than the rest
+            let group_start = self.first_data_block + group as u32 * self.blocks_per_group;
+            let blocks = self.blocks_per_group.min(self.blocks_count - group_start);
+
+            let Some(bit) = self.allocate_bit(state.groups[group].block_bitmap, blocks)? else {
+                continue;
+            };
+
+            state.groups[group].free_blocks -= 1;
+            state.free_blocks = state.free_blocks.saturating_sub(1);
+            state.superblock_dirty = true;
+            self.write_group(state, group)?;
+
+            let block = group_start + bit;
+            self.write_block(block, &vec![0; self.block_size as usize])?;
+
+            return Ok(block);
+        }
+
+        Err(FsError::NoSpace)
+    }
+
+    fn free_block(&self, state: &mut State, block: u32) -> Result<()> {
+        if block < self.first_data_block || block >= self.blocks_count {
+            return Err(FsError::Corrupted);
+        }
+
+        let group = ((block - self.first_data_block) / self.blocks_per_group) as usize;
+        let bit = (block - self.first_data_block) % self.blocks_per_group;
+        self.free_bit(state.groups[group].block_bitmap, bit)?;
+
+        state.groups[group].free_blocks += 1;
+        state.free_blocks += 1;
+        state.superblock_dirty = true;
+        self.write_group(state, group)
+    }
+
+    /// Allocates an inode number, preferring the group `goal`. The caller writes the inode itself.
+    fn allocate_inode(&self, state: &mut State, goal: usize, directory: bool) -> Result<u32> {
+        let group_count = state.groups.len();
+
+        for group in (goal..group_count).chain(0..goal) {
+            if state.groups[group].free_inodes == 0 {
+                continue;
+            }
+
+            let Some(bit) =
+                self.allocate_bit(state.groups[group].inode_bitmap, self.inodes_per_group)?
+            else {
+                continue;
+            };
+
+            let number = group as u32 * self.inodes_per_group + bit + 1;
+
+            // The reserved inodes are always marked as used, so this is a broken bitmap
+            if number < self.first_inode {
+                return Err(FsError::Corrupted);
+            }
+
+            let descriptor = &mut state.groups[group];
+            descriptor.free_inodes -= 1;
+            if directory {
+                descriptor.used_directories += 1;
+            }
+
+            state.free_inodes = state.free_inodes.saturating_sub(1);
+            state.superblock_dirty = true;
+            self.write_group(state, group)?;
+
+            return Ok(number);
+        }
+
+        Err(FsError::NoSpace)
+    }
+
+    fn free_inode(&self, state: &mut State, number: u32, directory: bool) -> Result<()> {
+        let group = self.group_of_inode(number);
+        self.free_bit(
+            state.groups[group].inode_bitmap,
+            (number - 1) % self.inodes_per_group,
+        )?;
+
+        let descriptor = &mut state.groups[group];
+        descriptor.free_inodes += 1;
+        if directory {
+            descriptor.used_directories = descriptor.used_directories.saturating_sub(1);
+        }
+
+        state.free_inodes += 1;
+        state.superblock_dirty = true;
+        self.write_group(state, group)
+    }
+
+    /// Which of `i_block` holds logical block `index`, how many levels of indirect blocks are below it, and the
+    /// index relative to the start of that tree
+    fn block_path(&self, index: u64) -> Result<(usize, u32, u64)> {
+        let pointers = self.pointers_per_block;
+
+        if index < inode::DIRECT_BLOCKS as u64 {
+            return Ok((index as usize, 0, 0));
+        }
+
+        let mut relative = index - inode::DIRECT_BLOCKS as u64;
+        let mut span = pointers;
+
+        for level in 1..=3 {
+            if relative < span {
+                return Ok((inode::DIRECT_BLOCKS + level as usize - 1, level, relative));
+            }
+
+            relative -= span;
+            span *= pointers;
+        }
+
+        Err(FsError::FileTooLarge)
+    }
+
+    /// The block holding logical block `index` of `inode`, or 0 for a hole
+    fn map_block(&self, inode: &DiskInode, index: u64) -> Result<u32> {
+        let (slot, levels, relative) = self.block_path(index)?;
+        let mut block = inode.block[slot];
+
+        for level in (0..levels).rev() {
+            if block == 0 {
+                break;
+            }
+
+            let entry = (relative / self.pointers_per_block.pow(level)) % self.pointers_per_block;
+            let mut pointer = [0; 4];
+            block::read_bytes(
+                &*self.device,
+                block as u64 * self.block_size + entry * 4,
+                &mut pointer,
+            )?;
+            block = u32::from_le_bytes(pointer);
+        }
+
+        Ok(block)
+    }
+
+    /// Like `map_block`, but fills in a hole (and any indirect blocks needed) with zeroed blocks. `inode` is
+    /// updated for the caller to write.
+    fn map_or_allocate_block(
+        &self,
+        state: &mut State,
+        number: u32,
+        inode: &mut DiskInode,
+        index: u64,
+    ) -> Result<u32> {
+        let (slot, levels, relative) = self.block_path(index)?;
+        let goal = self.group_of_inode(number);
+        let sectors_per_block = (self.block_size / 512) as u32;
+
+        let mut block = inode.block[slot];
+        if block == 0 {
+            block = self.allocate_block(state, goal)?;
+            inode.block[slot] = block;
+            inode.blocks += sectors_per_block;
+        }
+
+        for level in (0..levels).rev() {
+            let entry = (relative / self.pointers_per_block.pow(level)) % self.pointers_per_block;
+            let entry_offset = block as u64 * self.block_size + entry * 4;
+
+            let mut pointer = [0; 4];
+            block::read_bytes(&*self.device, entry_offset, &mut pointer)?;
+            let mut next = u32::from_le_bytes(pointer);
+
+            if next == 0 {
+                next = self.allocate_block(state, goal)?;
+                inode.blocks += sectors_per_block;
+                block::write_bytes(&*self.device, entry_offset, &next.to_le_bytes())?;
+            }
+
+            block = next;
+        }
+
+        Ok(block)
+    }
+
+    /// Frees every block of `inode` from logical block `keep` on, including indirect blocks that are no longer
+    /// needed. `inode` is updated for the caller to write.
+    fn free_blocks_from(&self, state: &mut State, inode: &mut DiskInode, keep: u64) -> Result<()> {
+        for slot in 0..inode::DIRECT_BLOCKS {
+            if slot as u64 >= keep && inode.block[slot] != 0 {
+                self.free_block(state, inode.block[slot])?;
+                inode.block[slot] = 0;
+                inode.blocks -= (self.block_size / 512) as u32;
+            }
+        }
+
+        let mut start = inode::DIRECT_BLOCKS as u64;
+        let mut span = self.pointers_per_block;
+
+        for level in 1..=3 {
+            let slot = inode::DIRECT_BLOCKS + level as usize - 1;
+            let root = inode.block[slot];
+
+            if root != 0 && keep < start + span {
+                let keep_relative = keep.saturating_sub(start);
+                let freed = self.free_tree(state, root, level, keep_relative)?;
+                inode.blocks -= freed * (self.block_size / 512) as u32;
+
+                if keep_relative == 0 {
+                    inode.block[slot] = 0;
+                }
+            }
+
+            start += span;
+            span *= self.pointers_per_block;
+        }
+
+        Ok(())
+    }
+
+    /// Frees the blocks under the indirect block `block` from index `keep` on (relative to the start of the tree),
+    /// and `block` itself if `keep` is 0. Returns how many blocks were freed.
+    fn free_tree(&self, state: &mut State, block: u32, level: u32, keep: u64) -> Result<u32> {
+        let mut pointers = vec![0; self.block_size as usize];
+        self.read_block(block, &mut pointers)?;
+
+        let child_span = self.pointers_per_block.pow(level - 1);
+        let mut freed = 0;
+
+        for entry in 0..self.pointers_per_block {
+            let child = u32_at(&pointers, entry as usize * 4);
+            let child_start = entry * child_span;
+
+            if child == 0 || keep >= child_start + child_span {
+                continue;
+            }
+
+            let child_keep = keep.saturating_sub(child_start);
+
+            if level == 1 {
+                self.free_block(state, child)?;
+                freed += 1;
+            } else {
+                freed += self.free_tree(state, child, level - 1, child_keep)?;
+            }
+
+            if child_keep == 0 {
+                pointers[entry as usize * 4..entry as usize * 4 + 4].fill(0);
+            }
+        }
+
+        if keep == 0 {
+            self.free_block(state, block)?;
+            freed += 1;
+        } else {
+            self.write_block(block, &pointers)?;
+        }
+
+        Ok(freed)
+    }
+}
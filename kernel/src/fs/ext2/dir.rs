@@ -0,0 +1,313 @@
+//! Directory entries. A directory's blocks are filled with variable length entries, each holding an inode number, the
+//! length of the record (which can be longer than the entry, leaving free space after it), the name and, with the
+//! `filetype` feature, the type of the file. An inode number of 0 marks an unused record.
+
+use super::inode::{DiskInode, INDEX_FLAG};
+use super::{State, Volume};
+use crate::fs::{FileType, FsError, Result};
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+const HEADER_SIZE: usize = 8;
+const MAX_NAME_LENGTH: usize = 255;
+
+const TYPE_FILE: u8 = 1;
+const TYPE_DIRECTORY: u8 = 2;
+const TYPE_SYMLINK: u8 = 7;
+
+pub(super) struct Entry {
+    pub(super) inode: u32,
+    pub(super) name: String,
+    /// From the entry, `None` without the `filetype` feature or for special files
+    pub(super) file_type: Option<FileType>,
+    /// The block the entry is in and where in it
+    block: u32,
+    offset: usize,
+}
+
+/// How much of a record an entry with a `name_length` byte name needs
+fn entry_length(name_length: usize) -> usize {
+    (HEADER_SIZE + name_length).next_multiple_of(4)
+}
+
+fn type_code(file_type: FileType) -> u8 {
+    match file_type {
+        FileType::File => TYPE_FILE,
+        FileType::Directory => TYPE_DIRECTORY,
+        FileType::Symlink => TYPE_SYMLINK,
+    }
+}
+
+pub(super) fn validate_name(name: &str) -> Result<()> {
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\0']) {
+        return Err(FsError::InvalidPath);
+    }
+
+    if name.len() > MAX_NAME_LENGTH {
+        return Err(FsError::NameTooLong);
+    }
+
+    Ok(())
+}
+
+/// A record's inode, record length and name length
+fn record(volume: &Volume, data: &[u8], offset: usize) -> Result<(u32, usize, usize)> {
+    let header = data
+        .get(offset..offset + HEADER_SIZE)
+        .ok_or(FsError::Corrupted)?;
+
+    let inode = u32::from_le_bytes(header[..4].try_into().unwrap());
+    let record_length = u16::from_le_bytes([header[4], header[5]]) as usize;
+
+    // Without the filetype feature the name length is 16 bits
+    let name_length = if volume.filetype {
+        header[6] as usize
+    } else {
+        u16::from_le_bytes([header[6], header[7]]) as usize
+    };
+
+    let valid = record_length >= HEADER_SIZE
+        && record_length % 4 == 0
+        && offset + record_length <= data.len()
+        && (inode == 0 || HEADER_SIZE + name_length <= record_length);
+
+    if !valid {
+        return Err(FsError::Corrupted);
+    }
+
+    Ok((inode, record_length, name_length))
+}
+
+fn write_record(
+    volume: &Volume,
+    data: &mut [u8],
+    offset: usize,
+    inode: u32,
+    record_length: usize,
+    name: &str,
+    file_type: FileType,
+) {
+    data[offset..offset + 4].copy_from_slice(&inode.to_le_bytes());
+    data[offset + 4..offset + 6].copy_from_slice(&(record_length as u16).to_le_bytes());
+    data[offset + 6] = name.len() as u8;
+    data[offset + 7] = if volume.filetype {
+        type_code(file_type)
+    } else {
+        0
+    };
+    data[offset + HEADER_SIZE..offset + HEADER_SIZE + name.len()].copy_from_slice(name.as_bytes());
+}
+
+/// The blocks of a directory with their contents. Directories don't have holes.
+fn blocks(volume: &Volume, directory: &DiskInode) -> Result<Vec<(u32, Vec<u8>)>> {
+    let count = directory.size / volume.block_size;
+    let mut blocks = Vec::new();
+
+    for index in 0..count {
+        let block = volume.map_block(directory, index)?;
+        if block == 0 {
+            return Err(FsError::Corrupted);
+        }
+
+        let mut data = vec![0; volume.block_size as usize];
+        volume.read_block(block, &mut data)?;
+        blocks.push((block, data));
+    }
+
+    Ok(blocks)
+}
+
+/// Every entry in a directory, including `.` and `..`
+pub(super) fn entries(volume: &Volume, directory: &DiskInode) -> Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+
+    for (block, data) in blocks(volume, directory)? {
+        let mut offset = 0;
+
+        while offset < data.len() {
+            let (inode, record_length, name_length) = record(volume, &data, offset)?;
+
+            if inode != 0 {
+                let name = &data[offset + HEADER_SIZE..offset + HEADER_SIZE + name_length];
+                let file_type = match data[offset + 7] {
+                    _ if !volume.filetype => None,
+                    TYPE_FILE => Some(FileType::File),
+                    TYPE_DIRECTORY => Some(FileType::Directory),
+                    TYPE_SYMLINK => Some(FileType::Symlink),
+                    _ => None,
+                };
+
+                entries.push(Entry {
+                    inode,
+                    name: String::from_utf8_lossy(name).into_owned(),
+                    file_type,
+                    block,
+                    offset,
+                });
+            }
+
+            offset += record_length;
+        }
+    }
+
+    Ok(entries)
+}
+
+pub(super) fn find(volume: &Volume, directory: &DiskInode, name: &str) -> Result<Entry> {
+    entries(volume, directory)?
+        .into_iter()
+        .find(|entry| entry.name == name)
+        .ok_or(FsError::NotFound)
+}
+
+/// Whether a directory holds nothing but `.` and `..`
+pub(super) fn is_empty(volume: &Volume, directory: &DiskInode) -> Result<bool> {
+    Ok(entries(volume, directory)?
+        .iter()
+        .all(|entry| entry.name == "." || entry.name == ".."))
+}
+
+/// Adds an entry in the first gap big enough for it, adding a block to the directory if there isn't one.
+/// `directory` is updated for the caller to write.
+pub(super) fn insert(
+    volume: &Volume,
+    state: &mut State,
+    number: u32,
+    directory: &mut DiskInode,
+    name: &str,
+    inode: u32,
+    file_type: FileType,
+) -> Result<()> {
+    let needed = entry_length(name.len());
+
+    // Changing a directory makes its hash tree (if it has one) out of date
+    directory.flags &= !INDEX_FLAG;
+
+    for (block, mut data) in blocks(volume, directory)? {
+        let mut offset = 0;
+
+        while offset < data.len() {
+            let (existing, record_length, name_length) = record(volume, &data, offset)?;
+            let used = if existing == 0 {
+                0
+            } else {
+                entry_length(name_length)
+            };
+
+            if record_length - used >= needed {
+                if existing == 0 {
+                    write_record(
+                        volume,
+                        &mut data,
+                        offset,
+                        inode,
+                        record_length,
+                        name,
+                        file_type,
+                    );
+                } else {
+                    data[offset + 4..offset + 6].copy_from_slice(&(used as u16).to_le_bytes());
+                    write_record(
+                        volume,
+                        &mut data,
+                        offset + used,
+                        inode,
+                        record_length - used,
+                        name,
+                        file_type,
+                    );
+                }
+
+                return volume.write_block(block, &data);
+            }
+
+            offset += record_length;
+        }
+    }
+
+    let index = directory.size / volume.block_size;
+    let block = volume.map_or_allocate_block(state, number, directory, index)?;
+
+    let mut data = vec![0; volume.block_size as usize];
+    let length = data.len();
+    write_record(volume, &mut data, 0, inode, length, name, file_type);
+    volume.write_block(block, &data)?;
+
+    directory.size += volume.block_size;
+    Ok(())
+}
+
+/// Removes `entry`, giving its space to the entry before it. `directory` is updated for the caller to write.
+pub(super) fn remove(volume: &Volume, directory: &mut DiskInode, entry: &Entry) -> Result<()> {
+    let mut data = vec![0; volume.block_size as usize];
+    volume.read_block(entry.block, &mut data)?;
+
+    let (_, record_length, _) = record(volume, &data, entry.offset)?;
+
+    // The first record in a block can't be merged with anything, so is just marked unused
+    let mut previous = None;
+    let mut offset = 0;
+    while offset < entry.offset {
+        previous = Some(offset);
+        offset += record(volume, &data, offset)?.1;
+    }
+
+    match previous {
+        Some(previous) => {
+            let (_, previous_length, _) = record(volume, &data, previous)?;
+            let merged = (previous_length + record_length) as u16;
+            data[previous + 4..previous + 6].copy_from_slice(&merged.to_le_bytes());
+        }
+        None => data[entry.offset..entry.offset + 4].fill(0),
+    }
+
+    directory.flags &= !INDEX_FLAG;
+    volume.write_block(entry.block, &data)
+}
+
+/// Points an existing entry at another inode
+pub(super) fn retarget(
+    volume: &Volume,
+    entry: &Entry,
+    inode: u32,
+    file_type: FileType,
+) -> Result<()> {
+    let mut data = vec![0; volume.block_size as usize];
+    volume.read_block(entry.block, &mut data)?;
+
+    data[entry.offset..entry.offset + 4].copy_from_slice(&inode.to_le_bytes());
+    if volume.filetype {
+        data[entry.offset + 7] = type_code(file_type);
+    }
+
+    volume.write_block(entry.block, &data)
+}
+
+/// The first block of a new directory, holding `.` and `..`
+pub(super) fn new_directory_block(volume: &Volume, inode: u32, parent: u32) -> Vec<u8> {
+    let mut data = vec![0; volume.block_size as usize];
+    let dot_length = entry_length(1);
+
+    write_record(
+        volume,
+        &mut data,
+        0,
+        inode,
+        dot_length,
+        ".",
+        FileType::Directory,
+    );
+    let length = data.len() - dot_length;
+    write_record(
+        volume,
+        &mut data,
+        dot_length,
+        parent,
+        length,
+        "..",
+        FileType::Directory,
+    );
+
+    data
+}
@@ -0,0 +1,672 @@
+use super::dir;
+use super::{State, Volume, ROOT_INODE};
+use crate::block;
+use crate::fs::{DirEntry, File, FileType, FsError, Inode, Metadata, Result};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::any::Any;
+
+/// The part of an inode every revision has, bigger inodes have extra fields after it which we leave alone
+pub(super) const DISK_INODE_SIZE: usize = 128;
+pub(super) const DIRECT_BLOCKS: usize = 12;
+/// Direct blocks then single, double and triple indirect blocks
+const BLOCK_POINTERS: usize = DIRECT_BLOCKS + 3;
+
+const MODE_TYPE_MASK: u16 = 0xf000;
+const MODE_FILE: u16 = 0x8000;
+const MODE_DIRECTORY: u16 = 0x4000;
+const MODE_SYMLINK: u16 = 0xa000;
+
+/// There is no RTC driver yet so deleted inodes get this. A small deletion time would read as the next inode in the
+/// orphan list.
+const DELETION_TIME: u32 = u32::MAX;
+
+/// Set on directories with a hash tree index
+pub(super) const INDEX_FLAG: u32 = 0x1000;
+
+/// Symlink targets shorter than this are kept in the block pointers instead of a block
+const FAST_SYMLINK_LENGTH: usize = BLOCK_POINTERS * 4;
+
+#[derive(Clone)]
+pub(super) struct DiskInode {
+    pub(super) mode: u16,
+    pub(super) size: u64,
+    pub(super) links_count: u16,
+    /// In 512 byte units, including indirect blocks
+    pub(super) blocks: u32,
+    pub(super) flags: u32,
+    pub(super) block: [u32; BLOCK_POINTERS],
+    deletion_time: u32,
+    raw: [u8; DISK_INODE_SIZE],
+}
+
+impl DiskInode {
+    pub(super) fn new(raw: [u8; DISK_INODE_SIZE]) -> Self {
+        let u16_at = |offset: usize| u16::from_le_bytes([raw[offset], raw[offset + 1]]);
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(raw[offset..offset + 4].try_into().unwrap());
+
+        let mode = u16_at(0);
+
+        // The high half of the size is only used for files, in directories the field is something else
+        let size_high = if mode & MODE_TYPE_MASK == MODE_FILE {
+            u32_at(108)
+        } else {
+            0
+        };
+
+        DiskInode {
+            mode,
+            size: u32_at(4) as u64 | (size_high as u64) << 32,
+            links_count: u16_at(26),
+            blocks: u32_at(28),
+            flags: u32_at(32),
+            block: core::array::from_fn(|index| u32_at(40 + index * 4)),
+            deletion_time: u32_at(20),
+            raw,
+        }
+    }
+
+    /// A new inode, with the permissions every inode gets as there are no users yet
+    fn empty(file_type: FileType) -> Self {
+        let mode = match file_type {
+            FileType::File => MODE_FILE | 0o644,
+            FileType::Directory => MODE_DIRECTORY | 0o755,
+            FileType::Symlink => MODE_SYMLINK | 0o777,
+        };
+
+        let mut inode = DiskInode::new([0; DISK_INODE_SIZE]);
+        inode.mode = mode;
+        inode
+    }
+
+    pub(super) fn to_raw(&self) -> [u8; DISK_INODE_SIZE] {
+        let mut raw = self.raw;
+
+        raw[0..2].copy_from_slice(&self.mode.to_le_bytes());
+        raw[4..8].copy_from_slice(&(self.size as u32).to_le_bytes());
+        raw[20..24].copy_from_slice(&self.deletion_time.to_le_bytes());
+        raw[26..28].copy_from_slice(&self.links_count.to_le_bytes());
+        raw[28..32].copy_from_slice(&self.blocks.to_le_bytes());
+        raw[32..36].copy_from_slice(&self.flags.to_le_bytes());
+
+        for (index, block) in self.block.iter().enumerate() {
+            raw[40 + index * 4..44 + index * 4].copy_from_slice(&block.to_le_bytes());
+        }
+
+        if self.mode & MODE_TYPE_MASK == MODE_FILE {
+            raw[108..112].copy_from_slice(&((self.size >> 32) as u32).to_le_bytes());
+        }
+
+        raw
+    }
+
+    /// `None` for devices, sockets and pipes
+    fn file_type(&self) -> Option<FileType> {
+        match self.mode & MODE_TYPE_MASK {
+            MODE_FILE => Some(FileType::File),
+            MODE_DIRECTORY => Some(FileType::Directory),
+            MODE_SYMLINK => Some(FileType::Symlink),
+            _ => None,
+        }
+    }
+
+    /// A symlink with its target in the block pointers. Extended attributes can take a block of their own, so only
+    /// the size says for sure.
+    fn is_fast_symlink(&self) -> bool {
+        self.mode & MODE_TYPE_MASK == MODE_SYMLINK && (self.size as usize) < FAST_SYMLINK_LENGTH
+    }
+
+    fn fast_symlink_target(&self) -> Vec<u8> {
+        let mut target: Vec<u8> = self
+            .block
+            .iter()
+            .flat_map(|block| block.to_le_bytes())
+            .collect();
+        target.truncate(self.size as usize);
+        target
+    }
+
+    fn set_fast_symlink_target(&mut self, target: &[u8]) {
+        let mut bytes = [0; FAST_SYMLINK_LENGTH];
+        bytes[..target.len()].copy_from_slice(target);
+
+        self.block = core::array::from_fn(|index| {
+            u32::from_le_bytes(bytes[index * 4..index * 4 + 4].try_into().unwrap())
+        });
+        self.size = target.len() as u64;
+    }
+}
+
+#[derive(Clone)]
+pub(super) struct Ext2Inode {
+    volume: Arc<Volume>,
+    number: u32,
+}
+
+impl Ext2Inode {
+    pub(super) fn new(volume: Arc<Volume>, number: u32) -> Self {
+        Ext2Inode { volume, number }
+    }
+
+    /// Reads the inode, which has to be done again every time as it may have been changed through another
+    /// `Ext2Inode`
+    fn load(&self, state: &State) -> Result<DiskInode> {
+        let inode = self.volume.read_inode(state, self.number)?;
+
+        // The inode was removed since it was looked up
+        if inode.links_count == 0 {
+            return Err(FsError::NotFound);
+        }
+
+        Ok(inode)
+    }
+
+    fn load_directory(&self, state: &State) -> Result<DiskInode> {
+        let inode = self.load(state)?;
+
+        if inode.file_type() != Some(FileType::Directory) {
+            return Err(FsError::NotADirectory);
+        }
+
+        Ok(inode)
+    }
+
+    fn child(&self, number: u32) -> Ext2Inode {
+        Ext2Inode::new(self.volume.clone(), number)
+    }
+
+    fn downcast<'a>(&self, other: &'a Arc<dyn Inode>) -> Result<&'a Ext2Inode> {
+        other
+            .as_any()
+            .downcast_ref::<Ext2Inode>()
+            .filter(|other| Arc::ptr_eq(&self.volume, &other.volume))
+            .ok_or(FsError::CrossDevice)
+    }
+
+    fn read_data(&self, inode: &DiskInode, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        let volume = &self.volume;
+
+        if offset >= inode.size {
+            return Ok(0);
+        }
+
+        let length = (inode.size - offset).min(buf.len() as u64) as usize;
+        let mut done = 0;
+
+        while done < length {
+            let position = offset + done as u64;
+            let block_offset = position % volume.block_size;
+            let chunk = ((volume.block_size - block_offset) as usize).min(length - done);
+
+            match volume.map_block(inode, position / volume.block_size)? {
+                0 => buf[done..done + chunk].fill(0),
+                block => block::read_bytes(
+                    &*volume.device,
+                    block as u64 * volume.block_size + block_offset,
+                    &mut buf[done..done + chunk],
+                )?,
+            }
+
+            done += chunk;
+        }
+
+        Ok(length)
+    }
+
+    /// Writes `buf` at `offset`, allocating blocks as needed. `inode` is updated for the caller to write.
+    fn write_data(
+        &self,
+        state: &mut State,
+        inode: &mut DiskInode,
+        offset: u64,
+        buf: &[u8],
+    ) -> Result<()> {
+        let volume = &self.volume;
+        let mut done = 0;
+
+        while done < buf.len() {
+            let position = offset + done as u64;
+            let block_offset = position % volume.block_size;
+            let chunk = ((volume.block_size - block_offset) as usize).min(buf.len() - done);
+
+            let block = volume.map_or_allocate_block(
+                state,
+                self.number,
+                inode,
+                position / volume.block_size,
+            )?;
+            block::write_bytes(
+                &*volume.device,
+                block as u64 * volume.block_size + block_offset,
+                &buf[done..done + chunk],
+            )?;
+
+            done += chunk;
+        }
+
+        inode.size = inode.size.max(offset + buf.len() as u64);
+        Ok(())
+    }
+
+    /// Removes one link to inode `number`, freeing it and its blocks if it was the last. Directories only ever have
+    /// one link from a name, so lose all of them.
+    fn drop_link(&self, state: &mut State, number: u32) -> Result<()> {
+        let volume = &self.volume;
+        let mut inode = volume.read_inode(state, number)?;
+        let directory = inode.file_type() == Some(FileType::Directory);
+
+        inode.links_count = if directory {
+            0
+        } else {
+            inode.links_count.saturating_sub(1)
+        };
+
+        if inode.links_count == 0 {
+            if !inode.is_fast_symlink() {
+                volume.free_blocks_from(state, &mut inode, 0)?;
+            }
+
+            inode.deletion_time = DELETION_TIME;
+            volume.write_inode(state, number, &inode)?;
+            volume.free_inode(state, number, directory)?;
+        } else {
+            volume.write_inode(state, number, &inode)?;
+        }
+
+        Ok(())
+    }
+
+    /// Adds `links` to the link count of the directory `number`, for its subdirectories' `..`
+    fn adjust_links(&self, state: &State, number: u32, links: i16) -> Result<()> {
+        let mut inode = self.volume.read_inode(state, number)?;
+        inode.links_count = inode.links_count.saturating_add_signed(links);
+        self.volume.write_inode(state, number, &inode)
+    }
+
+    /// Allocates and writes a new inode, then links it into this directory as `name`
+    fn create_inode(
+        &self,
+        state: &mut State,
+        name: &str,
+        file_type: FileType,
+        fill: impl FnOnce(&mut State, u32, &mut DiskInode) -> Result<()>,
+    ) -> Result<Arc<dyn Inode>> {
+        let volume = &self.volume;
+        volume.check_writable()?;
+        dir::validate_name(name)?;
+
+        let mut directory = self.load_directory(state)?;
+        match dir::find(volume, &directory, name) {
+            Ok(_) => return Err(FsError::AlreadyExists),
+            Err(FsError::NotFound) => {}
+            Err(error) => return Err(error),
+        }
+
+        let is_directory = file_type == FileType::Directory;
+        let number =
+            volume.allocate_inode(state, volume.group_of_inode(self.number), is_directory)?;
+
+        let mut inode = DiskInode::empty(file_type);
+        inode.links_count = 1;
+
+        let linked = fill(state, number, &mut inode)
+            .and_then(|_| volume.write_new_inode(state, number, &inode))
+            .and_then(|_| {
+                dir::insert(
+                    volume,
+                    state,
+                    self.number,
+                    &mut directory,
+                    name,
+                    number,
+                    file_type,
+                )
+            });
+
+        if let Err(error) = linked {
+            // Give back whatever the new inode got before the failure, and mark it deleted in case it was written
+            if !inode.is_fast_symlink() {
+                volume.free_blocks_from(state, &mut inode, 0)?;
+            }
+            inode.links_count = 0;
+            inode.deletion_time = DELETION_TIME;
+            volume.write_inode(state, number, &inode)?;
+            volume.free_inode(state, number, is_directory)?;
+            return Err(error);
+        }
+
+        if is_directory {
+            directory.links_count += 1;
+        }
+        volume.write_inode(state, self.number, &directory)?;
+
+        Ok(Arc::new(self.child(number)))
+    }
+}
+
+impl Inode for Ext2Inode {
+    fn metadata(&self) -> Result<Metadata> {
+        let state = self.volume.state.lock();
+        let inode = self.load(&state)?;
+
+        Ok(Metadata {
+            file_type: inode.file_type().ok_or(FsError::Unsupported)?,
+            size: inode.size,
+        })
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>> {
+        let state = self.volume.state.lock();
+        let directory = self.load_directory(&state)?;
+
+        if name == "." || name == ".." {
+            return Err(FsError::InvalidPath);
+        }
+
+        let entry = dir::find(&self.volume, &directory, name)?;
+        Ok(Arc::new(self.child(entry.inode)))
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>> {
+        let state = self.volume.state.lock();
+        let directory = self.load_directory(&state)?;
+        let mut entries = Vec::new();
+
+        for entry in dir::entries(&self.volume, &directory)? {
+            if entry.name == "." || entry.name == ".." {
+                continue;
+            }
+
+            // Without a type in the entry the inode has it, special files are left out
+            let file_type = match entry.file_type {
+                Some(file_type) => file_type,
+                None => match self.volume.read_inode(&state, entry.inode)?.file_type() {
+                    Some(file_type) => file_type,
+                    None => continue,
+                },
+            };
+
+            entries.push(DirEntry {
+                name: entry.name,
+                file_type,
+            });
+        }
+
+        Ok(entries)
+    }
+
+    fn open(&self) -> Result<Arc<dyn File>> {
+        let state = self.volume.state.lock();
+
+        match self.load(&state)?.file_type() {
+            Some(FileType::File) => Ok(Arc::new(self.clone())),
+            Some(FileType::Directory) => Err(FsError::IsADirectory),
+            _ => Err(FsError::Unsupported),
+        }
+    }
+
+    fn read_link(&self) -> Result<String> {
+        let state = self.volume.state.lock();
+        let inode = self.load(&state)?;
+
+        if inode.file_type() != Some(FileType::Symlink) {
+            return Err(FsError::NotASymlink);
+        }
+
+        let target = if inode.is_fast_symlink() {
+            inode.fast_symlink_target()
+        } else {
+            let mut target = vec![0; inode.size as usize];
+            self.read_data(&inode, 0, &mut target)?;
+            target
+        };
+
+        String::from_utf8(target).map_err(|_| FsError::Corrupted)
+    }
+
+    fn create(&self, name: &str, file_type: FileType) -> Result<Arc<dyn Inode>> {
+        let mut state = self.volume.state.lock();
+
+        match file_type {
+            FileType::File => self.create_inode(&mut state, name, file_type, |_, _, _| Ok(())),
+            FileType::Directory => {
+                self.create_inode(&mut state, name, file_type, |state, number, inode| {
+                    let volume = &self.volume;
+                    let block = volume.map_or_allocate_block(state, number, inode, 0)?;
+
+                    inode.links_count = 2;
+                    inode.size = volume.block_size;
+                    volume.write_block(
+                        block,
+                        &dir::new_directory_block(volume, number, self.number),
+                    )
+                })
+            }
+            FileType::Symlink => Err(FsError::InvalidArgument),
+        }
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> Result<Arc<dyn Inode>> {
+        if target.is_empty() {
+            return Err(FsError::InvalidArgument);
+        }
+
+        if target.len() >= self.volume.block_size as usize {
+            return Err(FsError::NameTooLong);
+        }
+
+        let mut state = self.volume.state.lock();
+
+        self.create_inode(&mut state, name, FileType::Symlink, |state, _, inode| {
+            if target.len() < FAST_SYMLINK_LENGTH {
+                inode.set_fast_symlink_target(target.as_bytes());
+                Ok(())
+            } else {
+                self.write_data(state, inode, 0, target.as_bytes())
+            }
+        })
+    }
+
+    fn unlink(&self, name: &str) -> Result<()> {
+        let volume = &self.volume;
+        volume.check_writable()?;
+
+        let mut state = volume.state.lock();
+        let mut directory = self.load_directory(&state)?;
+
+        if name == "." || name == ".." {
+            return Err(FsError::InvalidPath);
+        }
+
+        let entry = dir::find(volume, &directory, name)?;
+        let child = volume.read_inode(&state, entry.inode)?;
+        let child_is_directory = child.file_type() == Some(FileType::Directory);
+
+        if child_is_directory && !dir::is_empty(volume, &child)? {
+            return Err(FsError::DirectoryNotEmpty);
+        }
+
+        dir::remove(volume, &mut directory, &entry)?;
+
+        // The child's `..` no longer links to this directory
+        if child_is_directory {
+            directory.links_count -= 1;
+        }
+        volume.write_inode(&state, self.number, &directory)?;
+
+        self.drop_link(&mut state, entry.inode)
+    }
+
+    fn rename(&self, old_name: &str, new_parent: &Arc<dyn Inode>, new_name: &str) -> Result<()> {
+        let new_parent = self.downcast(new_parent)?.number;
+        let volume = &self.volume;
+        volume.check_writable()?;
+        dir::validate_name(new_name)?;
+
+        let mut state = volume.state.lock();
+        let old_directory = self.load_directory(&state)?;
+        let new_directory = self.child(new_parent).load_directory(&state)?;
+
+        let entry = dir::find(volume, &old_directory, old_name)?;
+        let child = volume.read_inode(&state, entry.inode)?;
+        let child_type = child.file_type().ok_or(FsError::Unsupported)?;
+        let moving_directory = child_type == FileType::Directory;
+
+        // A directory can't be moved inside itself, so walk up from the destination to the root. A path to the root
+        // can't be longer than there are inodes, so a longer one means the `..` entries form a loop.
+        if moving_directory {
+            let mut ancestor = new_parent;
+            let inodes_count = state.groups.len() as u64 * volume.inodes_per_group as u64;
+
+            for _ in 0..inodes_count {
+                if ancestor == ROOT_INODE || ancestor == entry.inode {
+                    break;
+                }
+
+                let inode = volume.read_inode(&state, ancestor)?;
+                ancestor = dir::find(volume, &inode, "..")?.inode;
+            }
+
+            match ancestor {
+                ROOT_INODE => {}
+                ancestor if ancestor == entry.inode => return Err(FsError::InvalidArgument),
+                _ => return Err(FsError::Corrupted),
+            }
+        }
+
+        match dir::find(volume, &new_directory, new_name) {
+            // Both names are links to the same inode, which leaves nothing to do
+            Ok(existing) if existing.inode == entry.inode => return Ok(()),
+            Ok(existing) => {
+                let existing_inode = volume.read_inode(&state, existing.inode)?;
+
+                match (child_type, existing_inode.file_type()) {
+                    (FileType::Directory, Some(FileType::Directory))
+                        if !dir::is_empty(volume, &existing_inode)? =>
+                    {
+                        return Err(FsError::DirectoryNotEmpty)
+                    }
+                    (FileType::Directory, Some(FileType::Directory)) => {
+                        self.adjust_links(&state, new_parent, -1)?;
+                    }
+                    (FileType::Directory, _) => return Err(FsError::NotADirectory),
+                    (_, Some(FileType::Directory)) => return Err(FsError::IsADirectory),
+                    _ => {}
+                }
+
+                // Replacing the entry in place means the name never goes missing
+                dir::retarget(volume, &existing, entry.inode, child_type)?;
+                self.drop_link(&mut state, existing.inode)?;
+            }
+            Err(FsError::NotFound) => {
+                let mut new_directory = volume.read_inode(&state, new_parent)?;
+                dir::insert(
+                    volume,
+                    &mut state,
+                    new_parent,
+                    &mut new_directory,
+                    new_name,
+                    entry.inode,
+                    child_type,
+                )?;
+                volume.write_inode(&state, new_parent, &new_directory)?;
+            }
+            Err(error) => return Err(error),
+        }
+
+        // Read again as adding the new entry may have changed it, if it is the same directory
+        let mut old_directory = volume.read_inode(&state, self.number)?;
+        dir::remove(volume, &mut old_directory, &entry)?;
+        volume.write_inode(&state, self.number, &old_directory)?;
+
+        if moving_directory && new_parent != self.number {
+            let dot_dot = dir::find(volume, &child, "..")?;
+            dir::retarget(volume, &dot_dot, new_parent, FileType::Directory)?;
+
+            self.adjust_links(&state, self.number, -1)?;
+            self.adjust_links(&state, new_parent, 1)?;
+        }
+
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl File for Ext2Inode {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        let state = self.volume.state.lock();
+        let inode = self.load(&state)?;
+        self.read_data(&inode, offset, buf)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize> {
+        let volume = &self.volume;
+        volume.check_writable()?;
+
+        offset
+            .checked_add(buf.len() as u64)
+            .filter(|&end| end <= volume.max_file_size())
+            .ok_or(FsError::FileTooLarge)?;
+
+        let mut state = volume.state.lock();
+        let mut inode = self.load(&state)?;
+
+        // Writing nothing doesn't extend the file, even past its end
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let written = self.write_data(&mut state, &mut inode, offset, buf);
+
+        // Blocks allocated before a failure are still recorded in the inode
+        volume.write_inode(&state, self.number, &inode)?;
+        written?;
+
+        Ok(buf.len())
+    }
+
+    fn size(&self) -> Result<u64> {
+        let state = self.volume.state.lock();
+        Ok(self.load(&state)?.size)
+    }
+
+    fn truncate(&self, size: u64) -> Result<()> {
+        let volume = &self.volume;
+        volume.check_writable()?;
+
+        if size > volume.max_file_size() {
+            return Err(FsError::FileTooLarge);
+        }
+
+        let mut state = volume.state.lock();
+        let mut inode = self.load(&state)?;
+
+        if size < inode.size {
+            // Growing the file again has to read zeros, not what was in the rest of the last block
+            let tail = size % volume.block_size;
+            if tail != 0 {
+                let block = volume.map_block(&inode, size / volume.block_size)?;
+                if block != 0 {
+                    let zeros = vec![0; (volume.block_size - tail) as usize];
+                    block::write_bytes(
+                        &*volume.device,
+                        block as u64 * volume.block_size + tail,
+                        &zeros,
+                    )?;
+                }
+            }
+
+            volume.free_blocks_from(&mut state, &mut inode, size.div_ceil(volume.block_size))?;
+        }
+
+        // Growing just leaves a hole
+        inode.size = size;
+        volume.write_inode(&state, self.number, &inode)
+    }
+}
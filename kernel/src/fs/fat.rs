@@ -0,0 +1,641 @@
+//! FAT12, FAT16 and FAT32, read and write, on any `BlockDevice`. Long file names are read and written, and names are
+//! matched case-insensitively like on other systems. Timestamps aren't kept up to date and there are no symlinks.
+//!
+//! The filesystem keeps nothing cached apart from the free cluster count, every operation reads what it needs from
+//! the device under one lock per volume. Open files refer to their directory entry by position, so a file which is
+//! renamed or removed while open has to be opened again.
+
+mod dir;
+mod inode;
+
+use crate::block::{self, BlockDevice, SECTOR_SIZE};
+use crate::fs::{Filesystem, FsError, Inode, Result};
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use inode::FatInode;
+use spin::Mutex;
+
+/// The first cluster in the data region, 0 and 1 are reserved FAT entries
+const FIRST_CLUSTER: u32 = 2;
+
+/// Stands for the fixed size root directory of FAT12 and FAT16, which isn't in a cluster. `..` entries pointing at
+/// the root always hold it, on FAT32 too.
+const ROOT_REGION: u32 = 0;
+
+/// Volumes with fewer clusters than these are FAT12 or FAT16, whatever the boot sector says
+const MAX_FAT12_CLUSTERS: u32 = 4084;
+const MAX_FAT16_CLUSTERS: u32 = 65524;
+
+const FSINFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+const FSINFO_STRUCT_SIGNATURE: u32 = 0x6141_7272;
+/// Unknown free count or next free cluster in the FSInfo sector
+const FSINFO_UNKNOWN: u32 = 0xffff_ffff;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+pub struct FatFs {
+    volume: Arc<Volume>,
+}
+
+impl FatFs {
+    /// Mounts the FAT volume on `device`, failing with `Corrupted` if it doesn't have a valid boot sector
+    pub fn new(device: Arc<dyn BlockDevice>) -> Result<Self> {
+        Ok(FatFs {
+            volume: Arc::new(Volume::new(device)?),
+        })
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        self.volume.fat_type
+    }
+
+    pub fn cluster_size(&self) -> usize {
+        self.volume.cluster_size as usize
+    }
+
+    /// How many clusters aren't in use, counted the first time it is needed
+    pub fn free_clusters(&self) -> Result<u32> {
+        let mut state = self.volume.state.lock();
+        self.volume.free_count(&mut state)
+    }
+}
+
+impl Filesystem for FatFs {
+    fn name(&self) -> &'static str {
+        match self.volume.fat_type {
+            FatType::Fat12 => "fat12",
+            FatType::Fat16 => "fat16",
+            FatType::Fat32 => "fat32",
+        }
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(FatInode::directory(
+            self.volume.clone(),
+            self.volume.root_directory,
+        ))
+    }
+
+    fn sync(&self) -> Result<()> {
+        let mut state = self.volume.state.lock();
+        self.volume.write_fsinfo(&mut state)?;
+        Ok(self.volume.device.flush()?)
+    }
+}
+
+/// The free cluster hint and count kept in FSInfo, and how often each directory slot has been reused. The FAT and
+/// the directory clusters have no lock of their own, so every operation holds this one from reading a cluster chain or
+/// an entry until its changes are written back.
+struct State {
+    /// Where to start looking for a free cluster
+    next_free: u32,
+    free_count: Option<u32>,
+    fsinfo_dirty: bool,
+    /// How many times each short entry has been removed, by directory and slot. A file is identified by where its
+    /// short entry is, so this tells an inode when its slot has been reused for another file.
+    removed_entries: BTreeMap<(u32, usize), u32>,
+}
+
+impl State {
+    fn generation(&self, directory: u32, slot: usize) -> u32 {
+        self.removed_entries
+            .get(&(directory, slot))
+            .copied()
+            .unwrap_or(0)
+    }
+}
+
+struct Volume {
+    device: Arc<dyn BlockDevice>,
+    fat_type: FatType,
+    cluster_size: u64,
+    /// Byte offsets of each FAT that is kept up to date
+    fats: Vec<u64>,
+    /// The FAT read from, which is the first unless FAT32 mirroring is turned off
+    active_fat: u64,
+    root_region_start: u64,
+    root_region_size: u64,
+    data_start: u64,
+    cluster_count: u32,
+    /// The root's first cluster on FAT32, `ROOT_REGION` otherwise
+    root_directory: u32,
+    fsinfo_offset: Option<u64>,
+    state: Mutex<State>,
+}
+
+fn u16_at(sector: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([sector[offset], sector[offset + 1]])
+}
+
+fn u32_at(sector: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(sector[offset..offset + 4].try_into().unwrap())
+}
+
+impl Volume {
+    fn new(device: Arc<dyn BlockDevice>) -> Result<Self> {
+        let mut boot_sector = [0; SECTOR_SIZE];
+        device.read_sectors(0, &mut boot_sector)?;
+
+        let bytes_per_sector = u16_at(&boot_sector, 11) as u64;
+        let sectors_per_cluster = boot_sector[13] as u64;
+        let reserved_sectors = u16_at(&boot_sector, 14) as u64;
+        let fat_count = boot_sector[16] as u64;
+        let root_entries = u16_at(&boot_sector, 17) as u64;
+        let total_sectors = match u16_at(&boot_sector, 19) {
+            0 => u32_at(&boot_sector, 32) as u64,
+            sectors => sectors as u64,
+        };
+        let sectors_per_fat = match u16_at(&boot_sector, 22) {
+            0 => u32_at(&boot_sector, 36) as u64,
+            sectors => sectors as u64,
+        };
+
+        let valid = boot_sector[510..] == [0x55, 0xaa]
+            && [512, 1024, 2048, 4096].contains(&bytes_per_sector)
+            && sectors_per_cluster.is_power_of_two()
+            && reserved_sectors != 0
+            && fat_count != 0
+            && sectors_per_fat != 0;
+
+        if !valid {
+            return Err(FsError::Corrupted);
+        }
+
+        let root_region_sectors =
+            (root_entries * dir::ENTRY_SIZE as u64).div_ceil(bytes_per_sector);
+        let data_sector = reserved_sectors + fat_count * sectors_per_fat + root_region_sectors;
+        let cluster_count = total_sectors
+            .checked_sub(data_sector)
+            .map(|sectors| sectors / sectors_per_cluster)
+            .and_then(|clusters| u32::try_from(clusters).ok())
+            .filter(|&clusters| clusters != 0)
+            .ok_or(FsError::Corrupted)?;
+
+        let fat_type = if cluster_count <= MAX_FAT12_CLUSTERS {
+            FatType::Fat12
+        } else if cluster_count <= MAX_FAT16_CLUSTERS {
+            FatType::Fat16
+        } else {
+            FatType::Fat32
+        };
+
+        // The FATs have to be big enough for every cluster, and the device big enough for the volume
+        let fat_bits = match fat_type {
+            FatType::Fat12 => 12,
+            FatType::Fat16 => 16,
+            FatType::Fat32 => 32,
+        };
+        let needed_fat_bytes = ((cluster_count as u64 + 2) * fat_bits).div_ceil(8);
+        let bytes = |sectors: u64| sectors * bytes_per_sector;
+
+        if bytes(sectors_per_fat) < needed_fat_bytes
+            || bytes(total_sectors) > device.sector_count() * SECTOR_SIZE as u64
+            || (fat_type == FatType::Fat32) != (root_entries == 0)
+        {
+            return Err(FsError::Corrupted);
+        }
+
+        let fat_offset = |index: u64| bytes(reserved_sectors + index * sectors_per_fat);
+        let mut fats: Vec<u64> = (0..fat_count).map(fat_offset).collect();
+        let mut active_fat = fats[0];
+        let mut root_directory = ROOT_REGION;
+        let mut fsinfo_offset = None;
+
+        if fat_type == FatType::Fat32 {
+            // Bit 7 of the extended flags turns off mirroring, the low bits then say which FAT is used
+            let extended_flags = u16_at(&boot_sector, 40);
+            if extended_flags & 0x80 != 0 {
+                let index = (extended_flags & 0xf) as u64;
+                if index >= fat_count {
+                    return Err(FsError::Corrupted);
+                }
+
+                active_fat = fat_offset(index);
+                fats = alloc::vec![active_fat];
+            }
+
+            root_directory = u32_at(&boot_sector, 44);
+            if !(FIRST_CLUSTER..cluster_count + FIRST_CLUSTER).contains(&root_directory) {
+                return Err(FsError::Corrupted);
+            }
+
+            fsinfo_offset = match u16_at(&boot_sector, 48) {
+                0 | 0xffff => None,
+                sector => Some(bytes(sector as u64)),
+            };
+        }
+
+        let mut volume = Volume {
+            device,
+            fat_type,
+            cluster_size: bytes(sectors_per_cluster),
+            fats,
+            active_fat,
+            root_region_start: bytes(reserved_sectors + fat_count * sectors_per_fat),
+            root_region_size: root_entries * dir::ENTRY_SIZE as u64,
+            data_start: bytes(data_sector),
+            cluster_count,
+            root_directory,
+            fsinfo_offset,
+            state: Mutex::new(State {
+                next_free: FIRST_CLUSTER,
+                free_count: None,
+                fsinfo_dirty: false,
+                removed_entries: BTreeMap::new(),
+            }),
+        };
+
+        volume.read_fsinfo()?;
+
+        Ok(volume)
+    }
+
+    /// Takes the allocation hints from the FSInfo sector, if it is valid
+    fn read_fsinfo(&mut self) -> Result<()> {
+        let Some(offset) = self.fsinfo_offset else {
+            return Ok(());
+        };
+
+        let mut fsinfo = [0; SECTOR_SIZE];
+        block::read_bytes(&*self.device, offset, &mut fsinfo)?;
+
+        if u32_at(&fsinfo, 0) != FSINFO_LEAD_SIGNATURE
+            || u32_at(&fsinfo, 484) != FSINFO_STRUCT_SIGNATURE
+        {
+            self.fsinfo_offset = None;
+            return Ok(());
+        }
+
+        let free_count = u32_at(&fsinfo, 488);
+        let next_free = u32_at(&fsinfo, 492);
+        let next_free_valid = self.is_cluster(next_free);
+        let cluster_count = self.cluster_count;
+        let state = self.state.get_mut();
+
+        if free_count <= cluster_count {
+            state.free_count = Some(free_count);
+        }
+
+        if next_free_valid {
+            state.next_free = next_free;
+        }
+
+        Ok(())
+    }
+
+    fn write_fsinfo(&self, state: &mut State) -> Result<()> {
+        let Some(offset) = self.fsinfo_offset.filter(|_| state.fsinfo_dirty) else {
+            return Ok(());
+        };
+
+        let mut fields = [0; 8];
+        fields[..4].copy_from_slice(&state.free_count.unwrap_or(FSINFO_UNKNOWN).to_le_bytes());
+        fields[4..].copy_from_slice(&state.next_free.to_le_bytes());
+        block::write_bytes(&*self.device, offset + 488, &fields)?;
+
+        state.fsinfo_dirty = false;
+        Ok(())
+    }
+
+    fn is_cluster(&self, cluster: u32) -> bool {
+        (FIRST_CLUSTER..self.cluster_count + FIRST_CLUSTER).contains(&cluster)
+    }
+
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.data_start + (cluster - FIRST_CLUSTER) as u64 * self.cluster_size
+    }
+
+    fn end_of_chain(&self) -> u32 {
+        match self.fat_type {
+            FatType::Fat12 => 0xfff,
+            FatType::Fat16 => 0xffff,
+            FatType::Fat32 => 0x0fff_ffff,
+        }
+    }
+
+    /// Where `cluster`'s entry is within a FAT, and how many bytes to access to get it
+    fn fat_entry_position(&self, cluster: u32) -> (u64, usize) {
+        match self.fat_type {
+            // FAT12 entries are a byte and a half, so they always straddle two bytes
+            FatType::Fat12 => (cluster as u64 * 3 / 2, 2),
+            FatType::Fat16 => (cluster as u64 * 2, 2),
+            FatType::Fat32 => (cluster as u64 * 4, 4),
+        }
+    }
+
+    fn fat_entry(&self, cluster: u32) -> Result<u32> {
+        let (offset, length) = self.fat_entry_position(cluster);
+        let mut bytes = [0; 4];
+        block::read_bytes(
+            &*self.device,
+            self.active_fat + offset,
+            &mut bytes[..length],
+        )?;
+        let raw = u32::from_le_bytes(bytes);
+
+        Ok(match self.fat_type {
+            FatType::Fat12 if cluster % 2 == 1 => raw >> 4,
+            FatType::Fat12 => raw & 0xfff,
+            FatType::Fat16 => raw,
+            // The top 4 bits are reserved
+            FatType::Fat32 => raw & 0x0fff_ffff,
+        })
+    }
+
+    /// Sets `cluster`'s entry in every FAT
+    fn set_fat_entry(&self, cluster: u32, value: u32) -> Result<()> {
+        let (offset, length) = self.fat_entry_position(cluster);
+
+        for &fat in &self.fats {
+            let mut bytes = [0; 4];
+
+            let raw = match self.fat_type {
+                FatType::Fat16 => value,
+                _ => {
+                    block::read_bytes(&*self.device, fat + offset, &mut bytes[..length])?;
+                    let old = u32::from_le_bytes(bytes);
+
+                    match self.fat_type {
+                        FatType::Fat12 if cluster % 2 == 1 => (old & 0x000f) | (value << 4),
+                        FatType::Fat12 => (old & 0xf000) | value,
+                        _ => (old & 0xf000_0000) | value,
+                    }
+                }
+            };
+
+            bytes = raw.to_le_bytes();
+            block::write_bytes(&*self.device, fat + offset, &bytes[..length])?;
+        }
+
+        Ok(())
+    }
+
+    /// The cluster after `cluster` in its chain, `None` at the end
+    fn next_cluster(&self, cluster: u32) -> Result<Option<u32>> {
+        let next = self.fat_entry(cluster)?;
+
+        // Anything from the bad cluster marker up ends a chain
+        if next >= self.end_of_chain() - 8 {
+            Ok(None)
+        } else if self.is_cluster(next) {
+            Ok(Some(next))
+        } else {
+            Err(FsError::Corrupted)
+        }
+    }
+
+    /// Every cluster in the chain starting at `first`, which is empty for 0 (an empty file)
+    fn chain(&self, first: u32) -> Result<Vec<u32>> {
+        let mut clusters = Vec::new();
+        let mut cluster = (first != 0).then_some(first);
+
+        while let Some(current) = cluster {
+            // A chain can't be longer than the volume, so a longer one must loop
+            if !self.is_cluster(current) || clusters.len() >= self.cluster_count as usize {
+                return Err(FsError::Corrupted);
+            }
+
+            clusters.push(current);
+            cluster = self.next_cluster(current)?;
+        }
+
+        Ok(clusters)
+    }
+
+    fn free_count(&self, state: &mut State) -> Result<u32> {
+        if let Some(count) = state.free_count {
+            return Ok(count);
+        }
+
+        let mut count = 0;
+        for cluster in FIRST_CLUSTER..self.cluster_count + FIRST_CLUSTER {
+            if self.fat_entry(cluster)? == 0 {
+                count += 1;
+            }
+        }
+
+        state.free_count = Some(count);
+        Ok(count)
+    }
+
+    /// Allocates a zeroed cluster and adds it to the end of the chain ending with `previous`, if there is one
+    fn allocate_cluster(&self, state: &mut State, previous: Option<u32>) -> Result<u32> {
+        let start = state.next_free;
+
+        for index in 0..self.cluster_count {
+            let cluster = FIRST_CLUSTER + (start - FIRST_CLUSTER + index) % self.cluster_count;
+
+            if self.fat_entry(cluster)? != 0 {
+                continue;
+            }
+
+            self.zero(self.cluster_offset(cluster), self.cluster_size)?;
+            self.set_fat_entry(cluster, self.end_of_chain())?;
+
+            if let Some(previous) = previous {
+                self.set_fat_entry(previous, cluster)?;
+            }
+
+            state.next_free = if self.is_cluster(cluster + 1) {
+                cluster + 1
+            } else {
+                FIRST_CLUSTER
+            };
+            state.free_count = state.free_count.map(|count| count.saturating_sub(1));
+            state.fsinfo_dirty = true;
+
+            return Ok(cluster);
+        }
+
+        Err(FsError::NoSpace)
+    }
+
+    /// Frees every cluster in the chain starting at `first`
+    fn free_chain(&self, state: &mut State, first: u32) -> Result<()> {
+        for cluster in self.chain(first)? {
+            self.set_fat_entry(cluster, 0)?;
+            state.free_count = state.free_count.map(|count| count + 1);
+        }
+
+        state.fsinfo_dirty = true;
+        Ok(())
+    }
+
+    /// Frees every cluster in the chain after the first `keep`, freeing all of it for 0
+    fn shorten_chain(&self, state: &mut State, first: u32, keep: usize) -> Result<()> {
+        if keep == 0 {
+            return self.free_chain(state, first);
+        }
+
+        let clusters = self.chain(first)?;
+        if let Some(&last) = clusters.get(keep - 1) {
+            if let Some(&next) = clusters.get(keep) {
+                self.set_fat_entry(last, self.end_of_chain())?;
+                self.free_chain(state, next)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn zero(&self, offset: u64, length: u64) -> Result<()> {
+        let zeros = [0; SECTOR_SIZE];
+        let mut done = 0;
+
+        while done < length {
+            let chunk = (length - done).min(SECTOR_SIZE as u64);
+            block::write_bytes(&*self.device, offset + done, &zeros[..chunk as usize])?;
+            done += chunk;
+        }
+
+        Ok(())
+    }
+
+    /// Where on the device `length` bytes at `offset` in a file made of `clusters` are, as offsets and lengths
+    fn file_extents(
+        &self,
+        clusters: &[u32],
+        offset: u64,
+        length: usize,
+    ) -> Result<Vec<(u64, usize)>> {
+        let mut extents = Vec::new();
+        let mut position = offset;
+        let end = offset + length as u64;
+
+        while position < end {
+            let cluster = *clusters
+                .get((position / self.cluster_size) as usize)
+                .ok_or(FsError::Corrupted)?;
+            let cluster_offset = position % self.cluster_size;
+            let chunk = (self.cluster_size - cluster_offset).min(end - position);
+
+            extents.push((
+                self.cluster_offset(cluster) + cluster_offset,
+                chunk as usize,
+            ));
+            position += chunk;
+        }
+
+        Ok(extents)
+    }
+
+    /// The runs of bytes on the device making up a directory, in order
+    fn directory_extents(&self, directory: u32) -> Result<Vec<(u64, u64)>> {
+        if directory == ROOT_REGION {
+            return Ok(alloc::vec![(self.root_region_start, self.root_region_size)]);
+        }
+
+        Ok(self
+            .chain(directory)?
+            .into_iter()
+            .map(|cluster| (self.cluster_offset(cluster), self.cluster_size))
+            .collect())
+    }
+
+    fn read_directory(&self, directory: u32) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+
+        for (offset, length) in self.directory_extents(directory)? {
+            let start = data.len();
+            data.resize(start + length as usize, 0);
+            block::read_bytes(&*self.device, offset, &mut data[start..])?;
+        }
+
+        Ok(data)
+    }
+
+    /// Where on the device slot `slot` of a directory is
+    fn slot_offset(&self, directory: u32, slot: usize) -> Result<u64> {
+        let mut position = (slot * dir::ENTRY_SIZE) as u64;
+
+        for (offset, length) in self.directory_extents(directory)? {
+            if position < length {
+                return Ok(offset + position);
+            }
+
+            position -= length;
+        }
+
+        Err(FsError::Corrupted)
+    }
+
+    fn read_slot(&self, directory: u32, slot: usize) -> Result<[u8; dir::ENTRY_SIZE]> {
+        let mut raw = [0; dir::ENTRY_SIZE];
+        block::read_bytes(&*self.device, self.slot_offset(directory, slot)?, &mut raw)?;
+        Ok(raw)
+    }
+
+    fn write_slot(&self, directory: u32, slot: usize, raw: &[u8; dir::ENTRY_SIZE]) -> Result<()> {
+        Ok(block::write_bytes(
+            &*self.device,
+            self.slot_offset(directory, slot)?,
+            raw,
+        )?)
+    }
+
+    /// Writes `entries` into the first run of free slots big enough for them, growing the directory if there isn't
+    /// one. Returns the slot of the last entry.
+    fn insert_entries(
+        &self,
+        state: &mut State,
+        directory: u32,
+        entries: &[[u8; dir::ENTRY_SIZE]],
+    ) -> Result<usize> {
+        let first_slot = loop {
+            let data = self.read_directory(directory)?;
+
+            if let Some(slot) = dir::find_free_slots(&data, entries.len()) {
+                break slot;
+            }
+
+            // The FAT12/16 root directory can't grow
+            if directory == ROOT_REGION {
+                return Err(FsError::NoSpace);
+            }
+
+            let last = *self.chain(directory)?.last().ok_or(FsError::Corrupted)?;
+            self.allocate_cluster(state, Some(last))?;
+        };
+
+        for (index, raw) in entries.iter().enumerate() {
+            self.write_slot(directory, first_slot + index, raw)?;
+        }
+
+        Ok(first_slot + entries.len() - 1)
+    }
+
+    /// Marks an entry and its LFN entries as deleted
+    fn remove_entry(&self, state: &mut State, directory: u32, entry: &dir::Entry) -> Result<()> {
+        *state
+            .removed_entries
+            .entry((directory, entry.slot))
+            .or_insert(0) += 1;
+
+        for slot in entry.first_slot..=entry.slot {
+            let mut raw = self.read_slot(directory, slot)?;
+            raw[0] = dir::DELETED;
+            self.write_slot(directory, slot, &raw)?;
+        }
+
+        Ok(())
+    }
+
+    /// The directory a directory's `..` entry points at
+    fn parent_directory(&self, directory: u32) -> Result<u32> {
+        let dot_dot = self.read_slot(directory, 1)?;
+        if &dot_dot[..2] != b".." {
+            return Err(FsError::Corrupted);
+        }
+
+        Ok(match dir::first_cluster(&dot_dot) {
+            ROOT_REGION => self.root_directory,
+            cluster => cluster,
+        })
+    }
+}
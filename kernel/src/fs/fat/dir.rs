@@ -0,0 +1,454 @@
+//! Directory entries. Every file has a 32 byte short entry holding an 8.3 name, its attributes, first cluster and
+//! size. Names which don't fit in 8.3 are also stored as a run of long file name (LFN) entries just before the short
+//! entry, each holding 13 UTF-16 code units of the name.
+
+use crate::fs::{FsError, Result};
+use alloc::string::String;
+use alloc::vec::Vec;
+
+pub(super) const ENTRY_SIZE: usize = 32;
+
+pub(super) const ATTR_VOLUME_ID: u8 = 0x08;
+pub(super) const ATTR_DIRECTORY: u8 = 0x10;
+pub(super) const ATTR_ARCHIVE: u8 = 0x20;
+/// Read only, hidden, system and volume ID together mark an LFN entry
+const ATTR_LONG_NAME: u8 = 0x0f;
+
+/// The first byte of a deleted entry
+pub(super) const DELETED: u8 = 0xe5;
+/// A first byte of 0 marks the end of the directory, every entry after it is free too
+const END: u8 = 0x00;
+
+/// Flags in the byte Windows NT uses to mark an 8.3 name's base or extension as lower case
+const LOWER_CASE_BASE: u8 = 0x08;
+const LOWER_CASE_EXTENSION: u8 = 0x10;
+
+/// Set on the first LFN entry (the one holding the end of the name)
+const LAST_LONG_ENTRY: u8 = 0x40;
+const LONG_NAME_CHARS_PER_ENTRY: usize = 13;
+/// Where each of the 13 UTF-16 code units is in an LFN entry
+const LONG_NAME_CHAR_OFFSETS: [usize; LONG_NAME_CHARS_PER_ENTRY] =
+    [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+const MAX_LONG_NAME_LENGTH: usize = 255;
+
+/// 1980-01-01, the earliest date FAT can store. There is no RTC driver yet so every timestamp gets this.
+const DEFAULT_DATE: u16 = (1 << 5) | 1;
+
+pub(super) type ShortName = [u8; 11];
+
+/// A short entry and the name it was found under
+pub(super) struct Entry {
+    /// The long name if there is one, otherwise the short name as `NAME.EXT`
+    pub(super) name: String,
+    pub(super) short_name: ShortName,
+    pub(super) raw: [u8; ENTRY_SIZE],
+    /// The first slot holding this entry's LFN entries, or `slot` if it has none
+    pub(super) first_slot: usize,
+    /// The slot of the short entry
+    pub(super) slot: usize,
+}
+
+impl Entry {
+    pub(super) fn attributes(&self) -> u8 {
+        self.raw[11]
+    }
+
+    pub(super) fn is_directory(&self) -> bool {
+        self.attributes() & ATTR_DIRECTORY != 0
+    }
+
+    pub(super) fn first_cluster(&self) -> u32 {
+        first_cluster(&self.raw)
+    }
+
+    pub(super) fn matches(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name)
+            || short_name_to_string(&self.short_name, 0).eq_ignore_ascii_case(name)
+    }
+}
+
+pub(super) fn first_cluster(raw: &[u8; ENTRY_SIZE]) -> u32 {
+    let high = u16::from_le_bytes([raw[20], raw[21]]) as u32;
+    let low = u16::from_le_bytes([raw[26], raw[27]]) as u32;
+    (high << 16) | low
+}
+
+pub(super) fn set_first_cluster(raw: &mut [u8; ENTRY_SIZE], cluster: u32) {
+    raw[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    raw[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+}
+
+pub(super) fn size(raw: &[u8; ENTRY_SIZE]) -> u32 {
+    u32::from_le_bytes(raw[28..32].try_into().unwrap())
+}
+
+pub(super) fn set_size(raw: &mut [u8; ENTRY_SIZE], size: u32) {
+    raw[28..32].copy_from_slice(&size.to_le_bytes());
+}
+
+/// Whether a slot holds the start of a live entry (rather than a deleted one or the end marker)
+pub(super) fn is_live(raw: &[u8; ENTRY_SIZE]) -> bool {
+    raw[0] != DELETED && raw[0] != END
+}
+
+fn slot(data: &[u8], index: usize) -> [u8; ENTRY_SIZE] {
+    data[index * ENTRY_SIZE..(index + 1) * ENTRY_SIZE]
+        .try_into()
+        .unwrap()
+}
+
+/// Every entry in a directory apart from `.`, `..` and the volume label
+pub(super) fn parse(data: &[u8]) -> Vec<Entry> {
+    let mut entries = Vec::new();
+
+    // The LFN run being collected: the slot it started at, its checksum, the sequence number expected next and the
+    // name so far (in entry order, so the end of the name comes first)
+    let mut long_name: Option<(usize, u8, u8, Vec<[u16; LONG_NAME_CHARS_PER_ENTRY]>)> = None;
+
+    for index in 0..data.len() / ENTRY_SIZE {
+        let raw = slot(data, index);
+
+        if raw[0] == END {
+            break;
+        }
+
+        if raw[0] == DELETED {
+            long_name = None;
+            continue;
+        }
+
+        if raw[11] & ATTR_LONG_NAME == ATTR_LONG_NAME {
+            let sequence = raw[0] & !LAST_LONG_ENTRY;
+            let checksum = raw[13];
+
+            let mut chars = [0; LONG_NAME_CHARS_PER_ENTRY];
+            for (char, &offset) in chars.iter_mut().zip(LONG_NAME_CHAR_OFFSETS.iter()) {
+                *char = u16::from_le_bytes([raw[offset], raw[offset + 1]]);
+            }
+
+            long_name = match long_name.take() {
+                _ if raw[0] & LAST_LONG_ENTRY != 0 && sequence != 0 => {
+                    Some((index, checksum, sequence - 1, alloc::vec![chars]))
+                }
+                Some((start, expected_checksum, expected, mut parts))
+                    if sequence == expected && checksum == expected_checksum && sequence != 0 =>
+                {
+                    parts.push(chars);
+                    Some((start, checksum, sequence - 1, parts))
+                }
+                // An orphaned or out of order LFN entry, which is ignored
+                _ => None,
+            };
+            continue;
+        }
+
+        let short_name: ShortName = raw[..11].try_into().unwrap();
+        let long_name = long_name.take();
+
+        if raw[11] & ATTR_VOLUME_ID != 0 || raw[0] == b'.' {
+            continue;
+        }
+
+        let (name, first_slot) = match long_name {
+            Some((start, checksum, 0, parts)) if checksum == self::checksum(&short_name) => {
+                (decode_long_name(&parts), start)
+            }
+            _ => (short_name_to_string(&short_name, raw[12]), index),
+        };
+
+        entries.push(Entry {
+            name,
+            short_name,
+            raw,
+            first_slot,
+            slot: index,
+        });
+    }
+
+    entries
+}
+
+fn decode_long_name(parts: &[[u16; LONG_NAME_CHARS_PER_ENTRY]]) -> String {
+    let units = parts
+        .iter()
+        .rev()
+        .flatten()
+        .copied()
+        .take_while(|&unit| unit != 0x0000 && unit != 0xffff);
+
+    char::decode_utf16(units)
+        .map(|char| char.unwrap_or(char::REPLACEMENT_CHARACTER))
+        .collect()
+}
+
+/// `NAME.EXT`, with the NT lower case flags in `case` applied
+fn short_name_to_string(short_name: &ShortName, case: u8) -> String {
+    let mut bytes = *short_name;
+
+    // 0xe5 is a valid character in some code pages, so a name starting with it is stored as 0x05
+    if bytes[0] == 0x05 {
+        bytes[0] = DELETED;
+    }
+
+    let convert = |bytes: &[u8], lower: bool| -> String {
+        bytes
+            .iter()
+            .map(|&byte| {
+                if lower {
+                    byte.to_ascii_lowercase()
+                } else {
+                    byte
+                }
+            })
+            .map(char::from)
+            .collect::<String>()
+            .trim_end_matches(' ')
+            .into()
+    };
+
+    let mut name = convert(&bytes[..8], case & LOWER_CASE_BASE != 0);
+    let extension = convert(&bytes[8..], case & LOWER_CASE_EXTENSION != 0);
+
+    if !extension.is_empty() {
+        name.push('.');
+        name.push_str(&extension);
+    }
+
+    name
+}
+
+/// The index of the first run of `count` free slots
+pub(super) fn find_free_slots(data: &[u8], count: usize) -> Option<usize> {
+    let mut run_start = 0;
+    let mut run_length = 0;
+
+    for index in 0..data.len() / ENTRY_SIZE {
+        let first_byte = data[index * ENTRY_SIZE];
+
+        if first_byte == DELETED || first_byte == END {
+            if run_length == 0 {
+                run_start = index;
+            }
+
+            run_length += 1;
+            if run_length == count {
+                return Some(run_start);
+            }
+        } else {
+            run_length = 0;
+        }
+    }
+
+    None
+}
+
+pub(super) fn validate_name(name: &str) -> Result<()> {
+    let invalid = |char: char| char < ' ' || "\"*/:<>?\\|".contains(char);
+
+    if name.is_empty()
+        || name == "."
+        || name == ".."
+        || name.chars().any(invalid)
+        || name.ends_with(['.', ' '])
+    {
+        return Err(FsError::InvalidPath);
+    }
+
+    if name.encode_utf16().count() > MAX_LONG_NAME_LENGTH {
+        return Err(FsError::NameTooLong);
+    }
+
+    Ok(())
+}
+
+fn is_short_name_char(char: char) -> bool {
+    char.is_ascii_alphanumeric() || "$%'-_@~`!(){}^#&".contains(char)
+}
+
+/// How a name is stored: its short name, the NT case flags, and whether it needs LFN entries as well
+pub(super) struct StoredName {
+    pub(super) short_name: ShortName,
+    pub(super) case: u8,
+    pub(super) needs_long_name: bool,
+}
+
+/// Works out how to store `name`. Names which fit in 8.3 (in a single case per part) are stored as just a short
+/// entry, others get a generated `BASENA~1.EXT` style short name. Either way the short name is one `is_taken` says
+/// isn't in use.
+pub(super) fn stored_name(name: &str, is_taken: impl Fn(&ShortName) -> bool) -> Result<StoredName> {
+    if let Some(stored) = exact_short_name(name).filter(|stored| !is_taken(&stored.short_name)) {
+        return Ok(stored);
+    }
+
+    let (base, extension) = match name.rfind('.') {
+        Some(dot) if dot > 0 => (&name[..dot], &name[dot + 1..]),
+        _ => (name, ""),
+    };
+
+    let to_short = |part: &str| -> Vec<u8> {
+        part.chars()
+            .filter(|&char| char != ' ' && char != '.')
+            .map(|char| {
+                if is_short_name_char(char) {
+                    char.to_ascii_uppercase() as u8
+                } else {
+                    b'_'
+                }
+            })
+            .collect()
+    };
+
+    let base = to_short(base);
+    let extension = to_short(extension);
+
+    let mut short_name = [b' '; 11];
+    for (byte, &char) in short_name[8..].iter_mut().zip(extension.iter()) {
+        *byte = char;
+    }
+
+    for number in 1..1_000_000u32 {
+        let tail = alloc::format!("~{}", number);
+        let base_length = base.len().min(8 - tail.len());
+
+        short_name[..8].fill(b' ');
+        short_name[..base_length].copy_from_slice(&base[..base_length]);
+        short_name[base_length..base_length + tail.len()].copy_from_slice(tail.as_bytes());
+
+        if !is_taken(&short_name) {
+            return Ok(StoredName {
+                short_name,
+                case: 0,
+                needs_long_name: true,
+            });
+        }
+    }
+
+    Err(FsError::NoSpace)
+}
+
+/// `name` as a short name if it is already valid 8.3
+fn exact_short_name(name: &str) -> Option<StoredName> {
+    let (base, extension) = match name.split_once('.') {
+        Some((base, extension)) => (base, extension),
+        None => (name, ""),
+    };
+
+    if base.is_empty() || base.len() > 8 || extension.len() > 3 || extension.contains('.') {
+        return None;
+    }
+
+    if !base
+        .chars()
+        .chain(extension.chars())
+        .all(is_short_name_char)
+    {
+        return None;
+    }
+
+    // Each part can be all upper or all lower case, mixed case needs a long name to be preserved
+    let case_flag = |part: &str, flag: u8| -> Option<u8> {
+        let has_upper = part.chars().any(|char| char.is_ascii_uppercase());
+        let has_lower = part.chars().any(|char| char.is_ascii_lowercase());
+
+        match (has_upper, has_lower) {
+            (true, true) => None,
+            (false, true) => Some(flag),
+            _ => Some(0),
+        }
+    };
+
+    let case = case_flag(base, LOWER_CASE_BASE)? | case_flag(extension, LOWER_CASE_EXTENSION)?;
+
+    let mut short_name = [b' '; 11];
+    short_name[..base.len()].copy_from_slice(base.to_ascii_uppercase().as_bytes());
+    short_name[8..8 + extension.len()].copy_from_slice(extension.to_ascii_uppercase().as_bytes());
+
+    if short_name[0] == DELETED {
+        short_name[0] = 0x05;
+    }
+
+    Some(StoredName {
+        short_name,
+        case,
+        needs_long_name: false,
+    })
+}
+
+pub(super) fn checksum(short_name: &ShortName) -> u8 {
+    short_name.iter().fold(0u8, |sum, &byte| {
+        ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(byte)
+    })
+}
+
+/// The slots for `name`: its LFN entries (if it needs them) in the order they go on disk, then `short_entry`
+pub(super) fn entries_for(
+    name: &str,
+    stored: &StoredName,
+    mut short_entry: [u8; ENTRY_SIZE],
+) -> Vec<[u8; ENTRY_SIZE]> {
+    short_entry[..11].copy_from_slice(&stored.short_name);
+    short_entry[12] = stored.case;
+
+    if !stored.needs_long_name {
+        return alloc::vec![short_entry];
+    }
+
+    let units: Vec<u16> = name.encode_utf16().collect();
+    let count = units.len().div_ceil(LONG_NAME_CHARS_PER_ENTRY);
+    let checksum = checksum(&stored.short_name);
+
+    let mut entries = Vec::with_capacity(count + 1);
+
+    for sequence in (1..=count).rev() {
+        let mut raw = [0; ENTRY_SIZE];
+        raw[0] = sequence as u8
+            | if sequence == count {
+                LAST_LONG_ENTRY
+            } else {
+                0
+            };
+        raw[11] = ATTR_LONG_NAME;
+        raw[13] = checksum;
+
+        // The name is terminated with a NUL if there is room, then padded with 0xffff
+        for (position, &offset) in LONG_NAME_CHAR_OFFSETS.iter().enumerate() {
+            let index = (sequence - 1) * LONG_NAME_CHARS_PER_ENTRY + position;
+            let unit = match index.cmp(&units.len()) {
+                core::cmp::Ordering::Less => units[index],
+                core::cmp::Ordering::Equal => 0x0000,
+                core::cmp::Ordering::Greater => 0xffff,
+            };
+
+            raw[offset..offset + 2].copy_from_slice(&unit.to_le_bytes());
+        }
+
+        entries.push(raw);
+    }
+
+    entries.push(short_entry);
+    entries
+}
+
+/// A short entry with no name yet, for `entries_for`
+pub(super) fn new_short_entry(attributes: u8, first_cluster: u32, size: u32) -> [u8; ENTRY_SIZE] {
+    let mut raw = [0; ENTRY_SIZE];
+    raw[11] = attributes;
+
+    // Creation, last access and last write dates
+    for offset in [16, 18, 24] {
+        raw[offset..offset + 2].copy_from_slice(&DEFAULT_DATE.to_le_bytes());
+    }
+
+    set_first_cluster(&mut raw, first_cluster);
+    set_size(&mut raw, size);
+
+    raw
+}
+
+/// The `.` or `..` entry at the start of a directory
+pub(super) fn dot_entry(name: &[u8], cluster: u32) -> [u8; ENTRY_SIZE] {
+    let mut raw = new_short_entry(ATTR_DIRECTORY, cluster, 0);
+    raw[..11].fill(b' ');
+    raw[..name.len()].copy_from_slice(name);
+    raw
+}
@@ -0,0 +1,483 @@
+use super::dir::{self, ENTRY_SIZE};
+use super::{State, Volume, ROOT_REGION};
+use crate::fs::{DirEntry, File, FileType, FsError, Inode, Metadata, Result};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+
+#[derive(Clone, Copy)]
+enum Node {
+    /// A directory by its first cluster (or `ROOT_REGION`)
+    Directory(u32),
+    /// A file by where its short entry is, and how many times that entry had been removed when it was looked up
+    File {
+        directory: u32,
+        slot: usize,
+        generation: u32,
+    },
+}
+
+#[derive(Clone)]
+pub(super) struct FatInode {
+    volume: Arc<Volume>,
+    node: Node,
+}
+
+impl FatInode {
+    pub(super) fn directory(volume: Arc<Volume>, directory: u32) -> Self {
+        FatInode {
+            volume,
+            node: Node::Directory(directory),
+        }
+    }
+
+    fn directory_cluster(&self) -> Result<u32> {
+        match self.node {
+            Node::Directory(directory) => Ok(directory),
+            Node::File { .. } => Err(FsError::NotADirectory),
+        }
+    }
+
+    fn entries(&self, directory: u32) -> Result<Vec<dir::Entry>> {
+        Ok(dir::parse(&self.volume.read_directory(directory)?))
+    }
+
+    fn child(&self, state: &State, directory: u32, entry: &dir::Entry) -> Result<FatInode> {
+        let node = if entry.is_directory() {
+            match entry.first_cluster() {
+                ROOT_REGION => return Err(FsError::Corrupted),
+                cluster => Node::Directory(cluster),
+            }
+        } else {
+            Node::File {
+                directory,
+                slot: entry.slot,
+                generation: state.generation(directory, entry.slot),
+            }
+        };
+
+        Ok(FatInode {
+            volume: self.volume.clone(),
+            node,
+        })
+    }
+
+    /// The file's short entry, which has to be read again every time as the file may have been written through
+    /// another inode
+    fn file_entry(&self, state: &State) -> Result<(u32, usize, [u8; ENTRY_SIZE])> {
+        let Node::File {
+            directory,
+            slot,
+            generation,
+        } = self.node
+        else {
+            return Err(FsError::IsADirectory);
+        };
+
+        // The entry may belong to another file by now
+        if state.generation(directory, slot) != generation {
+            return Err(FsError::StaleHandle);
+        }
+
+        let raw = self.volume.read_slot(directory, slot)?;
+
+        // The file was removed or renamed since it was looked up
+        if !dir::is_live(&raw) || raw[11] & (dir::ATTR_DIRECTORY | dir::ATTR_VOLUME_ID) != 0 {
+            return Err(FsError::NotFound);
+        }
+
+        Ok((directory, slot, raw))
+    }
+
+    /// What a `..` entry holds to point at `directory`
+    fn dot_dot_cluster(&self, directory: u32) -> u32 {
+        if directory == self.volume.root_directory {
+            ROOT_REGION
+        } else {
+            directory
+        }
+    }
+
+    /// Makes sure the file with short entry `raw` has clusters for `new_size` bytes, and zeroes whatever is between
+    /// its current size and `new_size`. Returns its clusters.
+    fn grow(
+        &self,
+        state: &mut State,
+        directory: u32,
+        slot: usize,
+        raw: &mut [u8; ENTRY_SIZE],
+        new_size: u64,
+    ) -> Result<Vec<u32>> {
+        let volume = &self.volume;
+        let mut clusters = volume.chain(dir::first_cluster(raw))?;
+        let old_size = dir::size(raw) as u64;
+
+        // The end of the last cluster can still hold data from before the file was truncated
+        let allocated = clusters.len() as u64 * volume.cluster_size;
+        let stale_end = allocated.min(new_size);
+        if stale_end > old_size {
+            for (offset, length) in
+                volume.file_extents(&clusters, old_size, (stale_end - old_size) as usize)?
+            {
+                volume.zero(offset, length as u64)?;
+            }
+        }
+
+        while (clusters.len() as u64 * volume.cluster_size) < new_size {
+            let cluster = volume.allocate_cluster(state, clusters.last().copied())?;
+
+            // Record the first cluster straight away so it isn't leaked if a later allocation fails
+            if clusters.is_empty() {
+                dir::set_first_cluster(raw, cluster);
+                volume.write_slot(directory, slot, raw)?;
+            }
+
+            clusters.push(cluster);
+        }
+
+        Ok(clusters)
+    }
+}
+
+impl Inode for FatInode {
+    fn metadata(&self) -> Result<Metadata> {
+        match self.node {
+            Node::Directory(_) => Ok(Metadata {
+                file_type: FileType::Directory,
+                size: 0,
+            }),
+            Node::File { .. } => {
+                let state = self.volume.state.lock();
+                let (_, _, raw) = self.file_entry(&state)?;
+
+                Ok(Metadata {
+                    file_type: FileType::File,
+                    size: dir::size(&raw) as u64,
+                })
+            }
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>> {
+        let directory = self.directory_cluster()?;
+        let state = self.volume.state.lock();
+
+        let entries = self.entries(directory)?;
+        let entry = entries
+            .iter()
+            .find(|entry| entry.matches(name))
+            .ok_or(FsError::NotFound)?;
+
+        Ok(Arc::new(self.child(&state, directory, entry)?))
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>> {
+        let directory = self.directory_cluster()?;
+        let _state = self.volume.state.lock();
+
+        Ok(self
+            .entries(directory)?
+            .into_iter()
+            .map(|entry| DirEntry {
+                file_type: if entry.is_directory() {
+                    FileType::Directory
+                } else {
+                    FileType::File
+                },
+                name: entry.name,
+            })
+            .collect())
+    }
+
+    fn open(&self) -> Result<Arc<dyn File>> {
+        match self.node {
+            Node::File { .. } => Ok(Arc::new(self.clone())),
+            Node::Directory(_) => Err(FsError::IsADirectory),
+        }
+    }
+
+    fn create(&self, name: &str, file_type: FileType) -> Result<Arc<dyn Inode>> {
+        let directory = self.directory_cluster()?;
+        dir::validate_name(name)?;
+
+        let volume = &self.volume;
+        let mut state = volume.state.lock();
+
+        let entries = self.entries(directory)?;
+        if entries.iter().any(|entry| entry.matches(name)) {
+            return Err(FsError::AlreadyExists);
+        }
+
+        let stored = dir::stored_name(name, |short_name| {
+            entries.iter().any(|entry| &entry.short_name == short_name)
+        })?;
+
+        let short_entry = match file_type {
+            FileType::File => dir::new_short_entry(dir::ATTR_ARCHIVE, 0, 0),
+            FileType::Directory => {
+                let cluster = volume.allocate_cluster(&mut state, None)?;
+
+                let initialised = volume
+                    .write_slot(cluster, 0, &dir::dot_entry(b".", cluster))
+                    .and_then(|_| {
+                        volume.write_slot(
+                            cluster,
+                            1,
+                            &dir::dot_entry(b"..", self.dot_dot_cluster(directory)),
+                        )
+                    });
+
+                if let Err(error) = initialised {
+                    volume.free_chain(&mut state, cluster)?;
+                    return Err(error);
+                }
+
+                dir::new_short_entry(dir::ATTR_DIRECTORY, cluster, 0)
+            }
+            FileType::Symlink => return Err(FsError::InvalidArgument),
+        };
+
+        let slot = match volume.insert_entries(
+            &mut state,
+            directory,
+            &dir::entries_for(name, &stored, short_entry),
+        ) {
+            Ok(slot) => slot,
+            Err(error) => {
+                if dir::first_cluster(&short_entry) != 0 {
+                    volume.free_chain(&mut state, dir::first_cluster(&short_entry))?;
+                }
+                return Err(error);
+            }
+        };
+
+        let entries = self.entries(directory)?;
+        let entry = entries
+            .iter()
+            .find(|entry| entry.slot == slot)
+            .ok_or(FsError::Corrupted)?;
+
+        Ok(Arc::new(self.child(&state, directory, entry)?))
+    }
+
+    fn symlink(&self, _name: &str, _target: &str) -> Result<Arc<dyn Inode>> {
+        Err(FsError::Unsupported)
+    }
+
+    fn unlink(&self, name: &str) -> Result<()> {
+        let directory = self.directory_cluster()?;
+        let volume = &self.volume;
+        let mut state = volume.state.lock();
+
+        let entries = self.entries(directory)?;
+        let entry = entries
+            .iter()
+            .find(|entry| entry.matches(name))
+            .ok_or(FsError::NotFound)?;
+
+        if entry.is_directory() && !self.entries(entry.first_cluster())?.is_empty() {
+            return Err(FsError::DirectoryNotEmpty);
+        }
+
+        // Remove the entry before freeing its clusters, so a failure part way leaks space rather than leaving an
+        // entry pointing at free clusters
+        volume.remove_entry(&mut state, directory, entry)?;
+
+        if entry.first_cluster() != 0 {
+            volume.free_chain(&mut state, entry.first_cluster())?;
+        }
+
+        Ok(())
+    }
+
+    fn rename(&self, old_name: &str, new_parent: &Arc<dyn Inode>, new_name: &str) -> Result<()> {
+        let new_parent = new_parent
+            .as_any()
+            .downcast_ref::<FatInode>()
+            .ok_or(FsError::CrossDevice)?;
+
+        if !Arc::ptr_eq(&self.volume, &new_parent.volume) {
+            return Err(FsError::CrossDevice);
+        }
+
+        let from = self.directory_cluster()?;
+        let to = new_parent.directory_cluster()?;
+        dir::validate_name(new_name)?;
+
+        let volume = &self.volume;
+        let mut state = volume.state.lock();
+
+        let from_entries = self.entries(from)?;
+        let entry = from_entries
+            .iter()
+            .find(|entry| entry.matches(old_name))
+            .ok_or(FsError::NotFound)?;
+
+        // A directory can't be moved inside itself, so walk up from the destination to the root
+        if entry.is_directory() {
+            let mut directory = to;
+
+            for _ in 0..volume.cluster_count {
+                if directory == entry.first_cluster() {
+                    return Err(FsError::InvalidArgument);
+                }
+
+                if directory == volume.root_directory {
+                    break;
+                }
+
+                directory = volume.parent_directory(directory)?;
+            }
+
+            if directory != volume.root_directory {
+                return Err(FsError::Corrupted);
+            }
+        }
+
+        let to_entries = self.entries(to)?;
+        let existing = to_entries
+            .iter()
+            .find(|existing| existing.matches(new_name))
+            // Renaming an entry to itself, eg. to change its case, just rewrites it
+            .filter(|existing| !(from == to && existing.slot == entry.slot));
+
+        if let Some(existing) = existing {
+            match (entry.is_directory(), existing.is_directory()) {
+                (true, true) if !self.entries(existing.first_cluster())?.is_empty() => {
+                    return Err(FsError::DirectoryNotEmpty)
+                }
+                (true, false) => return Err(FsError::NotADirectory),
+                (false, true) => return Err(FsError::IsADirectory),
+                _ => {}
+            }
+        }
+
+        // The new entries go in before the old ones are removed, so nothing is lost if the directory is full
+        let stored = dir::stored_name(new_name, |short_name| {
+            to_entries
+                .iter()
+                .any(|entry| &entry.short_name == short_name)
+        })?;
+        volume.insert_entries(
+            &mut state,
+            to,
+            &dir::entries_for(new_name, &stored, entry.raw),
+        )?;
+        volume.remove_entry(&mut state, from, entry)?;
+
+        if let Some(existing) = existing {
+            volume.remove_entry(&mut state, to, existing)?;
+
+            if existing.first_cluster() != 0 {
+                volume.free_chain(&mut state, existing.first_cluster())?;
+            }
+        }
+
+        if entry.is_directory() && from != to {
+            let mut dot_dot = volume.read_slot(entry.first_cluster(), 1)?;
+            dir::set_first_cluster(&mut dot_dot, self.dot_dot_cluster(to));
+            volume.write_slot(entry.first_cluster(), 1, &dot_dot)?;
+        }
+
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl File for FatInode {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        let volume = &self.volume;
+        let state = volume.state.lock();
+        let (_, _, raw) = self.file_entry(&state)?;
+
+        let size = dir::size(&raw) as u64;
+        if offset >= size {
+            return Ok(0);
+        }
+
+        let length = (size - offset).min(buf.len() as u64) as usize;
+        let clusters = volume.chain(dir::first_cluster(&raw))?;
+        let mut done = 0;
+
+        for (device_offset, extent_length) in volume.file_extents(&clusters, offset, length)? {
+            crate::block::read_bytes(
+                &*volume.device,
+                device_offset,
+                &mut buf[done..done + extent_length],
+            )?;
+            done += extent_length;
+        }
+
+        Ok(length)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize> {
+        let end = offset
+            .checked_add(buf.len() as u64)
+            .filter(|&end| end <= u32::MAX as u64)
+            .ok_or(FsError::FileTooLarge)?;
+
+        let volume = &self.volume;
+        let mut state = volume.state.lock();
+        let (directory, slot, mut raw) = self.file_entry(&state)?;
+
+        // Writing nothing doesn't extend the file, even past its end
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let clusters = self.grow(&mut state, directory, slot, &mut raw, end)?;
+        let mut done = 0;
+
+        for (device_offset, length) in volume.file_extents(&clusters, offset, buf.len())? {
+            crate::block::write_bytes(&*volume.device, device_offset, &buf[done..done + length])?;
+            done += length;
+        }
+
+        if end > dir::size(&raw) as u64 {
+            dir::set_size(&mut raw, end as u32);
+        }
+        raw[11] |= dir::ATTR_ARCHIVE;
+        volume.write_slot(directory, slot, &raw)?;
+
+        Ok(buf.len())
+    }
+
+    fn size(&self) -> Result<u64> {
+        let state = self.volume.state.lock();
+        let (_, _, raw) = self.file_entry(&state)?;
+        Ok(dir::size(&raw) as u64)
+    }
+
+    fn truncate(&self, size: u64) -> Result<()> {
+        let size = u32::try_from(size).map_err(|_| FsError::FileTooLarge)?;
+
+        let volume = &self.volume;
+        let mut state = volume.state.lock();
+        let (directory, slot, mut raw) = self.file_entry(&state)?;
+
+        if size > dir::size(&raw) {
+            self.grow(&mut state, directory, slot, &mut raw, size as u64)?;
+        } else {
+            let keep = (size as u64).div_ceil(volume.cluster_size) as usize;
+            let first = dir::first_cluster(&raw);
+
+            if first != 0 {
+                volume.shorten_chain(&mut state, first, keep)?;
+            }
+
+            if keep == 0 {
+                dir::set_first_cluster(&mut raw, 0);
+            }
+        }
+
+        dir::set_size(&mut raw, size);
+        raw[11] |= dir::ATTR_ARCHIVE;
+        volume.write_slot(directory, slot, &raw)?;
+
+        Ok(())
+    }
+}
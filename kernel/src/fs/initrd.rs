@@ -0,0 +1,123 @@
+//! Exposes the initrd through the VFS. It is read-only, and every inode just remembers its path in the archive.
+
+use crate::fs::{DirEntry, File, FileType, Filesystem, FsError, Inode, Metadata, Result};
+use crate::initrd::{EntryKind, Initrd};
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+
+pub struct InitrdFs {
+    initrd: &'static Initrd,
+}
+
+impl InitrdFs {
+    pub fn new(initrd: &'static Initrd) -> Self {
+        InitrdFs { initrd }
+    }
+}
+
+impl Filesystem for InitrdFs {
+    fn name(&self) -> &'static str {
+        "initrd"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(InitrdInode {
+            initrd: self.initrd,
+            path: String::new(),
+            kind: EntryKind::Directory,
+        })
+    }
+}
+
+struct InitrdInode {
+    initrd: &'static Initrd,
+    /// Empty for the root
+    path: String,
+    kind: EntryKind,
+}
+
+impl Inode for InitrdInode {
+    fn metadata(&self) -> Result<Metadata> {
+        Ok(match self.kind {
+            EntryKind::File => Metadata {
+                file_type: FileType::File,
+                size: self.initrd.file(&self.path).map_or(0, |data| data.len()) as u64,
+            },
+            EntryKind::Directory => Metadata {
+                file_type: FileType::Directory,
+                size: 0,
+            },
+        })
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>> {
+        if self.kind != EntryKind::Directory {
+            return Err(FsError::NotADirectory);
+        }
+
+        let path = if self.path.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}/{}", self.path, name)
+        };
+        let kind = self.initrd.kind(&path).ok_or(FsError::NotFound)?;
+
+        Ok(Arc::new(InitrdInode {
+            initrd: self.initrd,
+            path,
+            kind,
+        }))
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>> {
+        let entries = self
+            .initrd
+            .read_dir(&self.path)
+            .ok_or(FsError::NotADirectory)?;
+
+        Ok(entries
+            .into_iter()
+            .map(|entry| DirEntry {
+                name: entry.name.to_string(),
+                file_type: match entry.kind {
+                    EntryKind::File => FileType::File,
+                    EntryKind::Directory => FileType::Directory,
+                },
+            })
+            .collect())
+    }
+
+    fn open(&self) -> Result<Arc<dyn File>> {
+        match self.kind {
+            EntryKind::File => Ok(Arc::new(InitrdFile {
+                data: self.initrd.file(&self.path).ok_or(FsError::NotFound)?,
+            })),
+            EntryKind::Directory => Err(FsError::IsADirectory),
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+struct InitrdFile {
+    data: &'static [u8],
+}
+
+impl File for InitrdFile {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        let remaining = self.data.get(offset as usize..).unwrap_or(&[]);
+        let read = remaining.len().min(buf.len());
+        buf[..read].copy_from_slice(&remaining[..read]);
+
+        Ok(read)
+    }
+
+    fn size(&self) -> Result<u64> {
+        Ok(self.data.len() as u64)
+    }
+}
@@ -0,0 +1,400 @@
+//! A writable filesystem kept on the kernel heap. The data in files and symlinks counts towards a size limit, and the
+//! number of inodes is limited too, so a runaway writer gets `NoSpace` instead of exhausting the heap.
+//!
+//! Lookups only lock the directory they are in, but everything that changes the tree (create, unlink, rename) is
+//! serialised by one lock per filesystem, which keeps rename free of lock ordering problems.
+
+use crate::fs::{DirEntry, File, FileType, Filesystem, FsError, Inode, Metadata, Result};
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::any::Any;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::{Mutex, RwLock};
+
+/// Longest name in a directory, in bytes
+const MAX_NAME_LENGTH: usize = 255;
+
+pub struct Tmpfs {
+    root: Arc<TmpfsInode>,
+}
+
+impl Tmpfs {
+    /// A filesystem holding at most `max_bytes` of file data in at most `max_inodes` files, directories and symlinks
+    /// (including the root)
+    pub fn new(max_bytes: usize, max_inodes: usize) -> Self {
+        let usage = Arc::new(Usage {
+            bytes: AtomicUsize::new(0),
+            inodes: AtomicUsize::new(0),
+            max_bytes,
+            max_inodes,
+            namespace_lock: Mutex::new(()),
+        });
+
+        let root = TmpfsInode::new(&usage, Weak::new(), Node::Directory(BTreeMap::new()))
+            .expect("tmpfs needs space for its root directory");
+
+        Tmpfs { root }
+    }
+
+    /// Bytes of file data currently stored
+    pub fn used_bytes(&self) -> usize {
+        self.root.usage.bytes.load(Ordering::Relaxed)
+    }
+}
+
+impl Filesystem for Tmpfs {
+    fn name(&self) -> &'static str {
+        "tmpfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+/// Shared by every inode of one filesystem
+struct Usage {
+    bytes: AtomicUsize,
+    inodes: AtomicUsize,
+    max_bytes: usize,
+    max_inodes: usize,
+    namespace_lock: Mutex<()>,
+}
+
+impl Usage {
+    fn reserve(counter: &AtomicUsize, amount: usize, limit: usize) -> Result<()> {
+        let used = counter.fetch_add(amount, Ordering::Relaxed);
+
+        if !used.checked_add(amount).is_some_and(|total| total <= limit) {
+            counter.fetch_sub(amount, Ordering::Relaxed);
+            return Err(FsError::NoSpace);
+        }
+
+        Ok(())
+    }
+
+    fn reserve_bytes(&self, bytes: usize) -> Result<()> {
+        Usage::reserve(&self.bytes, bytes, self.max_bytes)
+    }
+
+    fn release_bytes(&self, bytes: usize) {
+        self.bytes.fetch_sub(bytes, Ordering::Relaxed);
+    }
+}
+
+enum Node {
+    File(Vec<u8>),
+    Directory(BTreeMap<String, Arc<TmpfsInode>>),
+    Symlink(String),
+}
+
+impl Node {
+    fn file_type(&self) -> FileType {
+        match self {
+            Node::File(_) => FileType::File,
+            Node::Directory(_) => FileType::Directory,
+            Node::Symlink(_) => FileType::Symlink,
+        }
+    }
+
+    /// How much of the size limit this node uses
+    fn size(&self) -> usize {
+        match self {
+            Node::File(data) => data.len(),
+            Node::Directory(_) => 0,
+            Node::Symlink(target) => target.len(),
+        }
+    }
+}
+
+struct TmpfsInode {
+    usage: Arc<Usage>,
+    /// Lets `open` hand out this inode as the `File`
+    this: Weak<TmpfsInode>,
+    /// Used to stop a directory being moved inside itself, empty for the root
+    parent: RwLock<Weak<TmpfsInode>>,
+    node: RwLock<Node>,
+}
+
+impl TmpfsInode {
+    /// Takes an inode (and the size of `node`) from the limits
+    fn new(usage: &Arc<Usage>, parent: Weak<TmpfsInode>, node: Node) -> Result<Arc<Self>> {
+        Usage::reserve(&usage.inodes, 1, usage.max_inodes)?;
+
+        if let Err(error) = usage.reserve_bytes(node.size()) {
+            usage.inodes.fetch_sub(1, Ordering::Relaxed);
+            return Err(error);
+        }
+
+        Ok(Arc::new_cyclic(|this| TmpfsInode {
+            usage: usage.clone(),
+            this: this.clone(),
+            parent: RwLock::new(parent),
+            node: RwLock::new(node),
+        }))
+    }
+
+    fn child(&self, name: &str) -> Result<Arc<TmpfsInode>> {
+        match &*self.node.read() {
+            Node::Directory(children) => children.get(name).cloned().ok_or(FsError::NotFound),
+            _ => Err(FsError::NotADirectory),
+        }
+    }
+
+    /// Adds a new child, the caller holds the namespace lock
+    fn insert(&self, name: &str, node: Node) -> Result<Arc<TmpfsInode>> {
+        check_name(name)?;
+
+        if !matches!(&*self.node.read(), Node::Directory(_)) {
+            return Err(FsError::NotADirectory);
+        }
+
+        if self.child(name).is_ok() {
+            return Err(FsError::AlreadyExists);
+        }
+
+        let inode = TmpfsInode::new(&self.usage, self.this.clone(), node)?;
+
+        if let Node::Directory(children) = &mut *self.node.write() {
+            children.insert(name.to_string(), inode.clone());
+        }
+
+        Ok(inode)
+    }
+
+    fn is_empty_directory(&self) -> bool {
+        matches!(&*self.node.read(), Node::Directory(children) if children.is_empty())
+    }
+
+    /// Whether `self` is `other` or one of its ancestors
+    fn contains(&self, other: &Arc<TmpfsInode>) -> bool {
+        let mut inode = Some(other.clone());
+
+        while let Some(current) = inode {
+            if core::ptr::eq(&*current, self) {
+                return true;
+            }
+
+            inode = current.parent.read().upgrade();
+        }
+
+        false
+    }
+}
+
+impl Drop for TmpfsInode {
+    fn drop(&mut self) {
+        self.usage.release_bytes(self.node.get_mut().size());
+        self.usage.inodes.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn check_name(name: &str) -> Result<()> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+        return Err(FsError::InvalidPath);
+    }
+
+    if name.len() > MAX_NAME_LENGTH {
+        return Err(FsError::NameTooLong);
+    }
+
+    Ok(())
+}
+
+impl Inode for TmpfsInode {
+    fn metadata(&self) -> Result<Metadata> {
+        let node = self.node.read();
+
+        Ok(Metadata {
+            file_type: node.file_type(),
+            size: node.size() as u64,
+        })
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>> {
+        Ok(self.child(name)?)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>> {
+        let Node::Directory(children) = &*self.node.read() else {
+            return Err(FsError::NotADirectory);
+        };
+
+        Ok(children
+            .iter()
+            .map(|(name, child)| DirEntry {
+                name: name.clone(),
+                file_type: child.node.read().file_type(),
+            })
+            .collect())
+    }
+
+    fn open(&self) -> Result<Arc<dyn File>> {
+        match &*self.node.read() {
+            Node::File(_) => Ok(self.this.upgrade().unwrap()),
+            Node::Directory(_) => Err(FsError::IsADirectory),
+            Node::Symlink(_) => Err(FsError::Unsupported),
+        }
+    }
+
+    fn read_link(&self) -> Result<String> {
+        match &*self.node.read() {
+            Node::Symlink(target) => Ok(target.clone()),
+            _ => Err(FsError::NotASymlink),
+        }
+    }
+
+    fn create(&self, name: &str, file_type: FileType) -> Result<Arc<dyn Inode>> {
+        let node = match file_type {
+            FileType::File => Node::File(Vec::new()),
+            FileType::Directory => Node::Directory(BTreeMap::new()),
+            FileType::Symlink => return Err(FsError::InvalidArgument),
+        };
+
+        let _namespace = self.usage.namespace_lock.lock();
+        Ok(self.insert(name, node)?)
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> Result<Arc<dyn Inode>> {
+        let _namespace = self.usage.namespace_lock.lock();
+        Ok(self.insert(name, Node::Symlink(target.to_string()))?)
+    }
+
+    fn unlink(&self, name: &str) -> Result<()> {
+        let _namespace = self.usage.namespace_lock.lock();
+        let child = self.child(name)?;
+
+        if child.node.read().file_type() == FileType::Directory && !child.is_empty_directory() {
+            return Err(FsError::DirectoryNotEmpty);
+        }
+
+        if let Node::Directory(children) = &mut *self.node.write() {
+            children.remove(name);
+        }
+
+        // Anything with the file open keeps its data (and its share of the limit) until it is closed
+        *child.parent.write() = Weak::new();
+
+        Ok(())
+    }
+
+    fn rename(&self, old_name: &str, new_parent: &Arc<dyn Inode>, new_name: &str) -> Result<()> {
+        let new_parent = new_parent
+            .as_any()
+            .downcast_ref::<TmpfsInode>()
+            .ok_or(FsError::CrossDevice)?;
+
+        if !Arc::ptr_eq(&self.usage, &new_parent.usage) {
+            return Err(FsError::CrossDevice);
+        }
+
+        check_name(new_name)?;
+
+        let _namespace = self.usage.namespace_lock.lock();
+        let child = self.child(old_name)?;
+        let new_parent = new_parent.this.upgrade().unwrap();
+
+        if child.node.read().file_type() == FileType::Directory && child.contains(&new_parent) {
+            return Err(FsError::InvalidArgument);
+        }
+
+        match new_parent.child(new_name) {
+            Ok(existing) if Arc::ptr_eq(&existing, &child) => return Ok(()),
+            Ok(existing) => {
+                let existing_type = existing.node.read().file_type();
+                let child_type = child.node.read().file_type();
+
+                match (child_type, existing_type) {
+                    (FileType::Directory, FileType::Directory)
+                        if !existing.is_empty_directory() =>
+                    {
+                        return Err(FsError::DirectoryNotEmpty)
+                    }
+                    (FileType::Directory, FileType::Directory) => {}
+                    (FileType::Directory, _) => return Err(FsError::NotADirectory),
+                    (_, FileType::Directory) => return Err(FsError::IsADirectory),
+                    _ => {}
+                }
+
+                *existing.parent.write() = Weak::new();
+            }
+            Err(FsError::NotFound) => {}
+            Err(error) => return Err(error),
+        }
+
+        if let Node::Directory(children) = &mut *self.node.write() {
+            children.remove(old_name);
+        }
+
+        if let Node::Directory(children) = &mut *new_parent.node.write() {
+            children.insert(new_name.to_string(), child.clone());
+        }
+
+        *child.parent.write() = Arc::downgrade(&new_parent);
+
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl File for TmpfsInode {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        let Node::File(data) = &*self.node.read() else {
+            return Err(FsError::IsADirectory);
+        };
+
+        let remaining = data.get(offset as usize..).unwrap_or(&[]);
+        let read = remaining.len().min(buf.len());
+        buf[..read].copy_from_slice(&remaining[..read]);
+
+        Ok(read)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize> {
+        let Node::File(data) = &mut *self.node.write() else {
+            return Err(FsError::IsADirectory);
+        };
+
+        let end = usize::try_from(offset)
+            .ok()
+            .and_then(|offset| offset.checked_add(buf.len()))
+            .ok_or(FsError::FileTooLarge)?;
+
+        if end > data.len() {
+            self.usage.reserve_bytes(end - data.len())?;
+            data.resize(end, 0);
+        }
+
+        data[offset as usize..end].copy_from_slice(buf);
+
+        Ok(buf.len())
+    }
+
+    fn size(&self) -> Result<u64> {
+        Ok(self.node.read().size() as u64)
+    }
+
+    fn truncate(&self, size: u64) -> Result<()> {
+        let Node::File(data) = &mut *self.node.write() else {
+            return Err(FsError::IsADirectory);
+        };
+
+        let size = usize::try_from(size).map_err(|_| FsError::FileTooLarge)?;
+
+        if size > data.len() {
+            self.usage.reserve_bytes(size - data.len())?;
+        } else {
+            self.usage.release_bytes(data.len() - size);
+        }
+
+        data.resize(size, 0);
+        data.shrink_to_fit();
+
+        Ok(())
+    }
+}
@@ -0,0 +1,635 @@
+//! A GDB remote serial protocol stub, so the kernel can be debugged over a serial port on real hardware.
+//!
+//! Once `init` has been called every breakpoint (`int3`) and debug exception stops the kernel and hands control to
+//! GDB until it continues or steps. Connect with `target remote /dev/ttyS1` (or a pty/socket when running in QEMU).
+//!
+//! Everything here runs inside an exception handler with interrupts disabled, so it only uses polled serial IO and
+//! fixed size buffers and never touches the heap or any lock the interrupted code could be holding.
+
+use crate::io::drivers::serial::{self, SerialPort};
+use crate::io::interrupts::crash_report::ExceptionFrame;
+use crate::memory;
+use conquer_once::spin::OnceCell;
+use core::fmt;
+use core::fmt::Write;
+use spin::Mutex;
+use x86_64::instructions::segmentation::{Segment, DS, ES, FS, GS};
+use x86_64::registers::control::{Cr0, Cr0Flags};
+use x86_64::VirtAddr;
+
+/// Largest packet we accept or send, advertised to GDB in `qSupported`
+const PACKET_SIZE: usize = 0x1000;
+
+const MAX_BREAKPOINTS: usize = 32;
+
+const INT3: u8 = 0xcc;
+
+/// The trap flag in RFLAGS makes the CPU raise a debug exception after the next instruction
+const RFLAGS_TF: u64 = 1 << 8;
+
+/// GDB's x86_64 register numbering. rip is followed by eflags and the segment registers which are all 32 bits wide.
+const REGISTER_COUNT: usize = 24;
+const RIP: usize = 16;
+
+/// SIGTRAP, the only reason we ever stop
+const STOP_REPLY: &[u8] = b"S05";
+
+/// Base of the serial port the stub talks over
+static GDB_PORT: OnceCell<u16> = OnceCell::uninit();
+
+static STATE: Mutex<GdbState> = Mutex::new(GdbState::new());
+
+/// Starts handling breakpoint and debug exceptions on the serial port at `serial_base`. The port is only used with
+/// polled IO, so it shouldn't be used for anything else.
+pub fn init(serial_base: u16) {
+    GDB_PORT.init_once(|| serial_base);
+    log::info!("GDB stub listening on serial port {:#x}", serial_base);
+}
+
+/// The serial port the stub talks over, if it is enabled
+pub fn serial_port() -> Option<u16> {
+    GDB_PORT.get().copied()
+}
+
+/// Stops and waits for GDB, if the stub is enabled
+#[inline(always)]
+pub fn breakpoint() {
+    if GDB_PORT.is_initialized() {
+        x86_64::instructions::interrupts::int3();
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Breakpoint {
+    address: u64,
+    original: u8,
+}
+
+struct GdbState {
+    breakpoints: [Option<Breakpoint>; MAX_BREAKPOINTS],
+    /// Whether the `int3`s are currently written into memory. They are taken out whenever we stop so that GDB sees the
+    /// original instructions.
+    inserted: bool,
+    /// Set while single stepping over a breakpoint so that it can be put back afterwards
+    stepping_over_breakpoint: bool,
+    /// Set once GDB has sent its first packet, before that there is nobody to send a stop reply to
+    attached: bool,
+    input: Packet,
+    output: Packet,
+}
+
+impl GdbState {
+    const fn new() -> Self {
+        GdbState {
+            breakpoints: [None; MAX_BREAKPOINTS],
+            inserted: false,
+            stepping_over_breakpoint: false,
+            attached: false,
+            input: Packet::new(),
+            output: Packet::new(),
+        }
+    }
+
+    fn breakpoint_at(&self, address: u64) -> bool {
+        self.breakpoints
+            .iter()
+            .flatten()
+            .any(|breakpoint| breakpoint.address == address)
+    }
+
+    fn insert_breakpoints(&mut self) {
+        if self.inserted {
+            return;
+        }
+
+        for breakpoint in self.breakpoints.iter_mut().flatten() {
+            if let Some(original) = read_byte(breakpoint.address) {
+                breakpoint.original = original;
+                write_byte(breakpoint.address, INT3);
+            }
+        }
+
+        self.inserted = true;
+    }
+
+    fn remove_breakpoints(&mut self) {
+        if !self.inserted {
+            return;
+        }
+
+        for breakpoint in self.breakpoints.iter().flatten() {
+            write_byte(breakpoint.address, breakpoint.original);
+        }
+
+        self.inserted = false;
+    }
+
+    fn add_breakpoint(&mut self, address: u64) -> bool {
+        if self.breakpoint_at(address) {
+            return true;
+        }
+
+        match self.breakpoints.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                *slot = Some(Breakpoint {
+                    address,
+                    original: 0,
+                });
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn remove_breakpoint(&mut self, address: u64) {
+        for slot in self.breakpoints.iter_mut() {
+            if slot.is_some_and(|breakpoint| breakpoint.address == address) {
+                *slot = None;
+            }
+        }
+    }
+}
+
+/// Called by the exception dispatcher for breakpoint and debug exceptions. Returns once GDB continues or steps.
+pub(crate) fn handle_exception(frame: &mut ExceptionFrame) {
+    let Some(&port) = GDB_PORT.get() else {
+        return;
+    };
+
+    // A breakpoint inside the stub itself can't be debugged
+    let Some(mut state) = STATE.try_lock() else {
+        return;
+    };
+
+    let was_inserted = state.inserted;
+    state.remove_breakpoints();
+
+    // After stepping over a breakpoint put it back and carry on
+    if frame.vector == 0x01 && state.stepping_over_breakpoint {
+        state.stepping_over_breakpoint = false;
+        frame.stack_frame.cpu_flags &= !RFLAGS_TF;
+        state.insert_breakpoints();
+        return;
+    }
+
+    // The CPU reports the address after the int3, GDB expects the address of the breakpoint
+    if frame.vector == 0x03 && was_inserted {
+        let address = frame.stack_frame.instruction_pointer.as_u64() - 1;
+        if state.breakpoint_at(address) {
+            frame.stack_frame.instruction_pointer = VirtAddr::new(address);
+        }
+    }
+
+    frame.stack_frame.cpu_flags &= !RFLAGS_TF;
+
+    let mut connection = Connection {
+        serial_port: serial::unlocked(port),
+    };
+
+    if state.attached {
+        connection.send_packet(STOP_REPLY);
+    }
+
+    loop {
+        connection.receive_packet(&mut state.input);
+        state.output.clear();
+        state.attached = true;
+
+        let command = state.input.as_bytes();
+        let (&kind, arguments) = match command.split_first() {
+            Some(split) => split,
+            None => (&0, &[][..]),
+        };
+
+        // Copy the arguments out so that the state can be borrowed mutably while handling them
+        let mut argument_buffer = [0; PACKET_SIZE];
+        argument_buffer[..arguments.len()].copy_from_slice(arguments);
+        let arguments = &argument_buffer[..arguments.len()];
+
+        let resume = match kind {
+            b'?' => {
+                state.output.push_bytes(STOP_REPLY);
+                None
+            }
+            b'g' => {
+                for register in 0..REGISTER_COUNT {
+                    let (value, size) = read_register(frame, register).unwrap();
+                    state.output.push_hex(&value.to_le_bytes()[..size]);
+                }
+                None
+            }
+            b'G' => {
+                write_registers(frame, arguments);
+                state.output.push_bytes(b"OK");
+                None
+            }
+            b'p' => {
+                match parse_hex(arguments)
+                    .and_then(|register| read_register(frame, register as usize))
+                {
+                    Some((value, size)) => state.output.push_hex(&value.to_le_bytes()[..size]),
+                    None => state.output.push_bytes(b"E00"),
+                }
+                None
+            }
+            b'P' => {
+                let written = split_at(arguments, b'=').and_then(|(register, value)| {
+                    let register = parse_hex(register)? as usize;
+                    let (_, size) = read_register(frame, register)?;
+                    write_register(frame, register, parse_le_hex(value, size)?);
+                    Some(())
+                });
+
+                state
+                    .output
+                    .push_bytes(if written.is_some() { b"OK" } else { b"E00" });
+                None
+            }
+            b'm' => {
+                read_memory_command(arguments, &mut state.output);
+                None
+            }
+            b'M' => {
+                let written = write_memory_command(arguments);
+                state
+                    .output
+                    .push_bytes(if written { b"OK" } else { b"E14" });
+                None
+            }
+            b'Z' | b'z' => {
+                breakpoint_command(kind == b'Z', arguments, &mut state);
+                None
+            }
+            b'c' => Some(false),
+            b's' => Some(true),
+            // Detaching or killing just lets the kernel carry on
+            b'D' => {
+                state.output.push_bytes(b"OK");
+                connection.send_packet(state.output.as_bytes());
+                state.breakpoints = [None; MAX_BREAKPOINTS];
+                state.attached = false;
+                return;
+            }
+            b'k' => {
+                state.breakpoints = [None; MAX_BREAKPOINTS];
+                state.attached = false;
+                return;
+            }
+            b'H' => {
+                state.output.push_bytes(b"OK");
+                None
+            }
+            b'q' if arguments.starts_with(b"Supported") => {
+                let _ = write!(state.output, "PacketSize={:x}", PACKET_SIZE);
+                None
+            }
+            b'q' if arguments.starts_with(b"Attached") => {
+                state.output.push_bytes(b"1");
+                None
+            }
+            b'q' if arguments == b"C" => {
+                state.output.push_bytes(b"QC1");
+                None
+            }
+            // An empty reply tells GDB the packet isn't supported
+            _ => None,
+        };
+
+        if let Some(step) = resume {
+            // `c addr` and `s addr` resume from a different address
+            if let Some(address) = parse_hex(arguments) {
+                frame.stack_frame.instruction_pointer = VirtAddr::new_truncate(address);
+            }
+
+            let rip = frame.stack_frame.instruction_pointer.as_u64();
+
+            if step {
+                frame.stack_frame.cpu_flags |= RFLAGS_TF;
+            } else if state.breakpoint_at(rip) {
+                // Execute the real instruction under the breakpoint first, it is put back by the debug exception
+                state.stepping_over_breakpoint = true;
+                frame.stack_frame.cpu_flags |= RFLAGS_TF;
+            } else {
+                state.insert_breakpoints();
+            }
+
+            return;
+        }
+
+        connection.send_packet(state.output.as_bytes());
+    }
+}
+
+fn breakpoint_command(insert: bool, arguments: &[u8], state: &mut GdbState) {
+    // Only software breakpoints (type 0) are supported, the kind is always a single byte int3
+    let mut fields = arguments.split(|&byte| byte == b',');
+    let (Some(b"0"), Some(address)) = (fields.next(), fields.next().and_then(parse_hex)) else {
+        return;
+    };
+
+    if insert {
+        if read_byte(address).is_none() || !state.add_breakpoint(address) {
+            state.output.push_bytes(b"E0e");
+            return;
+        }
+    } else {
+        state.remove_breakpoint(address);
+    }
+
+    state.output.push_bytes(b"OK");
+}
+
+fn read_memory_command(arguments: &[u8], output: &mut Packet) {
+    let Some((address, length)) = parse_address_length(arguments) else {
+        output.push_bytes(b"E01");
+        return;
+    };
+
+    let length = length.min((PACKET_SIZE / 2) as u64);
+
+    for offset in 0..length {
+        match read_byte(address.wrapping_add(offset)) {
+            Some(byte) => output.push_hex(&[byte]),
+            // GDB accepts a short read as long as it contains at least one byte
+            None if offset > 0 => return,
+            None => {
+                output.push_bytes(b"E14");
+                return;
+            }
+        }
+    }
+}
+
+fn write_memory_command(arguments: &[u8]) -> bool {
+    let Some((header, data)) = split_at(arguments, b':') else {
+        return false;
+    };
+    let Some((address, length)) = parse_address_length(header) else {
+        return false;
+    };
+
+    if data.len() as u64 != length * 2 {
+        return false;
+    }
+
+    // Check the whole range first so that a failed write doesn't leave it half written
+    if (0..length).any(|offset| read_byte(address.wrapping_add(offset)).is_none()) {
+        return false;
+    }
+
+    for (offset, hex) in data.chunks(2).enumerate() {
+        let Some(byte) = parse_hex(hex) else {
+            return false;
+        };
+        write_byte(address.wrapping_add(offset as u64), byte as u8);
+    }
+
+    true
+}
+
+fn parse_address_length(arguments: &[u8]) -> Option<(u64, u64)> {
+    let (address, length) = split_at(arguments, b',')?;
+    Some((parse_hex(address)?, parse_hex(length)?))
+}
+
+/// Returns the value of GDB register `register` and its size in bytes
+fn read_register(frame: &ExceptionFrame, register: usize) -> Option<(u64, usize)> {
+    let value = match register {
+        0 => frame.rax,
+        1 => frame.rbx,
+        2 => frame.rcx,
+        3 => frame.rdx,
+        4 => frame.rsi,
+        5 => frame.rdi,
+        6 => frame.rbp,
+        7 => frame.stack_frame.stack_pointer.as_u64(),
+        8 => frame.r8,
+        9 => frame.r9,
+        10 => frame.r10,
+        11 => frame.r11,
+        12 => frame.r12,
+        13 => frame.r13,
+        14 => frame.r14,
+        15 => frame.r15,
+        RIP => frame.stack_frame.instruction_pointer.as_u64(),
+        17 => return Some((frame.stack_frame.cpu_flags, 4)),
+        18 => return Some((frame.stack_frame.code_segment, 4)),
+        19 => return Some((frame.stack_frame.stack_segment, 4)),
+        20 => return Some((DS::get_reg().0 as u64, 4)),
+        21 => return Some((ES::get_reg().0 as u64, 4)),
+        22 => return Some((FS::get_reg().0 as u64, 4)),
+        23 => return Some((GS::get_reg().0 as u64, 4)),
+        _ => return None,
+    };
+
+    Some((value, 8))
+}
+
+/// Writes GDB register `register`. The segment registers are left alone since changing them under the kernel would
+/// only crash it.
+fn write_register(frame: &mut ExceptionFrame, register: usize, value: u64) {
+    match register {
+        0 => frame.rax = value,
+        1 => frame.rbx = value,
+        2 => frame.rcx = value,
+        3 => frame.rdx = value,
+        4 => frame.rsi = value,
+        5 => frame.rdi = value,
+        6 => frame.rbp = value,
+        7 => frame.stack_frame.stack_pointer = VirtAddr::new_truncate(value),
+        8 => frame.r8 = value,
+        9 => frame.r9 = value,
+        10 => frame.r10 = value,
+        11 => frame.r11 = value,
+        12 => frame.r12 = value,
+        13 => frame.r13 = value,
+        14 => frame.r14 = value,
+        15 => frame.r15 = value,
+        RIP => frame.stack_frame.instruction_pointer = VirtAddr::new_truncate(value),
+        17 => frame.stack_frame.cpu_flags = value,
+        _ => {}
+    }
+}
+
+fn write_registers(frame: &mut ExceptionFrame, mut data: &[u8]) {
+    for register in 0..REGISTER_COUNT {
+        let (_, size) = read_register(frame, register).unwrap();
+
+        let Some(value) = data.get(..size * 2).and_then(|hex| parse_le_hex(hex, size)) else {
+            return;
+        };
+        write_register(frame, register, value);
+
+        data = &data[size * 2..];
+    }
+}
+
+fn read_byte(address: u64) -> Option<u8> {
+    let address = VirtAddr::try_new(address).ok()?;
+    memory::translate(address)?;
+
+    Some(unsafe { address.as_ptr::<u8>().read_volatile() })
+}
+
+/// Writes to a mapped address, even if it is read only (eg. kernel code for breakpoints)
+fn write_byte(address: u64, byte: u8) {
+    let Ok(address) = VirtAddr::try_new(address) else {
+        return;
+    };
+    if memory::translate(address).is_none() {
+        return;
+    }
+
+    unsafe {
+        let cr0 = Cr0::read();
+        Cr0::write(cr0 - Cr0Flags::WRITE_PROTECT);
+        address.as_mut_ptr::<u8>().write_volatile(byte);
+        Cr0::write(cr0);
+    }
+}
+
+struct Connection {
+    serial_port: SerialPort,
+}
+
+impl Connection {
+    fn read_byte(&mut self) -> u8 {
+        loop {
+            if let Some(byte) = self.serial_port.read_byte_polled() {
+                return byte;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Waits for a `$data#checksum` packet with a valid checksum, asking GDB to resend any that are corrupted
+    fn receive_packet(&mut self, packet: &mut Packet) {
+        loop {
+            // Skip acks and interrupt requests (0x03) that arrive while we're already stopped
+            while self.read_byte() != b'$' {}
+
+            packet.clear();
+            let mut checksum = 0u8;
+
+            let mut byte = self.read_byte();
+            while byte != b'#' {
+                packet.push(byte);
+                checksum = checksum.wrapping_add(byte);
+                byte = self.read_byte();
+            }
+
+            let expected = [self.read_byte(), self.read_byte()];
+
+            if parse_hex(&expected) == Some(checksum as u64) {
+                self.serial_port.write_byte_polled(b'+');
+                return;
+            }
+
+            self.serial_port.write_byte_polled(b'-');
+        }
+    }
+
+    /// Sends `data` until GDB acknowledges it
+    fn send_packet(&mut self, data: &[u8]) {
+        loop {
+            self.serial_port.write_byte_polled(b'$');
+
+            let mut checksum = 0u8;
+            for &byte in data {
+                self.serial_port.write_byte_polled(byte);
+                checksum = checksum.wrapping_add(byte);
+            }
+
+            self.serial_port.write_byte_polled(b'#');
+            self.serial_port.write_byte_polled(hex_digit(checksum >> 4));
+            self.serial_port
+                .write_byte_polled(hex_digit(checksum & 0xf));
+
+            match self.read_byte() {
+                b'-' => continue,
+                _ => return,
+            }
+        }
+    }
+}
+
+struct Packet {
+    data: [u8; PACKET_SIZE],
+    len: usize,
+}
+
+impl Packet {
+    const fn new() -> Self {
+        Packet {
+            data: [0; PACKET_SIZE],
+            len: 0,
+        }
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    /// Anything past `PACKET_SIZE` is dropped, GDB never sends that much since it is what we advertise
+    fn push(&mut self, byte: u8) {
+        if self.len < PACKET_SIZE {
+            self.data[self.len] = byte;
+            self.len += 1;
+        }
+    }
+
+    fn push_bytes(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.push(byte);
+        }
+    }
+
+    fn push_hex(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.push(hex_digit(byte >> 4));
+            self.push(hex_digit(byte & 0xf));
+        }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+}
+
+impl fmt::Write for Packet {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.push_bytes(s.as_bytes());
+        Ok(())
+    }
+}
+
+fn hex_digit(value: u8) -> u8 {
+    b"0123456789abcdef"[(value & 0xf) as usize]
+}
+
+/// Parses a big endian hex number, as used for addresses and lengths
+fn parse_hex(hex: &[u8]) -> Option<u64> {
+    if hex.is_empty() || hex.len() > 16 {
+        return None;
+    }
+
+    hex.iter().try_fold(0u64, |value, &digit| {
+        Some(value << 4 | (digit as char).to_digit(16)? as u64)
+    })
+}
+
+/// Parses `size` bytes of little endian hex, as used for register values
+fn parse_le_hex(hex: &[u8], size: usize) -> Option<u64> {
+    if hex.len() != size * 2 {
+        return None;
+    }
+
+    hex.chunks(2)
+        .rev()
+        .try_fold(0u64, |value, byte| Some(value << 8 | parse_hex(byte)?))
+}
+
+fn split_at(bytes: &[u8], separator: u8) -> Option<(&[u8], &[u8])> {
+    let index = bytes.iter().position(|&byte| byte == separator)?;
+    Some((&bytes[..index], &bytes[index + 1..]))
+}
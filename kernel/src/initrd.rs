@@ -0,0 +1,200 @@
+//! The ramdisk loaded by the bootloader, a ustar archive built by the host (see `src/initrd.rs` in the runner). It
+//! holds the symbol table used for backtraces, the kernel command line and the contents of the `initrd` directory,
+//! and is exposed as a read-only filesystem.
+//!
+//! The bootloader maps the ramdisk for us and it is never freed, so file contents are borrowed straight from it.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use core::str;
+
+const BLOCK_SIZE: usize = 512;
+
+static INITRD: OnceCell<Initrd> = OnceCell::uninit();
+
+pub struct Initrd {
+    data: &'static [u8],
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Directory,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct InitrdEntry {
+    /// Relative to the root, without a leading or trailing `/`
+    pub path: &'static str,
+    pub kind: EntryKind,
+    /// Empty for directories
+    pub data: &'static [u8],
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: &'static str,
+    pub kind: EntryKind,
+}
+
+impl Initrd {
+    pub fn new(data: &'static [u8]) -> Self {
+        Initrd { data }
+    }
+
+    pub fn entries(&self) -> Entries {
+        Entries {
+            data: self.data,
+            offset: 0,
+        }
+    }
+
+    pub fn files(&self) -> impl Iterator<Item = InitrdEntry> {
+        self.entries().filter(|entry| entry.kind == EntryKind::File)
+    }
+
+    /// The contents of the file at `path`, which may start with a `/`
+    pub fn file(&self, path: &str) -> Option<&'static [u8]> {
+        let path = normalise(path);
+
+        self.files()
+            .find(|file| file.path == path)
+            .map(|file| file.data)
+    }
+
+    /// What is at `path`. Directories don't need their own entry in the archive, any directory a file is in exists.
+    pub fn kind(&self, path: &str) -> Option<EntryKind> {
+        let path = normalise(path);
+
+        if path.is_empty() {
+            return Some(EntryKind::Directory);
+        }
+
+        self.entries().find_map(|entry| {
+            if entry.path == path {
+                Some(entry.kind)
+            } else if parent_of(entry.path, path).is_some() {
+                Some(EntryKind::Directory)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// The entries directly inside the directory at `path`, sorted by name. `None` if it isn't a directory.
+    pub fn read_dir(&self, path: &str) -> Option<Vec<DirEntry>> {
+        let path = normalise(path);
+
+        if self.kind(path)? != EntryKind::Directory {
+            return None;
+        }
+
+        let mut children = BTreeMap::new();
+
+        for entry in self.entries() {
+            let Some(relative) = parent_of(entry.path, path) else {
+                continue;
+            };
+
+            match relative.split_once('/') {
+                Some((name, _)) => children.insert(name, EntryKind::Directory),
+                None => children.insert(relative, entry.kind),
+            };
+        }
+
+        Some(
+            children
+                .into_iter()
+                .map(|(name, kind)| DirEntry { name, kind })
+                .collect(),
+        )
+    }
+}
+
+/// `path` relative to `directory` if it is somewhere inside it
+fn parent_of<'a>(path: &'a str, directory: &str) -> Option<&'a str> {
+    if directory.is_empty() {
+        return Some(path);
+    }
+
+    path.strip_prefix(directory)?.strip_prefix('/')
+}
+
+fn normalise(path: &str) -> &str {
+    path.trim_matches('/')
+}
+
+/// Iterates over the files and directories in the archive, stopping at the first malformed header
+pub struct Entries {
+    data: &'static [u8],
+    offset: usize,
+}
+
+impl Iterator for Entries {
+    type Item = InitrdEntry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let header = self.data.get(self.offset..self.offset + BLOCK_SIZE)?;
+
+            // The archive ends with empty blocks
+            if header.iter().all(|&byte| byte == 0) || &header[257..262] != b"ustar" {
+                return None;
+            }
+
+            let size = parse_octal(&header[124..136])?;
+            let data_start = self.offset + BLOCK_SIZE;
+            let data = self.data.get(data_start..data_start + size)?;
+
+            self.offset = data_start + size.next_multiple_of(BLOCK_SIZE);
+
+            // Skip links, devices etc.
+            let kind = match header[156] {
+                b'0' | 0 => EntryKind::File,
+                b'5' => EntryKind::Directory,
+                _ => continue,
+            };
+
+            let name = str::from_utf8(until_nul(&header[..100])).ok()?;
+            let prefix = str::from_utf8(until_nul(&header[345..500])).ok()?;
+
+            // Paths too long for the name field are split with the directory part in the prefix, we only need to
+            // support the ones the host writes which always fit in the name. An entry for the root itself (`./`) adds
+            // nothing.
+            let path = normalise(name.trim_start_matches("./"));
+            if !prefix.is_empty() || path.is_empty() {
+                continue;
+            }
+
+            return Some(InitrdEntry {
+                path,
+                kind,
+                data: if kind == EntryKind::File { data } else { &[] },
+            });
+        }
+    }
+}
+
+fn until_nul(field: &[u8]) -> &[u8] {
+    let end = field
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(field.len());
+    &field[..end]
+}
+
+fn parse_octal(field: &[u8]) -> Option<usize> {
+    let digits = str::from_utf8(until_nul(field)).ok()?.trim();
+    usize::from_str_radix(digits, 8).ok()
+}
+
+pub(crate) fn init(data: &'static [u8]) -> &'static Initrd {
+    INITRD.init_once(|| Initrd::new(data));
+    INITRD.get().unwrap()
+}
+
+/// The initrd, if the bootloader loaded one
+pub fn get() -> Option<&'static Initrd> {
+    INITRD.get()
+}
@@ -1,80 +1,246 @@
 use crate::memory::virtual_addresses;
 use crate::memory::MemoryAllocator;
+use alloc::vec::Vec;
+use core::arch::x86_64::__cpuid;
 use x86_64::structures::paging::PageTableFlags;
 use x86_64::{PhysAddr, VirtAddr};
 
+pub const IOAPICVER_OFFSET: u32 = 0x01;
+/// The first IOREDTBL register, each entry takes two
+const IOREDTBL_OFFSET: u32 = 0x10;
+
 pub struct IoApic {
     ioregsel: &'static mut u32,
     iowin: &'static mut u32,
+    gsi_base: u32,
+    redirection_entries: u16,
 }
 
 #[allow(dead_code)]
-enum DestinationMode {
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum DestinationMode {
     Physical = 0,
     Logical = 1,
 }
 
-#[allow(dead_code)]
-enum PinPolarity {
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum PinPolarity {
     ActiveHigh = 0,
     ActiveLow = 1,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum TriggerMode {
+    Edge = 0,
+    Level = 1,
+}
+
+/// The configurable fields of an IOREDTBL entry. Delivery mode is always fixed.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct RedirectionEntry {
+    pub(crate) vector: u8,
+    pub(crate) destination_mode: DestinationMode,
+    pub(crate) polarity: PinPolarity,
+    pub(crate) trigger_mode: TriggerMode,
+    pub(crate) masked: bool,
+    /// A LAPIC id in physical destination mode or a set of processors in logical destination mode
+    pub(crate) destination: u32,
+}
+
+/// The LAPIC id in a redirection entry is too wide for the IOAPIC to deliver to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct InvalidDestination;
+
+/// Bits 56-63 of an IOREDTBL entry hold the low 8 bits of the destination
+const DESTINATION_SHIFT: u64 = 56;
+/// With extended destination ids bits 49-55 hold bits 8-14 of the destination, so an IOAPIC can reach LAPIC ids up to
+/// 0x7fff without interrupt remapping. Otherwise the IOAPIC ignores those bits.
+const EXTENDED_DESTINATION_SHIFT: u64 = 49;
+const MAX_EXTENDED_DESTINATION: u32 = 0x7fff;
+const MAX_DESTINATION: u32 = 0xff;
+
+/// CPUID.01H:ECX bit 31 is set when running under a hypervisor, which describes itself from leaf 0x40000000
+const CPUID_HYPERVISOR: u32 = 1 << 31;
+const CPUID_HYPERVISOR_BASE: u32 = 0x4000_0000;
+/// "KVMKVMKVM\0\0\0" in EBX, ECX and EDX of the hypervisor's base leaf
+const KVM_SIGNATURE: [u32; 3] = [0x4b4d_564b, 0x564b_4d56, 0x0000_004d];
+/// KVM_FEATURE_MSI_EXT_DEST_ID in EAX of KVM's features leaf
+const KVM_FEATURE_EXTENDED_DESTINATION: u32 = 1 << 15;
+
+/// Whether the hypervisor delivers IOAPIC interrupts to the extended destination id in bits 49-55. Real hardware only
+/// does so with interrupt remapping, which the kernel doesn't set up.
+fn extended_destination_supported() -> bool {
+    if __cpuid(0x1).ecx & CPUID_HYPERVISOR == 0 {
+        return false;
+    }
+
+    let base = __cpuid(CPUID_HYPERVISOR_BASE);
+    [base.ebx, base.ecx, base.edx] == KVM_SIGNATURE
+        && base.eax > CPUID_HYPERVISOR_BASE
+        && __cpuid(CPUID_HYPERVISOR_BASE + 1).eax & KVM_FEATURE_EXTENDED_DESTINATION != 0
+}
+
+impl RedirectionEntry {
+    /// The destination bits of the IOREDTBL entry
+    fn destination_bits(&self) -> Result<u64, InvalidDestination> {
+        let limit = if self.destination > MAX_DESTINATION && extended_destination_supported() {
+            MAX_EXTENDED_DESTINATION
+        } else {
+            MAX_DESTINATION
+        };
+        if self.destination > limit {
+            return Err(InvalidDestination);
+        }
+
+        Ok(((self.destination as u64 & 0xff) << DESTINATION_SHIFT)
+            | ((self.destination as u64 >> 8) << EXTENDED_DESTINATION_SHIFT))
+    }
+}
+
 impl IoApic {
-    pub(crate) fn set_ioredtbl(&mut self, irq: u8, vector: u8, lapic_id: u8) {
-        let low_offset = 0x10 + irq * 2;
-        let high_offset = 0x10 + irq * 2 + 1;
+    pub(crate) fn set_ioredtbl(
+        &mut self,
+        irq: u8,
+        entry: RedirectionEntry,
+    ) -> Result<(), InvalidDestination> {
+        let destination_bits = entry.destination_bits()?;
+        let low_offset = IOREDTBL_OFFSET + irq as u32 * 2;
+        let high_offset = low_offset + 1;
 
         let ioredtbl = (self.read(low_offset) as u64) | ((self.read(high_offset) as u64) << 32);
 
         let delivery_mode = 0b000u8;
 
-        let destination_mode = DestinationMode::Physical as u8;
-
-        let pin_polarity = PinPolarity::ActiveHigh as u8;
-
-        let ioredtbl = (ioredtbl & !0x0f0_0000_0001_efff)
-            | (vector as u64)
+        // Bits 12 (delivery status) and 14 (remote IRR) are read only, everything else we set is cleared first
+        let ioredtbl = (ioredtbl & !0xfffe_0000_0001_afff)
+            | (entry.vector as u64)
             | (((delivery_mode & 0b111) as u64) << 8)
-            | (((destination_mode & 0b1) as u64) << 11)
-            | (((pin_polarity & 0b1) as u64) << 13)
-            | (((lapic_id & 0xf) as u64) << 56);
+            | ((entry.destination_mode as u64) << 11)
+            | ((entry.polarity as u64) << 13)
+            | ((entry.trigger_mode as u64) << 15)
+            | ((entry.masked as u64) << 16)
+            | destination_bits;
 
         self.write(low_offset, ioredtbl as u32);
-        self.write(high_offset, (ioredtbl >> 32) as u32)
+        self.write(high_offset, (ioredtbl >> 32) as u32);
+        Ok(())
+    }
+
+    /// Masks or unmasks a pin without touching the rest of its IOREDTBL entry
+    pub(crate) fn set_masked(&mut self, irq: u8, masked: bool) {
+        let low_offset = IOREDTBL_OFFSET + irq as u32 * 2;
+        let low = self.read(low_offset);
+
+        if masked {
+            self.write(low_offset, low | (1 << 16));
+        } else {
+            self.write(low_offset, low & !(1 << 16));
+        }
     }
 
-    fn read(&mut self, offset: u8) -> u32 {
-        *self.ioregsel = offset as u32;
+    /// Returns the pin on this IOAPIC that `gsi` is connected to, if any
+    pub(crate) fn pin_for_gsi(&self, gsi: u32) -> Option<u8> {
+        if gsi >= self.gsi_base && gsi - self.gsi_base < self.redirection_entries as u32 {
+            Some((gsi - self.gsi_base) as u8)
+        } else {
+            None
+        }
+    }
+
+    fn read(&mut self, offset: u32) -> u32 {
+        *self.ioregsel = offset;
         *self.iowin
     }
 
-    fn write(&mut self, offset: u8, value: u32) {
-        *self.ioregsel = offset as u32;
+    fn write(&mut self, offset: u32, value: u32) {
+        *self.ioregsel = offset;
         *self.iowin = value;
     }
 
+    /// `index` selects which of the IOAPIC virtual address slots the registers are mapped to, so must be unique
     pub(crate) fn new(
         memory_allocator: &mut MemoryAllocator,
         io_apic: &acpi::platform::interrupt::IoApic,
+        index: usize,
     ) -> Self {
         let base_address = io_apic.address;
+        let virt_addr = VirtAddr::new(
+            (virtual_addresses::IOAPIC_START + index * virtual_addresses::IOAPIC_STRIDE) as u64,
+        );
 
         unsafe {
             memory_allocator.map_page_containing_address(
                 PhysAddr::new(base_address as u64),
-                VirtAddr::new(virtual_addresses::IOAPIC_START as u64),
+                virt_addr,
                 PageTableFlags::WRITABLE | PageTableFlags::PRESENT | PageTableFlags::NO_CACHE,
             );
         }
 
-        let ioregsel: *mut u32 = VirtAddr::new(virtual_addresses::IOAPIC_START as u64).as_mut_ptr();
+        let ioregsel: *mut u32 = virt_addr.as_mut_ptr();
         let ioregsel = unsafe { &mut *ioregsel };
 
-        let iowin: *mut u32 =
-            VirtAddr::new((virtual_addresses::IOAPIC_START as u64) + 0x10).as_mut_ptr();
+        let iowin: *mut u32 = (virt_addr + 0x10u64).as_mut_ptr();
         let iowin = unsafe { &mut *iowin };
 
-        IoApic { ioregsel, iowin }
+        let mut ioapic = IoApic {
+            ioregsel,
+            iowin,
+            gsi_base: io_apic.global_system_interrupt_base,
+            redirection_entries: 0,
+        };
+
+        // Bits 16-23 of IOAPICVER hold the index of the last redirection entry
+        ioapic.redirection_entries = ((ioapic.read(IOAPICVER_OFFSET) >> 16) & 0xff) as u16 + 1;
+
+        ioapic
+    }
+}
+
+/// Owns every IOAPIC in the system and routes GSIs to whichever one is connected to them
+pub struct GsiRouter {
+    ioapics: Vec<IoApic>,
+}
+
+impl GsiRouter {
+    pub(crate) fn new(
+        memory_allocator: &mut MemoryAllocator,
+        io_apics: &[acpi::platform::interrupt::IoApic],
+    ) -> Self {
+        let ioapics = io_apics
+            .iter()
+            .enumerate()
+            .map(|(index, io_apic)| IoApic::new(memory_allocator, io_apic, index))
+            .collect();
+
+        GsiRouter { ioapics }
+    }
+
+    /// Returns the IOAPIC connected to `gsi` along with the pin it is connected to
+    pub(crate) fn route(&mut self, gsi: u32) -> Option<(&mut IoApic, u8)> {
+        self.ioapics
+            .iter_mut()
+            .find_map(|ioapic| ioapic.pin_for_gsi(gsi).map(|pin| (ioapic, pin)))
+    }
+
+    /// Programs the redirection entry for `gsi`, panicking if no IOAPIC handles it. Leaves the entry alone if the
+    /// IOAPIC can't deliver to its destination.
+    pub(crate) fn set_gsi(
+        &mut self,
+        gsi: u32,
+        entry: RedirectionEntry,
+    ) -> Result<(), InvalidDestination> {
+        let (ioapic, pin) = self
+            .route(gsi)
+            .unwrap_or_else(|| panic!("No IOAPIC connected to GSI {}", gsi));
+
+        ioapic.set_ioredtbl(pin, entry)
+    }
+
+    pub(crate) fn set_gsi_masked(&mut self, gsi: u32, masked: bool) {
+        let (ioapic, pin) = self
+            .route(gsi)
+            .unwrap_or_else(|| panic!("No IOAPIC connected to GSI {}", gsi));
+
+        ioapic.set_masked(pin, masked);
     }
 }
@@ -1,6 +1,10 @@
+use crate::io::drivers::pit;
 use crate::memory::virtual_addresses;
 use crate::memory::MemoryAllocator;
+use core::arch::x86_64::__cpuid;
 use core::ptr::slice_from_raw_parts_mut;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::registers::model_specific::Msr;
 use x86_64::structures::paging::PageTableFlags;
 use x86_64::{PhysAddr, VirtAddr};
 
@@ -9,18 +13,76 @@ pub const SIVR_OFFSET: u64 = 0xf0;
 pub const DESTINATION_FORMAT_OFFSET: u64 = 0xe0;
 pub const TASK_PRIORITY_OFFSET: u64 = 0x80;
 pub const INITIAL_COUNT_REGISTER_OFFSET: u64 = 0x380;
+pub const CURRENT_COUNT_REGISTER_OFFSET: u64 = 0x390;
 pub const LVT_TIMER_OFFSET: u64 = 0x320;
 pub const DIVIDE_CONFIG_OFFSET: u64 = 0x3e0;
 
 pub const EOI_OFFSET: u64 = 0xB0;
-pub const LAPIC_BASE_PHYSICAL_ADDRESS: u64 = 0xFEE0_0000;
+pub const ICR_LOW_OFFSET: u64 = 0x300;
+pub const ICR_HIGH_OFFSET: u64 = 0x310;
+
+pub const IA32_APIC_BASE_MSR: u32 = 0x1b;
+pub const APIC_BASE_GLOBAL_ENABLE: u64 = 1 << 11;
+pub const APIC_BASE_X2APIC_ENABLE: u64 = 1 << 10;
+pub const APIC_BASE_ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// In x2APIC mode register `offset` of the xAPIC MMIO page is accessed through MSR `0x800 + offset / 16`
+pub const X2APIC_MSR_BASE: u32 = 0x800;
+
+/// Set once the LAPIC has been switched to x2APIC mode so that [`lapic_end_of_interrupt`] knows how to reach it
+static X2APIC_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Set once the LAPIC registers can be accessed (either mapped in xAPIC mode or switched to x2APIC mode)
+static LAPIC_ENABLED: AtomicBool = AtomicBool::new(false);
 
 pub unsafe fn lapic_end_of_interrupt() {
-    (VirtAddr::new(virtual_addresses::LAPIC_START as u64 + EOI_OFFSET).as_mut_ptr() as *mut u32)
-        .write(0);
+    if X2APIC_ENABLED.load(Ordering::Relaxed) {
+        Msr::new(x2apic_msr(EOI_OFFSET)).write(0);
+    } else {
+        (VirtAddr::new(virtual_addresses::LAPIC_START as u64 + EOI_OFFSET).as_mut_ptr()
+            as *mut u32)
+            .write(0);
+    }
+}
+
+/// Sends an NMI to every other CPU, used to stop them when the kernel panics. Does nothing if the LAPIC has not been
+/// set up yet.
+pub unsafe fn lapic_broadcast_nmi() {
+    if !LAPIC_ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    // Destination shorthand 0b11 is "all excluding self", delivery mode 0b100 is NMI and bit 14 asserts the interrupt
+    let icr = (0b11 << 18) | (1 << 14) | (0b100 << 8);
+
+    if X2APIC_ENABLED.load(Ordering::Relaxed) {
+        // In x2APIC mode the ICR is a single 64 bit MSR
+        Msr::new(x2apic_msr(ICR_LOW_OFFSET)).write(icr);
+    } else {
+        let lapic_start = virtual_addresses::LAPIC_START as u64;
+
+        // Writing the low half sends the IPI so the high half has to be written first
+        (VirtAddr::new(lapic_start + ICR_HIGH_OFFSET).as_mut_ptr::<u32>()).write_volatile(0);
+        (VirtAddr::new(lapic_start + ICR_LOW_OFFSET).as_mut_ptr::<u32>())
+            .write_volatile(icr as u32);
+    }
+}
+
+/// CPUID.01H:ECX bit 21 reports x2APIC support
+fn x2apic_supported() -> bool {
+    let cpuid = __cpuid(0x1);
+    cpuid.ecx & (1 << 21) != 0
 }
 
+fn x2apic_msr(offset: u64) -> u32 {
+    X2APIC_MSR_BASE + (offset >> 4) as u32
+}
+
+/// Length of the PIT wait the timer is calibrated against
+const CALIBRATION_MICROS: u64 = 10_000;
+
 #[allow(dead_code)]
+#[derive(Clone, Copy, Debug)]
 pub enum TimerDivideConfig {
     DivideBy2 = 0b0000,
     DivideBy4 = 0b0001,
@@ -32,13 +94,26 @@ pub enum TimerDivideConfig {
     DivideBy1 = 0b1011,
 }
 
+enum LapicRegisters {
+    XApic(&'static mut [u32]),
+    X2Apic,
+}
+
 pub struct Lapic {
-    mm_region: &'static mut [u32],
+    registers: LapicRegisters,
 }
 
 impl Lapic {
-    pub fn lapic_id(&self) -> u8 {
-        ((self.read(LAPIC_ID_OFFSET)) >> 24) as u8
+    /// In xAPIC mode the ID is held in the top 8 bits of the ID register, in x2APIC mode it is the full 32 bits
+    pub fn lapic_id(&self) -> u32 {
+        match self.registers {
+            LapicRegisters::XApic(_) => self.read(LAPIC_ID_OFFSET) >> 24,
+            LapicRegisters::X2Apic => self.read(LAPIC_ID_OFFSET),
+        }
+    }
+
+    pub fn is_x2apic(&self) -> bool {
+        matches!(self.registers, LapicRegisters::X2Apic)
     }
 
     /// Can only be called once
@@ -46,27 +121,48 @@ impl Lapic {
         memory_allocator: &mut MemoryAllocator,
         spurious_interrupt_vector: u8,
     ) -> Self {
-        let virt_addr = VirtAddr::new(virtual_addresses::LAPIC_START as u64);
-
-        unsafe {
-            memory_allocator.map_page_containing_address(
-                PhysAddr::new(LAPIC_BASE_PHYSICAL_ADDRESS),
-                virt_addr,
-                PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE,
-            );
-        }
-
-        let mm_region = slice_from_raw_parts_mut(virt_addr.as_mut_ptr(), 0x1000);
-        let mm_region = unsafe { &mut *mm_region };
-
-        let mut apic = Lapic { mm_region };
+        let mut apic_base_msr = Msr::new(IA32_APIC_BASE_MSR);
+        let apic_base = unsafe { apic_base_msr.read() };
+
+        let registers = if x2apic_supported() {
+            // xAPIC -> x2APIC is the only legal transition, and the global enable has to be set either before or
+            // at the same time as the x2APIC enable
+            unsafe {
+                apic_base_msr.write(apic_base | APIC_BASE_GLOBAL_ENABLE | APIC_BASE_X2APIC_ENABLE)
+            };
+            X2APIC_ENABLED.store(true, Ordering::Relaxed);
+
+            LapicRegisters::X2Apic
+        } else {
+            // Firmware may have relocated the LAPIC so use the base it reports instead of assuming 0xFEE0_0000
+            let base_address = apic_base & APIC_BASE_ADDRESS_MASK;
+            let virt_addr = VirtAddr::new(virtual_addresses::LAPIC_START as u64);
+
+            unsafe {
+                memory_allocator.map_page_containing_address(
+                    PhysAddr::new(base_address),
+                    virt_addr,
+                    PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE,
+                );
+
+                apic_base_msr.write(apic_base | APIC_BASE_GLOBAL_ENABLE);
+            }
+
+            let mm_region = slice_from_raw_parts_mut(virt_addr.as_mut_ptr(), 0x1000 / 4);
+            LapicRegisters::XApic(unsafe { &mut *mm_region })
+        };
+
+        let mut apic = Lapic { registers };
+        LAPIC_ENABLED.store(true, Ordering::Relaxed);
 
         // https://forum.osdev.org/viewtopic.php?f=1&t=12045&hilit=APIC+init
 
         apic.write(SIVR_OFFSET, 0x100 | (spurious_interrupt_vector as u32)); // 0x100 sets bit 8 to enable APIC
 
-        // set destination format register to flat mode
-        apic.write(DESTINATION_FORMAT_OFFSET, 0xFFFFFFFF);
+        // set destination format register to flat mode (the DFR does not exist in x2APIC mode)
+        if !apic.is_x2apic() {
+            apic.write(DESTINATION_FORMAT_OFFSET, 0xFFFFFFFF);
+        }
 
         // set task priority to accept all interrupts
         apic.write(TASK_PRIORITY_OFFSET, 0);
@@ -86,11 +182,34 @@ impl Lapic {
         self.write(INITIAL_COUNT_REGISTER_OFFSET, timer_initial);
     }
 
-    #[allow(dead_code)]
+    /// The timer frequency in Hz after `timer_divide` is applied, which varies between CPUs so is measured against
+    /// the PIT. The timer is left stopped.
+    pub fn measure_timer_frequency(&mut self, timer_divide: TimerDivideConfig) -> u32 {
+        const MASKED: u32 = 1 << 16;
+
+        self.write(DIVIDE_CONFIG_OFFSET, timer_divide as u32);
+        self.write(LVT_TIMER_OFFSET, MASKED);
+        self.write(INITIAL_COUNT_REGISTER_OFFSET, u32::MAX);
+
+        pit::busy_wait_us(CALIBRATION_MICROS);
+
+        let elapsed = u32::MAX - self.read(CURRENT_COUNT_REGISTER_OFFSET);
+        self.write(INITIAL_COUNT_REGISTER_OFFSET, 0);
+
+        (elapsed as u64 * 1_000_000 / CALIBRATION_MICROS) as u32
+    }
+
     fn read(&self, offset: u64) -> u32 {
-        self.mm_region[offset as usize / 4]
+        match &self.registers {
+            LapicRegisters::XApic(mm_region) => mm_region[offset as usize / 4],
+            LapicRegisters::X2Apic => unsafe { Msr::new(x2apic_msr(offset)).read() as u32 },
+        }
     }
+
     fn write(&mut self, offset: u64, val: u32) {
-        self.mm_region[offset as usize / 4] = val;
+        match &mut self.registers {
+            LapicRegisters::XApic(mm_region) => mm_region[offset as usize / 4] = val,
+            LapicRegisters::X2Apic => unsafe { Msr::new(x2apic_msr(offset)).write(val as u64) },
+        }
     }
 }
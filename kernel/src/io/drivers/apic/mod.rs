@@ -1,19 +1,22 @@
-pub(super) mod ioapic;
+pub(crate) mod ioapic;
 pub(super) mod lapic;
 
-pub use lapic::lapic_end_of_interrupt;
+pub use lapic::{lapic_broadcast_nmi, lapic_end_of_interrupt};
 
 use crate::io::drivers::apic::lapic::{Lapic, TimerDivideConfig};
 use crate::memory::MemoryAllocator;
+use acpi::platform::interrupt::{InterruptSourceOverride, Polarity};
 use acpi::InterruptModel;
 use alloc::alloc::Global;
-use ioapic::IoApic;
+use conquer_once::spin::OnceCell;
+use ioapic::{DestinationMode, GsiRouter, PinPolarity, RedirectionEntry, TriggerMode};
 use pic8259::ChainedPics;
+use spin::Mutex;
 use x86_64::instructions::port::Port;
-use x86_64::registers::model_specific::Msr;
 
 #[allow(dead_code)]
-enum IsaIrq {
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum IsaIrq {
     PitTimer = 0,
     Keyboard = 1,
     Com2 = 3,
@@ -31,12 +34,74 @@ enum IsaIrq {
     SecondaryAta = 15,
 }
 
+pub(crate) static GSI_ROUTER: OnceCell<Mutex<GsiRouter>> = OnceCell::uninit();
+
+/// LAPIC id of the bootstrap processor, which all IOAPIC interrupts are currently delivered to
+pub(crate) static BSP_LAPIC_ID: OnceCell<u32> = OnceCell::uninit();
+
+/// ISA IRQ n is delivered on vector ISA_IRQ_VECTOR_BASE + n
+pub(crate) const ISA_IRQ_VECTOR_BASE: u8 = 0x40;
+
+/// Where each ISA IRQ is connected once the MADT Interrupt Source Overrides have been applied
+pub(crate) static ISA_IRQ_ROUTES: OnceCell<[IsaIrqRoute; 16]> = OnceCell::uninit();
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct IsaIrqRoute {
+    pub(crate) gsi: u32,
+    pub(crate) polarity: PinPolarity,
+    pub(crate) trigger_mode: TriggerMode,
+    /// Whether the MADT had an Interrupt Source Override for this IRQ
+    pub(crate) overridden: bool,
+}
+
+/// ISA IRQs are active high and edge triggered, and are identity mapped to GSIs unless an override says otherwise
+fn isa_irq_routes(interrupt_source_overrides: &[InterruptSourceOverride]) -> [IsaIrqRoute; 16] {
+    let mut routes = [IsaIrqRoute {
+        gsi: 0,
+        polarity: PinPolarity::ActiveHigh,
+        trigger_mode: TriggerMode::Edge,
+        overridden: false,
+    }; 16];
+
+    for (irq, route) in routes.iter_mut().enumerate() {
+        route.gsi = irq as u32;
+    }
+
+    for interrupt_source_override in interrupt_source_overrides {
+        let Some(route) = routes.get_mut(interrupt_source_override.isa_source as usize) else {
+            continue;
+        };
+
+        route.gsi = interrupt_source_override.global_system_interrupt;
+        route.overridden = true;
+
+        route.polarity = match interrupt_source_override.polarity {
+            Polarity::SameAsBus | Polarity::ActiveHigh => PinPolarity::ActiveHigh,
+            Polarity::ActiveLow => PinPolarity::ActiveLow,
+        };
+
+        route.trigger_mode = match interrupt_source_override.trigger_mode {
+            acpi::platform::interrupt::TriggerMode::SameAsBus
+            | acpi::platform::interrupt::TriggerMode::Edge => TriggerMode::Edge,
+            acpi::platform::interrupt::TriggerMode::Level => TriggerMode::Level,
+        };
+    }
+
+    routes
+}
+
+/// The GSI an ISA IRQ is connected to
+pub(crate) fn isa_irq_gsi(irq: IsaIrq) -> u32 {
+    ISA_IRQ_ROUTES.get().expect("APIC not initialised")[irq as usize].gsi
+}
+
 pub struct Apic;
 
 impl Apic {
     pub fn new(
         memory_allocator: &mut MemoryAllocator,
         interrupt_model: &InterruptModel<Global>,
+        timer_hz: u32,
     ) -> Self {
         // The following section uses the overall steps from: https://blog.wesleyac.com/posts/ioapic-interrupts
 
@@ -49,9 +114,6 @@ impl Apic {
             }
         };
 
-        // Both QEMU and my test system only have one IOAPIC so this is fine for now.
-        let ioapic = &ioapics[0];
-
         // Step 1: Disable the PIC.
         unsafe {
             let mut pics = ChainedPics::new(0x20, 0x28);
@@ -66,37 +128,74 @@ impl Apic {
         imcr.enable_symmetric_io_mode();
 
         // Step 3: Configure the "Spurious Interrupt Vector Register" of the Local APIC to 0xFF
+        // This also enables the APIC by setting the 11th bit of the APIC base MSR (0x1B), switching to x2APIC mode if
+        // it is supported
         let mut lapic = unsafe { Lapic::new(memory_allocator, 0xff) };
+        log::info!(
+            "LAPIC {} enabled in {} mode",
+            lapic.lapic_id(),
+            if lapic.is_x2apic() { "x2APIC" } else { "xAPIC" }
+        );
+
+        // Step 4: read all of the Interrupt Source Override entries to work out which GSI, polarity and trigger mode
+        // each ISA IRQ uses
+        let isa_irq_routes = isa_irq_routes(interrupt_source_overrides);
+
+        // Step 5: Configure the IOREDTBL entry for every ISA IRQ, leaving them masked until a driver registers a
+        // handler with `interrupts::register_irq`
+        let mut gsi_router = GsiRouter::new(memory_allocator, ioapics);
+        log::debug!("Mapped {} IOAPIC(s)", ioapics.len());
+
+        for (irq, route) in isa_irq_routes.iter().enumerate() {
+            // An override can move another ISA IRQ onto this one's GSI (eg. the PIT is usually on GSI 2), in which case
+            // that IRQ owns the GSI and this one is not connected
+            let gsi_taken = isa_irq_routes
+                .iter()
+                .enumerate()
+                .any(|(other_irq, other_route)| {
+                    other_irq != irq && other_route.gsi == route.gsi && other_route.overridden
+                });
+
+            if gsi_taken && !route.overridden {
+                continue;
+            }
 
-        // Step 4: read all of the Interrupt Source Override entries - if the IRQ source of any of them is 1 (Keyboard) use that in IOREDTBL
-        let keyboard_gsi = interrupt_source_overrides
-            .iter()
-            .filter_map(|interrupt_source_override| {
-                if interrupt_source_override.isa_source == (IsaIrq::Keyboard as u8) {
-                    Some(interrupt_source_override.global_system_interrupt)
-                } else {
-                    None
-                }
-            })
-            .next()
-            .unwrap_or(ioapic.global_system_interrupt_base + (IsaIrq::Keyboard as u32)); // An educated guess is that it is connected to the IOAPIC pin corresponding to its usual PIC pin
-
-        if keyboard_gsi < ioapic.global_system_interrupt_base {
-            panic!("No IOAPIC connected to keyboard");
+            let routed = gsi_router.set_gsi(
+                route.gsi,
+                RedirectionEntry {
+                    vector: ISA_IRQ_VECTOR_BASE + irq as u8,
+                    destination_mode: DestinationMode::Physical,
+                    polarity: route.polarity,
+                    trigger_mode: route.trigger_mode,
+                    masked: true,
+                    destination: lapic.lapic_id(),
+                },
+            );
+
+            // `interrupts::register_irq` refuses every GSI in this case, so there's no point trying the others
+            if routed.is_err() {
+                log::warn!(
+                    "LAPIC id {:#x} can't be reached from an IOAPIC, IOAPIC interrupts won't work",
+                    lapic.lapic_id()
+                );
+                break;
+            }
         }
 
-        let gsi_base = ioapic.global_system_interrupt_base;
-
-        // Step 5: Configure the IOREDTBL entry in registers 0x12 and 0x13 (unless you need to use a different one, per the above step)
-        let mut ioapic = IoApic::new(memory_allocator, ioapic);
-        ioapic.set_ioredtbl((keyboard_gsi - gsi_base) as u8, 0x41, lapic.lapic_id());
-
-        // Step 6: Enable the APIC by setting the 11th bit of the APIC base MSR (0x1B)
-        let mut apic_base_msr = Msr::new(0x1b);
-        unsafe { apic_base_msr.write(apic_base_msr.read() | (1 << 11)) };
-
-        // Configure timer
-        lapic.configure_timer(0x31, 0xffffff, TimerDivideConfig::DivideBy16);
+        BSP_LAPIC_ID.init_once(|| lapic.lapic_id());
+        ISA_IRQ_ROUTES.init_once(|| isa_irq_routes);
+        GSI_ROUTER.init_once(|| Mutex::new(gsi_router));
+
+        // Configure the timer to fire `timer_hz` times a second
+        let timer_frequency = lapic.measure_timer_frequency(TimerDivideConfig::DivideBy16);
+        let timer_initial = (timer_frequency / timer_hz).max(1);
+        log::debug!(
+            "LAPIC timer runs at {}Hz, interrupting every {} ticks for {}Hz",
+            timer_frequency,
+            timer_initial,
+            timer_hz
+        );
+        lapic.configure_timer(0x31, timer_initial, TimerDivideConfig::DivideBy16);
 
         Apic
     }
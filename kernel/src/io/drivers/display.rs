@@ -1 +1,2 @@
 pub mod gop_buffer;
+pub mod panic_screen;
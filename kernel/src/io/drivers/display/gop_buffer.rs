@@ -1,6 +1,9 @@
+use crate::debug_log;
+use crate::io::framebuffer;
 use crate::io::framebuffer::FRAMEBUFFER;
 use core::fmt;
 use lazy_static::lazy_static;
+use log::LevelFilter;
 use noto_sans_mono_bitmap::{get_raster, get_raster_width, FontWeight, RasterHeight};
 use spin::{Mutex, MutexGuard};
 
@@ -15,19 +18,30 @@ pub struct Writer<'a> {
 
 impl Writer<'_> {
     pub unsafe fn init() {
-        *WRITER.lock() = Some(Writer {
+        let mut writer = Writer {
             column: 0,
             raw_framebuffer_lock: FRAMEBUFFER.try_get().unwrap().raw_framebuffer.lock(),
-        });
-    }
+        };
+
+        // Catch up on everything printed before the console existed
+        debug_log::replay(&mut writer, LevelFilter::Trace, true);
 
-    fn write_pixel(&mut self, x: usize, y: usize, pixel: u8) {
-        let stride = FRAMEBUFFER.get().unwrap().framebuffer_info.stride;
-        let raw_framebuffer = &mut self.raw_framebuffer_lock;
+        *WRITER.lock() = Some(writer);
+    }
 
-        raw_framebuffer[y * stride * 4 + x * 4] = pixel;
-        raw_framebuffer[y * stride * 4 + x * 4 + 1] = pixel;
-        raw_framebuffer[y * stride * 4 + x * 4 + 2] = pixel;
+    fn write_pixel(&mut self, x: usize, y: usize, intensity: u8) {
+        let framebuffer_info = &FRAMEBUFFER.get().unwrap().framebuffer_info;
+        let offset = (y * framebuffer_info.stride + x) * framebuffer_info.bytes_per_pixel;
+        let pixel =
+            &mut self.raw_framebuffer_lock[offset..offset + framebuffer_info.bytes_per_pixel];
+
+        framebuffer::write_colour(
+            framebuffer_info.pixel_format,
+            pixel,
+            intensity,
+            intensity,
+            intensity,
+        );
     }
 
     pub fn write_char(&mut self, character: char) {
@@ -68,19 +82,14 @@ impl Writer<'_> {
     fn new_line(&mut self) {
         let framebuffer_info = &FRAMEBUFFER.get().unwrap().framebuffer_info;
 
-        for row in 16..framebuffer_info.height {
-            for col in 0..framebuffer_info.width {
-                let raw_framebuffer = &mut self.raw_framebuffer_lock;
-                let pixel = raw_framebuffer[row * framebuffer_info.stride * 4 + col * 4];
+        let row_size = framebuffer_info.stride * framebuffer_info.bytes_per_pixel;
+        let screen_size = framebuffer_info.height * row_size;
 
-                self.write_pixel(col, row - 16, pixel);
-            }
-        }
-        for row in (framebuffer_info.height - 16)..framebuffer_info.height {
-            for col in 0..framebuffer_info.width {
-                self.write_pixel(col, row, 0);
-            }
-        }
+        // Scroll everything up a line, which works regardless of the pixel format, then clear the last line (0 is black
+        // in every format)
+        self.raw_framebuffer_lock
+            .copy_within(16 * row_size..screen_size, 0);
+        self.raw_framebuffer_lock[screen_size - 16 * row_size..screen_size].fill(0);
 
         self.column = 0;
     }
@@ -0,0 +1,148 @@
+use crate::io::framebuffer;
+use crate::io::framebuffer::FRAMEBUFFER;
+use bootloader_api::info::FrameBufferInfo;
+use core::{fmt, mem, slice};
+use noto_sans_mono_bitmap::{get_raster, get_raster_width, FontWeight, RasterHeight};
+
+const MARGIN: usize = 16;
+const LINE_HEIGHT: usize = 16;
+
+#[derive(Clone, Copy)]
+struct Colour {
+    red: u8,
+    green: u8,
+    blue: u8,
+}
+
+const BACKGROUND: Colour = Colour {
+    red: 0x80,
+    green: 0x00,
+    blue: 0x00,
+};
+
+const FOREGROUND: Colour = Colour {
+    red: 0xff,
+    green: 0xff,
+    blue: 0xff,
+};
+
+/// A full screen console used once the kernel has panicked. It does not go through `WRITER` since the panicking code
+/// may be holding its lock.
+pub struct PanicScreen {
+    buffer: &'static mut [u8],
+    info: FrameBufferInfo,
+    column: usize,
+    row: usize,
+}
+
+impl PanicScreen {
+    /// Takes the framebuffer from whoever has it locked (normally the `Writer`) and clears it.
+    ///
+    /// # Safety
+    /// Nothing else may draw to the framebuffer afterwards, so this should only be called from the panic handler
+    /// with interrupts disabled.
+    pub unsafe fn take() -> Option<Self> {
+        let framebuffer = FRAMEBUFFER.get()?;
+
+        unsafe { framebuffer.raw_framebuffer.force_unlock() };
+        let mut raw_framebuffer = framebuffer.raw_framebuffer.lock();
+        let buffer = unsafe {
+            slice::from_raw_parts_mut(raw_framebuffer.as_mut_ptr(), raw_framebuffer.len())
+        };
+        // The lock is never released so that nothing else can start drawing again
+        mem::forget(raw_framebuffer);
+
+        let mut panic_screen = PanicScreen {
+            buffer,
+            info: framebuffer.framebuffer_info,
+            column: MARGIN,
+            row: MARGIN,
+        };
+
+        panic_screen.clear();
+
+        Some(panic_screen)
+    }
+
+    fn clear(&mut self) {
+        for y in 0..self.info.height {
+            for x in 0..self.info.width {
+                self.write_pixel(x, y, BACKGROUND);
+            }
+        }
+    }
+
+    fn write_pixel(&mut self, x: usize, y: usize, colour: Colour) {
+        let offset = (y * self.info.stride + x) * self.info.bytes_per_pixel;
+
+        let pixel = &mut self.buffer[offset..offset + self.info.bytes_per_pixel];
+
+        framebuffer::write_colour(
+            self.info.pixel_format,
+            pixel,
+            colour.red,
+            colour.green,
+            colour.blue,
+        );
+    }
+
+    fn new_line(&mut self) {
+        self.column = MARGIN;
+        self.row += LINE_HEIGHT;
+    }
+
+    fn write_char(&mut self, character: char) {
+        match character {
+            '\n' => self.new_line(),
+            character => {
+                let width = get_raster_width(FontWeight::Regular, RasterHeight::Size16);
+
+                if self.column + width >= self.info.width - MARGIN {
+                    self.new_line();
+                }
+
+                // Anything that doesn't fit on the screen is still on the debug port
+                if self.row + LINE_HEIGHT >= self.info.height - MARGIN {
+                    return;
+                }
+
+                let char_raster = get_raster(character, FontWeight::Regular, RasterHeight::Size16)
+                    .unwrap_or(
+                        get_raster('?', FontWeight::Regular, RasterHeight::Size16)
+                            .expect("fallback char not supported"),
+                    );
+
+                for (row_i, row) in char_raster.raster().iter().enumerate() {
+                    for (col_i, intensity) in row.iter().enumerate() {
+                        let colour = blend(BACKGROUND, FOREGROUND, *intensity);
+                        self.write_pixel(self.column + col_i, self.row + row_i, colour);
+                    }
+                }
+
+                self.column += width;
+            }
+        }
+    }
+}
+
+fn blend(background: Colour, foreground: Colour, intensity: u8) -> Colour {
+    let mix = |background: u8, foreground: u8| {
+        ((background as u16 * (255 - intensity as u16) + foreground as u16 * intensity as u16)
+            / 255) as u8
+    };
+
+    Colour {
+        red: mix(background.red, foreground.red),
+        green: mix(background.green, foreground.green),
+        blue: mix(background.blue, foreground.blue),
+    }
+}
+
+impl fmt::Write for PanicScreen {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for character in s.chars() {
+            self.write_char(character);
+        }
+        Ok(())
+    }
+}
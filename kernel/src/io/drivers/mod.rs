@@ -1,2 +1,4 @@
-pub(super) mod apic;
+pub(crate) mod apic;
 pub mod display;
+pub(crate) mod pit;
+pub mod serial;
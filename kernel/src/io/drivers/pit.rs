@@ -0,0 +1,50 @@
+//! The legacy 8254 PIT, only used to time short waits (eg. calibrating the LAPIC timer). Channel 2 is used because its
+//! gate and output can be read through port 0x61 without needing an interrupt.
+
+use x86_64::instructions::port::Port;
+
+/// The PIT counts down at this rate on every PC
+pub const PIT_FREQUENCY: u64 = 1_193_182;
+
+const CHANNEL_2_DATA_PORT: u16 = 0x42;
+const COMMAND_PORT: u16 = 0x43;
+const CONTROL_PORT_B: u16 = 0x61;
+
+/// Channel 2, lobyte/hibyte access, mode 0 (interrupt on terminal count), binary
+const CHANNEL_2_ONE_SHOT: u8 = 0b1011_0000;
+
+const GATE_2: u8 = 1 << 0;
+const SPEAKER_ENABLE: u8 = 1 << 1;
+const OUT_2: u8 = 1 << 5;
+
+/// Spins for `micros` microseconds. The counter is 16 bits so waits are limited to about 54ms.
+pub(crate) fn busy_wait_us(micros: u64) {
+    let count = micros * PIT_FREQUENCY / 1_000_000;
+    assert!(
+        count <= u16::MAX as u64,
+        "PIT wait of {}us is too long",
+        micros
+    );
+
+    let mut data_port: Port<u8> = Port::new(CHANNEL_2_DATA_PORT);
+    let mut command_port: Port<u8> = Port::new(COMMAND_PORT);
+    let mut control_port: Port<u8> = Port::new(CONTROL_PORT_B);
+
+    unsafe {
+        // Keep the speaker off and hold the gate low while the count is loaded
+        let control = control_port.read() & !(GATE_2 | SPEAKER_ENABLE);
+        control_port.write(control);
+
+        command_port.write(CHANNEL_2_ONE_SHOT);
+        data_port.write(count as u8);
+        data_port.write((count >> 8) as u8);
+
+        // Raising the gate starts the count, OUT2 goes high when it reaches 0
+        control_port.write(control | GATE_2);
+        while control_port.read() & OUT_2 == 0 {
+            core::hint::spin_loop();
+        }
+
+        control_port.write(control);
+    }
+}
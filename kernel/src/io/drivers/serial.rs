@@ -0,0 +1,309 @@
+//! Driver for the 16550 UARTs on COM1 and COM2.
+//!
+//! Until `enable_interrupts` is called a port is polled. Afterwards received bytes are queued by the IRQ handler and
+//! output is queued and fed to the FIFO whenever the transmitter empties, only falling back to polling when the queue
+//! is full.
+
+use conquer_once::spin::OnceCell;
+use crossbeam_queue::ArrayQueue;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+
+pub const COM1_BASE: u16 = 0x3f8;
+pub const COM2_BASE: u16 = 0x2f8;
+
+/// The UART clock divided by 16, the divisor latch divides this down to the baud rate
+const MAX_BAUD_RATE: u32 = 115200;
+
+const DATA_OFFSET: u16 = 0;
+const INTERRUPT_ENABLE_OFFSET: u16 = 1;
+const INTERRUPT_IDENTIFICATION_OFFSET: u16 = 2;
+const FIFO_CONTROL_OFFSET: u16 = 2;
+const LINE_CONTROL_OFFSET: u16 = 3;
+const MODEM_CONTROL_OFFSET: u16 = 4;
+const LINE_STATUS_OFFSET: u16 = 5;
+const MODEM_STATUS_OFFSET: u16 = 6;
+const SCRATCH_OFFSET: u16 = 7;
+
+const INTERRUPT_ENABLE_RECEIVED_DATA: u8 = 1 << 0;
+const INTERRUPT_ENABLE_THR_EMPTY: u8 = 1 << 1;
+const INTERRUPT_ENABLE_LINE_STATUS: u8 = 1 << 2;
+
+/// Bit 0 of the IIR is clear while an interrupt is pending, bits 1-3 say which one
+const IIR_NO_INTERRUPT_PENDING: u8 = 1 << 0;
+const IIR_MODEM_STATUS: u8 = 0b000;
+const IIR_THR_EMPTY: u8 = 0b001;
+const IIR_RECEIVED_DATA: u8 = 0b010;
+const IIR_LINE_STATUS: u8 = 0b011;
+const IIR_CHARACTER_TIMEOUT: u8 = 0b110;
+
+const MODEM_CONTROL_DTR: u8 = 1 << 0;
+const MODEM_CONTROL_RTS: u8 = 1 << 1;
+/// OUT2 gates the UART's interrupt line on PCs
+const MODEM_CONTROL_OUT2: u8 = 1 << 3;
+const MODEM_CONTROL_LOOPBACK: u8 = 1 << 4;
+
+const LINE_STATUS_DATA_READY: u8 = 1 << 0;
+/// Set in the line status register once the transmit holding register can take another byte
+const LINE_STATUS_THR_EMPTY: u8 = 1 << 5;
+
+/// Size of the transmit FIFO on a 16550A
+const FIFO_SIZE: usize = 16;
+
+const RX_QUEUE_SIZE: usize = 256;
+const TX_QUEUE_SIZE: usize = 4096;
+
+static COM1_BUFFERS: OnceCell<SerialBuffers> = OnceCell::uninit();
+static COM2_BUFFERS: OnceCell<SerialBuffers> = OnceCell::uninit();
+
+lazy_static! {
+    pub static ref COM1: Mutex<SerialPort> = Mutex::new(SerialPort::new(COM1_BASE, &COM1_BUFFERS));
+    pub static ref COM2: Mutex<SerialPort> = Mutex::new(SerialPort::new(COM2_BASE, &COM2_BUFFERS));
+}
+
+struct SerialBuffers {
+    rx: ArrayQueue<u8>,
+    tx: ArrayQueue<u8>,
+}
+
+pub struct SerialPort {
+    base: u16,
+    buffers: &'static OnceCell<SerialBuffers>,
+    present: bool,
+}
+
+impl SerialPort {
+    const fn new(base: u16, buffers: &'static OnceCell<SerialBuffers>) -> Self {
+        SerialPort {
+            base,
+            buffers,
+            present: false,
+        }
+    }
+
+    fn port(&self, offset: u16) -> Port<u8> {
+        Port::new(self.base + offset)
+    }
+
+    /// Configures the port for `baud_rate` with 8 data bits, no parity and 1 stop bit. Returns false (and leaves the
+    /// port disabled) if no UART responds at this address.
+    ///
+    /// # Safety
+    /// Nothing else may be using the port
+    pub unsafe fn init(&mut self, baud_rate: u32) -> bool {
+        assert!(
+            baud_rate > 0 && MAX_BAUD_RATE.is_multiple_of(baud_rate),
+            "unsupported baud rate {}",
+            baud_rate
+        );
+        let divisor = (MAX_BAUD_RATE / baud_rate) as u16;
+
+        unsafe {
+            // Nothing decodes the scratch register if there isn't a UART here, so reads come back as 0xff
+            self.port(SCRATCH_OFFSET).write(0x5a);
+            if self.port(SCRATCH_OFFSET).read() != 0x5a {
+                return false;
+            }
+
+            self.port(INTERRUPT_ENABLE_OFFSET).write(0x00);
+
+            // Set DLAB to program the divisor
+            self.port(LINE_CONTROL_OFFSET).write(0x80);
+            self.port(DATA_OFFSET).write(divisor as u8);
+            self.port(INTERRUPT_ENABLE_OFFSET)
+                .write((divisor >> 8) as u8);
+
+            // Clear DLAB, 8N1
+            self.port(LINE_CONTROL_OFFSET).write(0x03);
+            // Enable and clear the FIFOs with a 14 byte threshold
+            self.port(FIFO_CONTROL_OFFSET).write(0xc7);
+
+            // Check that a byte sent in loopback mode comes back
+            self.port(MODEM_CONTROL_OFFSET)
+                .write(MODEM_CONTROL_LOOPBACK | MODEM_CONTROL_RTS);
+            self.port(DATA_OFFSET).write(0xae);
+            if self.port(DATA_OFFSET).read() != 0xae {
+                return false;
+            }
+
+            self.port(MODEM_CONTROL_OFFSET)
+                .write(MODEM_CONTROL_DTR | MODEM_CONTROL_RTS);
+        }
+
+        self.present = true;
+        true
+    }
+
+    pub fn base(&self) -> u16 {
+        self.base
+    }
+
+    pub fn is_present(&self) -> bool {
+        self.present
+    }
+
+    /// Switches the port to interrupt driven IO. The IRQ has to be routed to `handle_com1_interrupt` or
+    /// `handle_com2_interrupt` with `interrupts::register_irq`.
+    pub(crate) fn enable_interrupts(&mut self) {
+        if !self.present {
+            return;
+        }
+
+        self.buffers
+            .try_init_once(|| SerialBuffers {
+                rx: ArrayQueue::new(RX_QUEUE_SIZE),
+                tx: ArrayQueue::new(TX_QUEUE_SIZE),
+            })
+            .expect("serial port interrupts should only be enabled once");
+
+        unsafe {
+            self.port(MODEM_CONTROL_OFFSET)
+                .write(MODEM_CONTROL_DTR | MODEM_CONTROL_RTS | MODEM_CONTROL_OUT2);
+            self.port(INTERRUPT_ENABLE_OFFSET)
+                .write(INTERRUPT_ENABLE_RECEIVED_DATA | INTERRUPT_ENABLE_LINE_STATUS);
+        }
+    }
+
+    pub fn write_byte(&mut self, byte: u8) {
+        if !self.present {
+            return;
+        }
+
+        let Some(buffers) = self.buffers.get() else {
+            self.write_byte_polled(byte);
+            return;
+        };
+
+        // The IRQ handler also drains the queue, so it must not run in between popping a byte and writing it
+        without_interrupts(|| {
+            while buffers.tx.push(byte).is_err() {
+                let queued = buffers.tx.pop().unwrap();
+                self.write_byte_polled(queued);
+            }
+
+            self.set_interrupt_enabled(INTERRUPT_ENABLE_THR_EMPTY, true);
+        });
+    }
+
+    /// Writes a byte straight to the UART, bypassing the transmit queue
+    pub(crate) fn write_byte_polled(&mut self, byte: u8) {
+        unsafe {
+            while self.port(LINE_STATUS_OFFSET).read() & LINE_STATUS_THR_EMPTY == 0 {}
+            self.port(DATA_OFFSET).write(byte);
+        }
+    }
+
+    /// Returns the next received byte, if any
+    pub fn read_byte(&mut self) -> Option<u8> {
+        if !self.present {
+            return None;
+        }
+
+        match self.buffers.get() {
+            Some(buffers) => buffers.rx.pop(),
+            None => self.read_byte_polled(),
+        }
+    }
+
+    /// Reads a byte straight from the UART, bypassing the receive queue
+    pub(crate) fn read_byte_polled(&mut self) -> Option<u8> {
+        unsafe {
+            if self.port(LINE_STATUS_OFFSET).read() & LINE_STATUS_DATA_READY != 0 {
+                Some(self.port(DATA_OFFSET).read())
+            } else {
+                None
+            }
+        }
+    }
+
+    fn set_interrupt_enabled(&mut self, interrupt: u8, enabled: bool) {
+        unsafe {
+            let interrupt_enable = self.port(INTERRUPT_ENABLE_OFFSET).read();
+            let interrupt_enable = if enabled {
+                interrupt_enable | interrupt
+            } else {
+                interrupt_enable & !interrupt
+            };
+            self.port(INTERRUPT_ENABLE_OFFSET).write(interrupt_enable);
+        }
+    }
+
+    /// Services every pending interrupt. This is called on an `unlocked` handle.
+    fn handle_interrupt(&mut self) {
+        let Some(buffers) = self.buffers.get() else {
+            return;
+        };
+
+        loop {
+            let interrupt_identification =
+                unsafe { self.port(INTERRUPT_IDENTIFICATION_OFFSET).read() };
+            if interrupt_identification & IIR_NO_INTERRUPT_PENDING != 0 {
+                break;
+            }
+
+            match (interrupt_identification >> 1) & 0b111 {
+                IIR_RECEIVED_DATA | IIR_CHARACTER_TIMEOUT => {
+                    while let Some(byte) = self.read_byte_polled() {
+                        // Drop input nobody is reading rather than blocking in the handler
+                        buffers.rx.push(byte).unwrap_or_default();
+                    }
+                }
+                IIR_THR_EMPTY => {
+                    for _ in 0..FIFO_SIZE {
+                        match buffers.tx.pop() {
+                            Some(byte) => unsafe { self.port(DATA_OFFSET).write(byte) },
+                            None => {
+                                self.set_interrupt_enabled(INTERRUPT_ENABLE_THR_EMPTY, false);
+                                break;
+                            }
+                        }
+                    }
+                }
+                // Reading the status register clears these
+                IIR_LINE_STATUS => unsafe {
+                    self.port(LINE_STATUS_OFFSET).read();
+                },
+                IIR_MODEM_STATUS => unsafe {
+                    self.port(MODEM_STATUS_OFFSET).read();
+                },
+                _ => break,
+            }
+        }
+    }
+}
+
+/// A second handle to the UART at `base` for code which can't take the `COM1`/`COM2` lock because the code it
+/// interrupted may be holding it
+pub(crate) fn unlocked(base: u16) -> SerialPort {
+    let buffers = if base == COM2_BASE {
+        &COM2_BUFFERS
+    } else {
+        &COM1_BUFFERS
+    };
+
+    SerialPort::new(base, buffers)
+}
+
+/// IRQ handler for COM1, registered by `io::init`
+pub(crate) fn handle_com1_interrupt() {
+    unlocked(COM1_BASE).handle_interrupt();
+}
+
+/// IRQ handler for COM2, registered by `io::init`
+pub(crate) fn handle_com2_interrupt() {
+    unlocked(COM2_BASE).handle_interrupt();
+}
+
+impl core::fmt::Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for byte in s.bytes() {
+            if byte == b'\n' {
+                self.write_byte(b'\r');
+            }
+            self.write_byte(byte);
+        }
+        Ok(())
+    }
+}
@@ -1,4 +1,4 @@
-use bootloader_api::info::FrameBufferInfo;
+use bootloader_api::info::{FrameBufferInfo, PixelFormat};
 use conquer_once::spin::OnceCell;
 use spin::Mutex;
 
@@ -15,3 +15,33 @@ pub(crate) fn init(framebuffer: &'static mut bootloader_api::info::FrameBuffer)
         raw_framebuffer: Mutex::new(framebuffer.buffer_mut()),
     });
 }
+
+/// Writes a colour into the bytes of a single pixel. UEFI GOP framebuffers are normally 32 bit RGB/BGR but VESA modes
+/// used when booting via BIOS can be 24 bit or use arbitrary channel positions, and greyscale formats get the average.
+pub(crate) fn write_colour(
+    pixel_format: PixelFormat,
+    pixel: &mut [u8],
+    red: u8,
+    green: u8,
+    blue: u8,
+) {
+    match pixel_format {
+        PixelFormat::Rgb => pixel[..3].copy_from_slice(&[red, green, blue]),
+        PixelFormat::Bgr => pixel[..3].copy_from_slice(&[blue, green, red]),
+        PixelFormat::Unknown {
+            red_position,
+            green_position,
+            blue_position,
+        } => {
+            // Assumes 8 bit channels
+            let value = (red as u32) << red_position
+                | (green as u32) << green_position
+                | (blue as u32) << blue_position;
+
+            for (byte, value_byte) in pixel.iter_mut().zip(value.to_le_bytes()) {
+                *byte = value_byte;
+            }
+        }
+        _ => pixel[0] = ((red as u16 + green as u16 + blue as u16) / 3) as u8,
+    }
+}
@@ -6,17 +6,80 @@
 /// Interrupts 30-3F are Local APIC LVT interrupts (CMCI, Timer, Thermal Monitor, Performance Counter, LINT0, LINT1 and
 ///     Error) respectively
 /// Interrupt 40-4F are ISA IRQs with the interrupt number corresponding with the IRQ (eg. 0 is PIC, 1 is PS/2 Keyboard etc.)
-/// Interrupt 50-5F are PCI interrupts (not yet implemented)
+/// Interrupt 50-5F are PCI interrupts, handed out by `register_irq` to any GSI that is not an ISA IRQ, by
+///     `register_pci_irq` to pin interrupts and by `register_message_vectors` to devices using MSI or MSI-X
+///
+/// Vectors 40-5F all point at a trampoline which dispatches to whatever handler was registered. More PCI vectors can be
+/// handed out by raising `IRQ_VECTOR_END` and adding trampolines for the new vectors.
 ///
 /// Interrupt 80 is for syscalls from userspace (not yet implemented)
 ///
 /// Interrupt FF is spurious interrupt (currently from LAPIC only)
-use crate::io::drivers::apic::lapic_end_of_interrupt;
-use crate::io::keyboard::Keyboard;
+use crate::io::drivers::apic::ioapic::{
+    DestinationMode, InvalidDestination, PinPolarity, RedirectionEntry, TriggerMode,
+};
+use crate::io::drivers::apic::{
+    lapic_end_of_interrupt, BSP_LAPIC_ID, GSI_ROUTER, ISA_IRQ_ROUTES, ISA_IRQ_VECTOR_BASE,
+};
 use crate::memory::gdt;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
 use lazy_static::lazy_static;
-use x86_64::instructions::port::Port;
+use spin::RwLock;
+use x86_64::instructions::interrupts::without_interrupts;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use x86_64::VirtAddr;
+
+pub mod crash_report;
+mod exception_handlers;
+
+/// First and one past the last vector that can be handed out by `register_irq`
+const IRQ_VECTOR_START: u8 = 0x40;
+const IRQ_VECTOR_END: u8 = 0x60;
+
+/// First vector handed out to GSIs which are not ISA IRQs
+const PCI_VECTOR_START: u8 = 0x50;
+
+const IRQ_VECTOR_COUNT: usize = (IRQ_VECTOR_END - IRQ_VECTOR_START) as usize;
+
+/// An interrupt vector allocated by `register_irq`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Vector(u8);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IrqError {
+    /// Another handler is already registered for the GSI
+    GsiInUse,
+    /// Every vector in 50-5F has been handed out, or there isn't a long enough run of free ones left
+    NoFreeVectors,
+    /// The IOAPIC can't deliver to the boot CPU's LAPIC id, which is too wide without extended destination ids
+    InvalidDestination,
+}
+
+impl Vector {
+    pub fn as_u8(self) -> u8 {
+        self.0
+    }
+}
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+struct IrqSlot {
+    /// `None` for message signalled interrupts, which don't come through an IOAPIC
+    gsi: Option<u32>,
+    /// Shared so the trampoline can call it without holding `IRQ_HANDLERS`, letting a handler unregister itself
+    handler: Arc<dyn Fn() + Send + Sync>,
+}
+
+static IRQ_HANDLERS: RwLock<[Option<IrqSlot>; IRQ_VECTOR_COUNT]> =
+    RwLock::new([const { None }; IRQ_VECTOR_COUNT]);
+
+macro_rules! set_irq_trampolines {
+    ($idt:ident, $($vector:literal),*) => {
+        $($idt[$vector].set_handler_fn(irq_trampoline::<$vector>);)*
+    };
+}
 
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
@@ -25,35 +88,43 @@ lazy_static! {
         {
             use exception_handlers::*;
 
-            idt.divide_error.set_handler_fn(divide_error);
-            idt.debug.set_handler_fn(debug);
-            idt.non_maskable_interrupt
-                .set_handler_fn(non_maskable_interrupt);
-            idt.breakpoint.set_handler_fn(breakpoint_handler);
-            idt.overflow.set_handler_fn(overflow);
-            idt.bound_range_exceeded
-                .set_handler_fn(bound_range_exceeded);
-            idt.invalid_opcode.set_handler_fn(invalid_opcode);
-            idt.device_not_available
-                .set_handler_fn(device_not_available);
-
             unsafe {
+                idt.divide_error.set_handler_addr(stub_addr(divide_error));
+                idt.debug.set_handler_addr(stub_addr(debug));
+                idt.non_maskable_interrupt
+                    .set_handler_addr(stub_addr(non_maskable_interrupt));
+                idt.breakpoint
+                    .set_handler_addr(stub_addr(breakpoint_handler));
+                idt.overflow.set_handler_addr(stub_addr(overflow));
+                idt.bound_range_exceeded
+                    .set_handler_addr(stub_addr(bound_range_exceeded));
+                idt.invalid_opcode
+                    .set_handler_addr(stub_addr(invalid_opcode));
+                idt.device_not_available
+                    .set_handler_addr(stub_addr(device_not_available));
+
                 idt.double_fault
-                    .set_handler_fn(double_fault)
-                    .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX)
-            };
-
-            idt.invalid_tss.set_handler_fn(invalid_tss);
-            idt.segment_not_present.set_handler_fn(segment_not_present);
-            idt.stack_segment_fault.set_handler_fn(stack_segment_fault);
-            idt.general_protection_fault
-                .set_handler_fn(general_protection_fault);
-            idt.page_fault.set_handler_fn(page_fault);
-            idt.x87_floating_point.set_handler_fn(x87_floating_point);
-            idt.alignment_check.set_handler_fn(alignment_check);
-            idt.machine_check.set_handler_fn(machine_check);
-            idt.simd_floating_point.set_handler_fn(simd_floating_point);
-            idt.virtualization.set_handler_fn(virtualization);
+                    .set_handler_addr(stub_addr(double_fault))
+                    .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+
+                idt.invalid_tss.set_handler_addr(stub_addr(invalid_tss));
+                idt.segment_not_present
+                    .set_handler_addr(stub_addr(segment_not_present));
+                idt.stack_segment_fault
+                    .set_handler_addr(stub_addr(stack_segment_fault));
+                idt.general_protection_fault
+                    .set_handler_addr(stub_addr(general_protection_fault));
+                idt.page_fault.set_handler_addr(stub_addr(page_fault));
+                idt.x87_floating_point
+                    .set_handler_addr(stub_addr(x87_floating_point));
+                idt.alignment_check
+                    .set_handler_addr(stub_addr(alignment_check));
+                idt.machine_check.set_handler_addr(stub_addr(machine_check));
+                idt.simd_floating_point
+                    .set_handler_addr(stub_addr(simd_floating_point));
+                idt.virtualization
+                    .set_handler_addr(stub_addr(virtualization));
+            }
         }
 
         for irq in 0x20usize..0x30 {
@@ -61,7 +132,12 @@ lazy_static! {
         }
 
         idt[0x31].set_handler_fn(lapic_timer);
-        idt[0x41].set_handler_fn(keyboard);
+
+        set_irq_trampolines!(
+            idt, 0x40, 0x41, 0x42, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49, 0x4a, 0x4b, 0x4c,
+            0x4d, 0x4e, 0x4f, 0x50, 0x51, 0x52, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5a,
+            0x5b, 0x5c, 0x5d, 0x5e, 0x5f
+        );
 
         idt[0xff].set_handler_fn(spurious);
 
@@ -69,6 +145,15 @@ lazy_static! {
     };
 }
 
+fn stub_addr(stub: extern "C" fn()) -> VirtAddr {
+    VirtAddr::new(stub as usize as u64)
+}
+
+/// Number of LAPIC timer interrupts since the timer was started
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
 pub fn init_idt() {
     IDT.load();
 }
@@ -76,132 +161,184 @@ pub fn init_idt() {
 extern "x86-interrupt" fn spurious(_interrupt_stack_frame: InterruptStackFrame) {}
 
 extern "x86-interrupt" fn lapic_timer(_interrupt_stack_frame: InterruptStackFrame) {
-    unsafe { lapic_end_of_interrupt() }
-}
-
-extern "x86-interrupt" fn keyboard(_interrupt_stack_frame: InterruptStackFrame) {
-    let mut ps2_port = Port::new(0x60);
-
-    Keyboard::push_scancode(unsafe { ps2_port.read() }).unwrap_or_default();
+    TICKS.fetch_add(1, Ordering::Relaxed);
 
     unsafe { lapic_end_of_interrupt() }
 }
 
-pub(super) mod exception_handlers {
-    use x86_64::registers::control::Cr2;
-    use x86_64::structures::idt::{InterruptStackFrame, PageFaultErrorCode};
-
-    pub(super) extern "x86-interrupt" fn divide_error(_interrupt_stack_frame: InterruptStackFrame) {
-        panic!("[CPU Exception] Divide Error");
-    }
-
-    pub(super) extern "x86-interrupt" fn debug(_interrupt_stack_frame: InterruptStackFrame) {}
-    pub(super) extern "x86-interrupt" fn non_maskable_interrupt(
-        _interrupt_stack_frame: InterruptStackFrame,
-    ) {
-        panic!("[CPU Exception] Non-Maskable Interrupt")
+extern "x86-interrupt" fn irq_trampoline<const VECTOR: u8>(
+    _interrupt_stack_frame: InterruptStackFrame,
+) {
+    let handler = IRQ_HANDLERS.read()[(VECTOR - IRQ_VECTOR_START) as usize]
+        .as_ref()
+        .map(|slot| slot.handler.clone());
+    if let Some(handler) = handler {
+        handler();
     }
 
-    pub(super) extern "x86-interrupt" fn breakpoint_handler(
-        _interrupt_stack_frame: InterruptStackFrame,
-    ) {
-    }
-    pub(super) extern "x86-interrupt" fn overflow(_interrupt_stack_frame: InterruptStackFrame) {
-        panic!("[CPU Exception] Overflow")
-    }
-
-    pub(super) extern "x86-interrupt" fn bound_range_exceeded(
-        _interrupt_stack_frame: InterruptStackFrame,
-    ) {
-        panic!("[CPU Exception] Bound Range Exceeded")
-    }
-
-    pub(super) extern "x86-interrupt" fn invalid_opcode(
-        _interrupt_stack_frame: InterruptStackFrame,
-    ) {
-        panic!("[CPU Exception] Invalid Opcode")
-    }
-
-    pub(super) extern "x86-interrupt" fn device_not_available(
-        _interrupt_stack_frame: InterruptStackFrame,
-    ) {
-        panic!("[CPU Exception] Device Not Available")
-    }
-
-    pub(super) extern "x86-interrupt" fn double_fault(
-        _interrupt_stack_frame: InterruptStackFrame,
-        _error_code: u64,
-    ) -> ! {
-        panic!("[CPU Exception] Device Not Available")
-    }
-
-    pub(super) extern "x86-interrupt" fn invalid_tss(
-        _interrupt_stack_frame: InterruptStackFrame,
-        error_code: u64,
-    ) {
-        panic!("[CPU Exception] Invalid TSS {:?}", error_code)
-    }
+    unsafe { lapic_end_of_interrupt() }
+}
 
-    pub(super) extern "x86-interrupt" fn segment_not_present(
-        _interrupt_stack_frame: InterruptStackFrame,
-        error_code: u64,
-    ) {
-        panic!("[CPU Exception] Segment Not Present {:?}", error_code)
+/// Routes `gsi` through the IOAPIC to a free vector and calls `handler` (followed by an EOI) whenever it fires.
+///
+/// ISA IRQs always get their fixed vector in 40-4F and use the polarity and trigger mode from the MADT, every other
+/// GSI gets the first free vector in 50-5F and is assumed to be a level triggered, active low PCI interrupt.
+pub fn register_irq<F>(gsi: u32, handler: F) -> Result<Vector, IrqError>
+where
+    F: Fn() + Send + Sync + 'static,
+{
+    let isa_route = ISA_IRQ_ROUTES
+        .get()
+        .expect("APIC not initialised")
+        .iter()
+        .enumerate()
+        .find(|(_, route)| route.gsi == gsi);
+
+    match isa_route {
+        Some((irq, route)) => route_gsi(
+            gsi,
+            Some(ISA_IRQ_VECTOR_BASE + irq as u8),
+            route.polarity,
+            route.trigger_mode,
+            Arc::new(handler),
+        ),
+        None => route_gsi(
+            gsi,
+            None,
+            PinPolarity::ActiveLow,
+            TriggerMode::Level,
+            Arc::new(handler),
+        ),
     }
+}
 
-    pub(super) extern "x86-interrupt" fn stack_segment_fault(
-        _interrupt_stack_frame: InterruptStackFrame,
-        error_code: u64,
-    ) {
-        panic!("[CPU Exception] Stack Segment Fault {:?}", error_code)
-    }
+/// Like `register_irq`, but always gives `gsi` a vector in 50-5F. Firmware often connects PCI interrupts to the GSIs of
+/// ISA IRQs no ISA device uses, which `register_irq` would set up as ISA IRQs. The GSI is level triggered and active
+/// low unless the MADT has an Interrupt Source Override for it, whose polarity and trigger mode are used instead.
+pub fn register_pci_irq<F>(gsi: u32, handler: F) -> Result<Vector, IrqError>
+where
+    F: Fn() + Send + Sync + 'static,
+{
+    let (polarity, trigger_mode) = ISA_IRQ_ROUTES
+        .get()
+        .expect("APIC not initialised")
+        .iter()
+        .find(|route| route.overridden && route.gsi == gsi)
+        .map_or((PinPolarity::ActiveLow, TriggerMode::Level), |route| {
+            (route.polarity, route.trigger_mode)
+        });
+
+    route_gsi(gsi, None, polarity, trigger_mode, Arc::new(handler))
+}
 
-    pub(super) extern "x86-interrupt" fn general_protection_fault(
-        _interrupt_stack_frame: InterruptStackFrame,
-        error_code: u64,
-    ) {
-        panic!("[CPU Exception] General Protection Fault {:?}", error_code)
-    }
+/// `isa_vector` is the fixed vector of the ISA IRQ on `gsi`, without one the GSI gets the first free vector in 50-5F
+fn route_gsi(
+    gsi: u32,
+    isa_vector: Option<u8>,
+    polarity: PinPolarity,
+    trigger_mode: TriggerMode,
+    handler: Arc<dyn Fn() + Send + Sync>,
+) -> Result<Vector, IrqError> {
+    without_interrupts(|| {
+        let mut handlers = IRQ_HANDLERS.write();
+
+        if handlers.iter().flatten().any(|slot| slot.gsi == Some(gsi)) {
+            return Err(IrqError::GsiInUse);
+        }
 
-    pub(super) extern "x86-interrupt" fn page_fault(
-        _interrupt_stack_frame: InterruptStackFrame,
-        error_code: PageFaultErrorCode,
-    ) {
-        panic!(
-            "[CPU Exception] Page Fault on address {:?}, {:?}",
-            Cr2::read(),
-            error_code
-        )
-    }
+        let vector = match isa_vector {
+            Some(vector) => vector,
+            None => (PCI_VECTOR_START..IRQ_VECTOR_END)
+                .find(|vector| handlers[(vector - IRQ_VECTOR_START) as usize].is_none())
+                .ok_or(IrqError::NoFreeVectors)?,
+        };
 
-    pub(super) extern "x86-interrupt" fn x87_floating_point(
-        _interrupt_stack_frame: InterruptStackFrame,
-    ) {
-        panic!("[CPU Exception] x87 Floating Point Error")
-    }
+        let slot = &mut handlers[(vector - IRQ_VECTOR_START) as usize];
+        if slot.is_some() {
+            panic!("vector {:#x} is already in use", vector);
+        }
 
-    pub(super) extern "x86-interrupt" fn alignment_check(
-        _interrupt_stack_frame: InterruptStackFrame,
-        _error_code: u64,
-    ) {
-        panic!("[CPU Exception] Alignment Check")
-    }
+        GSI_ROUTER
+            .get()
+            .expect("APIC not initialised")
+            .lock()
+            .set_gsi(
+                gsi,
+                RedirectionEntry {
+                    vector,
+                    destination_mode: DestinationMode::Physical,
+                    polarity,
+                    trigger_mode,
+                    masked: false,
+                    destination: *BSP_LAPIC_ID.get().expect("APIC not initialised"),
+                },
+            )
+            .map_err(|_: InvalidDestination| IrqError::InvalidDestination)?;
+
+        *slot = Some(IrqSlot {
+            gsi: Some(gsi),
+            handler,
+        });
+
+        Ok(Vector(vector))
+    })
+}
 
-    pub(super) extern "x86-interrupt" fn machine_check(
-        _interrupt_stack_frame: InterruptStackFrame,
-    ) -> ! {
-        panic!("[CPU Exception] Machine Check")
-    }
+/// Hands out `count` consecutive free vectors in 50-5F for a device's message signalled interrupts, and calls `handler`
+/// (followed by an EOI) with the index of the vector that fired. The first vector is aligned to `count` rounded up to a
+/// power of two, as MSI with multiple messages needs.
+pub fn register_message_vectors<F>(count: usize, handler: F) -> Result<Vec<Vector>, IrqError>
+where
+    F: Fn(usize) + Send + Sync + 'static,
+{
+    let handler = Arc::new(handler);
+    let align = count.next_power_of_two();
+
+    without_interrupts(|| {
+        let mut handlers = IRQ_HANDLERS.write();
+
+        let first = (PCI_VECTOR_START as usize..IRQ_VECTOR_END as usize)
+            .step_by(align)
+            .find(|&first| {
+                first + count <= IRQ_VECTOR_END as usize
+                    && (first..first + count)
+                        .all(|vector| handlers[vector - IRQ_VECTOR_START as usize].is_none())
+            })
+            .ok_or(IrqError::NoFreeVectors)?;
+
+        Ok((0..count)
+            .map(|index| {
+                let handler = handler.clone();
+                handlers[first + index - IRQ_VECTOR_START as usize] = Some(IrqSlot {
+                    gsi: None,
+                    handler: Arc::new(move || handler(index)),
+                });
+
+                Vector((first + index) as u8)
+            })
+            .collect())
+    })
+}
 
-    pub(super) extern "x86-interrupt" fn simd_floating_point(
-        _interrupt_stack_frame: InterruptStackFrame,
-    ) {
-        panic!("[CPU Exception] SIMD Floating Point Error")
-    }
+/// The LAPIC id of the CPU which booted, which IOAPIC interrupts are delivered to
+pub fn boot_lapic_id() -> u32 {
+    *BSP_LAPIC_ID.get().expect("APIC not initialised")
+}
 
-    pub(super) extern "x86-interrupt" fn virtualization(
-        _interrupt_stack_frame: InterruptStackFrame,
-    ) {
-        panic!("[CPU Exception] Virtualization Error")
-    }
+/// Masks the GSI behind `vector`, if it has one, and frees the vector for reuse. A device using the vector for MSI has
+/// to stop sending it first.
+pub fn unregister_irq(vector: Vector) {
+    without_interrupts(|| {
+        let slot = IRQ_HANDLERS.write()[(vector.0 - IRQ_VECTOR_START) as usize]
+            .take()
+            .expect("vector is not registered");
+
+        if let Some(gsi) = slot.gsi {
+            GSI_ROUTER
+                .get()
+                .expect("APIC not initialised")
+                .lock()
+                .set_gsi_masked(gsi, true);
+        }
+    });
 }
@@ -0,0 +1,185 @@
+use core::fmt;
+use spin::Mutex;
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+use x86_64::structures::idt::{InterruptStackFrameValue, PageFaultErrorCode};
+
+/// Everything pushed onto the stack by the exception stubs, in the order they push it (lowest address first)
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct ExceptionFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    /// Pushed by the CPU for exceptions which have one, otherwise 0 is pushed by the stub
+    pub error_code: u64,
+    pub stack_frame: InterruptStackFrameValue,
+}
+
+/// The report for the exception currently being handled, so that the panic handler can unwind from where it happened
+static LAST_CRASH_REPORT: Mutex<Option<CrashReport>> = Mutex::new(None);
+
+/// Returns the report of the most recent fatal exception, if the lock can be taken
+pub fn last() -> Option<CrashReport> {
+    LAST_CRASH_REPORT.try_lock().and_then(|report| *report)
+}
+
+pub(crate) fn record(report: CrashReport) {
+    if let Some(mut last_crash_report) = LAST_CRASH_REPORT.try_lock() {
+        *last_crash_report = Some(report);
+    }
+}
+
+/// The state of the CPU at the time of an exception
+#[derive(Clone, Copy, Debug)]
+pub struct CrashReport {
+    pub frame: ExceptionFrame,
+    pub cr0: u64,
+    pub cr2: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+}
+
+impl CrashReport {
+    pub fn capture(frame: &ExceptionFrame) -> Self {
+        CrashReport {
+            frame: *frame,
+            cr0: Cr0::read_raw(),
+            cr2: Cr2::read_raw(),
+            cr3: Cr3::read_raw().0.start_address().as_u64(),
+            cr4: Cr4::read_raw(),
+        }
+    }
+
+    pub fn exception_name(&self) -> &'static str {
+        match self.frame.vector {
+            0x00 => "Divide Error",
+            0x01 => "Debug",
+            0x02 => "Non-Maskable Interrupt",
+            0x03 => "Breakpoint",
+            0x04 => "Overflow",
+            0x05 => "Bound Range Exceeded",
+            0x06 => "Invalid Opcode",
+            0x07 => "Device Not Available",
+            0x08 => "Double Fault",
+            0x0a => "Invalid TSS",
+            0x0b => "Segment Not Present",
+            0x0c => "Stack Segment Fault",
+            0x0d => "General Protection Fault",
+            0x0e => "Page Fault",
+            0x10 => "x87 Floating Point Error",
+            0x11 => "Alignment Check",
+            0x12 => "Machine Check",
+            0x13 => "SIMD Floating Point Error",
+            0x14 => "Virtualization Error",
+            _ => "Unknown Exception",
+        }
+    }
+
+    fn fmt_error_code(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let error_code = self.frame.error_code;
+
+        match self.frame.vector {
+            // Selector error code: bit 0 is set if the exception was caused by something external to the program,
+            // bits 1-2 say which table the selector indexes and bits 3-15 are the index
+            0x0a..=0x0d => {
+                let table = match (error_code >> 1) & 0b11 {
+                    0b00 => "GDT",
+                    0b10 => "LDT",
+                    _ => "IDT",
+                };
+
+                writeln!(
+                    f,
+                    "Error code: {:#x} ({} index {:#x}{})",
+                    error_code,
+                    table,
+                    (error_code >> 3) & 0x1fff,
+                    if error_code & 1 != 0 {
+                        ", external"
+                    } else {
+                        ""
+                    }
+                )
+            }
+            0x0e => {
+                writeln!(
+                    f,
+                    "Error code: {:#x} ({:?})",
+                    error_code,
+                    PageFaultErrorCode::from_bits_truncate(error_code)
+                )?;
+                writeln!(f, "Accessed address: {:#018x}", self.cr2)
+            }
+            // A double fault's error code is always zero, so there is nothing to decode
+            0x08 | 0x11 => writeln!(f, "Error code: {:#x}", error_code),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for CrashReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let frame = &self.frame;
+        let stack_frame = &frame.stack_frame;
+
+        writeln!(
+            f,
+            "[CPU Exception] {} (vector {:#04x})",
+            self.exception_name(),
+            frame.vector
+        )?;
+        self.fmt_error_code(f)?;
+
+        writeln!(
+            f,
+            "RIP: {:#018x} CS: {:#06x} RFLAGS: {:#018x}",
+            stack_frame.instruction_pointer.as_u64(),
+            stack_frame.code_segment,
+            stack_frame.cpu_flags
+        )?;
+        writeln!(
+            f,
+            "RSP: {:#018x} SS: {:#06x}",
+            stack_frame.stack_pointer.as_u64(),
+            stack_frame.stack_segment
+        )?;
+        writeln!(
+            f,
+            "CR0: {:#018x} CR2: {:#018x} CR3: {:#018x} CR4: {:#018x}",
+            self.cr0, self.cr2, self.cr3, self.cr4
+        )?;
+        writeln!(
+            f,
+            "RAX: {:#018x} RBX: {:#018x} RCX: {:#018x} RDX: {:#018x}",
+            frame.rax, frame.rbx, frame.rcx, frame.rdx
+        )?;
+        writeln!(
+            f,
+            "RSI: {:#018x} RDI: {:#018x} RBP: {:#018x}",
+            frame.rsi, frame.rdi, frame.rbp
+        )?;
+        writeln!(
+            f,
+            "R8:  {:#018x} R9:  {:#018x} R10: {:#018x} R11: {:#018x}",
+            frame.r8, frame.r9, frame.r10, frame.r11
+        )?;
+        write!(
+            f,
+            "R12: {:#018x} R13: {:#018x} R14: {:#018x} R15: {:#018x}",
+            frame.r12, frame.r13, frame.r14, frame.r15
+        )
+    }
+}
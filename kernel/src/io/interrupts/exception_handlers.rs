@@ -0,0 +1,100 @@
+//! Every exception enters through a naked stub which saves the general purpose registers so that they can be included
+//! in the crash report. Exceptions without an error code push a 0 in its place so that every stub leaves the same
+//! `ExceptionFrame` on the stack.
+
+use crate::gdb;
+use crate::io::interrupts::crash_report::{self, CrashReport, ExceptionFrame};
+use crate::panic;
+use core::arch::naked_asm;
+
+macro_rules! exception_stub {
+    ($name:ident, $vector:literal) => {
+        exception_stub!(@stub $name, $vector, ["push 0"]);
+    };
+    ($name:ident, $vector:literal, error_code) => {
+        exception_stub!(@stub $name, $vector, []);
+    };
+    (@stub $name:ident, $vector:literal, [$($push_error_code:literal),*]) => {
+        #[unsafe(naked)]
+        pub(in crate::io::interrupts) extern "C" fn $name() {
+            naked_asm!(
+                $($push_error_code,)*
+                "push {vector}",
+                "push rax",
+                "push rbx",
+                "push rcx",
+                "push rdx",
+                "push rsi",
+                "push rdi",
+                "push rbp",
+                "push r8",
+                "push r9",
+                "push r10",
+                "push r11",
+                "push r12",
+                "push r13",
+                "push r14",
+                "push r15",
+                // The CPU aligns the stack to 16 bytes before pushing its frame and we have pushed an even number of
+                // registers since then, so the stack is still aligned for the call
+                "mov rdi, rsp",
+                "call {dispatch}",
+                "pop r15",
+                "pop r14",
+                "pop r13",
+                "pop r12",
+                "pop r11",
+                "pop r10",
+                "pop r9",
+                "pop r8",
+                "pop rbp",
+                "pop rdi",
+                "pop rsi",
+                "pop rdx",
+                "pop rcx",
+                "pop rbx",
+                "pop rax",
+                // Skip the vector and error code
+                "add rsp, 16",
+                "iretq",
+                vector = const $vector,
+                dispatch = sym exception_dispatch,
+            )
+        }
+    };
+}
+
+exception_stub!(divide_error, 0x00);
+exception_stub!(debug, 0x01);
+exception_stub!(non_maskable_interrupt, 0x02);
+exception_stub!(breakpoint_handler, 0x03);
+exception_stub!(overflow, 0x04);
+exception_stub!(bound_range_exceeded, 0x05);
+exception_stub!(invalid_opcode, 0x06);
+exception_stub!(device_not_available, 0x07);
+exception_stub!(double_fault, 0x08, error_code);
+exception_stub!(invalid_tss, 0x0a, error_code);
+exception_stub!(segment_not_present, 0x0b, error_code);
+exception_stub!(stack_segment_fault, 0x0c, error_code);
+exception_stub!(general_protection_fault, 0x0d, error_code);
+exception_stub!(page_fault, 0x0e, error_code);
+exception_stub!(x87_floating_point, 0x10);
+exception_stub!(alignment_check, 0x11, error_code);
+exception_stub!(machine_check, 0x12);
+exception_stub!(simd_floating_point, 0x13);
+exception_stub!(virtualization, 0x14);
+
+extern "C" fn exception_dispatch(frame: &mut ExceptionFrame) {
+    match frame.vector {
+        // Hands over to GDB if the stub is enabled, otherwise just carries on
+        0x01 | 0x03 => gdb::handle_exception(frame),
+        // Another CPU has panicked and wants everyone else to stop
+        0x02 if panic::is_panicking() => panic::halt(),
+        _ => {
+            let report = CrashReport::capture(frame);
+            crash_report::record(report);
+
+            panic!("{}", report)
+        }
+    }
+}
@@ -3,6 +3,7 @@ use conquer_once::TryGetError;
 use crossbeam_queue::ArrayQueue;
 use pc_keyboard::layouts::Us104Key;
 use pc_keyboard::{DecodedKey, EventDecoder, HandleControl, ScancodeSet, ScancodeSet1};
+use x86_64::instructions::port::Port;
 
 static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
 
@@ -29,6 +30,13 @@ impl Keyboard {
         Ok(())
     }
 
+    /// IRQ handler for the PS/2 keyboard, registered by `io::init`
+    pub(crate) fn handle_interrupt() {
+        let mut ps2_port = Port::new(0x60);
+
+        Keyboard::push_scancode(unsafe { ps2_port.read() }).unwrap_or_default();
+    }
+
     pub fn poll_next(&mut self) -> Option<DecodedKey> {
         let next_scancode = SCANCODE_QUEUE.try_get().unwrap().pop();
         if next_scancode.is_none() {
@@ -1,19 +1,73 @@
+use crate::boot_config::BootConfig;
+use crate::gdb;
 use crate::io::bench_acpi::BenchAcpiHandler;
+use crate::io::drivers::apic::IsaIrq;
+use crate::io::drivers::serial::SerialPort;
+use crate::io::keyboard::Keyboard;
 use crate::memory::MemoryAllocator;
 use acpi::AcpiTables;
+use spin::Mutex;
 
 mod bench_acpi;
 pub(crate) mod drivers;
-pub(crate) mod interrupts;
-pub mod keyboard;
 pub(crate) mod framebuffer;
+pub mod interrupts;
+pub mod keyboard;
+pub mod pci;
+pub mod virtio;
+
+pub use drivers::serial;
 
-pub(crate) unsafe fn init(memory_allocator: &mut MemoryAllocator, rsdp_addr: usize) {
+/// `rsdp_addr` comes from the bootloader, which always finds it with UEFI. If it is missing (which can happen when
+/// booting via BIOS) the BIOS areas are searched instead. Drivers disabled in `config` are not loaded.
+pub(crate) unsafe fn init(
+    memory_allocator: &mut MemoryAllocator,
+    rsdp_addr: Option<usize>,
+    config: &BootConfig,
+) {
     let acpi_handler = BenchAcpiHandler::new(memory_allocator.phys_offset());
 
-    let acpi_tables =
-        unsafe { AcpiTables::from_rsdp(acpi_handler, rsdp_addr).expect("rsdp init failed") };
+    let acpi_tables = unsafe {
+        match rsdp_addr {
+            Some(rsdp_addr) => AcpiTables::from_rsdp(acpi_handler, rsdp_addr),
+            None => AcpiTables::search_for_rsdp_bios(acpi_handler),
+        }
+    }
+    .expect("rsdp init failed");
     let platform_info = acpi_tables.platform_info().unwrap();
 
-    drivers::apic::Apic::new(memory_allocator, &platform_info.interrupt_model);
+    drivers::apic::Apic::new(
+        memory_allocator,
+        &platform_info.interrupt_model,
+        config.timer_hz,
+    );
+
+    pci::init(memory_allocator, &acpi_tables);
+
+    if config.keyboard {
+        let registered = interrupts::register_irq(
+            drivers::apic::isa_irq_gsi(IsaIrq::Keyboard),
+            Keyboard::handle_interrupt,
+        );
+        if let Err(error) = registered {
+            log::warn!("Can't enable keyboard interrupts: {:?}", error);
+        }
+    }
+
+    if config.serial {
+        enable_serial_interrupts(&serial::COM1, IsaIrq::Com1, serial::handle_com1_interrupt);
+        enable_serial_interrupts(&serial::COM2, IsaIrq::Com2, serial::handle_com2_interrupt);
+    }
+}
+
+fn enable_serial_interrupts(serial_port: &Mutex<SerialPort>, irq: IsaIrq, handler: fn()) {
+    let mut serial_port = serial_port.lock();
+
+    // The GDB stub polls its port, interrupts would steal its input
+    if serial_port.is_present() && gdb::serial_port() != Some(serial_port.base()) {
+        match interrupts::register_irq(drivers::apic::isa_irq_gsi(irq), handler) {
+            Ok(_) => serial_port.enable_interrupts(),
+            Err(error) => log::warn!("Can't enable {:?} interrupts: {:?}", irq, error),
+        }
+    }
 }
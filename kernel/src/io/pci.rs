@@ -0,0 +1,612 @@
+//! PCI devices, found by scanning each host bridge's bus and the buses behind every PCI-to-PCI bridge on it. Drivers
+//! look up what they handle with [`find`] once [`init`] has run, and read and write the device's configuration space
+//! through the [`PciDevice`] they are given.
+
+use crate::io::pci::config::ConfigSpace;
+use crate::memory::{self, MemoryAllocator};
+use acpi::{AcpiHandler, AcpiTables};
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use core::fmt;
+use spin::Mutex;
+use x86_64::{PhysAddr, VirtAddr};
+
+mod config;
+pub mod intx;
+pub mod msi;
+
+pub use config::{ConfigValue, ECAM_CONFIG_SIZE, LEGACY_CONFIG_SIZE};
+
+pub const VENDOR_ID_OFFSET: u16 = 0x00;
+pub const DEVICE_ID_OFFSET: u16 = 0x02;
+pub const COMMAND_OFFSET: u16 = 0x04;
+pub const STATUS_OFFSET: u16 = 0x06;
+pub const REVISION_OFFSET: u16 = 0x08;
+pub const HEADER_TYPE_OFFSET: u16 = 0x0e;
+pub const BAR_OFFSET: u16 = 0x10;
+pub const SECONDARY_BUS_OFFSET: u16 = 0x19;
+pub const SUBSYSTEM_VENDOR_ID_OFFSET: u16 = 0x2c;
+pub const SUBSYSTEM_ID_OFFSET: u16 = 0x2e;
+pub const CAPABILITIES_POINTER_OFFSET: u16 = 0x34;
+pub const CARDBUS_CAPABILITIES_POINTER_OFFSET: u16 = 0x14;
+pub const INTERRUPT_LINE_OFFSET: u16 = 0x3c;
+pub const INTERRUPT_PIN_OFFSET: u16 = 0x3d;
+
+pub const COMMAND_IO_SPACE: u16 = 1 << 0;
+pub const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+pub const COMMAND_INTERRUPT_DISABLE: u16 = 1 << 10;
+pub const STATUS_CAPABILITIES_LIST: u16 = 1 << 4;
+
+pub const HEADER_TYPE_GENERAL: u8 = 0x00;
+pub const HEADER_TYPE_PCI_BRIDGE: u8 = 0x01;
+pub const HEADER_TYPE_CARDBUS_BRIDGE: u8 = 0x02;
+/// Set in the header type of function 0 when the device has functions other than 0
+const HEADER_TYPE_MULTIFUNCTION: u8 = 0x80;
+
+pub const CLASS_BRIDGE: u8 = 0x06;
+pub const SUBCLASS_HOST_BRIDGE: u8 = 0x00;
+
+/// Extended capabilities start straight after the legacy configuration space
+const EXTENDED_CAPABILITIES_OFFSET: u16 = 0x100;
+
+/// More capabilities than fit in configuration space means the list loops
+const MAX_CAPABILITIES: usize = 1024;
+
+static CONFIG_SPACE: OnceCell<ConfigSpace> = OnceCell::uninit();
+
+static DEVICES: OnceCell<Vec<PciDevice>> = OnceCell::uninit();
+
+/// Where each memory BAR mapped by `PciDevice::map_bar` is, by function and BAR index
+static MAPPED_BARS: Mutex<BTreeMap<(PciAddress, usize), VirtAddr>> = Mutex::new(BTreeMap::new());
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PciAddress {
+    pub segment: u16,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl PciAddress {
+    pub const fn new(segment: u16, bus: u8, device: u8, function: u8) -> Self {
+        PciAddress {
+            segment,
+            bus,
+            device,
+            function,
+        }
+    }
+}
+
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04x}:{:02x}:{:02x}.{:x}",
+            self.segment, self.bus, self.device, self.function
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Bar {
+    Memory {
+        address: u64,
+        size: u64,
+        prefetchable: bool,
+        /// Whether the BAR took the next slot for the top half of its address too
+        is_64bit: bool,
+    },
+    Io {
+        port: u16,
+        size: u16,
+    },
+}
+
+/// An entry in the capabilities list. `offset` is where its registers are in configuration space.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Capability {
+    pub id: u8,
+    pub offset: u16,
+}
+
+impl Capability {
+    pub const POWER_MANAGEMENT: u8 = 0x01;
+    pub const MSI: u8 = 0x05;
+    pub const VENDOR_SPECIFIC: u8 = 0x09;
+    pub const PCI_EXPRESS: u8 = 0x10;
+    pub const MSI_X: u8 = 0x11;
+}
+
+/// An entry in the PCI Express extended capabilities list, which is only reachable through ECAM
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ExtendedCapability {
+    pub id: u16,
+    pub version: u8,
+    pub offset: u16,
+}
+
+/// A function found while scanning the buses, with its header decoded. Multi-function devices are a `PciDevice` for
+/// each function.
+#[derive(Clone, Debug)]
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    /// Without the multi-function bit
+    pub header_type: u8,
+    /// Only general devices have subsystem ids, they are 0 for bridges
+    pub subsystem_vendor_id: u16,
+    pub subsystem_id: u16,
+    /// A 64 bit BAR leaves the slot after it `None`
+    pub bars: [Option<Bar>; 6],
+    pub capabilities: Vec<Capability>,
+    pub extended_capabilities: Vec<ExtendedCapability>,
+    /// INTA# to INTD# are 1 to 4, 0 means the function doesn't use a pin
+    pub interrupt_pin: u8,
+    /// Whatever the firmware left here, only meaningful with the legacy PIC
+    pub interrupt_line: u8,
+}
+
+impl PciDevice {
+    /// Reads the function's header, or `None` if there is nothing at `address`
+    fn probe(config: &ConfigSpace, address: PciAddress) -> Option<Self> {
+        let vendor_id: u16 = config.read(address, VENDOR_ID_OFFSET);
+        if vendor_id == u16::ABSENT {
+            return None;
+        }
+
+        let class_revision: u32 = config.read(address, REVISION_OFFSET);
+        let header_type =
+            config.read::<u8>(address, HEADER_TYPE_OFFSET) & !HEADER_TYPE_MULTIFUNCTION;
+        let general = header_type == HEADER_TYPE_GENERAL;
+
+        let mut device = PciDevice {
+            address,
+            vendor_id,
+            device_id: config.read(address, DEVICE_ID_OFFSET),
+            class: (class_revision >> 24) as u8,
+            subclass: (class_revision >> 16) as u8,
+            prog_if: (class_revision >> 8) as u8,
+            revision: class_revision as u8,
+            header_type,
+            subsystem_vendor_id: if general {
+                config.read(address, SUBSYSTEM_VENDOR_ID_OFFSET)
+            } else {
+                0
+            },
+            subsystem_id: if general {
+                config.read(address, SUBSYSTEM_ID_OFFSET)
+            } else {
+                0
+            },
+            bars: [None; 6],
+            capabilities: Vec::new(),
+            extended_capabilities: Vec::new(),
+            interrupt_pin: config.read(address, INTERRUPT_PIN_OFFSET),
+            interrupt_line: config.read(address, INTERRUPT_LINE_OFFSET),
+        };
+
+        device.bars = read_bars(config, address, header_type);
+        device.capabilities = read_capabilities(config, address, header_type);
+        if config.size() > EXTENDED_CAPABILITIES_OFFSET
+            && device.capability(Capability::PCI_EXPRESS).is_some()
+        {
+            device.extended_capabilities = read_extended_capabilities(config, address);
+        }
+
+        Some(device)
+    }
+
+    /// Reads a register in the function's configuration space. `offset` must be aligned to the register's size.
+    pub fn read_config<T: ConfigValue>(&self, offset: u16) -> T {
+        config_space().read(self.address, offset)
+    }
+
+    pub fn write_config<T: ConfigValue>(&self, offset: u16, value: T) {
+        config_space().write(self.address, offset, value)
+    }
+
+    /// How many bytes of configuration space can be reached, which is only the first 256 with the legacy ports
+    pub fn config_size(&self) -> u16 {
+        config_space().size()
+    }
+
+    /// The first capability with `id`
+    pub fn capability(&self, id: u8) -> Option<Capability> {
+        self.capabilities
+            .iter()
+            .find(|capability| capability.id == id)
+            .copied()
+    }
+
+    /// Maps memory BAR `index` uncached and turns on memory decoding, returning `None` if it isn't a memory BAR or the
+    /// firmware didn't give it an address. Every call for the same BAR gives the same mapping.
+    pub fn map_bar(&self, index: usize) -> Option<VirtAddr> {
+        let Some(Bar::Memory { address, size, .. }) = self.bars.get(index).copied().flatten()
+        else {
+            return None;
+        };
+        if address == 0 {
+            return None;
+        }
+
+        let virt_addr = *MAPPED_BARS
+            .lock()
+            .entry((self.address, index))
+            .or_insert_with(|| memory::map_mmio(PhysAddr::new(address), size));
+        self.update_command(COMMAND_MEMORY_SPACE, 0);
+
+        Some(virt_addr)
+    }
+
+    /// Sets and clears bits in the command register
+    pub fn update_command(&self, set: u16, clear: u16) {
+        let command: u16 = self.read_config(COMMAND_OFFSET);
+        self.write_config(COMMAND_OFFSET, (command & !clear) | set);
+    }
+}
+
+impl fmt::Display for PciDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {:04x}:{:04x} class {:02x}.{:02x}.{:02x}",
+            self.address, self.vendor_id, self.device_id, self.class, self.subclass, self.prog_if
+        )
+    }
+}
+
+/// What a driver handles. Fields left as `None` match anything.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PciMatch {
+    pub vendor_id: Option<u16>,
+    pub device_id: Option<u16>,
+    pub class: Option<u8>,
+    pub subclass: Option<u8>,
+    pub prog_if: Option<u8>,
+}
+
+impl PciMatch {
+    pub const fn device(vendor_id: u16, device_id: u16) -> Self {
+        PciMatch {
+            vendor_id: Some(vendor_id),
+            device_id: Some(device_id),
+            class: None,
+            subclass: None,
+            prog_if: None,
+        }
+    }
+
+    pub const fn class(class: u8, subclass: u8) -> Self {
+        PciMatch {
+            vendor_id: None,
+            device_id: None,
+            class: Some(class),
+            subclass: Some(subclass),
+            prog_if: None,
+        }
+    }
+
+    pub const fn with_prog_if(self, prog_if: u8) -> Self {
+        PciMatch {
+            prog_if: Some(prog_if),
+            ..self
+        }
+    }
+
+    pub fn matches(&self, device: &PciDevice) -> bool {
+        fn field<T: PartialEq>(expected: Option<T>, actual: T) -> bool {
+            expected.is_none_or(|expected| expected == actual)
+        }
+
+        field(self.vendor_id, device.vendor_id)
+            && field(self.device_id, device.device_id)
+            && field(self.class, device.class)
+            && field(self.subclass, device.subclass)
+            && field(self.prog_if, device.prog_if)
+    }
+}
+
+/// Every function found by [`init`], in the order they were found. Empty until then.
+pub fn devices() -> &'static [PciDevice] {
+    DEVICES.get().map_or(&[], Vec::as_slice)
+}
+
+pub fn find(pattern: PciMatch) -> impl Iterator<Item = &'static PciDevice> {
+    devices()
+        .iter()
+        .filter(move |device| pattern.matches(device))
+}
+
+pub fn device_at(address: PciAddress) -> Option<&'static PciDevice> {
+    devices().iter().find(|device| device.address == address)
+}
+
+fn config_space() -> &'static ConfigSpace {
+    CONFIG_SPACE.get().expect("PCI has not been initialised")
+}
+
+/// How configuration space is reached
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConfigMechanism {
+    Ecam,
+    /// Ports 0xcf8 and 0xcfc, which only reach the first 256 bytes of segment 0
+    Legacy,
+}
+
+pub fn config_mechanism() -> ConfigMechanism {
+    match config_space() {
+        ConfigSpace::Ecam(_) => ConfigMechanism::Ecam,
+        ConfigSpace::Legacy => ConfigMechanism::Legacy,
+    }
+}
+
+/// Every function on segment 0 the legacy configuration ports can see, found by trying every bus rather than
+/// following bridges. Machines with ECAM answer on the ports too, so this is something to check `devices` against.
+pub fn scan_legacy() -> Vec<PciAddress> {
+    let config = ConfigSpace::Legacy;
+    let mut addresses = Vec::new();
+
+    for bus in 0..=255 {
+        for device in 0..32 {
+            let address = PciAddress::new(0, bus, device, 0);
+            if config.read::<u16>(address, VENDOR_ID_OFFSET) == u16::ABSENT {
+                continue;
+            }
+
+            let header_type: u8 = config.read(address, HEADER_TYPE_OFFSET);
+            let functions = if header_type & HEADER_TYPE_MULTIFUNCTION != 0 {
+                8
+            } else {
+                1
+            };
+
+            addresses.extend(
+                (0..functions)
+                    .map(|function| PciAddress::new(0, bus, device, function))
+                    .filter(|&address| {
+                        config.read::<u16>(address, VENDOR_ID_OFFSET) != u16::ABSENT
+                    }),
+            );
+        }
+    }
+
+    addresses
+}
+
+/// Uses ECAM if the MCFG describes any regions, and falls back to the legacy ports otherwise
+pub(crate) fn init<H: AcpiHandler>(
+    memory_allocator: &mut MemoryAllocator,
+    acpi_tables: &AcpiTables<H>,
+) {
+    let mut config = ConfigSpace::new(acpi_tables);
+
+    let mut scan = BusScan {
+        config: &mut config,
+        memory_allocator,
+        scanned: BTreeSet::new(),
+        devices: Vec::new(),
+    };
+    for (segment, bus) in scan.config.roots() {
+        scan.scan_root(segment, bus);
+    }
+    let devices = scan.devices;
+
+    log::info!(
+        "Found {} PCI functions using {}",
+        devices.len(),
+        match config {
+            ConfigSpace::Ecam(_) => "ECAM",
+            ConfigSpace::Legacy => "the legacy configuration ports",
+        }
+    );
+    for device in &devices {
+        log::debug!("PCI {}", device);
+    }
+
+    CONFIG_SPACE.init_once(|| config);
+    DEVICES.init_once(|| devices);
+}
+
+struct BusScan<'a> {
+    config: &'a mut ConfigSpace,
+    memory_allocator: &'a mut MemoryAllocator,
+    /// Keeps a misconfigured bridge from sending the scan round in circles
+    scanned: BTreeSet<(u16, u8)>,
+    devices: Vec<PciDevice>,
+}
+
+impl BusScan<'_> {
+    /// A multi-function host bridge has a function for each host controller, and function n's bus is `bus + n`
+    fn scan_root(&mut self, segment: u16, bus: u8) {
+        self.scan_bus(segment, bus);
+
+        let host_bridges: Vec<u8> = self
+            .devices
+            .iter()
+            .filter(|device| {
+                device.address.segment == segment
+                    && device.address.bus == bus
+                    && device.address.device == 0
+                    && device.address.function != 0
+                    && device.class == CLASS_BRIDGE
+                    && device.subclass == SUBCLASS_HOST_BRIDGE
+            })
+            .map(|device| device.address.function)
+            .collect();
+
+        for function in host_bridges {
+            self.scan_bus(segment, bus.wrapping_add(function));
+        }
+    }
+
+    fn scan_bus(&mut self, segment: u16, bus: u8) {
+        if !self.scanned.insert((segment, bus))
+            || !self.config.map_bus(self.memory_allocator, segment, bus)
+        {
+            return;
+        }
+
+        for device in 0..32 {
+            let address = PciAddress::new(segment, bus, device, 0);
+            if self.config.read::<u16>(address, VENDOR_ID_OFFSET) == u16::ABSENT {
+                continue;
+            }
+
+            let header_type: u8 = self.config.read(address, HEADER_TYPE_OFFSET);
+            let functions = if header_type & HEADER_TYPE_MULTIFUNCTION != 0 {
+                8
+            } else {
+                1
+            };
+
+            for function in 0..functions {
+                let address = PciAddress::new(segment, bus, device, function);
+                let Some(device) = PciDevice::probe(self.config, address) else {
+                    continue;
+                };
+
+                let secondary_bus = (device.header_type == HEADER_TYPE_PCI_BRIDGE)
+                    .then(|| self.config.read::<u8>(address, SECONDARY_BUS_OFFSET));
+
+                self.devices.push(device);
+
+                if let Some(secondary_bus) = secondary_bus {
+                    self.scan_bus(segment, secondary_bus);
+                }
+            }
+        }
+    }
+}
+
+/// Sizes each BAR by writing all ones and seeing which bits stick, with decoding turned off so the device doesn't
+/// answer at the half written address in the meantime
+fn read_bars(config: &ConfigSpace, address: PciAddress, header_type: u8) -> [Option<Bar>; 6] {
+    let mut bars = [None; 6];
+    let count = match header_type {
+        HEADER_TYPE_GENERAL => 6,
+        HEADER_TYPE_PCI_BRIDGE => 2,
+        _ => 0,
+    };
+
+    let command: u16 = config.read(address, COMMAND_OFFSET);
+    config.write(
+        address,
+        COMMAND_OFFSET,
+        command & !(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE),
+    );
+
+    let size_register = |offset: u16| -> (u32, u32) {
+        let original: u32 = config.read(address, offset);
+        config.write(address, offset, u32::MAX);
+        let mask: u32 = config.read(address, offset);
+        config.write(address, offset, original);
+        (original, mask)
+    };
+
+    let mut index = 0;
+    while index < count {
+        let offset = BAR_OFFSET + index as u16 * 4;
+        let (original, mask) = size_register(offset);
+
+        if original & 1 == 1 {
+            // Only the bits the device decodes stick, which can be just the bottom 16
+            let mask = mask & 0xffff_fffc;
+            if mask != 0 {
+                bars[index] = Some(Bar::Io {
+                    port: (original & 0xffff_fffc) as u16,
+                    size: (!mask).wrapping_add(1) as u16,
+                });
+            }
+            index += 1;
+            continue;
+        }
+
+        let is_64bit = (original >> 1) & 0b11 == 0b10 && index + 1 < count;
+        let (mut base, mut mask) = ((original & 0xffff_fff0) as u64, (mask & 0xffff_fff0) as u64);
+        if is_64bit {
+            let (original_high, mask_high) = size_register(offset + 4);
+            base |= (original_high as u64) << 32;
+            mask |= (mask_high as u64) << 32;
+        } else if mask != 0 {
+            mask |= 0xffff_ffff_0000_0000;
+        }
+
+        // A 64-bit BAR of 4 GiB or more only has bits set in its upper half
+        if mask != 0 {
+            bars[index] = Some(Bar::Memory {
+                address: base,
+                size: (!mask).wrapping_add(1),
+                prefetchable: original & (1 << 3) != 0,
+                is_64bit,
+            });
+        }
+        index += if is_64bit { 2 } else { 1 };
+    }
+
+    config.write(address, COMMAND_OFFSET, command);
+    bars
+}
+
+fn read_capabilities(
+    config: &ConfigSpace,
+    address: PciAddress,
+    header_type: u8,
+) -> Vec<Capability> {
+    let mut capabilities = Vec::new();
+
+    let status: u16 = config.read(address, STATUS_OFFSET);
+    if status & STATUS_CAPABILITIES_LIST == 0 {
+        return capabilities;
+    }
+
+    let pointer_offset = match header_type {
+        HEADER_TYPE_CARDBUS_BRIDGE => CARDBUS_CAPABILITIES_POINTER_OFFSET,
+        _ => CAPABILITIES_POINTER_OFFSET,
+    };
+
+    // The bottom two bits of every pointer are reserved, and the header comes before the first 0x40 bytes
+    let mut offset = (config.read::<u8>(address, pointer_offset) & 0xfc) as u16;
+    while offset >= 0x40 && capabilities.len() < MAX_CAPABILITIES {
+        let header: u16 = config.read(address, offset);
+        capabilities.push(Capability {
+            id: header as u8,
+            offset,
+        });
+        offset = (header >> 8) & 0xfc;
+    }
+
+    capabilities
+}
+
+fn read_extended_capabilities(
+    config: &ConfigSpace,
+    address: PciAddress,
+) -> Vec<ExtendedCapability> {
+    let mut capabilities = Vec::new();
+    let mut offset = EXTENDED_CAPABILITIES_OFFSET;
+
+    while offset >= EXTENDED_CAPABILITIES_OFFSET && capabilities.len() < MAX_CAPABILITIES {
+        let header: u32 = config.read(address, offset);
+        // An empty list is a header of 0, and a function without the space at all reads as all ones
+        if header == 0 || header == u32::ABSENT {
+            break;
+        }
+
+        capabilities.push(ExtendedCapability {
+            id: header as u16,
+            version: (header >> 16) as u8 & 0xf,
+            offset,
+        });
+        offset = (header >> 20) as u16 & 0xffc;
+    }
+
+    capabilities
+}
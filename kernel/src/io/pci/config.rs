@@ -0,0 +1,227 @@
+//! Access to configuration space, through the memory mapped regions described by the MCFG (ECAM) when there are any,
+//! otherwise through the legacy address and data ports.
+
+use crate::io::pci::PciAddress;
+use crate::memory::virtual_addresses::{ECAM_BUS_SIZE, ECAM_END, ECAM_START};
+use crate::memory::MemoryAllocator;
+use acpi::mcfg::PciConfigRegions;
+use acpi::{AcpiHandler, AcpiTables};
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::ops::RangeInclusive;
+use spin::Mutex;
+use x86_64::instructions::port::{Port, PortRead, PortWrite};
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::{PhysAddr, VirtAddr};
+
+const CONFIG_DATA_PORT: u16 = 0xcfc;
+
+/// Selects the register `CONFIG_DATA_PORT` reaches. Held for as long as the pair is in use.
+static CONFIG_ADDRESS_PORT: Mutex<Port<u32>> = Mutex::new(Port::new(0xcf8));
+
+/// The legacy ports only reach the standard header and capabilities, extended capabilities need ECAM
+pub const LEGACY_CONFIG_SIZE: u16 = 0x100;
+pub const ECAM_CONFIG_SIZE: u16 = 0x1000;
+
+/// The widths configuration space can be read and written with
+pub trait ConfigValue: PortRead + PortWrite + Copy {
+    /// What reading a function that isn't there gives
+    const ABSENT: Self;
+}
+
+impl ConfigValue for u8 {
+    const ABSENT: Self = u8::MAX;
+}
+
+impl ConfigValue for u16 {
+    const ABSENT: Self = u16::MAX;
+}
+
+impl ConfigValue for u32 {
+    const ABSENT: Self = u32::MAX;
+}
+
+pub(crate) enum ConfigSpace {
+    Ecam(Ecam),
+    /// Ports 0xcf8 and 0xcfc, which only reach segment 0
+    Legacy,
+}
+
+pub(crate) struct Ecam {
+    regions: Vec<EcamRegion>,
+    /// Where each bus mapped so far is in virtual memory, buses are mapped as they are found
+    buses: BTreeMap<(u16, u8), VirtAddr>,
+}
+
+struct EcamRegion {
+    segment: u16,
+    buses: RangeInclusive<u8>,
+    /// The physical address of bus 0, even when the region starts at a later bus
+    base: u64,
+}
+
+impl ConfigSpace {
+    /// Uses ECAM if the firmware has an MCFG
+    pub(crate) fn new<H: AcpiHandler>(acpi_tables: &AcpiTables<H>) -> Self {
+        let Ok(regions) = PciConfigRegions::new(acpi_tables) else {
+            return ConfigSpace::Legacy;
+        };
+
+        let regions: Vec<_> = regions
+            .iter()
+            .map(|region| EcamRegion {
+                segment: region.segment_group,
+                buses: region.bus_range,
+                base: region.physical_address as u64,
+            })
+            .collect();
+
+        if regions.is_empty() {
+            ConfigSpace::Legacy
+        } else {
+            ConfigSpace::Ecam(Ecam {
+                regions,
+                buses: BTreeMap::new(),
+            })
+        }
+    }
+
+    /// The segment and first bus of each host bridge's hierarchy
+    pub(crate) fn roots(&self) -> Vec<(u16, u8)> {
+        match self {
+            ConfigSpace::Ecam(ecam) => ecam
+                .regions
+                .iter()
+                .map(|region| (region.segment, *region.buses.start()))
+                .collect(),
+            ConfigSpace::Legacy => Vec::from([(0, 0)]),
+        }
+    }
+
+    /// How many bytes of each function's configuration space can be reached
+    pub(crate) fn size(&self) -> u16 {
+        match self {
+            ConfigSpace::Ecam(_) => ECAM_CONFIG_SIZE,
+            ConfigSpace::Legacy => LEGACY_CONFIG_SIZE,
+        }
+    }
+
+    /// Makes `bus` reachable, returning false if it isn't in any region or there's no room left to map it
+    pub(crate) fn map_bus(
+        &mut self,
+        memory_allocator: &mut MemoryAllocator,
+        segment: u16,
+        bus: u8,
+    ) -> bool {
+        let ConfigSpace::Ecam(ecam) = self else {
+            return segment == 0;
+        };
+
+        if ecam.buses.contains_key(&(segment, bus)) {
+            return true;
+        }
+
+        let Some(region) = ecam
+            .regions
+            .iter()
+            .find(|region| region.segment == segment && region.buses.contains(&bus))
+        else {
+            return false;
+        };
+
+        let virt_addr = ECAM_START + ecam.buses.len() * ECAM_BUS_SIZE;
+        if virt_addr + ECAM_BUS_SIZE > ECAM_END {
+            log::warn!(
+                "No room to map PCI bus {:04x}:{:02x}, it is left out",
+                segment,
+                bus
+            );
+            return false;
+        }
+
+        let phys_addr = region.base + bus as u64 * ECAM_BUS_SIZE as u64;
+        let virt_addr = VirtAddr::new(virt_addr as u64);
+
+        for offset in (0..ECAM_BUS_SIZE as u64).step_by(0x1000) {
+            unsafe {
+                memory_allocator.map_page_containing_address(
+                    PhysAddr::new(phys_addr + offset),
+                    virt_addr + offset,
+                    PageTableFlags::WRITABLE | PageTableFlags::PRESENT | PageTableFlags::NO_CACHE,
+                );
+            }
+        }
+
+        ecam.buses.insert((segment, bus), virt_addr);
+        true
+    }
+
+    /// Reads a function that can't be reached as if it wasn't there
+    pub(crate) fn read<T: ConfigValue>(&self, address: PciAddress, offset: u16) -> T {
+        check_offset::<T>(offset, self.size());
+
+        match self {
+            ConfigSpace::Ecam(ecam) => match ecam.register(address, offset) {
+                Some(register) => unsafe { register.as_ptr::<T>().read_volatile() },
+                None => T::ABSENT,
+            },
+            ConfigSpace::Legacy if address.segment == 0 => {
+                let mut address_port = CONFIG_ADDRESS_PORT.lock();
+                unsafe {
+                    address_port.write(legacy_address(address, offset));
+                    Port::<T>::new(CONFIG_DATA_PORT + (offset & 3)).read()
+                }
+            }
+            ConfigSpace::Legacy => T::ABSENT,
+        }
+    }
+
+    /// Writes to a function that can't be reached are dropped
+    pub(crate) fn write<T: ConfigValue>(&self, address: PciAddress, offset: u16, value: T) {
+        check_offset::<T>(offset, self.size());
+
+        match self {
+            ConfigSpace::Ecam(ecam) => {
+                if let Some(register) = ecam.register(address, offset) {
+                    unsafe { register.as_mut_ptr::<T>().write_volatile(value) };
+                }
+            }
+            ConfigSpace::Legacy if address.segment == 0 => {
+                let mut address_port = CONFIG_ADDRESS_PORT.lock();
+                unsafe {
+                    address_port.write(legacy_address(address, offset));
+                    Port::<T>::new(CONFIG_DATA_PORT + (offset & 3)).write(value);
+                }
+            }
+            ConfigSpace::Legacy => {}
+        }
+    }
+}
+
+impl Ecam {
+    fn register(&self, address: PciAddress, offset: u16) -> Option<VirtAddr> {
+        let bus = self.buses.get(&(address.segment, address.bus))?;
+        let function = (address.device as u64) << 15 | (address.function as u64) << 12;
+
+        Some(*bus + function + offset as u64)
+    }
+}
+
+/// Registers are accessed at their natural alignment, which also keeps a legacy access inside one dword
+fn check_offset<T>(offset: u16, size: u16) {
+    assert!(
+        offset.is_multiple_of(size_of::<T>() as u16) && offset < size,
+        "configuration space offset {:#x} is out of range or misaligned",
+        offset
+    );
+}
+
+/// Bit 31 enables the access, the low two bits are left to the data port
+fn legacy_address(address: PciAddress, offset: u16) -> u32 {
+    1 << 31
+        | (address.bus as u32) << 16
+        | (address.device as u32) << 11
+        | (address.function as u32) << 8
+        | (offset as u32 & 0xfc)
+}
@@ -0,0 +1,179 @@
+//! Pin interrupts (INTA# to INTD#). Functions share GSIs, so each GSI in use has one handler which calls the handler of
+//! every function on it, and each driver checks whether its own device is the one interrupting.
+//!
+//! The ACPI `_PRT` methods say which GSI each pin is connected to, but evaluating them needs an AML interpreter which
+//! the kernel doesn't have yet. Until then the GSI comes from the IRQ the firmware wrote to the interrupt line register
+//! when it set up the PCI interrupt routers, which is right on the chipsets QEMU emulates.
+
+use crate::io::drivers::apic::ISA_IRQ_ROUTES;
+use crate::io::interrupts::{self, IrqError, Vector};
+use crate::io::pci::{PciDevice, COMMAND_INTERRUPT_DISABLE};
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::RwLock;
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// What the firmware leaves in the interrupt line register of a function it didn't route
+const INTERRUPT_LINE_UNROUTED: u8 = 0xff;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IntxError {
+    /// The function doesn't use an interrupt pin
+    NoPin,
+    /// The firmware didn't connect the function's pin to anything
+    Unrouted,
+    /// The GSI couldn't be routed through the IOAPIC
+    Interrupt(IrqError),
+}
+
+impl From<IrqError> for IntxError {
+    fn from(error: IrqError) -> Self {
+        IntxError::Interrupt(error)
+    }
+}
+
+/// The handlers on each GSI used for pin interrupts
+static SHARED_GSIS: RwLock<BTreeMap<u32, SharedGsi>> = RwLock::new(BTreeMap::new());
+
+static NEXT_HANDLER_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Set while `dispatch` calls the handlers, which mustn't register or drop handlers themselves
+static DISPATCHING: AtomicBool = AtomicBool::new(false);
+
+struct SharedGsi {
+    vector: Vector,
+    /// Copied on write, so that `dispatch` can take the handlers out of the lock without allocating
+    handlers: Arc<Vec<SharedHandler>>,
+}
+
+#[derive(Clone)]
+struct SharedHandler {
+    id: u64,
+    device: &'static PciDevice,
+    handler: Arc<dyn Fn() + Send + Sync>,
+}
+
+/// The GSI the function's interrupt pin is connected to
+pub fn gsi(device: &PciDevice) -> Result<u32, IntxError> {
+    if device.interrupt_pin == 0 {
+        return Err(IntxError::NoPin);
+    }
+
+    match device.interrupt_line {
+        // IRQ 0 is the timer, so 0 is what firmware which doesn't bother with the register leaves there
+        0 | INTERRUPT_LINE_UNROUTED => Err(IntxError::Unrouted),
+        // The ISA IRQ lines the routers drive are connected to the IOAPIC like the ISA IRQs are
+        line => Ok(ISA_IRQ_ROUTES
+            .get()
+            .expect("APIC not initialised")
+            .get(line as usize)
+            .map_or(line as u32, |route| route.gsi)),
+    }
+}
+
+/// A handler for a function's pin interrupt, which is removed when this is dropped
+pub struct IntxHandler {
+    device: &'static PciDevice,
+    gsi: u32,
+    id: u64,
+}
+
+impl IntxHandler {
+    /// Calls `handler` (followed by an EOI) whenever the function's pin, or any other pin connected to the same GSI, is
+    /// asserted. PCI pins are level triggered, so `handler` has to get the device to deassert its pin or it will be
+    /// called again straight away.
+    ///
+    /// Can't be called from a pin interrupt handler, since changing the handlers on a GSI copies its handler list.
+    pub fn register<F>(device: &'static PciDevice, handler: F) -> Result<Self, IntxError>
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        debug_assert!(
+            !DISPATCHING.load(Ordering::Relaxed),
+            "pin interrupt handlers can't register handlers"
+        );
+
+        let gsi = gsi(device)?;
+        let id = NEXT_HANDLER_ID.fetch_add(1, Ordering::Relaxed);
+        let handler = SharedHandler {
+            id,
+            device,
+            handler: Arc::new(handler),
+        };
+
+        without_interrupts(|| {
+            let mut shared_gsis = SHARED_GSIS.write();
+
+            match shared_gsis.get_mut(&gsi) {
+                Some(shared_gsi) => Arc::make_mut(&mut shared_gsi.handlers).push(handler),
+                None => {
+                    let vector = interrupts::register_pci_irq(gsi, move || dispatch(gsi))?;
+                    shared_gsis.insert(
+                        gsi,
+                        SharedGsi {
+                            vector,
+                            handlers: Arc::new(vec![handler]),
+                        },
+                    );
+                }
+            }
+
+            Ok::<_, IntxError>(())
+        })?;
+
+        device.update_command(0, COMMAND_INTERRUPT_DISABLE);
+
+        Ok(IntxHandler { device, gsi, id })
+    }
+
+    pub fn gsi(&self) -> u32 {
+        self.gsi
+    }
+}
+
+/// Once the function has no handlers left its pin is turned off, so that it can't keep a shared GSI asserted with
+/// nothing to acknowledge it. Like registering, this can't be done from a pin interrupt handler.
+impl Drop for IntxHandler {
+    fn drop(&mut self) {
+        debug_assert!(
+            !DISPATCHING.load(Ordering::Relaxed),
+            "pin interrupt handlers can't drop handlers"
+        );
+
+        without_interrupts(|| {
+            let mut shared_gsis = SHARED_GSIS.write();
+            let shared_gsi = shared_gsis.get_mut(&self.gsi).unwrap();
+            Arc::make_mut(&mut shared_gsi.handlers).retain(|handler| handler.id != self.id);
+
+            if !shared_gsi
+                .handlers
+                .iter()
+                .any(|handler| handler.device.address == self.device.address)
+            {
+                self.device.update_command(COMMAND_INTERRUPT_DISABLE, 0);
+            }
+
+            if shared_gsi.handlers.is_empty() {
+                interrupts::unregister_irq(shared_gsi.vector);
+                shared_gsis.remove(&self.gsi);
+            }
+        });
+    }
+}
+
+/// The handlers are called with the lock released, so nothing they do can deadlock against it
+fn dispatch(gsi: u32) {
+    let handlers = SHARED_GSIS
+        .read()
+        .get(&gsi)
+        .map(|shared_gsi| shared_gsi.handlers.clone());
+
+    DISPATCHING.store(true, Ordering::Relaxed);
+    for handler in handlers.iter().flat_map(|handlers| handlers.iter()) {
+        (handler.handler)();
+    }
+    DISPATCHING.store(false, Ordering::Relaxed);
+}
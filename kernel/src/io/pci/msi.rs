@@ -0,0 +1,307 @@
+//! Message signalled interrupts. Instead of asserting a pin routed through an IOAPIC, a device using MSI or MSI-X
+//! writes its vector straight to a LAPIC, so each of its interrupts gets a vector of its own in 50-5F.
+
+use crate::io::interrupts::{self, IrqError, Vector};
+use crate::io::pci::{Capability, PciDevice, COMMAND_BUS_MASTER, COMMAND_INTERRUPT_DISABLE};
+use alloc::vec::Vec;
+use x86_64::VirtAddr;
+
+/// Messages written here go to the LAPIC whose id is in bits 12-19
+const MSI_ADDRESS_BASE: u32 = 0xfee0_0000;
+
+const MESSAGE_CONTROL_OFFSET: u16 = 0x02;
+const MSI_ADDRESS_OFFSET: u16 = 0x04;
+
+const MSI_CONTROL_ENABLE: u16 = 1 << 0;
+const MSI_CONTROL_64BIT: u16 = 1 << 7;
+const MSI_CONTROL_PER_VECTOR_MASKING: u16 = 1 << 8;
+
+const MSI_X_TABLE_OFFSET: u16 = 0x04;
+const MSI_X_CONTROL_FUNCTION_MASK: u16 = 1 << 14;
+const MSI_X_CONTROL_ENABLE: u16 = 1 << 15;
+const MSI_X_ENTRY_SIZE: u64 = 16;
+const MSI_X_VECTOR_CONTROL_MASKED: u32 = 1 << 0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MsiError {
+    /// The device has neither MSI nor MSI-X
+    Unsupported,
+    /// The device can't send that many different messages
+    TooManyVectors,
+    /// There isn't a long enough run of free vectors left
+    NoFreeVectors,
+    /// Without interrupt remapping messages can only reach LAPICs with an id below 256
+    InvalidDestination,
+    /// The device's MSI capability can't mask its vectors one at a time
+    MaskingUnsupported,
+    /// The MSI-X table isn't in a memory BAR
+    InvalidTable,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MsiKind {
+    Msi,
+    MsiX,
+}
+
+/// A device's message signalled interrupts, from when they are enabled until this is dropped
+pub struct MessageInterrupts {
+    device: &'static PciDevice,
+    mode: Mode,
+    vectors: Vec<Vector>,
+}
+
+enum Mode {
+    Msi {
+        capability: u16,
+        is_64bit: bool,
+        per_vector_masking: bool,
+    },
+    MsiX {
+        capability: u16,
+        table: VirtAddr,
+        table_size: usize,
+    },
+}
+
+impl MessageInterrupts {
+    /// Which kind of message signalled interrupts the device has and how many vectors it can use, preferring MSI-X
+    pub fn available(device: &PciDevice) -> Option<(MsiKind, usize)> {
+        if let Some(capability) = device.capability(Capability::MSI_X) {
+            let control: u16 = device.read_config(capability.offset + MESSAGE_CONTROL_OFFSET);
+            return Some((MsiKind::MsiX, (control & 0x7ff) as usize + 1));
+        }
+
+        let capability = device.capability(Capability::MSI)?;
+        let control: u16 = device.read_config(capability.offset + MESSAGE_CONTROL_OFFSET);
+        Some((MsiKind::Msi, 1 << ((control >> 1) & 0b111)))
+    }
+
+    /// Gives the device `count` vectors delivered to the LAPIC with id `destination`, and calls `handler` with the
+    /// index of the message whenever one arrives. Uses MSI-X when the device has it, where the vectors can be anywhere.
+    /// MSI needs a power of two, so `count` is rounded up and the extra vectors are never sent.
+    ///
+    /// Pin interrupts are turned off and bus mastering on, since a message is a write from the device.
+    pub fn enable<F>(
+        device: &'static PciDevice,
+        count: usize,
+        destination: u32,
+        handler: F,
+    ) -> Result<Self, MsiError>
+    where
+        F: Fn(usize) + Send + Sync + 'static,
+    {
+        let (kind, available) = Self::available(device).ok_or(MsiError::Unsupported)?;
+        let count = match kind {
+            MsiKind::Msi => count.next_power_of_two(),
+            MsiKind::MsiX => count,
+        };
+        if count == 0 || count > available {
+            return Err(MsiError::TooManyVectors);
+        }
+        if destination > 0xff {
+            return Err(MsiError::InvalidDestination);
+        }
+
+        let mode = match kind {
+            MsiKind::Msi => {
+                let capability = device.capability(Capability::MSI).unwrap().offset;
+                let control: u16 = device.read_config(capability + MESSAGE_CONTROL_OFFSET);
+
+                Mode::Msi {
+                    capability,
+                    is_64bit: control & MSI_CONTROL_64BIT != 0,
+                    per_vector_masking: control & MSI_CONTROL_PER_VECTOR_MASKING != 0,
+                }
+            }
+            MsiKind::MsiX => {
+                let capability = device.capability(Capability::MSI_X).unwrap().offset;
+                let table: u32 = device.read_config(capability + MSI_X_TABLE_OFFSET);
+                // The bottom three bits are the BAR, the table is 8 byte aligned in it
+                let bar = device
+                    .map_bar((table & 0b111) as usize)
+                    .ok_or(MsiError::InvalidTable)?;
+
+                Mode::MsiX {
+                    capability,
+                    table: bar + (table & !0b111) as u64,
+                    table_size: available,
+                }
+            }
+        };
+
+        let vectors = interrupts::register_message_vectors(count, handler)
+            .map_err(|_: IrqError| MsiError::NoFreeVectors)?;
+        let address = MSI_ADDRESS_BASE | destination << 12;
+
+        match mode {
+            Mode::Msi {
+                capability,
+                is_64bit,
+                per_vector_masking,
+            } => {
+                // The device ORs the message index into the bottom bits of the data
+                device.write_config(capability + MSI_ADDRESS_OFFSET, address);
+                let data_offset = if is_64bit {
+                    device.write_config(capability + MSI_ADDRESS_OFFSET + 4, 0u32);
+                    capability + 0x0c
+                } else {
+                    capability + 0x08
+                };
+                device.write_config(data_offset, vectors[0].as_u8() as u16);
+                if per_vector_masking {
+                    device.write_config(data_offset + 4, 0u32);
+                }
+
+                let control: u16 = device.read_config(capability + MESSAGE_CONTROL_OFFSET);
+                let enabled_log2 = count.trailing_zeros() as u16;
+                device.write_config(
+                    capability + MESSAGE_CONTROL_OFFSET,
+                    (control & !(0b111 << 4)) | enabled_log2 << 4 | MSI_CONTROL_ENABLE,
+                );
+            }
+            Mode::MsiX {
+                capability,
+                table,
+                table_size,
+            } => {
+                // The function stays masked while its table is filled in
+                let control: u16 = device.read_config(capability + MESSAGE_CONTROL_OFFSET);
+                device.write_config(
+                    capability + MESSAGE_CONTROL_OFFSET,
+                    control | MSI_X_CONTROL_ENABLE | MSI_X_CONTROL_FUNCTION_MASK,
+                );
+
+                for index in 0..table_size {
+                    let entry = table + index as u64 * MSI_X_ENTRY_SIZE;
+                    match vectors.get(index) {
+                        Some(vector) => unsafe {
+                            write_u32(entry, address);
+                            write_u32(entry + 4u64, 0);
+                            write_u32(entry + 8u64, vector.as_u8() as u32);
+                            write_u32(entry + 12u64, 0);
+                        },
+                        None => unsafe { write_u32(entry + 12u64, MSI_X_VECTOR_CONTROL_MASKED) },
+                    }
+                }
+
+                device.write_config(
+                    capability + MESSAGE_CONTROL_OFFSET,
+                    (control | MSI_X_CONTROL_ENABLE) & !MSI_X_CONTROL_FUNCTION_MASK,
+                );
+            }
+        }
+
+        device.update_command(COMMAND_BUS_MASTER | COMMAND_INTERRUPT_DISABLE, 0);
+
+        Ok(MessageInterrupts {
+            device,
+            mode,
+            vectors,
+        })
+    }
+
+    pub fn kind(&self) -> MsiKind {
+        match self.mode {
+            Mode::Msi { .. } => MsiKind::Msi,
+            Mode::MsiX { .. } => MsiKind::MsiX,
+        }
+    }
+
+    /// The vector each message is delivered on
+    pub fn vectors(&self) -> &[Vector] {
+        &self.vectors
+    }
+
+    /// Stops the device sending message `index` while it is masked. A message the device wants to send meanwhile is
+    /// held as pending and sent once it is unmasked.
+    pub fn set_masked(&self, index: usize, masked: bool) -> Result<(), MsiError> {
+        assert!(
+            index < self.vectors.len(),
+            "message {} is out of range",
+            index
+        );
+
+        match self.mode {
+            Mode::Msi {
+                per_vector_masking: false,
+                ..
+            } => Err(MsiError::MaskingUnsupported),
+            Mode::Msi {
+                capability,
+                is_64bit,
+                ..
+            } => {
+                let mask_offset = capability + if is_64bit { 0x10 } else { 0x0c };
+                let mask: u32 = self.device.read_config(mask_offset);
+                let mask = if masked {
+                    mask | 1 << index
+                } else {
+                    mask & !(1 << index)
+                };
+                self.device.write_config(mask_offset, mask);
+                Ok(())
+            }
+            Mode::MsiX { table, .. } => {
+                let vector_control = table + index as u64 * MSI_X_ENTRY_SIZE + 12u64;
+                let value = if masked {
+                    MSI_X_VECTOR_CONTROL_MASKED
+                } else {
+                    0
+                };
+                unsafe { write_u32(vector_control, value) };
+                Ok(())
+            }
+        }
+    }
+
+    /// Whether message `index` is masked
+    pub fn is_masked(&self, index: usize) -> bool {
+        match self.mode {
+            Mode::Msi {
+                per_vector_masking: false,
+                ..
+            } => false,
+            Mode::Msi {
+                capability,
+                is_64bit,
+                ..
+            } => {
+                let mask: u32 = self
+                    .device
+                    .read_config(capability + if is_64bit { 0x10 } else { 0x0c });
+                mask & 1 << index != 0
+            }
+            Mode::MsiX { table, .. } => {
+                let vector_control = table + index as u64 * MSI_X_ENTRY_SIZE + 12u64;
+                unsafe {
+                    vector_control.as_ptr::<u32>().read_volatile() & MSI_X_VECTOR_CONTROL_MASKED
+                        != 0
+                }
+            }
+        }
+    }
+}
+
+/// Turns the messages off before freeing their vectors. The pin stays off too, until a driver registers a handler for it
+/// with `IntxHandler`.
+impl Drop for MessageInterrupts {
+    fn drop(&mut self) {
+        let (capability, enable) = match self.mode {
+            Mode::Msi { capability, .. } => (capability, MSI_CONTROL_ENABLE),
+            Mode::MsiX { capability, .. } => (capability, MSI_X_CONTROL_ENABLE),
+        };
+
+        let control: u16 = self.device.read_config(capability + MESSAGE_CONTROL_OFFSET);
+        self.device
+            .write_config(capability + MESSAGE_CONTROL_OFFSET, control & !enable);
+
+        for &vector in &self.vectors {
+            interrupts::unregister_irq(vector);
+        }
+    }
+}
+
+unsafe fn write_u32(address: VirtAddr, value: u32) {
+    address.as_mut_ptr::<u32>().write_volatile(value);
+}
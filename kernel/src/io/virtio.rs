@@ -0,0 +1,515 @@
+//! The virtio PCI transport. Modern devices describe where their registers are with vendor specific capabilities: the
+//! common configuration for feature negotiation, the status handshake and queue setup, the notification area the
+//! driver writes to when it has put buffers on a queue, the ISR status for pin interrupts and the device specific
+//! configuration.
+//!
+//! Device drivers are built on `VirtioPci`. They negotiate features, set up the queues they use and then tell the
+//! device they are ready with `driver_ok`, after which they exchange buffers over the queues.
+
+use crate::io::drivers::pit;
+use crate::io::interrupts;
+use crate::io::pci::intx::{IntxError, IntxHandler};
+use crate::io::pci::msi::{MessageInterrupts, MsiError, MsiKind};
+use crate::io::pci::{self, Bar, Capability, PciDevice};
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::RwLock;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::VirtAddr;
+
+pub mod queue;
+
+pub use queue::{Segment, Token, Virtqueue};
+
+/// Every virtio device uses this vendor id
+pub const VENDOR_ID: u16 = 0x1af4;
+/// Transitional devices have the device type as their subsystem id
+const TRANSITIONAL_DEVICE_IDS: core::ops::RangeInclusive<u16> = 0x1000..=0x103f;
+/// Modern devices have the device type added to this as their device id
+const MODERN_DEVICE_ID_BASE: u16 = 0x1040;
+const MODERN_DEVICE_IDS: core::ops::RangeInclusive<u16> = 0x1040..=0x107f;
+
+pub const DEVICE_TYPE_NETWORK: u16 = 1;
+pub const DEVICE_TYPE_BLOCK: u16 = 2;
+pub const DEVICE_TYPE_CONSOLE: u16 = 3;
+pub const DEVICE_TYPE_ENTROPY: u16 = 4;
+
+/// The device conforms to version 1 of the specification rather than the legacy interface, which this transport needs
+pub const FEATURE_VERSION_1: u64 = 1 << 32;
+
+const CAPABILITY_CFG_TYPE_OFFSET: u16 = 0x03;
+const CAPABILITY_BAR_OFFSET: u16 = 0x04;
+const CAPABILITY_OFFSET_OFFSET: u16 = 0x08;
+const CAPABILITY_LENGTH_OFFSET: u16 = 0x0c;
+const CAPABILITY_NOTIFY_MULTIPLIER_OFFSET: u16 = 0x10;
+
+const CFG_TYPE_COMMON: u8 = 1;
+const CFG_TYPE_NOTIFY: u8 = 2;
+const CFG_TYPE_ISR: u8 = 3;
+const CFG_TYPE_DEVICE: u8 = 4;
+
+const COMMON_DEVICE_FEATURE_SELECT: u64 = 0x00;
+const COMMON_DEVICE_FEATURE: u64 = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: u64 = 0x08;
+const COMMON_DRIVER_FEATURE: u64 = 0x0c;
+const COMMON_MSIX_CONFIG: u64 = 0x10;
+const COMMON_NUM_QUEUES: u64 = 0x12;
+const COMMON_DEVICE_STATUS: u64 = 0x14;
+const COMMON_CONFIG_GENERATION: u64 = 0x15;
+const COMMON_QUEUE_SELECT: u64 = 0x16;
+const COMMON_QUEUE_SIZE: u64 = 0x18;
+const COMMON_QUEUE_MSIX_VECTOR: u64 = 0x1a;
+const COMMON_QUEUE_ENABLE: u64 = 0x1c;
+const COMMON_QUEUE_NOTIFY_OFF: u64 = 0x1e;
+const COMMON_QUEUE_DESC: u64 = 0x20;
+const COMMON_QUEUE_DRIVER: u64 = 0x28;
+const COMMON_QUEUE_DEVICE: u64 = 0x30;
+
+pub const STATUS_ACKNOWLEDGE: u8 = 1 << 0;
+pub const STATUS_DRIVER: u8 = 1 << 1;
+pub const STATUS_DRIVER_OK: u8 = 1 << 2;
+pub const STATUS_FEATURES_OK: u8 = 1 << 3;
+pub const STATUS_NEEDS_RESET: u8 = 1 << 6;
+pub const STATUS_FAILED: u8 = 1 << 7;
+
+const ISR_QUEUE: u8 = 1 << 0;
+const ISR_CONFIG: u8 = 1 << 1;
+
+/// What the device reads back from an MSI-X vector register it couldn't set
+const NO_VECTOR: u16 = 0xffff;
+/// The largest queue set up, however many entries the device allows
+const MAX_QUEUE_SIZE: u16 = 256;
+/// How long a device gets to finish resetting
+const RESET_TIMEOUT_MS: u32 = 100;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VirtioError {
+    /// The function isn't a virtio device
+    NotVirtio,
+    /// The device doesn't have the capability for one of the register areas, or it isn't in a memory BAR
+    MissingCapability,
+    /// The device only has the legacy interface
+    Legacy,
+    /// The device didn't accept the features
+    FeaturesRejected,
+    /// The device doesn't have the queue, or it has already been set up
+    QueueUnavailable,
+    /// There aren't enough free descriptors for the chain
+    QueueFull,
+    /// Neither MSI-X nor a pin interrupt could be set up
+    NoInterrupt,
+    /// The device's notification address for the queue is outside the notification area
+    BadNotifyOffset,
+    /// The device didn't finish resetting in time
+    ResetTimedOut,
+}
+
+/// The virtio device type of the function, if it is one
+pub fn device_type(device: &PciDevice) -> Option<u16> {
+    if device.vendor_id != VENDOR_ID {
+        return None;
+    }
+
+    if TRANSITIONAL_DEVICE_IDS.contains(&device.device_id) {
+        Some(device.subsystem_id)
+    } else if MODERN_DEVICE_IDS.contains(&device.device_id) {
+        Some(device.device_id - MODERN_DEVICE_ID_BASE)
+    } else {
+        None
+    }
+}
+
+/// Every virtio function of the type
+pub fn find(device_type: u16) -> impl Iterator<Item = &'static PciDevice> {
+    pci::devices()
+        .iter()
+        .filter(move |&device| self::device_type(device) == Some(device_type))
+}
+
+/// A register area described by a vendor specific capability
+#[derive(Clone, Copy)]
+struct Region {
+    address: VirtAddr,
+    length: u32,
+}
+
+/// What the interrupt handlers need, shared with the transport
+struct Shared {
+    queues: RwLock<BTreeMap<u16, Arc<Virtqueue>>>,
+    config_changed: AtomicBool,
+}
+
+impl Shared {
+    fn handle_queue(&self, index: u16) {
+        if let Some(queue) = self.queues.read().get(&index) {
+            queue.process_used();
+        }
+    }
+
+    fn handle_all_queues(&self) {
+        for queue in self.queues.read().values() {
+            queue.process_used();
+        }
+    }
+}
+
+enum Interrupts {
+    /// Message 0 is for configuration changes and message `i + 1` for queue `i`
+    MsiX(MessageInterrupts),
+    /// Shared by everything, the ISR status says what happened
+    Intx(IntxHandler),
+}
+
+/// A virtio device driven through the modern PCI transport. Dropping it resets the device, so that it stops using the
+/// queues before they are freed.
+pub struct VirtioPci {
+    device: &'static PciDevice,
+    device_type: u16,
+    common: VirtAddr,
+    notify: Region,
+    notify_multiplier: u32,
+    device_config: Option<Region>,
+    num_queues: u16,
+    shared: Arc<Shared>,
+    interrupts: Interrupts,
+}
+
+impl VirtioPci {
+    /// Finds the device's registers, resets it and acknowledges it, leaving it ready for feature negotiation. Queue
+    /// interrupts are handled with MSI-X when the device has a vector for every queue, and with its pin otherwise.
+    pub fn new(device: &'static PciDevice) -> Result<Self, VirtioError> {
+        let device_type = device_type(device).ok_or(VirtioError::NotVirtio)?;
+
+        let mut common = None;
+        let mut notify = None;
+        let mut isr = None;
+        let mut device_config = None;
+        for capability in device
+            .capabilities
+            .iter()
+            .filter(|capability| capability.id == Capability::VENDOR_SPECIFIC)
+        {
+            let cfg_type: u8 = device.read_config(capability.offset + CAPABILITY_CFG_TYPE_OFFSET);
+            // The specification says to use the first capability of each type the driver understands
+            let slot = match cfg_type {
+                CFG_TYPE_COMMON => &mut common,
+                CFG_TYPE_NOTIFY => &mut notify,
+                CFG_TYPE_ISR => &mut isr,
+                CFG_TYPE_DEVICE => &mut device_config,
+                _ => continue,
+            };
+            if slot.is_none() {
+                *slot = map_region(device, *capability).map(|region| (region, capability.offset));
+            }
+        }
+
+        let (common, _) = common.ok_or(VirtioError::MissingCapability)?;
+        let (notify, notify_capability) = notify.ok_or(VirtioError::MissingCapability)?;
+        let (isr, _) = isr.ok_or(VirtioError::MissingCapability)?;
+        let notify_multiplier =
+            device.read_config(notify_capability + CAPABILITY_NOTIFY_MULTIPLIER_OFFSET);
+
+        let common = common.address;
+        let num_queues: u16 = unsafe { read(common, COMMON_NUM_QUEUES) };
+
+        // The device has to be reset before anything else, which also turns off any interrupts it was left with
+        reset(common)?;
+        unsafe {
+            write(
+                common,
+                COMMON_DEVICE_STATUS,
+                STATUS_ACKNOWLEDGE | STATUS_DRIVER,
+            )
+        };
+
+        let shared = Arc::new(Shared {
+            queues: RwLock::new(BTreeMap::new()),
+            config_changed: AtomicBool::new(false),
+        });
+        // Failing from here on resets the device again, rather than leaving it acknowledged without a driver
+        let interrupts = match enable_msi_x(device, num_queues, shared.clone()) {
+            Ok(msi_x) => {
+                unsafe { write(common, COMMON_MSIX_CONFIG, 0u16) };
+                if unsafe { read::<u16>(common, COMMON_MSIX_CONFIG) } == NO_VECTOR {
+                    let _ = reset(common);
+                    return Err(VirtioError::NoInterrupt);
+                }
+                Interrupts::MsiX(msi_x)
+            }
+            Err(_) => {
+                let shared = shared.clone();
+                let isr = isr.address;
+                let intx = IntxHandler::register(device, move || {
+                    // Reading the ISR status acknowledges the interrupt
+                    let status = unsafe { isr.as_ptr::<u8>().read_volatile() };
+                    if status & ISR_CONFIG != 0 {
+                        shared.config_changed.store(true, Ordering::Relaxed);
+                    }
+                    if status & ISR_QUEUE != 0 {
+                        shared.handle_all_queues();
+                    }
+                })
+                .map_err(|_: IntxError| {
+                    let _ = reset(common);
+                    VirtioError::NoInterrupt
+                })?;
+                Interrupts::Intx(intx)
+            }
+        };
+
+        Ok(VirtioPci {
+            device,
+            device_type,
+            common,
+            notify,
+            notify_multiplier,
+            device_config: device_config.map(|(region, _)| region),
+            num_queues,
+            shared,
+            interrupts,
+        })
+    }
+
+    pub fn device(&self) -> &'static PciDevice {
+        self.device
+    }
+
+    pub fn device_type(&self) -> u16 {
+        self.device_type
+    }
+
+    pub fn num_queues(&self) -> u16 {
+        self.num_queues
+    }
+
+    /// The device's MSI-X messages, if its interrupts don't come through the pin
+    pub fn msi_x(&self) -> Option<&MessageInterrupts> {
+        match &self.interrupts {
+            Interrupts::MsiX(msi_x) => Some(msi_x),
+            Interrupts::Intx(_) => None,
+        }
+    }
+
+    /// The GSI of the device's pin, if its interrupts come through it
+    pub fn intx_gsi(&self) -> Option<u32> {
+        match &self.interrupts {
+            Interrupts::MsiX(_) => None,
+            Interrupts::Intx(intx) => Some(intx.gsi()),
+        }
+    }
+
+    pub fn status(&self) -> u8 {
+        unsafe { read(self.common, COMMON_DEVICE_STATUS) }
+    }
+
+    fn set_status(&self, bits: u8) {
+        let status = self.status();
+        unsafe { write(self.common, COMMON_DEVICE_STATUS, status | bits) };
+    }
+
+    /// Every feature the device offers
+    pub fn device_features(&self) -> u64 {
+        let mut features = 0;
+        for half in 0..2u32 {
+            unsafe {
+                write(self.common, COMMON_DEVICE_FEATURE_SELECT, half);
+                features |= (read::<u32>(self.common, COMMON_DEVICE_FEATURE) as u64) << (half * 32);
+            }
+        }
+        features
+    }
+
+    /// Accepts the features in `wanted` which the device offers, along with `FEATURE_VERSION_1`, and returns the ones
+    /// accepted. The device marks itself failed if it rejects them.
+    pub fn negotiate_features(&self, wanted: u64) -> Result<u64, VirtioError> {
+        let offered = self.device_features();
+        if offered & FEATURE_VERSION_1 == 0 {
+            self.set_status(STATUS_FAILED);
+            return Err(VirtioError::Legacy);
+        }
+
+        let accepted = offered & (wanted | FEATURE_VERSION_1);
+        for half in 0..2u32 {
+            unsafe {
+                write(self.common, COMMON_DRIVER_FEATURE_SELECT, half);
+                write(
+                    self.common,
+                    COMMON_DRIVER_FEATURE,
+                    (accepted >> (half * 32)) as u32,
+                );
+            }
+        }
+
+        self.set_status(STATUS_FEATURES_OK);
+        if self.status() & STATUS_FEATURES_OK == 0 {
+            self.set_status(STATUS_FAILED);
+            return Err(VirtioError::FeaturesRejected);
+        }
+
+        Ok(accepted)
+    }
+
+    /// Sets up queue `index` with as many entries as the device allows, up to 256. Has to be done after feature
+    /// negotiation and before `driver_ok`.
+    pub fn setup_queue(&self, index: u16) -> Result<Arc<Virtqueue>, VirtioError> {
+        if index >= self.num_queues || self.shared.queues.read().contains_key(&index) {
+            return Err(VirtioError::QueueUnavailable);
+        }
+
+        unsafe { write(self.common, COMMON_QUEUE_SELECT, index) };
+        let device_size: u16 = unsafe { read(self.common, COMMON_QUEUE_SIZE) };
+        if device_size == 0 {
+            return Err(VirtioError::QueueUnavailable);
+        }
+        // Split queues can be made smaller than the device's maximum, and this implementation needs a power of two
+        let size = 1 << device_size.min(MAX_QUEUE_SIZE).ilog2();
+
+        let notify_off: u16 = unsafe { read(self.common, COMMON_QUEUE_NOTIFY_OFF) };
+        let notify_offset = notify_off as u64 * self.notify_multiplier as u64;
+        if notify_offset + 2 > self.notify.length as u64 {
+            return Err(VirtioError::BadNotifyOffset);
+        }
+
+        let queue = Arc::new(Virtqueue::new(
+            index,
+            size,
+            self.notify.address + notify_offset,
+        ));
+        let (descriptors, available, used) = queue.addresses();
+        unsafe {
+            write(self.common, COMMON_QUEUE_SIZE, size);
+            write_u64(self.common, COMMON_QUEUE_DESC, descriptors.as_u64());
+            write_u64(self.common, COMMON_QUEUE_DRIVER, available.as_u64());
+            write_u64(self.common, COMMON_QUEUE_DEVICE, used.as_u64());
+        }
+
+        if self.msi_x().is_some() {
+            unsafe { write(self.common, COMMON_QUEUE_MSIX_VECTOR, index + 1) };
+            if unsafe { read::<u16>(self.common, COMMON_QUEUE_MSIX_VECTOR) } == NO_VECTOR {
+                return Err(VirtioError::NoInterrupt);
+            }
+        }
+
+        without_interrupts(|| self.shared.queues.write().insert(index, queue.clone()));
+        unsafe { write(self.common, COMMON_QUEUE_ENABLE, 1u16) };
+
+        Ok(queue)
+    }
+
+    /// Tells the device the driver is set up, after which it can use the queues
+    pub fn driver_ok(&self) {
+        self.set_status(STATUS_DRIVER_OK);
+    }
+
+    /// Whether the device configuration has changed since the last call
+    pub fn take_config_changed(&self) -> bool {
+        self.shared.config_changed.swap(false, Ordering::Relaxed)
+    }
+
+    /// Reads a field of the device specific configuration. The device can change the configuration at any time, so
+    /// the read is repeated until the generation counter shows it saw a consistent value.
+    pub fn read_device_config<T: Copy>(&self, offset: u64) -> Option<T> {
+        let region = self.device_config?;
+        if offset + core::mem::size_of::<T>() as u64 > region.length as u64 {
+            return None;
+        }
+
+        loop {
+            let generation: u8 = unsafe { read(self.common, COMMON_CONFIG_GENERATION) };
+            let value = unsafe { read(region.address, offset) };
+            if unsafe { read::<u8>(self.common, COMMON_CONFIG_GENERATION) } == generation {
+                return Some(value);
+            }
+        }
+    }
+
+    /// Writes a field of the device specific configuration, returning false if it isn't there
+    pub fn write_device_config<T: Copy>(&self, offset: u64, value: T) -> bool {
+        match self.device_config {
+            Some(region) if offset + core::mem::size_of::<T>() as u64 <= region.length as u64 => {
+                unsafe { write(region.address, offset, value) };
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Drop for VirtioPci {
+    fn drop(&mut self) {
+        if reset(self.common).is_err() {
+            log::warn!(
+                "virtio device at {} didn't reset, it may still be using its queues",
+                self.device.address
+            );
+        }
+    }
+}
+
+/// Writes 0 to the device status, then waits for the device to read it back as 0 once it has finished resetting
+fn reset(common: VirtAddr) -> Result<(), VirtioError> {
+    unsafe { write::<u8>(common, COMMON_DEVICE_STATUS, 0) };
+    for _ in 0..=RESET_TIMEOUT_MS {
+        if unsafe { read::<u8>(common, COMMON_DEVICE_STATUS) } == 0 {
+            return Ok(());
+        }
+        pit::busy_wait_us(1000);
+    }
+
+    Err(VirtioError::ResetTimedOut)
+}
+
+/// Maps the part of a BAR a vendor specific capability points to, if it is in a memory BAR
+fn map_region(device: &PciDevice, capability: Capability) -> Option<Region> {
+    let bar: u8 = device.read_config(capability.offset + CAPABILITY_BAR_OFFSET);
+    let offset: u32 = device.read_config(capability.offset + CAPABILITY_OFFSET_OFFSET);
+    let length: u32 = device.read_config(capability.offset + CAPABILITY_LENGTH_OFFSET);
+
+    let Some(Bar::Memory { size, .. }) = device.bars.get(bar as usize).copied().flatten() else {
+        return None;
+    };
+    if offset as u64 + length as u64 > size {
+        return None;
+    }
+
+    Some(Region {
+        address: device.map_bar(bar as usize)? + offset as u64,
+        length,
+    })
+}
+
+/// Gives the device a message for configuration changes and one for each queue, if it has enough of them
+fn enable_msi_x(
+    device: &'static PciDevice,
+    num_queues: u16,
+    shared: Arc<Shared>,
+) -> Result<MessageInterrupts, MsiError> {
+    let count = num_queues as usize + 1;
+    match MessageInterrupts::available(device) {
+        Some((MsiKind::MsiX, available)) if available >= count => {}
+        Some((MsiKind::MsiX, _)) => return Err(MsiError::TooManyVectors),
+        _ => return Err(MsiError::Unsupported),
+    }
+
+    MessageInterrupts::enable(device, count, interrupts::boot_lapic_id(), move |message| {
+        match message {
+            0 => shared.config_changed.store(true, Ordering::Relaxed),
+            queue => shared.handle_queue(queue as u16 - 1),
+        }
+    })
+}
+
+unsafe fn read<T: Copy>(base: VirtAddr, offset: u64) -> T {
+    (base + offset).as_ptr::<T>().read_volatile()
+}
+
+unsafe fn write<T: Copy>(base: VirtAddr, offset: u64, value: T) {
+    (base + offset).as_mut_ptr::<T>().write_volatile(value)
+}
+
+/// 64 bit fields are written as two halves, since devices don't have to take wider accesses
+unsafe fn write_u64(base: VirtAddr, offset: u64, value: u64) {
+    write(base, offset, value as u32);
+    write(base, offset + 4, (value >> 32) as u32);
+}
@@ -0,0 +1,435 @@
+//! Split virtqueues. The driver puts chains of descriptors on the available ring, and the device returns the head of each
+//! chain on the used ring once it is done with it, along with how many bytes it wrote.
+
+use crate::dma::DmaBuffer;
+use crate::io::virtio::VirtioError;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::mem::{self, size_of};
+use core::sync::atomic::{fence, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::{self, without_interrupts};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// The chain continues in the descriptor in `next`
+const DESCRIPTOR_NEXT: u16 = 1 << 0;
+/// The device writes to the buffer instead of reading it
+const DESCRIPTOR_WRITE: u16 = 1 << 1;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Descriptor {
+    address: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+/// The rings start with a flags and an index field before their entries
+const RING_HEADER_SIZE: usize = 4;
+const USED_ELEMENT_SIZE: usize = 8;
+
+/// Part of a buffer handed to the device
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Segment {
+    pub phys_addr: PhysAddr,
+    pub len: u32,
+    pub device_writable: bool,
+}
+
+impl Segment {
+    /// A segment the device reads
+    pub fn readable(buffer: &DmaBuffer) -> Self {
+        Segment {
+            phys_addr: buffer.phys_addr(),
+            len: buffer.len() as u32,
+            device_writable: false,
+        }
+    }
+
+    /// A segment the device writes
+    pub fn writable(buffer: &DmaBuffer) -> Self {
+        Segment {
+            phys_addr: buffer.phys_addr(),
+            len: buffer.len() as u32,
+            device_writable: true,
+        }
+    }
+}
+
+/// Identifies a submitted chain until it is taken back with `Virtqueue::take_completed`. The chain's head descriptor is
+/// reused once the chain has been taken back, so the token also has the number of chains submitted before it, which
+/// keeps it from matching a later chain with the same head.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Token {
+    generation: u64,
+    head: u16,
+}
+
+pub struct Virtqueue {
+    index: u16,
+    size: u16,
+    /// Where writing the queue's index tells the device there is something new on it
+    notify_address: VirtAddr,
+    /// Locked with interrupts disabled everywhere but `process_used`, which the interrupt handlers call. `process_used`
+    /// doesn't allocate or free anything, so that the interrupt path doesn't wait on the heap's lock.
+    state: Mutex<QueueState>,
+}
+
+/// A chain which hasn't been taken back yet
+#[derive(Clone, Copy)]
+struct Chain {
+    token: Token,
+    /// How many bytes the device wrote to the chain, once it has finished with it
+    written: Option<u32>,
+}
+
+struct QueueState {
+    /// The descriptor table, then the available ring, then the used ring
+    memory: DmaBuffer,
+    /// The first free descriptor, the rest are linked through `next`
+    free_head: u16,
+    free_count: u16,
+    /// Our copy of the available ring's index
+    available_index: u16,
+    /// How far through the used ring we have got
+    last_used_index: u16,
+    /// How many chains have been submitted
+    generation: u64,
+    /// The chain starting at each descriptor, if it is the head of one. Its descriptors are only freed when it is
+    /// taken back, so a head can't be reused while the device might still return it.
+    chains: Vec<Option<Chain>>,
+    /// Used ring entries which weren't the head of a chain the device had yet to finish, since they were last logged
+    bad_used_entries: u16,
+}
+
+impl Virtqueue {
+    /// `size` must be a power of two
+    pub(crate) fn new(index: u16, size: u16, notify_address: VirtAddr) -> Self {
+        assert!(
+            size.is_power_of_two(),
+            "virtqueue size {} is not a power of two",
+            size
+        );
+
+        let memory = DmaBuffer::new(Self::used_offset(size) + Self::used_size(size));
+        let mut state = QueueState {
+            memory,
+            free_head: 0,
+            free_count: size,
+            available_index: 0,
+            last_used_index: 0,
+            generation: 0,
+            chains: vec![None; size as usize],
+            bad_used_entries: 0,
+        };
+
+        for descriptor in 0..size {
+            state.write_descriptor(
+                descriptor,
+                Descriptor {
+                    address: 0,
+                    len: 0,
+                    flags: 0,
+                    next: (descriptor + 1) % size,
+                },
+            );
+        }
+
+        Virtqueue {
+            index,
+            size,
+            notify_address,
+            state: Mutex::new(state),
+        }
+    }
+
+    /// Locks the state with interrupts disabled, so that an interrupt handler can't spin on it forever. Also logs the
+    /// bad used ring entries `process_used` found, which it can't do itself from the interrupt handler.
+    fn with_state<T>(&self, f: impl FnOnce(&mut QueueState) -> T) -> T {
+        let (result, bad_used_entries) = without_interrupts(|| {
+            let mut state = self.state.lock();
+            let result = f(&mut state);
+            (result, mem::take(&mut state.bad_used_entries))
+        });
+
+        if bad_used_entries != 0 {
+            log::warn!(
+                "virtqueue {} ignored {} used entries which weren't the head of an unfinished chain",
+                self.index,
+                bad_used_entries
+            );
+        }
+
+        result
+    }
+
+    fn available_offset(size: u16) -> usize {
+        size as usize * size_of::<Descriptor>()
+    }
+
+    /// The used ring has to be 4 byte aligned
+    fn used_offset(size: u16) -> usize {
+        (Self::available_offset(size) + RING_HEADER_SIZE + size as usize * 2 + 2)
+            .next_multiple_of(4)
+    }
+
+    fn used_size(size: u16) -> usize {
+        RING_HEADER_SIZE + size as usize * USED_ELEMENT_SIZE + 2
+    }
+
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// Where the device finds the descriptor table, available ring and used ring
+    pub(crate) fn addresses(&self) -> (PhysAddr, PhysAddr, PhysAddr) {
+        let base = self.with_state(|state| state.memory.phys_addr());
+
+        (
+            base,
+            base + Self::available_offset(self.size) as u64,
+            base + Self::used_offset(self.size) as u64,
+        )
+    }
+
+    /// How many more descriptors can be submitted
+    pub fn free_descriptors(&self) -> u16 {
+        self.with_state(|state| state.free_count)
+    }
+
+    /// Puts `segments` on the available ring as one chain, without telling the device. The buffers must stay alive
+    /// until the chain has been taken back.
+    pub fn submit(&self, segments: &[Segment]) -> Result<Token, VirtioError> {
+        assert!(!segments.is_empty(), "a descriptor chain can't be empty");
+
+        self.with_state(|state| self.submit_locked(state, segments))
+    }
+
+    fn submit_locked(
+        &self,
+        state: &mut QueueState,
+        segments: &[Segment],
+    ) -> Result<Token, VirtioError> {
+        if segments.len() > state.free_count as usize {
+            return Err(VirtioError::QueueFull);
+        }
+
+        let head = state.free_head;
+        let mut descriptor = head;
+        for (index, segment) in segments.iter().enumerate() {
+            let next = state.read_descriptor(descriptor).next;
+            let last = index == segments.len() - 1;
+
+            let mut flags = 0;
+            if segment.device_writable {
+                flags |= DESCRIPTOR_WRITE;
+            }
+            if !last {
+                flags |= DESCRIPTOR_NEXT;
+            }
+
+            state.write_descriptor(
+                descriptor,
+                Descriptor {
+                    address: segment.phys_addr.as_u64(),
+                    len: segment.len,
+                    flags,
+                    next,
+                },
+            );
+
+            if last {
+                state.free_head = next;
+            }
+            descriptor = next;
+        }
+        state.free_count -= segments.len() as u16;
+
+        let slot = state.available_index % self.size;
+        state.write_u16(
+            Self::available_offset(self.size) + RING_HEADER_SIZE + slot as usize * 2,
+            head,
+        );
+
+        // The device mustn't see the new index before the entry and descriptors it covers
+        fence(Ordering::SeqCst);
+        state.available_index = state.available_index.wrapping_add(1);
+        let available_index = state.available_index;
+        state.write_u16(Self::available_offset(self.size) + 2, available_index);
+
+        let token = Token {
+            generation: state.generation,
+            head,
+        };
+        state.generation += 1;
+        state.chains[head as usize] = Some(Chain {
+            token,
+            written: None,
+        });
+
+        Ok(token)
+    }
+
+    /// Tells the device there are new chains on the available ring
+    pub fn notify(&self) {
+        fence(Ordering::SeqCst);
+        unsafe {
+            self.notify_address
+                .as_mut_ptr::<u16>()
+                .write_volatile(self.index)
+        };
+    }
+
+    /// Marks every chain the device has put on the used ring since the last call as completed. Called by the transport
+    /// when the queue's interrupt arrives, and returns how many chains it found.
+    pub fn process_used(&self) -> usize {
+        let mut state = self.state.lock();
+        let used_offset = Self::used_offset(self.size);
+        let mut found = 0;
+
+        loop {
+            let used_index = state.read_u16(used_offset + 2);
+            if used_index == state.last_used_index {
+                break;
+            }
+            // The entries are only valid once the index covering them has been seen
+            fence(Ordering::SeqCst);
+
+            let slot = state.last_used_index % self.size;
+            let element = used_offset + RING_HEADER_SIZE + slot as usize * USED_ELEMENT_SIZE;
+            let head = state.read_u32(element);
+            let written = state.read_u32(element + 4);
+
+            match state.chains.get_mut(head as usize) {
+                Some(Some(chain)) if chain.written.is_none() => chain.written = Some(written),
+                _ => state.bad_used_entries = state.bad_used_entries.saturating_add(1),
+            }
+            state.last_used_index = state.last_used_index.wrapping_add(1);
+            found += 1;
+        }
+
+        found
+    }
+
+    /// How many bytes the device wrote to the chain, if it has finished with it. The chain's descriptors are freed.
+    pub fn take_completed(&self, token: Token) -> Option<u32> {
+        self.with_state(|state| {
+            let head = token.head as usize;
+            let written = state.chains[head]
+                .filter(|chain| chain.token == token)?
+                .written?;
+
+            state.chains[head] = None;
+            if !state.free_chain(token.head, self.size) {
+                log::warn!(
+                    "virtqueue {} chain {} is broken, its descriptors are lost",
+                    self.index,
+                    token.head
+                );
+            }
+
+            Some(written)
+        })
+    }
+
+    /// Waits for the device to finish with the chain, relying on the transport's interrupt handler to find it. Returns
+    /// with interrupts enabled.
+    pub fn wait(&self, token: Token) -> u32 {
+        loop {
+            // An interrupt between the check and the `hlt` would otherwise leave us halted until the next one
+            interrupts::disable();
+            if let Some(written) = self.take_completed(token) {
+                interrupts::enable();
+                return written;
+            }
+
+            interrupts::enable_and_hlt();
+        }
+    }
+
+    /// The tokens of every completed chain, oldest first
+    pub fn completed(&self) -> Vec<Token> {
+        let mut completed: Vec<_> = self.with_state(|state| {
+            state
+                .chains
+                .iter()
+                .flatten()
+                .filter(|chain| chain.written.is_some())
+                .map(|chain| chain.token)
+                .collect()
+        });
+        completed.sort();
+        completed
+    }
+}
+
+impl QueueState {
+    fn address(&self, offset: usize) -> VirtAddr {
+        self.memory.virt_addr() + offset as u64
+    }
+
+    fn read_u16(&self, offset: usize) -> u16 {
+        unsafe { self.address(offset).as_ptr::<u16>().read_volatile() }
+    }
+
+    fn write_u16(&mut self, offset: usize, value: u16) {
+        unsafe {
+            self.address(offset)
+                .as_mut_ptr::<u16>()
+                .write_volatile(value)
+        }
+    }
+
+    fn read_u32(&self, offset: usize) -> u32 {
+        unsafe { self.address(offset).as_ptr::<u32>().read_volatile() }
+    }
+
+    fn read_descriptor(&self, index: u16) -> Descriptor {
+        let offset = index as usize * size_of::<Descriptor>();
+        unsafe { self.address(offset).as_ptr::<Descriptor>().read_volatile() }
+    }
+
+    fn write_descriptor(&mut self, index: u16, descriptor: Descriptor) {
+        let offset = index as usize * size_of::<Descriptor>();
+        unsafe {
+            self.address(offset)
+                .as_mut_ptr::<Descriptor>()
+                .write_volatile(descriptor)
+        }
+    }
+
+    /// Puts the descriptors of the chain starting at `head` back on the free list. Returns false without freeing
+    /// anything if the chain leaves the table or doesn't end within `size` descriptors.
+    fn free_chain(&mut self, head: u16, size: u16) -> bool {
+        if head >= size {
+            return false;
+        }
+
+        let mut last = head;
+        let mut length = 1;
+        loop {
+            let descriptor = self.read_descriptor(last);
+            if descriptor.flags & DESCRIPTOR_NEXT == 0 {
+                break;
+            }
+            if descriptor.next >= size || length == size {
+                return false;
+            }
+            last = descriptor.next;
+            length += 1;
+        }
+
+        let mut descriptor = self.read_descriptor(last);
+        descriptor.next = self.free_head;
+        self.write_descriptor(last, descriptor);
+
+        self.free_head = head;
+        self.free_count += length;
+        true
+    }
+}
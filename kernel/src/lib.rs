@@ -1,17 +1,43 @@
 #![no_std]
+#![cfg_attr(test, no_main)]
 #![feature(asm_const)]
 #![feature(abi_x86_interrupt)]
 #![feature(allocator_api)]
+#![feature(custom_test_frameworks)]
+#![test_runner(crate::testing::test_runner)]
+#![reexport_test_harness_main = "test_main"]
 
 extern crate alloc;
 
+pub mod backtrace;
+pub mod block;
+pub mod boot_config;
 pub mod debug_log;
+pub mod fs;
+pub mod gdb;
+pub mod initrd;
 pub mod io;
+pub mod logger;
 mod memory;
+pub mod panic;
+pub mod testing;
 
+pub use memory::dma;
+
+use boot_config::BootConfig;
+use bootloader_api::config::Mapping;
+use bootloader_api::BootloaderConfig;
 use io::drivers::display::gop_buffer::Writer;
+use io::drivers::serial::{COM1, COM2, COM2_BASE};
 use io::framebuffer;
 
+/// Shared by the kernel binary and every test kernel
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::FixedAddress(0x20000000000));
+    config
+};
+
 pub unsafe fn init(boot_info: &'static mut bootloader_api::BootInfo) {
     init_inner(boot_info)
 }
@@ -19,17 +45,60 @@ pub unsafe fn init(boot_info: &'static mut bootloader_api::BootInfo) {
 fn init_inner(boot_info: &'static mut bootloader_api::BootInfo) {
     x86_64::instructions::interrupts::disable();
 
-    framebuffer::init(
-        boot_info
-            .framebuffer
-            .as_mut()
-            .expect("Expected framebuffer"),
-    );
+    // The command line lives in the initrd, so the initrd has to be found before anything configurable starts
+    let cmdline = match boot_info.ramdisk_addr.into_option() {
+        Some(ramdisk_addr) => {
+            let ramdisk = unsafe {
+                core::slice::from_raw_parts(
+                    ramdisk_addr as *const u8,
+                    boot_info.ramdisk_len as usize,
+                )
+            };
+            let initrd = initrd::init(ramdisk);
+
+            if let Some(symbol_table) = initrd.file("kernel.sym") {
+                backtrace::init(symbol_table);
+            }
+
+            initrd
+                .file("cmdline")
+                .and_then(|cmdline| core::str::from_utf8(cmdline).ok())
+                .unwrap_or("")
+        }
+        None => "",
+    };
+    let config = boot_config::init(cmdline);
+
+    // Booting via BIOS only gives us a framebuffer if a VESA mode could be set, output still goes to the debug port
+    // and serial without it
+    if let Some(boot_framebuffer) = boot_info.framebuffer.as_mut() {
+        framebuffer::init(boot_framebuffer);
+        unsafe { Writer::init() };
+    }
+
+    let com2_present = config.serial
+        && unsafe {
+            COM1.lock().init(115200);
+            COM2.lock().init(115200)
+        };
+    logger::init(config.log);
+
+    for option in BootConfig::invalid_options(cmdline) {
+        log::warn!("Ignoring invalid command line option `{}`", option);
+    }
 
-    unsafe { Writer::init() };
+    match initrd::get() {
+        Some(initrd) => log::debug!("Loaded an initrd with {} files", initrd.files().count()),
+        None => log::warn!("The bootloader didn't load an initrd"),
+    }
 
     io::interrupts::init_idt();
 
+    // COM1 is the console, so GDB gets COM2
+    if config.gdb && com2_present {
+        gdb::init(COM2_BASE);
+    }
+
     let mut memory_allocator = unsafe {
         memory::init(
             boot_info
@@ -37,15 +106,41 @@ fn init_inner(boot_info: &'static mut bootloader_api::BootInfo) {
                 .into_option()
                 .expect("no physical memory offset"),
             &boot_info.memory_regions,
+            config,
         )
     };
 
+    fs::init(config);
+
     unsafe {
         io::init(
             &mut memory_allocator,
-            boot_info.rsdp_addr.into_option().expect("no rsdp") as usize,
+            boot_info
+                .rsdp_addr
+                .into_option()
+                .map(|rsdp_addr| rsdp_addr as usize),
+            config,
         );
     }
 
+    memory::keep_allocator(memory_allocator);
+
     x86_64::instructions::interrupts::enable();
 }
+
+#[cfg(test)]
+bootloader_api::entry_point!(test_kernel_main, config = &BOOTLOADER_CONFIG);
+
+#[cfg(test)]
+fn test_kernel_main(boot_info: &'static mut bootloader_api::BootInfo) -> ! {
+    unsafe { init(boot_info) };
+    test_main();
+
+    panic::halt()
+}
+
+#[cfg(test)]
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    testing::test_panic_handler(info)
+}
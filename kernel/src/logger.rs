@@ -0,0 +1,166 @@
+//! Backend for the `log` facade. Every record is prefixed with the number of LAPIC timer ticks since boot and the id
+//! of the CPU that logged it, then sent to each sink whose level filter allows it.
+
+use crate::debug_log;
+use crate::debug_log::DEBUG_PORT;
+use crate::io::drivers::display::gop_buffer::WRITER;
+use crate::io::drivers::serial::COM1;
+use crate::io::interrupts;
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+use core::fmt;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use log::{LevelFilter, Log, Metadata, Record};
+use x86_64::instructions::interrupts::without_interrupts;
+
+static LOGGER: Logger = Logger {
+    levels: [
+        AtomicUsize::new(LevelFilter::Off as usize),
+        AtomicUsize::new(LevelFilter::Off as usize),
+        AtomicUsize::new(LevelFilter::Off as usize),
+    ],
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Sink {
+    /// The Bochs/QEMU 0xE9 port
+    DebugPort = 0,
+    /// The GOP framebuffer console
+    Console = 1,
+    /// COM1
+    Serial = 2,
+}
+
+const SINKS: [Sink; 3] = [Sink::DebugPort, Sink::Console, Sink::Serial];
+
+/// Level filters for each sink
+#[derive(Clone, Copy, Debug)]
+pub struct LoggerConfig {
+    pub debug_port: LevelFilter,
+    pub console: LevelFilter,
+    pub serial: LevelFilter,
+}
+
+impl Default for LoggerConfig {
+    fn default() -> Self {
+        LoggerConfig {
+            debug_port: LevelFilter::Trace,
+            console: LevelFilter::Info,
+            serial: LevelFilter::Debug,
+        }
+    }
+}
+
+struct Logger {
+    levels: [AtomicUsize; 3],
+}
+
+impl Logger {
+    fn level(&self, sink: Sink) -> LevelFilter {
+        match self.levels[sink as usize].load(Ordering::Relaxed) {
+            0 => LevelFilter::Off,
+            1 => LevelFilter::Error,
+            2 => LevelFilter::Warn,
+            3 => LevelFilter::Info,
+            4 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        }
+    }
+
+    /// The sinks' locks are also taken by interrupt handlers that log, so interrupts are off while one is held
+    fn write_to(&self, sink: Sink, args: fmt::Arguments) {
+        without_interrupts(|| match sink {
+            Sink::DebugPort => {
+                DEBUG_PORT.lock().write_fmt(args).unwrap();
+            }
+            Sink::Console => {
+                if let Some(writer) = WRITER.lock().as_mut() {
+                    writer.write_fmt(args).unwrap();
+                }
+            }
+            Sink::Serial => {
+                COM1.lock().write_fmt(args).unwrap();
+            }
+        })
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        SINKS
+            .iter()
+            .any(|sink| metadata.level() <= self.level(*sink))
+    }
+
+    fn log(&self, record: &Record) {
+        let args = format_args!(
+            "[{:>10}] [cpu{}] {:<5} {}: {}\n",
+            interrupts::ticks(),
+            cpu_id(),
+            record.level(),
+            record.target(),
+            record.args()
+        );
+
+        debug_log::record(Some(record.level()), args);
+
+        for sink in SINKS {
+            if record.level() <= self.level(sink) {
+                self.write_to(sink, args);
+            }
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+struct SinkWriter(Sink);
+
+impl fmt::Write for SinkWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        LOGGER.write_to(self.0, format_args!("{}", s));
+        Ok(())
+    }
+}
+
+/// The x2APIC id of the current CPU from CPUID leaf 0BH, which is the full 32 bit id. CPUs without the leaf only have
+/// the 8 bit initial APIC id in CPUID.01H:EBX bits 24-31.
+fn cpu_id() -> u32 {
+    // The leaf is only there if the highest basic leaf reaches it and its first level isn't empty
+    if __cpuid(0x0).eax >= 0xb {
+        let topology = __cpuid_count(0xb, 0);
+        if topology.ebx != 0 {
+            return topology.edx;
+        }
+    }
+
+    __cpuid(0x1).ebx >> 24
+}
+
+/// Sets the level filter of a single sink. If the sink was off, everything it missed that is still in the log buffer
+/// is replayed to it.
+pub fn set_level(sink: Sink, level: LevelFilter) {
+    let previous_level = LOGGER.level(sink);
+    LOGGER.levels[sink as usize].store(level as usize, Ordering::Relaxed);
+
+    if previous_level == LevelFilter::Off && level != LevelFilter::Off {
+        let mut sink_writer = SinkWriter(sink);
+        debug_log::replay(&mut sink_writer, level, false);
+    }
+
+    let max_level = SINKS
+        .iter()
+        .map(|sink| LOGGER.level(*sink))
+        .max()
+        .unwrap_or(LevelFilter::Off);
+    log::set_max_level(max_level);
+}
+
+/// Can only be called once
+pub fn init(config: LoggerConfig) {
+    set_level(Sink::DebugPort, config.debug_port);
+    set_level(Sink::Console, config.console);
+    set_level(Sink::Serial, config.serial);
+
+    log::set_logger(&LOGGER).expect("logger::init should only be called once");
+}
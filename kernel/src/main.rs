@@ -1,28 +1,21 @@
 #![no_std]
 #![no_main]
 
-use bootloader_api::config::Mapping;
-use bootloader_api::BootloaderConfig;
 use core::panic::PanicInfo;
 use pc_keyboard::DecodedKey::Unicode;
 
 use kernel::io::keyboard::Keyboard;
-use kernel::{init, print, println};
+use kernel::io::serial::COM1;
+use kernel::{init, print};
+use x86_64::instructions::interrupts::without_interrupts;
 
 /// This function is called on panic.
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    println!("{}", info);
-    loop {}
+    kernel::panic::handle_panic(info)
 }
 
-pub static BOOTLOADER_CONFIG: BootloaderConfig = {
-    let mut config = BootloaderConfig::new_default();
-    config.mappings.physical_memory = Some(Mapping::FixedAddress(0x20000000000));
-    config
-};
-
-bootloader_api::entry_point!(kernel_early, config = &BOOTLOADER_CONFIG);
+bootloader_api::entry_point!(kernel_early, config = &kernel::BOOTLOADER_CONFIG);
 
 fn kernel_early(boot_info: &'static mut bootloader_api::BootInfo) -> ! {
     unsafe { init(boot_info) };
@@ -35,5 +28,13 @@ fn kernel_early(boot_info: &'static mut bootloader_api::BootInfo) -> ! {
         if let Some(Unicode(key)) = keyboard.poll_next() {
             print!("{}", key);
         }
+
+        // Terminals send a carriage return for enter. Interrupt handlers log through COM1, so they mustn't find it locked
+        let serial_input = without_interrupts(|| COM1.lock().read_byte());
+        match serial_input {
+            Some(b'\r') => print!("\n"),
+            Some(byte) => print!("{}", byte as char),
+            None => {}
+        }
     }
 }
@@ -0,0 +1,86 @@
+//! Memory for devices to read and write directly. Each buffer is physically contiguous, page aligned and zeroed, and
+//! the CPU reaches it through the physical memory mapping, which x86 keeps coherent with device accesses.
+
+use crate::memory::{allocate_contiguous_frames, phys_to_virt};
+use alloc::vec::Vec;
+use core::slice;
+use spin::Mutex;
+use x86_64::{PhysAddr, VirtAddr};
+
+const PAGE_SIZE: usize = 4096;
+
+/// The frames of dropped buffers, as their first frame and page count. The frame allocator can't take frames back, so
+/// new buffers reuse these when they are the same size.
+static FREE_RUNS: Mutex<Vec<(PhysAddr, usize)>> = Mutex::new(Vec::new());
+
+pub struct DmaBuffer {
+    phys_addr: PhysAddr,
+    pages: usize,
+    len: usize,
+}
+
+impl DmaBuffer {
+    /// Panics if there isn't a run of physical memory `len` bytes long left
+    pub fn new(len: usize) -> Self {
+        assert!(len > 0, "a DMA buffer can't be empty");
+        let pages = len.div_ceil(PAGE_SIZE);
+
+        let reused = {
+            let mut free_runs = FREE_RUNS.lock();
+            free_runs
+                .iter()
+                .position(|&(_, run_pages)| run_pages == pages)
+                .map(|index| free_runs.swap_remove(index).0)
+        };
+        let phys_addr = reused.unwrap_or_else(|| {
+            allocate_contiguous_frames(pages).expect("out of physical memory for DMA")
+        });
+
+        let buffer = DmaBuffer {
+            phys_addr,
+            pages,
+            len,
+        };
+        unsafe {
+            buffer
+                .virt_addr()
+                .as_mut_ptr::<u8>()
+                .write_bytes(0, pages * PAGE_SIZE)
+        };
+
+        buffer
+    }
+
+    /// What the device is given
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys_addr
+    }
+
+    pub fn virt_addr(&self) -> VirtAddr {
+        phys_to_virt(self.phys_addr)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The contents as the CPU sees them, which a device may be changing if it has been given the buffer
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.virt_addr().as_ptr(), self.len) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.virt_addr().as_mut_ptr(), self.len) }
+    }
+}
+
+/// The device must be done with the buffer by now, its frames go to the next buffer of the same size
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        FREE_RUNS.lock().push((self.phys_addr, self.pages));
+    }
+}
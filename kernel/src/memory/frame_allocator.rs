@@ -33,6 +33,30 @@ impl BootInfoFrameAllocator {
             memory_regions,
         }
     }
+
+    /// Allocates `count` physically consecutive frames, returning the first. Frames passed over to find them are never
+    /// handed out.
+    pub fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrame<Size4KiB>> {
+        let mut first = None;
+        let mut length = 0;
+
+        for (index, frame) in self.available_frames().enumerate().skip(self.next) {
+            match first {
+                Some(first) if first + length == frame => length += 1,
+                _ => {
+                    first = Some(frame);
+                    length = 1;
+                }
+            }
+
+            if length == count as u64 {
+                self.next = index + 1;
+                return first;
+            }
+        }
+
+        None
+    }
 }
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
@@ -1,6 +1,8 @@
 use crate::memory::frame_allocator::BootInfoFrameAllocator;
 use crate::memory::virtual_addresses::HEAP_START;
+use core::alloc::{GlobalAlloc, Layout};
 use linked_list_allocator::LockedHeap;
+use x86_64::instructions::interrupts::without_interrupts;
 use x86_64::structures::paging::mapper::MapToError;
 use x86_64::structures::paging::{
     FrameAllocator, Mapper, OffsetPageTable, Page, PageTableFlags, Size4KiB,
@@ -8,16 +10,30 @@ use x86_64::structures::paging::{
 use x86_64::VirtAddr;
 
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: InterruptSafeHeap = InterruptSafeHeap(LockedHeap::empty());
 
-pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
+/// Takes the heap's lock with interrupts disabled. Interrupt handlers free memory (eg. a handler which unregisters
+/// itself drops the last reference to its closure), which would spin forever on a lock held by the code they
+/// interrupted.
+struct InterruptSafeHeap(LockedHeap);
+
+unsafe impl GlobalAlloc for InterruptSafeHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        without_interrupts(|| self.0.alloc(layout))
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        without_interrupts(|| self.0.dealloc(ptr, layout))
+    }
+}
 
 pub fn init_heap(
     frame_allocator: &mut BootInfoFrameAllocator,
     mapper: &mut OffsetPageTable<'static>,
+    heap_size: usize,
 ) -> Result<(), MapToError<Size4KiB>> {
     let heap_start = VirtAddr::new(HEAP_START as u64);
-    let heap_end = heap_start + HEAP_SIZE - 1u64;
+    let heap_end = heap_start + heap_size - 1u64;
     let page_range = Page::range_inclusive(
         Page::containing_address(heap_start),
         Page::containing_address(heap_end),
@@ -34,7 +50,7 @@ pub fn init_heap(
     }
 
     unsafe {
-        ALLOCATOR.lock().init(heap_start.as_mut_ptr(), HEAP_SIZE);
+        ALLOCATOR.0.lock().init(heap_start.as_mut_ptr(), heap_size);
     }
 
     Ok(())
@@ -1,6 +1,6 @@
 use x86_64::registers::control::Cr3;
-use x86_64::structures::paging::{OffsetPageTable, PageTable};
-use x86_64::VirtAddr;
+use x86_64::structures::paging::{OffsetPageTable, PageTable, PageTableFlags};
+use x86_64::{PhysAddr, VirtAddr};
 
 pub unsafe fn new(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
     let level_4_table = active_level_4_table(physical_memory_offset);
@@ -15,3 +15,37 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
 
     &mut *pointer
 }
+
+/// Walks the active page tables without creating a mapper, so that it can be used while the `MemoryAllocator` is
+/// borrowed (eg. from an exception handler)
+pub fn translate(physical_memory_offset: VirtAddr, addr: VirtAddr) -> Option<PhysAddr> {
+    let (level_4_table, _) = Cr3::read();
+    let mut table_address = level_4_table.start_address();
+
+    let indices = [
+        addr.p4_index(),
+        addr.p3_index(),
+        addr.p2_index(),
+        addr.p1_index(),
+    ];
+
+    for (level, index) in indices.into_iter().enumerate() {
+        let table: &PageTable =
+            unsafe { &*(physical_memory_offset + table_address.as_u64()).as_ptr() };
+        let entry = &table[index];
+
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+
+        // Level 3 and 2 entries can map 1GiB and 2MiB pages directly
+        if (level == 1 || level == 2) && entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            let page_size: u64 = if level == 1 { 1 << 30 } else { 1 << 21 };
+            return Some(entry.addr() + (addr.as_u64() & (page_size - 1)));
+        }
+
+        table_address = entry.addr();
+    }
+
+    Some(table_address + u64::from(addr.page_offset()))
+}
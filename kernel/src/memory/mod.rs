@@ -1,17 +1,33 @@
+use crate::boot_config::BootConfig;
 use crate::memory::frame_allocator::BootInfoFrameAllocator;
 use crate::memory::heap_allocator::init_heap;
+use crate::memory::virtual_addresses::{HEAP_START, LAPIC_START, MMIO_END, MMIO_START};
 use bootloader_api::info::MemoryRegions;
+use conquer_once::spin::OnceCell;
+use spin::Mutex;
 use x86_64::structures::paging::{
     Mapper, OffsetPageTable, Page, PageTableFlags, PhysFrame, Size4KiB,
 };
 use x86_64::{PhysAddr, VirtAddr};
 
+pub mod dma;
 pub(crate) mod frame_allocator;
 pub(crate) mod gdt;
 mod heap_allocator;
 pub(crate) mod mapper;
 pub(crate) mod virtual_addresses;
 
+static PHYSICAL_MEMORY_OFFSET: OnceCell<VirtAddr> = OnceCell::uninit();
+
+/// Kept once the kernel has finished initialising, for the drivers that map memory after that
+static KEPT_ALLOCATOR: OnceCell<Mutex<KeptAllocator>> = OnceCell::uninit();
+
+struct KeptAllocator {
+    memory_allocator: MemoryAllocator,
+    /// Where the next MMIO mapping goes
+    next_mmio: usize,
+}
+
 pub struct MemoryAllocator(pub BootInfoFrameAllocator, pub OffsetPageTable<'static>);
 
 impl MemoryAllocator {
@@ -20,7 +36,12 @@ impl MemoryAllocator {
     }
 
     /// phys_address and virt_address should be aligned to 4KiB boundary
-    pub unsafe fn map_page_containing_address(&mut self, phys_address: PhysAddr, virt_addr: VirtAddr, flags: PageTableFlags) {
+    pub unsafe fn map_page_containing_address(
+        &mut self,
+        phys_address: PhysAddr,
+        virt_addr: VirtAddr,
+        flags: PageTableFlags,
+    ) {
         let (frame_allocator, mapper) = (&mut self.0, &mut self.1);
 
         let frame: PhysFrame<Size4KiB> = PhysFrame::containing_address(phys_address);
@@ -39,13 +60,112 @@ impl MemoryAllocator {
 pub unsafe fn init(
     physical_memory_offset: u64,
     memory_regions: &'static MemoryRegions,
+    config: &BootConfig,
 ) -> MemoryAllocator {
     gdt::init();
 
+    PHYSICAL_MEMORY_OFFSET.init_once(|| VirtAddr::new(physical_memory_offset));
+
     let mut frame_allocator = BootInfoFrameAllocator::new(memory_regions);
     let mut mapper = mapper::new(VirtAddr::new(physical_memory_offset));
 
-    init_heap(&mut frame_allocator, &mut mapper).expect("heap initialisation failed");
+    assert!(
+        config.heap_size <= LAPIC_START - HEAP_START,
+        "heap_size {:#x} is larger than the heap's address space",
+        config.heap_size
+    );
+    init_heap(&mut frame_allocator, &mut mapper, config.heap_size)
+        .expect("heap initialisation failed");
 
     MemoryAllocator(frame_allocator, mapper)
 }
+
+/// Hands the memory allocator over once `init` is done with it, so drivers can keep mapping memory
+pub(crate) fn keep_allocator(memory_allocator: MemoryAllocator) {
+    KEPT_ALLOCATOR.init_once(|| {
+        Mutex::new(KeptAllocator {
+            memory_allocator,
+            next_mmio: MMIO_START,
+        })
+    });
+}
+
+/// Maps `size` bytes of device memory starting at `phys_addr` uncached, and returns the address `phys_addr` ends up
+/// at. Mappings are never removed, so drivers should map their registers once.
+pub(crate) fn map_mmio(phys_addr: PhysAddr, size: u64) -> VirtAddr {
+    let mut kept = KEPT_ALLOCATOR
+        .get()
+        .expect("the memory allocator is still in use by init")
+        .lock();
+
+    let first_frame = phys_addr.align_down(0x1000u64);
+    let mapped_size = (phys_addr + size).align_up(0x1000u64) - first_frame;
+    let virt_addr = kept.next_mmio;
+    assert!(
+        (mapped_size as usize) <= MMIO_END - virt_addr,
+        "out of address space for MMIO"
+    );
+    kept.next_mmio += mapped_size as usize;
+
+    for offset in (0..mapped_size).step_by(0x1000) {
+        unsafe {
+            kept.memory_allocator.map_page_containing_address(
+                first_frame + offset,
+                VirtAddr::new(virt_addr as u64 + offset),
+                PageTableFlags::WRITABLE | PageTableFlags::PRESENT | PageTableFlags::NO_CACHE,
+            );
+        }
+    }
+
+    VirtAddr::new(virt_addr as u64) + (phys_addr - first_frame)
+}
+
+/// Allocates `count` physically consecutive frames after `init` is done, returning the address of the first
+pub(crate) fn allocate_contiguous_frames(count: usize) -> Option<PhysAddr> {
+    let mut kept = KEPT_ALLOCATOR
+        .get()
+        .expect("the memory allocator is still in use by init")
+        .lock();
+
+    kept.memory_allocator
+        .0
+        .allocate_contiguous(count)
+        .map(|frame| frame.start_address())
+}
+
+/// Where physical memory at `addr` can be reached
+pub(crate) fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    *PHYSICAL_MEMORY_OFFSET
+        .get()
+        .expect("memory not initialised")
+        + addr.as_u64()
+}
+
+/// The physical address `addr` is mapped to in the active page tables, or `None` if it isn't mapped (or memory has
+/// not been initialised yet)
+pub(crate) fn translate(addr: VirtAddr) -> Option<PhysAddr> {
+    mapper::translate(*PHYSICAL_MEMORY_OFFSET.get()?, addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn heap_is_mapped() {
+        assert!(translate(VirtAddr::new(HEAP_START as u64)).is_some());
+    }
+
+    #[test_case]
+    fn null_page_is_not_mapped() {
+        assert!(translate(VirtAddr::new(0)).is_none());
+    }
+
+    #[test_case]
+    fn physical_memory_is_mapped_at_offset() {
+        let offset = *PHYSICAL_MEMORY_OFFSET.get().unwrap();
+        let phys = PhysAddr::new(0x1000);
+
+        assert_eq!(translate(offset + phys.as_u64()), Some(phys));
+    }
+}
@@ -2,3 +2,15 @@ pub const HEAP_START: usize = 0x_4444_4444_0000;
 pub const LAPIC_START: usize = 0x_4444_5000_0000;
 
 pub const IOAPIC_START: usize = 0x_4444_6000_0000;
+/// Each IOAPIC gets its own page starting at IOAPIC_START
+pub const IOAPIC_STRIDE: usize = 0x1000;
+
+/// PCI buses reached through ECAM are mapped one after another from ECAM_START, each taking ECAM_BUS_SIZE. There is
+/// room for 256 of them before ECAM_END.
+pub const ECAM_START: usize = 0x_4444_7000_0000;
+pub const ECAM_END: usize = MMIO_START;
+pub const ECAM_BUS_SIZE: usize = 0x10_0000;
+
+/// Device memory mapped by drivers after boot, see `memory::map_mmio`
+pub const MMIO_START: usize = 0x_4444_8000_0000;
+pub const MMIO_END: usize = 0x_4445_0000_0000;
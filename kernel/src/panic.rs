@@ -0,0 +1,64 @@
+use crate::backtrace;
+use crate::debug_log::DebugPort;
+use crate::io::drivers::apic::lapic_broadcast_nmi;
+use crate::io::drivers::display::panic_screen::PanicScreen;
+use core::fmt;
+use core::fmt::Write;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::{hlt, interrupts};
+
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+pub fn is_panicking() -> bool {
+    PANICKING.load(Ordering::Relaxed)
+}
+
+/// Stops this CPU for good
+pub fn halt() -> ! {
+    interrupts::disable();
+
+    loop {
+        hlt();
+    }
+}
+
+/// Writes to the debug port and the panic screen without taking any locks which the panicking code could be holding
+struct PanicWriter {
+    debug_port: DebugPort,
+    panic_screen: Option<PanicScreen>,
+}
+
+impl fmt::Write for PanicWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.debug_port.write_str(s)?;
+
+        if let Some(panic_screen) = self.panic_screen.as_mut() {
+            panic_screen.write_str(s)?;
+        }
+
+        Ok(())
+    }
+}
+
+pub fn handle_panic(info: &PanicInfo) -> ! {
+    interrupts::disable();
+
+    // If we panic while reporting a panic the screen is probably what is broken
+    if PANICKING.swap(true, Ordering::Relaxed) {
+        let _ = writeln!(DebugPort::new(), "Panicked while panicking: {}", info);
+        halt();
+    }
+
+    unsafe { lapic_broadcast_nmi() };
+
+    let mut writer = PanicWriter {
+        debug_port: DebugPort::new(),
+        panic_screen: unsafe { PanicScreen::take() },
+    };
+
+    let _ = writeln!(writer, "KERNEL PANIC\n\n{}\n", info);
+    let _ = backtrace::write_backtrace(&mut writer);
+
+    halt()
+}
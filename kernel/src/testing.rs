@@ -0,0 +1,104 @@
+//! Support for running the kernel's tests under QEMU.
+//!
+//! `cargo ktest` builds every test target as its own kernel and hands it to the host runner, which boots it with an
+//! `isa-debug-exit` device. Each test reports itself over the debug port and the runner reads the result from the
+//! exit code written to `QEMU_EXIT_PORT`.
+
+use crate::backtrace;
+use crate::block::ramdisk::RamDisk;
+use crate::block::BlockDevice;
+use crate::debug_log::DebugPort;
+use crate::fs::{self, Filesystem};
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::type_name;
+use core::fmt::Write;
+use core::panic::PanicInfo;
+use x86_64::instructions::port::Port;
+
+/// Must match the `iobase` of the `isa-debug-exit` device added by the host runner
+const QEMU_EXIT_PORT: u16 = 0xf4;
+
+/// QEMU exits with `(code << 1) | 1`, so neither of these can be confused with QEMU failing by itself
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum QemuExitCode {
+    Success = 0x10,
+    Failed = 0x11,
+}
+
+pub fn exit_qemu(exit_code: QemuExitCode) -> ! {
+    unsafe { Port::new(QEMU_EXIT_PORT).write(exit_code as u32) };
+
+    // Only reached if there is no isa-debug-exit device
+    crate::panic::halt()
+}
+
+pub trait Testable {
+    fn run(&self);
+}
+
+impl<T: Fn()> Testable for T {
+    fn run(&self) {
+        crate::print!("{}... ", type_name::<T>());
+        self();
+        crate::println!("[ok]");
+    }
+}
+
+pub fn test_runner(tests: &[&dyn Testable]) {
+    crate::println!("running {} tests", tests.len());
+
+    for test in tests {
+        test.run();
+    }
+
+    exit_qemu(QemuExitCode::Success);
+}
+
+/// Panic handler for test kernels. Reports the failure and exits QEMU instead of showing the panic screen.
+pub fn test_panic_handler(info: &PanicInfo) -> ! {
+    x86_64::instructions::interrupts::disable();
+
+    // The panicking code may be holding the lock on DEBUG_PORT
+    let mut debug_port = DebugPort::new();
+    let _ = writeln!(debug_port, "[failed]\n\n{}\n", info);
+    let _ = backtrace::write_backtrace(&mut debug_port);
+
+    exit_qemu(QemuExitCode::Failed)
+}
+
+/// A RAM disk over `test/<name>.img` in the initrd, one of the images the host runner builds in
+/// `src/test_images.rs`. Writes stay on the disk so every call starts from the image as built.
+pub fn test_disk(name: &str, sector_count: u64) -> Arc<dyn BlockDevice> {
+    let image = crate::initrd::get()
+        .unwrap()
+        .file(&format!("test/{}.img", name))
+        .unwrap_or_else(|| panic!("the runner didn't pack test/{}.img", name));
+
+    Arc::new(RamDisk::from_image(image, sector_count))
+}
+
+/// Mounts `filesystem` on a new directory at `path`, runs `test` and removes both again
+pub fn with_mounted(path: &str, filesystem: Arc<dyn Filesystem>, test: impl FnOnce()) {
+    fs::create_dir(path).unwrap();
+    fs::mount(path, filesystem).unwrap();
+
+    test();
+
+    fs::unmount(path).unwrap();
+    fs::remove(path).unwrap();
+}
+
+/// The names in the directory at `path`, sorted as filesystems list them in their own order
+pub fn sorted_names(path: &str) -> Vec<String> {
+    let mut names: Vec<_> = fs::read_dir(path)
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect();
+    names.sort();
+    names
+}
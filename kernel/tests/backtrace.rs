@@ -0,0 +1,53 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::testing::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::string::String;
+use core::panic::PanicInfo;
+use kernel::backtrace::{self, StackWalker};
+
+bootloader_api::entry_point!(main, config = &kernel::BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut bootloader_api::BootInfo) -> ! {
+    unsafe { kernel::init(boot_info) };
+    test_main();
+
+    kernel::panic::halt()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::testing::test_panic_handler(info)
+}
+
+/// The backtrace starts at the caller of this, so the frame for its own caller is the first one
+#[inline(never)]
+fn capture() -> String {
+    let mut backtrace = String::new();
+    backtrace::write_backtrace(&mut backtrace).unwrap();
+    backtrace
+}
+
+#[test_case]
+fn backtrace_names_the_caller() {
+    let backtrace = capture();
+
+    let first_frame = backtrace.lines().nth(1).unwrap();
+    assert!(
+        first_frame.contains("backtrace::backtrace_names_the_caller+"),
+        "{}",
+        backtrace
+    );
+}
+
+#[test_case]
+fn unmapped_frame_pointers_end_the_walk() {
+    assert_eq!(StackWalker::new(0x1000).next(), None);
+    assert_eq!(StackWalker::new(0xdead_0000_0000).next(), None);
+    // Not canonical
+    assert_eq!(StackWalker::new(0x8000_0000_0000).next(), None);
+}
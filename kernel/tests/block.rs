@@ -0,0 +1,338 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::testing::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use kernel::block::cache::{BufferCache, CacheStats};
+use kernel::block::partition::{self, Guid, Partition, PartitionKind};
+use kernel::block::ramdisk::RamDisk;
+use kernel::block::{BlockDevice, BlockError, SECTOR_SIZE};
+use kernel::fs::fat::FatFs;
+use kernel::fs::{FileType, Filesystem};
+use kernel::testing::test_disk;
+
+/// The size of the MBR and GPT images in sectors (see `src/test_images.rs`)
+const PARTITIONED_SECTORS: u64 = 384;
+
+bootloader_api::entry_point!(main, config = &kernel::BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut bootloader_api::BootInfo) -> ! {
+    unsafe { kernel::init(boot_info) };
+    test_main();
+
+    kernel::panic::halt()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::testing::test_panic_handler(info)
+}
+
+/// The number, start, size and kind of each partition
+fn layout(partitions: &[Partition]) -> Vec<(usize, u64, u64, PartitionKind)> {
+    partitions
+        .iter()
+        .map(|partition| {
+            (
+                partition.number(),
+                partition.start(),
+                partition.sector_count(),
+                partition.kind().clone(),
+            )
+        })
+        .collect()
+}
+
+/// The test image writes `partition <number>` at the start of the partitions without a filesystem
+fn assert_marker(partition: &Partition) {
+    let mut sector = [0; SECTOR_SIZE];
+    partition.read_sectors(0, &mut sector).unwrap();
+
+    let marker = format!("partition {}", partition.number());
+    assert_eq!(&sector[..marker.len()], marker.as_bytes());
+}
+
+fn assert_holds_fat(partition: Partition) {
+    let fat = FatFs::new(Arc::new(partition)).unwrap();
+    let file = fat.root().lookup("HELLO.TXT").unwrap().open().unwrap();
+
+    let mut contents = [0; 16];
+    assert_eq!(file.read_at(0, &mut contents).unwrap(), 16);
+    assert_eq!(&contents, b"Hello from FAT!\n");
+}
+
+#[test_case]
+fn mbr_partitions() {
+    let mut partitions =
+        partition::read_partitions(&test_disk("mbr", PARTITIONED_SECTORS)).unwrap();
+
+    // The extended partition 2 holds 5 and 6, and isn't listed itself
+    assert_eq!(
+        layout(&partitions),
+        [
+            (1, 16, 128, PartitionKind::Mbr(0x01)),
+            (4, 320, 40, PartitionKind::Mbr(0x83)),
+            (5, 161, 39, PartitionKind::Mbr(0x83)),
+            (6, 201, 59, PartitionKind::Mbr(0x83)),
+        ]
+    );
+
+    for partition in &partitions[1..] {
+        assert_marker(partition);
+    }
+    assert_holds_fat(partitions.swap_remove(0));
+}
+
+#[test_case]
+fn gpt_partitions() {
+    let mut partitions =
+        partition::read_partitions(&test_disk("gpt", PARTITIONED_SECTORS)).unwrap();
+
+    assert_eq!(
+        layout(&partitions),
+        [
+            (
+                1,
+                34,
+                128,
+                PartitionKind::Gpt {
+                    type_guid: Guid::BASIC_DATA,
+                    name: String::from("bench fat"),
+                }
+            ),
+            (
+                2,
+                162,
+                64,
+                PartitionKind::Gpt {
+                    type_guid: Guid::LINUX_FILESYSTEM,
+                    name: String::from("bench data"),
+                }
+            ),
+        ]
+    );
+
+    assert_marker(&partitions[1]);
+    assert_holds_fat(partitions.swap_remove(0));
+}
+
+#[test_case]
+fn damaged_gpt_falls_back_to_the_backup() {
+    let garbage = [0xa5; SECTOR_SIZE];
+
+    // The primary header, then the first sector of its entries
+    for sector in [1, 2] {
+        let disk = test_disk("gpt", PARTITIONED_SECTORS);
+        disk.write_sectors(sector, &garbage).unwrap();
+        assert_eq!(partition::read_partitions(&disk).unwrap().len(), 2);
+    }
+
+    // With the backup header gone too there is nothing left to trust
+    let disk = test_disk("gpt", PARTITIONED_SECTORS);
+    disk.write_sectors(1, &garbage).unwrap();
+    disk.write_sectors(PARTITIONED_SECTORS - 1, &garbage)
+        .unwrap();
+    assert_eq!(
+        partition::read_partitions(&disk).err(),
+        Some(BlockError::InvalidPartitionTable)
+    );
+}
+
+#[test_case]
+fn disks_without_a_partition_table() {
+    assert!(
+        partition::read_partitions(&(Arc::new(RamDisk::new(64)) as Arc<dyn BlockDevice>))
+            .unwrap()
+            .is_empty()
+    );
+
+    // A FAT volume's boot sector has the MBR's signature but isn't one
+    assert!(partition::read_partitions(&test_disk("fat12", 1024))
+        .unwrap()
+        .is_empty());
+}
+
+#[test_case]
+fn partitions_stay_inside_their_sectors() {
+    let disk = test_disk("mbr", PARTITIONED_SECTORS);
+    let partitions = partition::read_partitions(&disk).unwrap();
+    let partition = &partitions[1];
+
+    let mut sector = [0; SECTOR_SIZE];
+    assert_eq!(
+        partition.read_sectors(40, &mut sector),
+        Err(BlockError::OutOfRange)
+    );
+
+    partition.write_sectors(39, &[0x5a; SECTOR_SIZE]).unwrap();
+    disk.read_sectors(320 + 39, &mut sector).unwrap();
+    assert_eq!(sector, [0x5a; SECTOR_SIZE]);
+}
+
+#[test_case]
+fn guids_are_shown_in_their_usual_form() {
+    assert_eq!(
+        format!("{}", Guid::LINUX_FILESYSTEM),
+        "0fc63daf-8483-4772-8e79-3d69d8477de4"
+    );
+}
+
+/// A RAM disk which counts what is done to it
+struct CountingDisk {
+    disk: RamDisk,
+    reads: AtomicUsize,
+    writes: AtomicUsize,
+    flushes: AtomicUsize,
+}
+
+impl CountingDisk {
+    fn new() -> Arc<Self> {
+        Arc::new(CountingDisk {
+            disk: RamDisk::new(64),
+            reads: AtomicUsize::new(0),
+            writes: AtomicUsize::new(0),
+            flushes: AtomicUsize::new(0),
+        })
+    }
+
+    fn sector(&self, sector: u64) -> [u8; SECTOR_SIZE] {
+        let mut data = [0; SECTOR_SIZE];
+        self.disk.read_sectors(sector, &mut data).unwrap();
+        data
+    }
+}
+
+impl BlockDevice for CountingDisk {
+    fn sector_count(&self) -> u64 {
+        self.disk.sector_count()
+    }
+
+    fn read_sectors(&self, start: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        self.disk.read_sectors(start, buf)
+    }
+
+    fn write_sectors(&self, start: u64, buf: &[u8]) -> Result<(), BlockError> {
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        self.disk.write_sectors(start, buf)
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.flushes.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+fn read_sector(cache: &BufferCache, sector: u64) -> [u8; SECTOR_SIZE] {
+    let mut data = [0; SECTOR_SIZE];
+    cache.read_sectors(sector, &mut data).unwrap();
+    data
+}
+
+#[test_case]
+fn cached_sectors_are_not_read_again() {
+    let disk = CountingDisk::new();
+    disk.disk.write_sectors(3, &[7; SECTOR_SIZE]).unwrap();
+    let cache = BufferCache::new(disk.clone(), 16);
+
+    assert_eq!(read_sector(&cache, 3), [7; SECTOR_SIZE]);
+    assert_eq!(read_sector(&cache, 3), [7; SECTOR_SIZE]);
+    assert_eq!(disk.reads.load(Ordering::Relaxed), 1);
+
+    // Only the sectors between the cached ones are read, in one go
+    let mut data = vec![0; 5 * SECTOR_SIZE];
+    cache.read_sectors(1, &mut data).unwrap();
+    assert_eq!(&data[2 * SECTOR_SIZE..3 * SECTOR_SIZE], &[7; SECTOR_SIZE]);
+    assert_eq!(disk.reads.load(Ordering::Relaxed), 3);
+
+    assert_eq!(
+        cache.stats(),
+        CacheStats {
+            hits: 2,
+            misses: 5,
+            write_backs: 0,
+        }
+    );
+}
+
+#[test_case]
+fn writes_wait_for_a_flush() {
+    let disk = CountingDisk::new();
+    let cache = BufferCache::new(disk.clone(), 16);
+
+    cache.write_sectors(2, &[1; 3 * SECTOR_SIZE]).unwrap();
+    cache.write_sectors(3, &[2; SECTOR_SIZE]).unwrap();
+    assert_eq!(read_sector(&cache, 3), [2; SECTOR_SIZE]);
+    assert_eq!(disk.sector(3), [0; SECTOR_SIZE]);
+    assert_eq!(cache.dirty_sectors(), 3);
+
+    // Consecutive dirty sectors go out in one write
+    cache.flush().unwrap();
+    assert_eq!(disk.writes.load(Ordering::Relaxed), 1);
+    assert_eq!(disk.flushes.load(Ordering::Relaxed), 1);
+    assert_eq!(disk.sector(2), [1; SECTOR_SIZE]);
+    assert_eq!(disk.sector(3), [2; SECTOR_SIZE]);
+    assert_eq!(cache.dirty_sectors(), 0);
+
+    // Dropping the cache flushes it too
+    cache.write_sectors(10, &[3; SECTOR_SIZE]).unwrap();
+    drop(cache);
+    assert_eq!(disk.sector(10), [3; SECTOR_SIZE]);
+}
+
+#[test_case]
+fn least_recently_used_sectors_are_evicted() {
+    let disk = CountingDisk::new();
+    let cache = BufferCache::new(disk.clone(), 2);
+
+    read_sector(&cache, 0);
+    read_sector(&cache, 1);
+    read_sector(&cache, 0);
+    // Evicts 1, which was used longest ago
+    read_sector(&cache, 2);
+    assert_eq!(disk.reads.load(Ordering::Relaxed), 3);
+
+    read_sector(&cache, 0);
+    assert_eq!(disk.reads.load(Ordering::Relaxed), 3);
+    read_sector(&cache, 1);
+    assert_eq!(disk.reads.load(Ordering::Relaxed), 4);
+
+    // A dirty sector is written back when it is evicted
+    cache.write_sectors(5, &[9; SECTOR_SIZE]).unwrap();
+    read_sector(&cache, 6);
+    assert_eq!(disk.sector(5), [0; SECTOR_SIZE]);
+    read_sector(&cache, 7);
+    assert_eq!(disk.sector(5), [9; SECTOR_SIZE]);
+    assert_eq!(cache.stats().write_backs, 1);
+}
+
+#[test_case]
+fn filesystem_on_a_cached_partition() {
+    let mut partitions =
+        partition::read_partitions(&test_disk("gpt", PARTITIONED_SECTORS)).unwrap();
+    let partition: Arc<dyn BlockDevice> = Arc::new(partitions.swap_remove(0));
+    let cache = Arc::new(BufferCache::new(partition.clone(), 32));
+
+    let fat = FatFs::new(cache.clone()).unwrap();
+    let file = fat.root().create("CACHED.TXT", FileType::File).unwrap();
+    file.open().unwrap().write_at(0, &[0x42; 3000]).unwrap();
+    fat.sync().unwrap();
+    assert_eq!(cache.dirty_sectors(), 0);
+
+    // Everything reached the partition, without the cache in the way
+    let fat = FatFs::new(partition).unwrap();
+    let file = fat.root().lookup("CACHED.TXT").unwrap().open().unwrap();
+    let mut contents = [0; 3000];
+    assert_eq!(file.read_at(0, &mut contents).unwrap(), 3000);
+    assert!(contents.iter().all(|&byte| byte == 0x42));
+}
@@ -0,0 +1,230 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::testing::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::panic::PanicInfo;
+use kernel::block::ramdisk::RamDisk;
+use kernel::block::SECTOR_SIZE;
+use kernel::fs::ext2::Ext2Fs;
+use kernel::fs::{self, FileType, Filesystem, FsError, OpenOptions, SeekFrom};
+use kernel::testing;
+
+/// The size the runner formats the image with (see `src/test_images.rs`)
+const IMAGE_SECTORS: u64 = 768 * 1024 / SECTOR_SIZE as u64;
+
+const ROOT: &str = "/tmp/ext2";
+
+bootloader_api::entry_point!(main, config = &kernel::BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut bootloader_api::BootInfo) -> ! {
+    unsafe { kernel::init(boot_info) };
+    test_main();
+
+    kernel::panic::halt()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::testing::test_panic_handler(info)
+}
+
+/// Mounts a fresh copy of the image on `/tmp/ext2` and runs `test`
+fn with_image(test: impl FnOnce()) {
+    let ext2 = Ext2Fs::new(testing::test_disk("ext2", IMAGE_SECTORS)).unwrap();
+    assert_eq!(ext2.block_size(), 1024);
+
+    testing::with_mounted(ROOT, Arc::new(ext2), test);
+}
+
+fn path(name: &str) -> String {
+    format!("{}/{}", ROOT, name)
+}
+
+fn names(name: &str) -> Vec<String> {
+    testing::sorted_names(&path(name))
+}
+
+#[test_case]
+fn reads_files_written_by_the_host() {
+    with_image(|| {
+        assert_eq!(
+            names(""),
+            [
+                "big.bin",
+                "docs",
+                "hello.txt",
+                "link",
+                "long-link",
+                "lost+found"
+            ]
+        );
+        assert_eq!(fs::read(&path("hello.txt")).unwrap(), b"Hello from ext2!\n");
+        assert_eq!(fs::read(&path("docs/readme.md")).unwrap(), b"# Docs\n");
+
+        // Needs the single indirect block
+        let big = fs::read(&path("big.bin")).unwrap();
+        assert_eq!(big.len(), 20000);
+        assert!(big
+            .iter()
+            .enumerate()
+            .all(|(index, &byte)| byte == (index % 251) as u8));
+
+        // Names are case sensitive
+        assert_eq!(fs::metadata(&path("HELLO.TXT")), Err(FsError::NotFound));
+    });
+}
+
+#[test_case]
+fn symlinks() {
+    with_image(|| {
+        // One target is kept in the inode, the other is too long and has a block
+        assert_eq!(fs::read_link(&path("link")).unwrap(), "hello.txt");
+        assert_eq!(fs::read(&path("link")).unwrap(), b"Hello from ext2!\n");
+        assert_eq!(fs::read(&path("long-link")).unwrap(), b"# Docs\n");
+
+        let long_target = format!("{}hello.txt", "../docs/".repeat(10));
+        fs::symlink("hello.txt", &path("docs/short")).unwrap();
+        fs::symlink(&long_target, &path("docs/long")).unwrap();
+        assert_eq!(fs::read_link(&path("docs/short")).unwrap(), "hello.txt");
+        assert_eq!(fs::read_link(&path("docs/long")).unwrap(), long_target);
+        assert_eq!(
+            fs::lookup_no_follow(&path("docs/long"))
+                .unwrap()
+                .metadata()
+                .unwrap()
+                .file_type,
+            FileType::Symlink
+        );
+
+        fs::remove(&path("docs/short")).unwrap();
+        fs::remove(&path("docs/long")).unwrap();
+        assert_eq!(names("docs"), ["readme.md"]);
+    });
+}
+
+#[test_case]
+fn write_and_read_back() {
+    with_image(|| {
+        let file_path = path("new file");
+        let data: Vec<u8> = (0..30000u32).map(|index| (index * 7) as u8).collect();
+
+        fs::write(&file_path, &data).unwrap();
+        assert_eq!(fs::read(&file_path).unwrap(), data);
+
+        // Far enough in to need a double indirect block, leaving a hole before it
+        let mut file = OpenOptions::new().write(true).open(&file_path).unwrap();
+        file.seek(SeekFrom::Start(300 * 1024)).unwrap();
+        file.write_all(b"tail").unwrap();
+
+        let contents = fs::read(&file_path).unwrap();
+        assert_eq!(contents.len(), 300 * 1024 + 4);
+        assert_eq!(&contents[..30000], &data[..]);
+        assert!(contents[30000..300 * 1024].iter().all(|&byte| byte == 0));
+        assert_eq!(&contents[300 * 1024..], b"tail");
+
+        // Truncating and growing again mustn't bring back the old data
+        file.set_len(100).unwrap();
+        file.set_len(2000).unwrap();
+        let contents = fs::read(&file_path).unwrap();
+        assert_eq!(&contents[..100], &data[..100]);
+        assert!(contents[100..].iter().all(|&byte| byte == 0));
+
+        // Writing nothing past the end leaves the size alone
+        file.seek(SeekFrom::Start(100_000)).unwrap();
+        assert_eq!(file.write(b""), Ok(0));
+        assert_eq!(fs::metadata(&file_path).unwrap().size, 2000);
+    });
+}
+
+#[test_case]
+fn directories() {
+    with_image(|| {
+        let directory = path("lots of files");
+        fs::create_dir(&directory).unwrap();
+        assert_eq!(fs::create_dir(&directory), Err(FsError::AlreadyExists));
+
+        // Enough entries to need more than one block
+        for index in 0..60 {
+            fs::write(&format!("{}/file number {}", directory, index), b"x").unwrap();
+        }
+        assert_eq!(fs::read_dir(&directory).unwrap().len(), 60);
+
+        assert_eq!(fs::remove(&directory), Err(FsError::DirectoryNotEmpty));
+
+        for index in 0..60 {
+            fs::remove(&format!("{}/file number {}", directory, index)).unwrap();
+        }
+        fs::remove(&directory).unwrap();
+        assert_eq!(fs::metadata(&directory), Err(FsError::NotFound));
+    });
+}
+
+#[test_case]
+fn rename() {
+    with_image(|| {
+        fs::rename(&path("hello.txt"), &path("docs/hello again.txt")).unwrap();
+        assert_eq!(
+            fs::read(&path("docs/hello again.txt")).unwrap(),
+            b"Hello from ext2!\n"
+        );
+        assert_eq!(fs::metadata(&path("hello.txt")), Err(FsError::NotFound));
+
+        // Moving a directory updates its `..`
+        fs::create_dir(&path("docs/inner")).unwrap();
+        fs::write(&path("docs/inner/file"), b"inner").unwrap();
+        fs::rename(&path("docs/inner"), &path("moved")).unwrap();
+        assert_eq!(fs::read(&path("moved/file")).unwrap(), b"inner");
+
+        assert_eq!(
+            fs::rename(&path("docs"), &path("docs/x")),
+            Err(FsError::InvalidArgument)
+        );
+
+        // Replacing a file
+        fs::rename(&path("big.bin"), &path("docs/readme.md")).unwrap();
+        assert_eq!(fs::metadata(&path("docs/readme.md")).unwrap().size, 20000);
+        assert_eq!(names("docs"), ["hello again.txt", "readme.md"]);
+    });
+}
+
+#[test_case]
+fn changes_survive_a_remount() {
+    let disk = testing::test_disk("ext2", IMAGE_SECTORS);
+
+    let ext2 = Ext2Fs::new(disk.clone()).unwrap();
+    let free_blocks = ext2.free_blocks();
+    let free_inodes = ext2.free_inodes();
+    let root = ext2.root();
+
+    let file = root.create("persistent", FileType::File).unwrap();
+    file.open().unwrap().write_at(0, &[0xaa; 1500]).unwrap();
+    root.unlink("big.bin").unwrap();
+    ext2.sync().unwrap();
+
+    let ext2 = Ext2Fs::new(disk).unwrap();
+    let file = ext2.root().lookup("persistent").unwrap().open().unwrap();
+    let mut contents = [0; 1500];
+    assert_eq!(file.read_at(0, &mut contents).unwrap(), 1500);
+    assert!(contents.iter().all(|&byte| byte == 0xaa));
+    assert_eq!(ext2.root().lookup("big.bin").err(), Some(FsError::NotFound));
+
+    // big.bin had 20 blocks and an indirect block, the new file takes 2
+    assert_eq!(ext2.free_blocks(), free_blocks + 21 - 2);
+    assert_eq!(ext2.free_inodes(), free_inodes);
+}
+
+#[test_case]
+fn rejects_a_disk_that_is_not_ext2() {
+    assert_eq!(
+        Ext2Fs::new(Arc::new(RamDisk::new(64))).err(),
+        Some(FsError::Corrupted)
+    );
+}
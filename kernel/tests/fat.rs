@@ -0,0 +1,282 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::testing::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::panic::PanicInfo;
+use kernel::block::ramdisk::RamDisk;
+use kernel::block::SECTOR_SIZE;
+use kernel::fs::fat::{FatFs, FatType};
+use kernel::fs::{self, FileType, Filesystem, FsError, OpenOptions, SeekFrom};
+use kernel::testing::{self, sorted_names};
+
+/// The images the runner packs into `test/` in the initrd, with the size they were formatted with (see
+/// `src/test_images.rs`)
+const IMAGES: [(&str, FatType, u64); 3] = [
+    ("fat12", FatType::Fat12, 512 * 1024),
+    ("fat16", FatType::Fat16, 4 * 1024 * 1024),
+    ("fat32", FatType::Fat32, 40 * 1024 * 1024),
+];
+
+bootloader_api::entry_point!(main, config = &kernel::BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut bootloader_api::BootInfo) -> ! {
+    unsafe { kernel::init(boot_info) };
+    test_main();
+
+    kernel::panic::halt()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::testing::test_panic_handler(info)
+}
+
+/// Mounts a fresh copy of each image on `/tmp/<name>` and runs `test` with the path
+fn with_each_image(test: impl Fn(&str)) {
+    for (name, fat_type, size) in IMAGES {
+        let fat = FatFs::new(testing::test_disk(name, size / SECTOR_SIZE as u64)).unwrap();
+        assert_eq!(fat.fat_type(), fat_type);
+
+        let path = format!("/tmp/{}", name);
+        testing::with_mounted(&path, Arc::new(fat), || test(&path));
+    }
+}
+
+#[test_case]
+fn reads_files_written_by_the_host() {
+    with_each_image(|root| {
+        assert_eq!(
+            sorted_names(root),
+            ["A long file name.txt", "HELLO.TXT", "big.bin", "docs"]
+        );
+        assert_eq!(
+            fs::metadata(&format!("{}/docs", root)).unwrap().file_type,
+            FileType::Directory
+        );
+
+        assert_eq!(
+            fs::read(&format!("{}/HELLO.TXT", root)).unwrap(),
+            b"Hello from FAT!\n"
+        );
+        assert_eq!(
+            fs::read(&format!("{}/A long file name.txt", root)).unwrap(),
+            b"Long file names work too.\n"
+        );
+        assert_eq!(
+            fs::read(&format!("{}/docs/readme.md", root)).unwrap(),
+            b"# Docs\n"
+        );
+
+        // Spans several 512 byte clusters
+        let big = fs::read(&format!("{}/big.bin", root)).unwrap();
+        assert_eq!(big.len(), 5000);
+        assert!(big
+            .iter()
+            .enumerate()
+            .all(|(index, &byte)| byte == (index % 251) as u8));
+    });
+}
+
+#[test_case]
+fn names_are_case_insensitive() {
+    with_each_image(|root| {
+        assert!(fs::read(&format!("{}/hello.txt", root)).is_ok());
+        assert!(fs::read(&format!("{}/a LONG file NAME.TXT", root)).is_ok());
+        assert_eq!(
+            fs::write(&format!("{}/Hello.Txt", root), b"replaced"),
+            Ok(())
+        );
+        assert_eq!(
+            sorted_names(root)
+                .iter()
+                .filter(|name| name.eq_ignore_ascii_case("hello.txt"))
+                .count(),
+            1
+        );
+    });
+}
+
+#[test_case]
+fn write_and_read_back() {
+    with_each_image(|root| {
+        let path = format!("{}/A New File With A Long Name.txt", root);
+        let data: Vec<u8> = (0..3000u32).map(|index| (index * 7) as u8).collect();
+
+        fs::write(&path, &data).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), data);
+
+        let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(4000)).unwrap();
+        file.write_all(b"tail").unwrap();
+
+        let contents = fs::read(&path).unwrap();
+        assert_eq!(contents.len(), 4004);
+        assert!(contents[3000..4000].iter().all(|&byte| byte == 0));
+        assert_eq!(&contents[4000..], b"tail");
+
+        // Truncating and growing again mustn't bring back the old data
+        file.set_len(100).unwrap();
+        file.set_len(2000).unwrap();
+        let contents = fs::read(&path).unwrap();
+        assert_eq!(&contents[..100], &data[..100]);
+        assert!(contents[100..].iter().all(|&byte| byte == 0));
+
+        assert!(sorted_names(root).contains(&String::from("A New File With A Long Name.txt")));
+    });
+}
+
+#[test_case]
+fn directories() {
+    with_each_image(|root| {
+        let directory = format!("{}/Lots of files", root);
+        fs::create_dir(&directory).unwrap();
+        assert_eq!(fs::create_dir(&directory), Err(FsError::AlreadyExists));
+
+        // Enough entries to need more than one cluster
+        for index in 0..40 {
+            fs::write(&format!("{}/file number {}", directory, index), b"x").unwrap();
+        }
+        assert_eq!(fs::read_dir(&directory).unwrap().len(), 40);
+
+        assert_eq!(fs::remove(&directory), Err(FsError::DirectoryNotEmpty));
+
+        for index in 0..40 {
+            fs::remove(&format!("{}/FILE NUMBER {}", directory, index)).unwrap();
+        }
+        fs::remove(&directory).unwrap();
+        assert_eq!(fs::metadata(&directory), Err(FsError::NotFound));
+    });
+}
+
+#[test_case]
+fn rename() {
+    with_each_image(|root| {
+        fs::rename(
+            &format!("{}/HELLO.TXT", root),
+            &format!("{}/docs/Hello again.txt", root),
+        )
+        .unwrap();
+        assert_eq!(
+            fs::read(&format!("{}/docs/hello AGAIN.txt", root)).unwrap(),
+            b"Hello from FAT!\n"
+        );
+        assert_eq!(
+            fs::metadata(&format!("{}/HELLO.TXT", root)),
+            Err(FsError::NotFound)
+        );
+
+        // Moving a directory updates its `..`
+        fs::create_dir(&format!("{}/docs/inner", root)).unwrap();
+        fs::write(&format!("{}/docs/inner/file", root), b"inner").unwrap();
+        fs::rename(&format!("{}/docs/inner", root), &format!("{}/moved", root)).unwrap();
+        assert_eq!(fs::read(&format!("{}/moved/file", root)).unwrap(), b"inner");
+
+        assert_eq!(
+            fs::rename(&format!("{}/docs", root), &format!("{}/docs/x", root)),
+            Err(FsError::InvalidArgument)
+        );
+
+        // Only changing the case
+        fs::rename(&format!("{}/big.bin", root), &format!("{}/BIG.bin", root)).unwrap();
+        assert!(sorted_names(root).contains(&String::from("BIG.bin")));
+
+        // Replacing a file
+        fs::rename(
+            &format!("{}/BIG.bin", root),
+            &format!("{}/A long file name.txt", root),
+        )
+        .unwrap();
+        assert_eq!(
+            fs::metadata(&format!("{}/A long file name.txt", root))
+                .unwrap()
+                .size,
+            5000
+        );
+    });
+}
+
+#[test_case]
+fn handles_to_removed_files_are_stale() {
+    with_each_image(|root| {
+        let path = format!("{}/EMPTY.TXT", root);
+        fs::write(&path, b"").unwrap();
+        let mut old = OpenOptions::new().write(true).open(&path).unwrap();
+
+        // The new file's short entry goes in the slot the old one was removed from
+        fs::remove(&path).unwrap();
+        fs::write(&path, b"").unwrap();
+
+        assert_eq!(old.write(b"stale"), Err(FsError::StaleHandle));
+        assert_eq!(fs::read(&path).unwrap(), b"");
+    });
+}
+
+#[test_case]
+fn empty_writes_past_the_end_do_nothing() {
+    with_each_image(|root| {
+        let path = format!("{}/SHORT.TXT", root);
+        fs::write(&path, b"short").unwrap();
+
+        let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(100_000)).unwrap();
+        assert_eq!(file.write(b""), Ok(0));
+        assert_eq!(fs::metadata(&path).unwrap().size, 5);
+    });
+}
+
+#[test_case]
+fn symlinks_are_unsupported() {
+    with_each_image(|root| {
+        assert_eq!(
+            fs::symlink("HELLO.TXT", &format!("{}/link", root)),
+            Err(FsError::Unsupported)
+        );
+    });
+}
+
+#[test_case]
+fn changes_survive_a_remount() {
+    for (name, _, size) in IMAGES {
+        let disk = testing::test_disk(name, size / SECTOR_SIZE as u64);
+
+        let fat = FatFs::new(disk.clone()).unwrap();
+        let free_before = fat.free_clusters().unwrap();
+        let root = fat.root();
+
+        let file = root.create("persistent file.txt", FileType::File).unwrap();
+        file.open().unwrap().write_at(0, &[0xaa; 1500]).unwrap();
+        root.unlink("big.bin").unwrap();
+        fat.sync().unwrap();
+
+        let fat = FatFs::new(disk).unwrap();
+        let file = fat
+            .root()
+            .lookup("PERSISTENT FILE.TXT")
+            .unwrap()
+            .open()
+            .unwrap();
+        let mut contents = [0; 1500];
+        assert_eq!(file.read_at(0, &mut contents).unwrap(), 1500);
+        assert!(contents.iter().all(|&byte| byte == 0xaa));
+        assert_eq!(fat.root().lookup("big.bin").err(), Some(FsError::NotFound));
+
+        // big.bin's 10 clusters were freed, the new file took 3
+        assert_eq!(fat.free_clusters().unwrap(), free_before + 10 - 3);
+    }
+}
+
+#[test_case]
+fn rejects_a_disk_that_is_not_fat() {
+    assert_eq!(
+        FatFs::new(Arc::new(RamDisk::new(64))).err(),
+        Some(FsError::Corrupted)
+    );
+}
@@ -0,0 +1,49 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::testing::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::panic::PanicInfo;
+
+bootloader_api::entry_point!(main, config = &kernel::BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut bootloader_api::BootInfo) -> ! {
+    unsafe { kernel::init(boot_info) };
+    test_main();
+
+    kernel::panic::halt()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::testing::test_panic_handler(info)
+}
+
+#[test_case]
+fn simple_allocation() {
+    let first = Box::new(41);
+    let second = Box::new(13);
+    assert_eq!(*first, 41);
+    assert_eq!(*second, 13);
+}
+
+#[test_case]
+fn large_vec() {
+    let n = 1000;
+    let vec: Vec<u64> = (0..n).collect();
+    assert_eq!(vec.iter().sum::<u64>(), (n - 1) * n / 2);
+}
+
+/// Fails if freed memory is never reused, since far more is allocated in total than the heap holds
+#[test_case]
+fn many_boxes() {
+    for i in 0..100_000 {
+        let x = Box::new(i);
+        assert_eq!(*x, i);
+    }
+}
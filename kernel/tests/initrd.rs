@@ -0,0 +1,62 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::testing::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use core::panic::PanicInfo;
+use kernel::initrd::{self, DirEntry, EntryKind};
+
+bootloader_api::entry_point!(main, config = &kernel::BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut bootloader_api::BootInfo) -> ! {
+    unsafe { kernel::init(boot_info) };
+    test_main();
+
+    kernel::panic::halt()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::testing::test_panic_handler(info)
+}
+
+#[test_case]
+fn initrd_is_loaded() {
+    let initrd = initrd::get().expect("no initrd");
+
+    assert!(initrd.file("kernel.sym").unwrap().starts_with(b"BSYM"));
+    assert!(initrd.file("/cmdline").is_some());
+}
+
+#[test_case]
+fn packed_directory_is_readable() {
+    let initrd = initrd::get().unwrap();
+
+    assert_eq!(initrd.kind("/etc"), Some(EntryKind::Directory));
+    assert_eq!(initrd.kind("etc/motd"), Some(EntryKind::File));
+    assert!(!initrd.file("/etc/motd").unwrap().is_empty());
+    assert_eq!(initrd.kind("/etc/missing"), None);
+}
+
+#[test_case]
+fn read_dir_lists_children() {
+    let initrd = initrd::get().unwrap();
+
+    let root = initrd.read_dir("/").unwrap();
+    assert!(root.contains(&DirEntry {
+        name: "etc",
+        kind: EntryKind::Directory
+    }));
+    assert!(root.contains(&DirEntry {
+        name: "cmdline",
+        kind: EntryKind::File
+    }));
+
+    let etc = initrd.read_dir("/etc").unwrap();
+    assert!(etc.iter().any(|entry| entry.name == "motd"));
+
+    assert!(initrd.read_dir("/etc/motd").is_none());
+}
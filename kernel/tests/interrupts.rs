@@ -0,0 +1,109 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::testing::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use kernel::io::interrupts::{self, IrqError, Vector};
+use spin::Mutex;
+
+bootloader_api::entry_point!(main, config = &kernel::BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut bootloader_api::BootInfo) -> ! {
+    unsafe { kernel::init(boot_info) };
+    test_main();
+
+    kernel::panic::halt()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::testing::test_panic_handler(info)
+}
+
+#[test_case]
+fn breakpoint_returns() {
+    x86_64::instructions::interrupts::int3();
+}
+
+#[test_case]
+fn lapic_timer_ticks() {
+    let start = interrupts::ticks();
+
+    while interrupts::ticks() == start {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Runs the handler on the first PCI vector the way the IOAPIC would, which is the vector GSI 16 gets as nothing else
+/// has registered one
+fn raise_first_pci_vector() {
+    unsafe { core::arch::asm!("int 0x50") };
+}
+
+/// A GSI which isn't an ISA IRQ gets a vector from the PCI range, which is freed again by `unregister_irq`
+#[test_case]
+fn register_and_unregister_irq() {
+    let calls = Arc::new(AtomicU64::new(0));
+    let handler_calls = calls.clone();
+
+    let vector = interrupts::register_irq(16, move || {
+        handler_calls.fetch_add(1, Ordering::Relaxed);
+    })
+    .unwrap();
+    assert_eq!(vector.as_u8(), 0x50);
+    assert_eq!(interrupts::register_irq(16, || {}), Err(IrqError::GsiInUse));
+    assert_eq!(
+        interrupts::register_pci_irq(16, || {}),
+        Err(IrqError::GsiInUse)
+    );
+
+    raise_first_pci_vector();
+    assert_eq!(calls.load(Ordering::Relaxed), 1);
+
+    // Once unregistered the old handler isn't called and the vector can be handed out again
+    interrupts::unregister_irq(vector);
+    raise_first_pci_vector();
+    assert_eq!(calls.load(Ordering::Relaxed), 1);
+
+    let new_calls = Arc::new(AtomicU64::new(0));
+    let handler_new_calls = new_calls.clone();
+    let new_vector = interrupts::register_irq(16, move || {
+        handler_new_calls.fetch_add(1, Ordering::Relaxed);
+    })
+    .unwrap();
+    assert_eq!(new_vector, vector);
+
+    raise_first_pci_vector();
+    assert_eq!(calls.load(Ordering::Relaxed), 1);
+    assert_eq!(new_calls.load(Ordering::Relaxed), 1);
+
+    interrupts::unregister_irq(new_vector);
+}
+
+/// The vector of the handler which unregisters itself
+static SELF_UNREGISTERING_VECTOR: Mutex<Option<Vector>> = Mutex::new(None);
+
+#[test_case]
+fn handlers_can_unregister_themselves() {
+    let calls = Arc::new(AtomicU64::new(0));
+    let handler_calls = calls.clone();
+
+    let vector = interrupts::register_irq(16, move || {
+        handler_calls.fetch_add(1, Ordering::Relaxed);
+        if let Some(vector) = SELF_UNREGISTERING_VECTOR.lock().take() {
+            interrupts::unregister_irq(vector);
+        }
+    })
+    .unwrap();
+    *SELF_UNREGISTERING_VECTOR.lock() = Some(vector);
+
+    raise_first_pci_vector();
+    raise_first_pci_vector();
+    assert_eq!(calls.load(Ordering::Relaxed), 1);
+}
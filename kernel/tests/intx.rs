@@ -0,0 +1,138 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::testing::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use kernel::io::interrupts::{self, IrqError};
+use kernel::io::pci::intx::{self, IntxError, IntxHandler};
+use kernel::io::pci::{self, PciAddress, PciDevice, PciMatch, COMMAND_INTERRUPT_DISABLE};
+use x86_64::VirtAddr;
+
+/// The runner adds QEMU's `edu` device, which asserts INTA# when its raise register is written and MSI is off
+const EDU: PciMatch = PciMatch::device(0x1234, 0x11e8);
+const EDU_STATUS_OFFSET: u64 = 0x24;
+const EDU_RAISE_OFFSET: u64 = 0x60;
+const EDU_ACKNOWLEDGE_OFFSET: u64 = 0x64;
+
+/// Calls to the handler which acknowledges the edu device and to one which only watches
+static ACKNOWLEDGED: AtomicUsize = AtomicUsize::new(0);
+static WATCHED: AtomicUsize = AtomicUsize::new(0);
+
+bootloader_api::entry_point!(main, config = &kernel::BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut bootloader_api::BootInfo) -> ! {
+    unsafe { kernel::init(boot_info) };
+    test_main();
+
+    kernel::panic::halt()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::testing::test_panic_handler(info)
+}
+
+fn edu() -> (&'static PciDevice, VirtAddr) {
+    let edu = pci::find(EDU).next().unwrap();
+    (edu, edu.map_bar(0).unwrap())
+}
+
+unsafe fn read_register(registers: VirtAddr, offset: u64) -> u32 {
+    (registers + offset).as_ptr::<u32>().read_volatile()
+}
+
+unsafe fn write_register(registers: VirtAddr, offset: u64, value: u32) {
+    (registers + offset)
+        .as_mut_ptr::<u32>()
+        .write_volatile(value)
+}
+
+/// Any timer tick wakes the loop up, so this gives the interrupt a few
+fn wait_for(counter: &AtomicUsize, count: usize) {
+    for _ in 0..100 {
+        if counter.load(Ordering::Relaxed) >= count {
+            break;
+        }
+        x86_64::instructions::hlt();
+    }
+    assert_eq!(counter.load(Ordering::Relaxed), count);
+}
+
+#[test_case]
+fn pins_are_routed_to_a_gsi() {
+    let (edu, _) = edu();
+    assert_eq!(edu.interrupt_pin, 1);
+    assert!(intx::gsi(edu).is_ok());
+
+    // The host bridge doesn't interrupt
+    let host_bridge = pci::device_at(PciAddress::new(0, 0, 0, 0)).unwrap();
+    assert_eq!(intx::gsi(host_bridge), Err(IntxError::NoPin));
+    assert_eq!(
+        IntxHandler::register(host_bridge, || {}).err(),
+        Some(IntxError::NoPin)
+    );
+}
+
+#[test_case]
+fn handlers_share_the_gsi() {
+    let (edu, registers) = edu();
+
+    let acknowledging = IntxHandler::register(edu, move || unsafe {
+        let status = read_register(registers, EDU_STATUS_OFFSET);
+        if status != 0 {
+            write_register(registers, EDU_ACKNOWLEDGE_OFFSET, status);
+            ACKNOWLEDGED.fetch_add(1, Ordering::Relaxed);
+        }
+    })
+    .unwrap();
+    let watching = IntxHandler::register(edu, || {
+        WATCHED.fetch_add(1, Ordering::Relaxed);
+    })
+    .unwrap();
+    assert_eq!(acknowledging.gsi(), watching.gsi());
+    assert_eq!(
+        edu.read_config::<u16>(pci::COMMAND_OFFSET) & COMMAND_INTERRUPT_DISABLE,
+        0
+    );
+
+    unsafe { write_register(registers, EDU_RAISE_OFFSET, 1) };
+    wait_for(&ACKNOWLEDGED, 1);
+    assert!(WATCHED.load(Ordering::Relaxed) >= 1);
+    assert_eq!(unsafe { read_register(registers, EDU_STATUS_OFFSET) }, 0);
+
+    drop(watching);
+    let watched = WATCHED.load(Ordering::Relaxed);
+    unsafe { write_register(registers, EDU_RAISE_OFFSET, 2) };
+    wait_for(&ACKNOWLEDGED, 2);
+    assert_eq!(WATCHED.load(Ordering::Relaxed), watched);
+
+    // With the last handler gone the pin is turned off
+    drop(acknowledging);
+    assert_ne!(
+        edu.read_config::<u16>(pci::COMMAND_OFFSET) & COMMAND_INTERRUPT_DISABLE,
+        0
+    );
+}
+
+#[test_case]
+fn gsis_taken_by_other_handlers_are_refused() {
+    let (edu, _) = edu();
+    let vector = interrupts::register_pci_irq(intx::gsi(edu).unwrap(), || {}).unwrap();
+
+    assert_eq!(
+        IntxHandler::register(edu, || {}).err(),
+        Some(IntxError::Interrupt(IrqError::GsiInUse))
+    );
+    assert_ne!(
+        edu.read_config::<u16>(pci::COMMAND_OFFSET) & COMMAND_INTERRUPT_DISABLE,
+        0
+    );
+
+    interrupts::unregister_irq(vector);
+    drop(IntxHandler::register(edu, || {}).unwrap());
+}
@@ -0,0 +1,166 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::testing::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use kernel::io::interrupts::{self, IrqError, Vector};
+use kernel::io::pci::msi::{MessageInterrupts, MsiError, MsiKind};
+use kernel::io::pci::{self, PciAddress, PciDevice, PciMatch};
+
+/// The runner adds QEMU's `edu` device, which sends an MSI when its raise register is written
+const EDU: PciMatch = PciMatch::device(0x1234, 0x11e8);
+const EDU_RAISE_OFFSET: u64 = 0x60;
+const EDU_ACKNOWLEDGE_OFFSET: u64 = 0x64;
+
+/// How many messages the edu device has sent
+static EDU_MESSAGES: AtomicUsize = AtomicUsize::new(0);
+
+bootloader_api::entry_point!(main, config = &kernel::BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut bootloader_api::BootInfo) -> ! {
+    unsafe { kernel::init(boot_info) };
+    test_main();
+
+    kernel::panic::halt()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::testing::test_panic_handler(info)
+}
+
+fn edu() -> &'static PciDevice {
+    pci::find(EDU).next().unwrap()
+}
+
+/// The runner also adds a `virtio-rng-pci`, which has an MSI-X table with 2 entries
+fn virtio_rng() -> &'static PciDevice {
+    pci::devices()
+        .iter()
+        .find(|device| device.vendor_id == 0x1af4 && matches!(device.device_id, 0x1005 | 0x1044))
+        .unwrap()
+}
+
+fn is_pci_vector(vector: Vector) -> bool {
+    (0x50..0x60).contains(&vector.as_u8())
+}
+
+#[test_case]
+fn edu_sends_its_message() {
+    let edu = edu();
+    assert_eq!(MessageInterrupts::available(edu), Some((MsiKind::Msi, 1)));
+
+    let msi = MessageInterrupts::enable(edu, 1, interrupts::boot_lapic_id(), |index| {
+        assert_eq!(index, 0);
+        EDU_MESSAGES.fetch_add(1, Ordering::Relaxed);
+    })
+    .unwrap();
+    assert_eq!(msi.kind(), MsiKind::Msi);
+    assert!(is_pci_vector(msi.vectors()[0]));
+
+    let registers = edu.map_bar(0).unwrap();
+    unsafe {
+        (registers + EDU_RAISE_OFFSET)
+            .as_mut_ptr::<u32>()
+            .write_volatile(1)
+    };
+
+    // Any timer tick wakes the loop up, so give it a few
+    for _ in 0..100 {
+        if EDU_MESSAGES.load(Ordering::Relaxed) != 0 {
+            break;
+        }
+        x86_64::instructions::hlt();
+    }
+    assert_eq!(EDU_MESSAGES.load(Ordering::Relaxed), 1);
+
+    unsafe {
+        (registers + EDU_ACKNOWLEDGE_OFFSET)
+            .as_mut_ptr::<u32>()
+            .write_volatile(1)
+    };
+
+    // The edu device's MSI capability has no mask bits
+    assert_eq!(msi.set_masked(0, true), Err(MsiError::MaskingUnsupported));
+}
+
+#[test_case]
+fn msi_x_vectors_are_masked_one_at_a_time() {
+    let rng = virtio_rng();
+    assert_eq!(MessageInterrupts::available(rng), Some((MsiKind::MsiX, 2)));
+    assert_eq!(
+        MessageInterrupts::enable(rng, 3, interrupts::boot_lapic_id(), |_| {}).err(),
+        Some(MsiError::TooManyVectors)
+    );
+
+    let msi_x = MessageInterrupts::enable(rng, 2, interrupts::boot_lapic_id(), |_| {}).unwrap();
+    assert_eq!(msi_x.kind(), MsiKind::MsiX);
+    assert_eq!(msi_x.vectors().len(), 2);
+    assert_ne!(msi_x.vectors()[0], msi_x.vectors()[1]);
+    assert!(msi_x.vectors().iter().all(|&vector| is_pci_vector(vector)));
+
+    msi_x.set_masked(1, true).unwrap();
+    assert!(!msi_x.is_masked(0));
+    assert!(msi_x.is_masked(1));
+    msi_x.set_masked(1, false).unwrap();
+    assert!(!msi_x.is_masked(1));
+
+    // Dropping turns MSI-X off and frees the vectors
+    let capability = rng.capability(pci::Capability::MSI_X).unwrap();
+    let first_vector = msi_x.vectors()[0];
+    assert_ne!(rng.read_config::<u16>(capability.offset + 2) & (1 << 15), 0);
+    drop(msi_x);
+    assert_eq!(rng.read_config::<u16>(capability.offset + 2) & (1 << 15), 0);
+
+    let vectors = interrupts::register_message_vectors(1, |_| {}).unwrap();
+    assert_eq!(vectors[0], first_vector);
+    interrupts::unregister_irq(vectors[0]);
+}
+
+#[test_case]
+fn devices_and_destinations_that_cannot_be_used() {
+    let host_bridge = pci::device_at(PciAddress::new(0, 0, 0, 0)).unwrap();
+    assert_eq!(MessageInterrupts::available(host_bridge), None);
+    assert_eq!(
+        MessageInterrupts::enable(host_bridge, 1, interrupts::boot_lapic_id(), |_| {}).err(),
+        Some(MsiError::Unsupported)
+    );
+
+    assert_eq!(
+        MessageInterrupts::enable(edu(), 1, 256, |_| {}).err(),
+        Some(MsiError::InvalidDestination)
+    );
+}
+
+#[test_case]
+fn message_vectors_are_aligned_and_run_out() {
+    let single = interrupts::register_message_vectors(1, |_| {}).unwrap();
+    assert_eq!(single[0].as_u8(), 0x50);
+
+    // Four vectors for multiple message MSI have to start on a multiple of four
+    let block = interrupts::register_message_vectors(4, |_| {}).unwrap();
+    assert_eq!(block[0].as_u8(), 0x54);
+
+    let rest = interrupts::register_message_vectors(8, |_| {}).unwrap();
+    assert_eq!(rest[0].as_u8(), 0x58);
+    assert_eq!(
+        interrupts::register_message_vectors(4, |_| {}).err(),
+        Some(IrqError::NoFreeVectors)
+    );
+
+    for vector in single.into_iter().chain(block).chain(rest) {
+        interrupts::unregister_irq(vector);
+    }
+
+    // With everything freed the whole range is one run again
+    let all = interrupts::register_message_vectors(16, |_| {}).unwrap();
+    assert_eq!(all.len(), 16);
+    for vector in all {
+        interrupts::unregister_irq(vector);
+    }
+}
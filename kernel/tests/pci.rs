@@ -0,0 +1,124 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::testing::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::format;
+use alloc::vec::Vec;
+use core::panic::PanicInfo;
+use kernel::io::pci::{
+    self, Bar, PciAddress, PciMatch, BAR_OFFSET, COMMAND_MEMORY_SPACE, COMMAND_OFFSET,
+};
+
+// QEMU's default machine is an i440FX with its standard VGA card and an e1000
+const VGA: PciMatch = PciMatch::device(0x1234, 0x1111);
+const E1000: PciMatch = PciMatch::device(0x8086, 0x100e);
+
+bootloader_api::entry_point!(main, config = &kernel::BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut bootloader_api::BootInfo) -> ! {
+    unsafe { kernel::init(boot_info) };
+    test_main();
+
+    kernel::panic::halt()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::testing::test_panic_handler(info)
+}
+
+#[test_case]
+fn finds_the_host_bridge() {
+    let host_bridge = pci::device_at(PciAddress::new(0, 0, 0, 0)).unwrap();
+
+    assert_eq!(host_bridge.vendor_id, 0x8086);
+    assert_eq!(
+        (host_bridge.class, host_bridge.subclass),
+        (pci::CLASS_BRIDGE, pci::SUBCLASS_HOST_BRIDGE)
+    );
+    assert_eq!(host_bridge.header_type, pci::HEADER_TYPE_GENERAL);
+}
+
+#[test_case]
+fn finds_every_function_of_a_device() {
+    // The PIIX3 has its ISA bridge, IDE controller and power management in one device
+    let functions: Vec<_> = pci::devices()
+        .iter()
+        .filter(|device| device.address.bus == 0 && device.address.device == 1)
+        .map(|device| device.address.function)
+        .collect();
+
+    assert!(functions.starts_with(&[0, 1]));
+}
+
+#[test_case]
+fn matches_on_ids_and_class() {
+    let vga = pci::find(VGA).next().unwrap();
+    assert_eq!((vga.class, vga.subclass, vga.prog_if), (0x03, 0x00, 0x00));
+
+    let by_class: Vec<_> = pci::find(PciMatch::class(0x03, 0x00).with_prog_if(0))
+        .map(|device| device.address)
+        .collect();
+    assert_eq!(by_class, [vga.address]);
+
+    assert_eq!(pci::find(PciMatch::device(0x8086, 0xffff)).count(), 0);
+    assert_eq!(pci::find(PciMatch::default()).count(), pci::devices().len());
+}
+
+#[test_case]
+fn decodes_bars() {
+    let vga = pci::find(VGA).next().unwrap();
+    let Some(Bar::Memory {
+        address,
+        size,
+        prefetchable,
+        ..
+    }) = vga.bars[0]
+    else {
+        panic!("the VGA framebuffer should be a memory BAR");
+    };
+    assert_eq!(size, 16 * 1024 * 1024);
+    assert!(prefetchable);
+    assert_eq!(address % size, 0);
+
+    let e1000 = pci::find(E1000).next().unwrap();
+    assert!(matches!(
+        e1000.bars[0],
+        Some(Bar::Memory {
+            size: 0x20000,
+            prefetchable: false,
+            ..
+        })
+    ));
+    assert!(matches!(e1000.bars[1], Some(Bar::Io { size: 0x40, .. })));
+}
+
+#[test_case]
+fn sizing_bars_leaves_them_as_they_were() {
+    let vga = pci::find(VGA).next().unwrap();
+    let Some(Bar::Memory { address, .. }) = vga.bars[0] else {
+        panic!("the VGA framebuffer should be a memory BAR");
+    };
+
+    assert_eq!(
+        vga.read_config::<u32>(BAR_OFFSET) & 0xffff_fff0,
+        address as u32
+    );
+    assert_ne!(
+        vga.read_config::<u16>(COMMAND_OFFSET) & COMMAND_MEMORY_SPACE,
+        0
+    );
+    assert_eq!(vga.read_config::<u16>(0), vga.vendor_id);
+}
+
+#[test_case]
+fn addresses_are_shown_in_their_usual_form() {
+    assert_eq!(
+        format!("{}", PciAddress::new(0, 0x1f, 3, 2)),
+        "0000:1f:03.2"
+    );
+}
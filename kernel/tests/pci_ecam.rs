@@ -0,0 +1,51 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::testing::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use core::panic::PanicInfo;
+use kernel::io::pci::{self, ConfigMechanism, PciAddress, ECAM_CONFIG_SIZE};
+
+// The runner boots this one on QEMU's q35 machine, whose MCFG covers every bus on segment 0 (see `src/main.rs`)
+
+bootloader_api::entry_point!(main, config = &kernel::BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut bootloader_api::BootInfo) -> ! {
+    unsafe { kernel::init(boot_info) };
+    test_main();
+
+    kernel::panic::halt()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::testing::test_panic_handler(info)
+}
+
+#[test_case]
+fn uses_ecam() {
+    assert_eq!(pci::config_mechanism(), ConfigMechanism::Ecam);
+
+    // The Q35 host bridge
+    let host_bridge = pci::device_at(PciAddress::new(0, 0, 0, 0)).unwrap();
+    assert_eq!(
+        (host_bridge.vendor_id, host_bridge.device_id),
+        (0x8086, 0x29c0)
+    );
+    assert_eq!(host_bridge.config_size(), ECAM_CONFIG_SIZE);
+}
+
+#[test_case]
+fn finds_what_the_legacy_ports_find() {
+    let mut found: Vec<_> = pci::devices().iter().map(|device| device.address).collect();
+    found.sort();
+
+    let mut legacy = pci::scan_legacy();
+    legacy.sort();
+
+    assert_eq!(found, legacy);
+}
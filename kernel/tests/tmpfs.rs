@@ -0,0 +1,160 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::testing::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use core::panic::PanicInfo;
+use kernel::fs::tmpfs::Tmpfs;
+use kernel::fs::{self, FileType, FsError, OpenOptions, SeekFrom};
+
+bootloader_api::entry_point!(main, config = &kernel::BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut bootloader_api::BootInfo) -> ! {
+    unsafe { kernel::init(boot_info) };
+    test_main();
+
+    kernel::panic::halt()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::testing::test_panic_handler(info)
+}
+
+#[test_case]
+fn tmp_is_mounted() {
+    assert!(fs::mounts()
+        .iter()
+        .any(|mount| mount.path == "/tmp" && mount.filesystem == "tmpfs"));
+}
+
+#[test_case]
+fn write_and_read_back() {
+    fs::write("/tmp/hello", b"hello world").unwrap();
+    assert_eq!(fs::read("/tmp/hello").unwrap(), b"hello world");
+
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/tmp/hello")
+        .unwrap();
+    file.seek(SeekFrom::Start(6)).unwrap();
+    file.write_all(b"there").unwrap();
+    assert_eq!(fs::read("/tmp/hello").unwrap(), b"hello there");
+
+    let mut file = OpenOptions::new().append(true).open("/tmp/hello").unwrap();
+    file.write_all(b"!").unwrap();
+    assert_eq!(fs::read("/tmp/hello").unwrap(), b"hello there!");
+
+    fs::remove("/tmp/hello").unwrap();
+}
+
+#[test_case]
+fn writing_past_the_end_fills_with_zeros() {
+    let mut file = fs::create("/tmp/sparse").unwrap();
+    file.seek(SeekFrom::Start(4)).unwrap();
+    file.write_all(b"x").unwrap();
+    assert_eq!(fs::read("/tmp/sparse").unwrap(), b"\0\0\0\0x");
+
+    file.set_len(2).unwrap();
+    assert_eq!(fs::metadata("/tmp/sparse").unwrap().size, 2);
+
+    fs::remove("/tmp/sparse").unwrap();
+}
+
+#[test_case]
+fn directories() {
+    fs::create_dir("/tmp/dir").unwrap();
+    fs::write("/tmp/dir/file", b"1").unwrap();
+    assert_eq!(fs::create_dir("/tmp/dir"), Err(FsError::AlreadyExists));
+
+    let entries = fs::read_dir("/tmp/dir").unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].name, "file");
+    assert_eq!(entries[0].file_type, FileType::File);
+
+    assert_eq!(fs::remove("/tmp/dir"), Err(FsError::DirectoryNotEmpty));
+    fs::remove("/tmp/dir/file").unwrap();
+    fs::remove("/tmp/dir").unwrap();
+    assert_eq!(fs::metadata("/tmp/dir"), Err(FsError::NotFound));
+}
+
+#[test_case]
+fn rename_moves_and_replaces() {
+    fs::create_dir("/tmp/a").unwrap();
+    fs::create_dir("/tmp/b").unwrap();
+    fs::write("/tmp/a/file", b"new").unwrap();
+    fs::write("/tmp/b/file", b"old").unwrap();
+
+    fs::rename("/tmp/a/file", "/tmp/b/file").unwrap();
+    assert_eq!(fs::read("/tmp/b/file").unwrap(), b"new");
+    assert_eq!(fs::metadata("/tmp/a/file"), Err(FsError::NotFound));
+
+    assert_eq!(
+        fs::rename("/tmp/a", "/tmp/a/inside"),
+        Err(FsError::InvalidArgument)
+    );
+    assert_eq!(
+        fs::rename("/tmp/a", "/tmp/b"),
+        Err(FsError::DirectoryNotEmpty)
+    );
+    assert_eq!(
+        fs::rename("/tmp/b/file", "/etc/file"),
+        Err(FsError::CrossDevice)
+    );
+
+    fs::rename("/tmp/b", "/tmp/a/b").unwrap();
+    assert_eq!(fs::read("/tmp/a/b/file").unwrap(), b"new");
+
+    fs::remove("/tmp/a/b/file").unwrap();
+    fs::remove("/tmp/a/b").unwrap();
+    fs::remove("/tmp/a").unwrap();
+}
+
+#[test_case]
+fn symlinks_are_followed() {
+    fs::create_dir("/tmp/target").unwrap();
+    fs::write("/tmp/target/file", b"linked").unwrap();
+    fs::symlink("target", "/tmp/link").unwrap();
+    fs::symlink("/tmp/loop", "/tmp/loop").unwrap();
+
+    assert_eq!(fs::read("/tmp/link/file").unwrap(), b"linked");
+    assert_eq!(fs::read_link("/tmp/link").unwrap(), "target");
+    assert_eq!(fs::metadata("/tmp/loop"), Err(FsError::TooManySymlinks));
+
+    fs::remove("/tmp/loop").unwrap();
+    fs::remove("/tmp/link").unwrap();
+    assert!(fs::metadata("/tmp/target/file").is_ok());
+
+    fs::remove("/tmp/target/file").unwrap();
+    fs::remove("/tmp/target").unwrap();
+}
+
+#[test_case]
+fn limits_are_enforced() {
+    fs::create_dir("/tmp/small").unwrap();
+
+    let tmpfs = Arc::new(Tmpfs::new(16, 3));
+    fs::mount("/tmp/small", tmpfs.clone()).unwrap();
+
+    let mut file = fs::create("/tmp/small/file").unwrap();
+    file.write_all(&[1; 16]).unwrap();
+    assert_eq!(file.write(b"x"), Err(FsError::NoSpace));
+    assert_eq!(tmpfs.used_bytes(), 16);
+
+    fs::create_dir("/tmp/small/dir").unwrap();
+    assert_eq!(fs::create_dir("/tmp/small/another"), Err(FsError::NoSpace));
+
+    // The data is freed once the file is unlinked and closed
+    fs::remove("/tmp/small/file").unwrap();
+    assert_eq!(tmpfs.used_bytes(), 16);
+    drop(file);
+    assert_eq!(tmpfs.used_bytes(), 0);
+
+    fs::unmount("/tmp/small").unwrap();
+    fs::remove("/tmp/small").unwrap();
+}
@@ -0,0 +1,119 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::testing::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use core::panic::PanicInfo;
+use kernel::fs::initrd::InitrdFs;
+use kernel::fs::{self, FileType, FsError, OpenOptions, SeekFrom};
+
+bootloader_api::entry_point!(main, config = &kernel::BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut bootloader_api::BootInfo) -> ! {
+    unsafe { kernel::init(boot_info) };
+    test_main();
+
+    kernel::panic::halt()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::testing::test_panic_handler(info)
+}
+
+#[test_case]
+fn initrd_is_mounted_at_root() {
+    let mounts = fs::mounts();
+    assert_eq!(mounts[0].path, "/");
+    assert_eq!(mounts[0].filesystem, "initrd");
+
+    let motd = kernel::initrd::get().unwrap().file("etc/motd").unwrap();
+    assert_eq!(fs::read("/etc/motd").unwrap(), motd);
+}
+
+#[test_case]
+fn paths_are_normalised() {
+    assert_eq!(
+        fs::metadata("/etc/./../etc//motd").unwrap().file_type,
+        FileType::File
+    );
+    assert_eq!(fs::metadata("/..").unwrap().file_type, FileType::Directory);
+    assert_eq!(fs::metadata("etc/motd"), Err(FsError::InvalidPath));
+    assert_eq!(fs::metadata("/etc/motd/x"), Err(FsError::NotADirectory));
+    assert_eq!(fs::metadata("/missing"), Err(FsError::NotFound));
+}
+
+#[test_case]
+fn read_dir_lists_entries() {
+    let root = fs::read_dir("/").unwrap();
+    assert!(root
+        .iter()
+        .any(|entry| entry.name == "etc" && entry.file_type == FileType::Directory));
+    assert_eq!(fs::read_dir("/etc/motd"), Err(FsError::NotADirectory));
+}
+
+#[test_case]
+fn handles_seek_and_read() {
+    let contents = fs::read("/etc/motd").unwrap();
+    let mut file = fs::open("/etc/motd").unwrap();
+
+    let mut buf = [0; 4];
+    assert_eq!(file.seek(SeekFrom::Start(2)).unwrap(), 2);
+    assert_eq!(file.read(&mut buf).unwrap(), 4);
+    assert_eq!(&buf, &contents[2..6]);
+
+    assert_eq!(
+        file.seek(SeekFrom::End(-1)).unwrap(),
+        contents.len() as u64 - 1
+    );
+    assert_eq!(file.read(&mut buf).unwrap(), 1);
+    assert_eq!(file.read(&mut buf).unwrap(), 0);
+
+    assert_eq!(
+        file.seek(SeekFrom::Current(-100)),
+        Err(FsError::InvalidArgument)
+    );
+    assert_eq!(file.write(b"x"), Err(FsError::BadHandle));
+}
+
+#[test_case]
+fn initrd_is_read_only() {
+    assert_eq!(fs::create_dir("/new"), Err(FsError::ReadOnly));
+    assert_eq!(fs::remove("/etc/motd"), Err(FsError::ReadOnly));
+    assert!(fs::create("/etc/motd").is_err());
+
+    let mut file = OpenOptions::new().write(true).open("/etc/motd").unwrap();
+    assert_eq!(file.write(b"x"), Err(FsError::ReadOnly));
+}
+
+#[test_case]
+fn mounts_nest() {
+    let initrd = kernel::initrd::get().unwrap();
+
+    assert_eq!(
+        fs::mount("/etc/motd", Arc::new(InitrdFs::new(initrd))),
+        Err(FsError::NotADirectory)
+    );
+
+    fs::mount("/etc", Arc::new(InitrdFs::new(initrd))).unwrap();
+    assert_eq!(
+        fs::read("/etc/etc/motd").unwrap(),
+        fs::read("/etc/motd").unwrap()
+    );
+    assert_eq!(
+        fs::metadata("/etc/cmdline").unwrap().file_type,
+        FileType::File
+    );
+    assert_eq!(
+        fs::rename("/cmdline", "/etc/cmdline2"),
+        Err(FsError::CrossDevice)
+    );
+    assert_eq!(fs::remove("/etc"), Err(FsError::Busy));
+
+    fs::unmount("/etc").unwrap();
+    assert_eq!(fs::metadata("/etc/etc"), Err(FsError::NotFound));
+}
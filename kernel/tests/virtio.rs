@@ -0,0 +1,165 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::testing::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use core::panic::PanicInfo;
+use kernel::dma::DmaBuffer;
+use kernel::io::pci::{self, PciAddress, PciDevice};
+use kernel::io::virtio::{
+    self, Segment, VirtioError, VirtioPci, FEATURE_VERSION_1, STATUS_ACKNOWLEDGE, STATUS_DRIVER,
+    STATUS_DRIVER_OK, STATUS_FEATURES_OK,
+};
+
+bootloader_api::entry_point!(main, config = &kernel::BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut bootloader_api::BootInfo) -> ! {
+    unsafe { kernel::init(boot_info) };
+    test_main();
+
+    kernel::panic::halt()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::testing::test_panic_handler(info)
+}
+
+/// The runner adds a `virtio-rng-pci`, which fills every buffer on its one queue with random bytes
+fn virtio_rng() -> &'static PciDevice {
+    virtio::find(virtio::DEVICE_TYPE_ENTROPY).next().unwrap()
+}
+
+/// Resets the device and negotiates no optional features
+fn ready_rng() -> VirtioPci {
+    let rng = VirtioPci::new(virtio_rng()).unwrap();
+    rng.negotiate_features(0).unwrap();
+    rng
+}
+
+#[test_case]
+fn device_types_are_recognised() {
+    assert_eq!(
+        virtio::device_type(virtio_rng()),
+        Some(virtio::DEVICE_TYPE_ENTROPY)
+    );
+
+    let host_bridge = pci::device_at(PciAddress::new(0, 0, 0, 0)).unwrap();
+    assert_eq!(virtio::device_type(host_bridge), None);
+    assert_eq!(
+        VirtioPci::new(host_bridge).err(),
+        Some(VirtioError::NotVirtio)
+    );
+}
+
+#[test_case]
+fn handshake_reaches_driver_ok() {
+    let rng = VirtioPci::new(virtio_rng()).unwrap();
+    assert_eq!(rng.status(), STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+    assert_ne!(rng.device_features() & FEATURE_VERSION_1, 0);
+
+    // Features the device doesn't offer are left out
+    assert_eq!(rng.negotiate_features(1 << 63), Ok(FEATURE_VERSION_1));
+    assert_ne!(rng.status() & STATUS_FEATURES_OK, 0);
+
+    // The 2 MSI-X entries cover configuration changes and the queue
+    assert_eq!(rng.num_queues(), 1);
+    assert!(rng.msi_x().is_some());
+    assert_eq!(rng.intx_gsi(), None);
+
+    let queue = rng.setup_queue(0).unwrap();
+    assert!(queue.size().is_power_of_two());
+    assert_eq!(queue.free_descriptors(), queue.size());
+    assert_eq!(
+        rng.setup_queue(0).err(),
+        Some(VirtioError::QueueUnavailable)
+    );
+    assert_eq!(
+        rng.setup_queue(1).err(),
+        Some(VirtioError::QueueUnavailable)
+    );
+
+    rng.driver_ok();
+    assert_ne!(rng.status() & STATUS_DRIVER_OK, 0);
+
+    // Dropping the transport resets the device
+    let device = rng.device();
+    drop(queue);
+    drop(rng);
+    let rng = VirtioPci::new(device).unwrap();
+    assert_eq!(rng.status(), STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+}
+
+#[test_case]
+fn rng_fills_a_buffer() {
+    let rng = ready_rng();
+    let queue = rng.setup_queue(0).unwrap();
+    rng.driver_ok();
+
+    let buffer = DmaBuffer::new(64);
+    let token = queue.submit(&[Segment::writable(&buffer)]).unwrap();
+    assert_eq!(queue.free_descriptors(), queue.size() - 1);
+    queue.notify();
+
+    assert_eq!(queue.wait(token), 64);
+    assert!(buffer.as_slice().iter().any(|&byte| byte != 0));
+    assert_eq!(queue.free_descriptors(), queue.size());
+    assert_eq!(queue.take_completed(token), None);
+
+    // The next chain reuses the head descriptor but not the token
+    let next = queue.submit(&[Segment::writable(&buffer)]).unwrap();
+    assert_ne!(next, token);
+    queue.notify();
+    assert_eq!(queue.wait(next), 64);
+    assert_eq!(queue.take_completed(token), None);
+}
+
+#[test_case]
+fn rng_fills_a_chain() {
+    let rng = ready_rng();
+    let queue = rng.setup_queue(0).unwrap();
+    rng.driver_ok();
+
+    let first = DmaBuffer::new(32);
+    let second = DmaBuffer::new(48);
+    let token = queue
+        .submit(&[Segment::writable(&first), Segment::writable(&second)])
+        .unwrap();
+    assert_eq!(queue.free_descriptors(), queue.size() - 2);
+    queue.notify();
+
+    assert_eq!(queue.wait(token), 80);
+    assert!(first.as_slice().iter().any(|&byte| byte != 0));
+    assert!(second.as_slice().iter().any(|&byte| byte != 0));
+    assert_eq!(queue.free_descriptors(), queue.size());
+}
+
+#[test_case]
+fn chains_longer_than_the_free_descriptors_are_refused() {
+    let rng = ready_rng();
+    let queue = rng.setup_queue(0).unwrap();
+
+    let buffer = DmaBuffer::new(8);
+    let segments = alloc::vec![Segment::writable(&buffer); queue.size() as usize + 1];
+    assert_eq!(queue.submit(&segments).err(), Some(VirtioError::QueueFull));
+    assert_eq!(queue.free_descriptors(), queue.size());
+}
+
+#[test_case]
+fn dma_buffers_are_aligned_zeroed_and_reused() {
+    let mut buffer = DmaBuffer::new(3 * 4096);
+    assert!(buffer.phys_addr().is_aligned(4096u64));
+    assert!(buffer.as_slice().iter().all(|&byte| byte == 0));
+    buffer.as_mut_slice().fill(0xa5);
+
+    // A buffer with the same number of pages gets the same frames, zeroed again
+    let phys_addr = buffer.phys_addr();
+    drop(buffer);
+    let buffer = DmaBuffer::new(3 * 4096 - 100);
+    assert_eq!(buffer.phys_addr(), phys_addr);
+    assert_eq!(buffer.len(), 3 * 4096 - 100);
+    assert!(buffer.as_slice().iter().all(|&byte| byte == 0));
+}
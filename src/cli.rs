@@ -0,0 +1,226 @@
+use std::path::PathBuf;
+
+pub const USAGE: &str = "\
+usage: bench2 [options] [-- <qemu args>...]
+       bench2 test <test kernel>
+
+options:
+    --headless           run without a display (-display none)
+    --gdb                wait for GDB on localhost:1234 before booting (-s -S)
+    --gdb-stub           turn on the kernel's GDB stub and connect COM2 to localhost:1235
+    --memory <size>      guest memory, eg. 512M or 2G
+    --smp <cpus>         number of CPUs
+    --serial <target>    connect COM1 to `stdio` or to a file
+    --kvm                use KVM acceleration with the host CPU model
+    --firmware <type>    boot with `uefi` (the default) or legacy `bios`
+    --cmdline <options>  rebuild the image with a different kernel command line
+    --initrd <dir>       rebuild the image with a different directory packed into the initrd
+    --image-only         only write the disk image, don't start QEMU
+    -h, --help           print this message
+
+Anything after `--` is passed to QEMU unchanged.";
+
+/// Where COM1 is connected
+#[derive(Debug, PartialEq, Eq)]
+pub enum Serial {
+    /// Shares stdio with the debug port
+    Stdio,
+    File(PathBuf),
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Firmware {
+    #[default]
+    Uefi,
+    Bios,
+}
+
+#[derive(Debug, Default)]
+pub struct Options {
+    pub headless: bool,
+    pub gdb: bool,
+    pub gdb_stub: bool,
+    pub memory: Option<String>,
+    pub smp: Option<u32>,
+    pub serial: Option<Serial>,
+    pub kvm: bool,
+    pub firmware: Firmware,
+    pub cmdline: Option<String>,
+    pub initrd: Option<PathBuf>,
+    pub image_only: bool,
+    pub help: bool,
+    pub qemu_args: Vec<String>,
+}
+
+impl Options {
+    /// Parses the arguments after the program name. Options which take a value accept it either as the next argument
+    /// or after an `=`.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let mut options = Options::default();
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            let (name, inline_value) = match arg.split_once('=') {
+                Some((name, value)) if name.starts_with("--") => {
+                    (name.to_string(), Some(value.to_string()))
+                }
+                _ => (arg.clone(), None),
+            };
+
+            let mut value = || {
+                inline_value
+                    .clone()
+                    .or_else(|| args.next())
+                    .ok_or_else(|| format!("{} needs a value", name))
+            };
+
+            match name.as_str() {
+                "--headless" => options.headless = true,
+                "--gdb" => options.gdb = true,
+                "--gdb-stub" => options.gdb_stub = true,
+                "--memory" => options.memory = Some(value()?),
+                "--smp" => {
+                    let cpus = value()?;
+                    options.smp = Some(
+                        cpus.parse()
+                            .map_err(|_| format!("invalid CPU count `{}`", cpus))?,
+                    );
+                }
+                "--serial" => {
+                    options.serial = Some(match value()?.as_str() {
+                        "stdio" => Serial::Stdio,
+                        path => Serial::File(PathBuf::from(path)),
+                    });
+                }
+                "--kvm" => options.kvm = true,
+                "--firmware" => {
+                    options.firmware = match value()?.as_str() {
+                        "uefi" => Firmware::Uefi,
+                        "bios" => Firmware::Bios,
+                        firmware => return Err(format!("unknown firmware `{}`", firmware)),
+                    };
+                }
+                "--cmdline" => options.cmdline = Some(value()?),
+                "--initrd" => options.initrd = Some(PathBuf::from(value()?)),
+                "--image-only" => options.image_only = true,
+                "-h" | "--help" => options.help = true,
+                "--" => {
+                    options.qemu_args.extend(args.by_ref());
+                }
+                _ => return Err(format!("unknown option `{}`", arg)),
+            }
+        }
+
+        Ok(options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        Options::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn defaults() {
+        let options = parse(&[]).unwrap();
+
+        assert!(!options.headless);
+        assert!(!options.gdb);
+        assert!(!options.gdb_stub);
+        assert!(!options.kvm);
+        assert!(!options.image_only);
+        assert!(!options.help);
+        assert_eq!(options.memory, None);
+        assert_eq!(options.smp, None);
+        assert_eq!(options.serial, None);
+        assert_eq!(options.firmware, Firmware::Uefi);
+        assert_eq!(options.cmdline, None);
+        assert_eq!(options.initrd, None);
+        assert!(options.qemu_args.is_empty());
+    }
+
+    #[test]
+    fn parses_flags() {
+        let options = parse(&[
+            "--headless",
+            "--gdb",
+            "--gdb-stub",
+            "--kvm",
+            "--image-only",
+            "-h",
+        ])
+        .unwrap();
+
+        assert!(options.headless);
+        assert!(options.gdb);
+        assert!(options.gdb_stub);
+        assert!(options.kvm);
+        assert!(options.image_only);
+        assert!(options.help);
+    }
+
+    #[test]
+    fn parses_values_in_either_form() {
+        let options = parse(&[
+            "--memory",
+            "2G",
+            "--smp=4",
+            "--serial",
+            "stdio",
+            "--firmware=bios",
+            "--cmdline",
+            "log=trace timer_hz=250",
+            "--initrd=files",
+        ])
+        .unwrap();
+
+        assert_eq!(options.memory.as_deref(), Some("2G"));
+        assert_eq!(options.smp, Some(4));
+        assert_eq!(options.serial, Some(Serial::Stdio));
+        assert_eq!(options.firmware, Firmware::Bios);
+        assert_eq!(options.cmdline.as_deref(), Some("log=trace timer_hz=250"));
+        assert_eq!(options.initrd, Some(PathBuf::from("files")));
+
+        let options = parse(&["--serial=com1.log"]).unwrap();
+        assert_eq!(
+            options.serial,
+            Some(Serial::File(PathBuf::from("com1.log")))
+        );
+    }
+
+    #[test]
+    fn passes_everything_after_the_separator_to_qemu() {
+        let options = parse(&["--headless", "--", "--gdb", "-device", "edu"]).unwrap();
+
+        assert!(options.headless);
+        assert!(!options.gdb);
+        assert_eq!(options.qemu_args, ["--gdb", "-device", "edu"]);
+    }
+
+    #[test]
+    fn reports_invalid_arguments() {
+        assert_eq!(
+            parse(&["--memory"]).err().unwrap(),
+            "--memory needs a value"
+        );
+        assert_eq!(
+            parse(&["--smp", "many"]).err().unwrap(),
+            "invalid CPU count `many`"
+        );
+        assert_eq!(
+            parse(&["--firmware=efi"]).err().unwrap(),
+            "unknown firmware `efi`"
+        );
+        assert_eq!(
+            parse(&["--bogus"]).err().unwrap(),
+            "unknown option `--bogus`"
+        );
+        assert_eq!(
+            parse(&["--bogus=1"]).err().unwrap(),
+            "unknown option `--bogus=1`"
+        );
+    }
+}
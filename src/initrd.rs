@@ -0,0 +1,127 @@
+//! Shared by build.rs and the runner, which both build the ramdisk the bootloader hands to the kernel. It is a ustar
+//! archive, read by `kernel::initrd`.
+
+use crate::symbols;
+use std::{fs, path::Path};
+
+const BLOCK_SIZE: usize = 512;
+
+/// Paths the kernel reads itself, which can't be overridden from the packed directory
+const RESERVED_PATHS: [&str; 2] = ["kernel.sym", "cmdline"];
+
+pub enum Entry {
+    File(Vec<u8>),
+    Directory,
+}
+
+/// Writes the initrd for `kernel`, holding its symbol table (for backtraces), command line, the contents of
+/// `directory` if there is one and `extra_entries`
+pub fn write_kernel_initrd(
+    kernel_path: &Path,
+    cmdline: &str,
+    directory: Option<&Path>,
+    extra_entries: Vec<(String, Entry)>,
+    initrd_path: &Path,
+) {
+    let mut entries = vec![
+        (
+            "kernel.sym".to_string(),
+            Entry::File(symbols::symbol_table(kernel_path)),
+        ),
+        (
+            "cmdline".to_string(),
+            Entry::File(cmdline.as_bytes().to_vec()),
+        ),
+    ];
+
+    if let Some(directory) = directory {
+        pack_directory(directory, "", &mut entries);
+    }
+
+    entries.extend(extra_entries);
+
+    write_initrd(&entries, initrd_path);
+}
+
+/// Adds everything under `directory` to `entries`, sorted so the archive is the same on every build. Symlinks are
+/// followed.
+fn pack_directory(directory: &Path, prefix: &str, entries: &mut Vec<(String, Entry)>) {
+    let mut children: Vec<_> = fs::read_dir(directory)
+        .unwrap_or_else(|error| panic!("can't read {}: {}", directory.display(), error))
+        .map(|child| child.unwrap().path())
+        .collect();
+    children.sort();
+
+    for child in children {
+        let name = child
+            .file_name()
+            .unwrap()
+            .to_str()
+            .unwrap_or_else(|| panic!("initrd path {} is not valid UTF-8", child.display()));
+        let path = format!("{}{}", prefix, name);
+
+        if RESERVED_PATHS.contains(&path.as_str()) {
+            panic!("{} is reserved in the initrd", child.display());
+        }
+
+        if child.is_dir() {
+            entries.push((path.clone(), Entry::Directory));
+            pack_directory(&child, &format!("{}/", path), entries);
+        } else {
+            entries.push((path, Entry::File(fs::read(&child).unwrap())));
+        }
+    }
+}
+
+/// Writes `entries` (path inside the archive and contents) as a ustar archive
+pub fn write_initrd(entries: &[(String, Entry)], initrd_path: &Path) {
+    let mut archive = Vec::new();
+
+    for (path, entry) in entries {
+        match entry {
+            Entry::File(contents) => {
+                archive.extend_from_slice(&header(path, contents.len(), b'0', 0o444));
+                archive.extend_from_slice(contents);
+                archive.resize(archive.len().next_multiple_of(BLOCK_SIZE), 0);
+            }
+            Entry::Directory => {
+                archive.extend_from_slice(&header(&format!("{}/", path), 0, b'5', 0o555));
+            }
+        }
+    }
+
+    // The end of the archive is marked by two empty blocks
+    archive.resize(archive.len() + 2 * BLOCK_SIZE, 0);
+
+    fs::write(initrd_path, archive).unwrap();
+}
+
+fn header(path: &str, size: usize, type_flag: u8, mode: u64) -> [u8; BLOCK_SIZE] {
+    // Longer paths need the ustar prefix field, which the kernel doesn't support
+    assert!(path.len() <= 100, "initrd path {} is too long", path);
+
+    let mut header = [0; BLOCK_SIZE];
+
+    header[..path.len()].copy_from_slice(path.as_bytes());
+    write_octal(&mut header[100..108], mode);
+    write_octal(&mut header[108..116], 0); // uid
+    write_octal(&mut header[116..124], 0); // gid
+    write_octal(&mut header[124..136], size as u64);
+    write_octal(&mut header[136..148], 0); // mtime
+    header[156] = type_flag;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+
+    // The checksum is calculated with the checksum field itself filled with spaces
+    header[148..156].fill(b' ');
+    let checksum: u64 = header.iter().map(|&byte| byte as u64).sum();
+    write_octal(&mut header[148..155], checksum);
+
+    header
+}
+
+/// Zero padded octal followed by a NUL, filling the field
+fn write_octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}\0", value, width = field.len() - 1);
+    field.copy_from_slice(digits.as_bytes());
+}
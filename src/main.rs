@@ -1,19 +1,292 @@
-use std::process::Command;
-use std::{env, fs, process};
+use bootloader::DiskImageBuilder;
+use cli::{Firmware, Options, Serial};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::time::{Duration, Instant};
+use std::{env, fs, process, thread};
+
+mod cli;
+mod initrd;
+mod symbols;
+mod test_images;
+
+/// Written to the `isa-debug-exit` port by `kernel::testing`. QEMU exits with `(code << 1) | 1`.
+const QEMU_EXIT_SUCCESS: i32 = (0x10 << 1) | 1;
+const QEMU_EXIT_FAILED: i32 = (0x11 << 1) | 1;
+
+/// Lets the kernel exit QEMU with a status code by writing to port 0xf4
+const ISA_DEBUG_EXIT: &str = "isa-debug-exit,iobase=0xf4,iosize=0x04";
+
+/// PCI devices for the test kernels to drive: `edu` sends an MSI when asked to and `virtio-rng-pci` has MSI-X and a
+/// virtqueue to read random bytes from
+const TEST_DEVICES: [&str; 2] = ["edu", "virtio-rng-pci"];
+
+/// Test kernels to boot on QEMU's q35 machine instead of the default i440FX, which has no ECAM
+const Q35_TESTS: [&str; 1] = ["pci_ecam"];
+
+/// Test kernel that needs `mke2fs` on the host for its image, and is skipped without it
+const EXT2_TEST: &str = "ext2";
+
+/// The TCP port COM2 is connected to with `--gdb-stub`
+const GDB_STUB_PORT: u16 = 1235;
+
+const DEFAULT_TEST_TIMEOUT: Duration = Duration::from_secs(60);
 
 fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    // cargo calls us as `bench2 test <test kernel>` for every test target (see .cargo/config.toml)
+    if args.first().map(String::as_str) == Some("test") {
+        let kernel = args.get(1).expect("usage: bench2 test <test kernel>");
+        process::exit(run_test(Path::new(kernel)));
+    }
+
+    let options = match Options::parse(args) {
+        Ok(options) => options,
+        Err(error) => {
+            eprintln!("{}\n\n{}", error, cli::USAGE);
+            process::exit(2);
+        }
+    };
+
+    if options.help {
+        println!("{}", cli::USAGE);
+        return;
+    }
+
     let current_exe = env::current_exe().unwrap();
-    let uefi_target = current_exe.with_file_name("uefi.img");
 
-    fs::copy(env!("UEFI_IMAGE"), &uefi_target).unwrap();
+    let (name, image, image_target) = match options.firmware {
+        Firmware::Uefi => (
+            "UEFI",
+            env!("UEFI_IMAGE"),
+            current_exe.with_file_name("uefi.img"),
+        ),
+        Firmware::Bios => (
+            "BIOS",
+            env!("BIOS_IMAGE"),
+            current_exe.with_file_name("bios.img"),
+        ),
+    };
+
+    // The kernel only runs its GDB stub when the command line asks for it
+    let cmdline = match (&options.cmdline, options.gdb_stub) {
+        (Some(cmdline), true) => Some(format!("{} gdb=on", cmdline)),
+        (None, true) => Some(String::from("gdb=on")),
+        (cmdline, false) => cmdline.clone(),
+    };
 
-    println!("UEFI disk image at {}", uefi_target.display());
+    if cmdline.is_some() || options.initrd.is_some() {
+        create_image(
+            Path::new(env!("KERNEL_PATH")),
+            cmdline.as_deref().unwrap_or(""),
+            &options
+                .initrd
+                .unwrap_or_else(|| PathBuf::from(env!("INITRD_DIR"))),
+            Vec::new(),
+            options.firmware,
+            &image_target,
+        );
+    } else {
+        fs::copy(image, &image_target).unwrap();
+    }
+
+    println!("{} disk image at {}", name, image_target.display());
+
+    if options.image_only {
+        return;
+    }
+
+    let mut qemu = Command::new("qemu-system-x86_64");
+    qemu.arg("-drive");
+    qemu.arg(format!("format=raw,file={}", image_target.display()));
+
+    // QEMU's default firmware is SeaBIOS
+    if options.firmware == Firmware::Uefi {
+        qemu.arg("-bios").arg(ovmf_prebuilt::ovmf_pure_efi());
+    }
+    qemu.arg("-device").arg(ISA_DEBUG_EXIT);
+
+    match &options.serial {
+        // Only one device can use stdio directly, so the debug port and COM1 share it through a multiplexer
+        Some(Serial::Stdio) => {
+            qemu.arg("-chardev").arg("stdio,id=stdio,mux=on,signal=off");
+            qemu.arg("-debugcon").arg("chardev:stdio");
+            qemu.arg("-serial").arg("chardev:stdio");
+        }
+        Some(Serial::File(path)) => {
+            qemu.arg("-debugcon").arg("stdio");
+            qemu.arg("-serial").arg(format!("file:{}", path.display()));
+        }
+        None => {
+            qemu.arg("-debugcon").arg("stdio");
+            // COM2 is the second `-serial`, so COM1 needs one too
+            if options.gdb_stub {
+                qemu.arg("-serial").arg("vc");
+            }
+        }
+    }
+
+    if options.gdb_stub {
+        println!("GDB stub on localhost:{}", GDB_STUB_PORT);
+        qemu.arg("-serial")
+            .arg(format!("tcp::{},server,nowait", GDB_STUB_PORT));
+    }
+
+    if options.headless {
+        qemu.arg("-display").arg("none");
+    }
+
+    if options.gdb {
+        println!("Waiting for GDB on localhost:1234");
+        qemu.arg("-s").arg("-S");
+    }
+
+    if let Some(memory) = &options.memory {
+        qemu.arg("-m").arg(memory);
+    }
+
+    if let Some(cpus) = options.smp {
+        qemu.arg("-smp").arg(cpus.to_string());
+    }
+
+    if options.kvm {
+        qemu.arg("-enable-kvm").arg("-cpu").arg("host");
+    }
+
+    qemu.args(&options.qemu_args);
+
+    let exit_status = qemu.status().unwrap();
+    process::exit(exit_code(exit_status));
+}
+
+/// Maps the exit codes `kernel::testing::exit_qemu` causes back to 0 for success and 1 for failure. Anything else is
+/// passed through, and being killed by a signal becomes -1.
+fn exit_code(exit_status: ExitStatus) -> i32 {
+    match exit_status.code() {
+        Some(QEMU_EXIT_SUCCESS) => 0,
+        Some(QEMU_EXIT_FAILED) => 1,
+        Some(code) => code,
+        None => -1,
+    }
+}
+
+/// Boots a test kernel headless and returns 0 if it reported that every test in it passed. The results are printed by the kernel over
+/// the debug port.
+fn run_test(kernel: &Path) -> i32 {
+    if test_name(kernel) == EXT2_TEST && !test_images::mke2fs_installed() {
+        println!(
+            "\nSkipping {}: mke2fs isn't installed (it comes with e2fsprogs)",
+            kernel.display()
+        );
+        return 0;
+    }
+
+    let image = create_test_image(kernel);
+
+    let timeout = env::var("KERNEL_TEST_TIMEOUT")
+        .ok()
+        .and_then(|seconds| seconds.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_TEST_TIMEOUT);
 
     let mut qemu = Command::new("qemu-system-x86_64");
     qemu.arg("-debugcon").arg("stdio");
     qemu.arg("-drive");
-    qemu.arg(format!("format=raw,file={}", env!("UEFI_IMAGE")));
+    qemu.arg(format!("format=raw,file={}", image.display()));
     qemu.arg("-bios").arg(ovmf_prebuilt::ovmf_pure_efi());
-    let exit_status = qemu.status().unwrap();
-    process::exit(exit_status.code().unwrap_or(-1));
+    qemu.arg("-device").arg(ISA_DEBUG_EXIT);
+    // A triple fault would otherwise reboot the kernel and run until the timeout
+    qemu.arg("-no-reboot");
+    if Q35_TESTS.contains(&test_name(kernel)) {
+        qemu.arg("-machine").arg("q35");
+    }
+    for device in TEST_DEVICES {
+        qemu.arg("-device").arg(device);
+    }
+    qemu.arg("-display").arg("none");
+    qemu.arg("-serial").arg("null");
+    qemu.stdin(Stdio::null());
+
+    let mut child = qemu.spawn().unwrap();
+    let start = Instant::now();
+
+    let exit_status = loop {
+        if let Some(exit_status) = child.try_wait().unwrap() {
+            break exit_status;
+        }
+
+        if start.elapsed() > timeout {
+            child.kill().unwrap();
+            child.wait().unwrap();
+            eprintln!("\n{} timed out after {:?}", kernel.display(), timeout);
+            return 1;
+        }
+
+        thread::sleep(Duration::from_millis(100));
+    };
+
+    // Only the kernel's own exit counts as a pass, QEMU also exits with 0 when the guest powers off
+    match exit_status.code() {
+        Some(QEMU_EXIT_SUCCESS) => 0,
+        Some(code) => {
+            eprintln!("\n{} failed (QEMU exit code {})", kernel.display(), code);
+            1
+        }
+        None => {
+            eprintln!("\n{} failed (QEMU was killed)", kernel.display());
+            1
+        }
+    }
+}
+
+/// Cargo names test kernels `<test>-<hash>`
+fn test_name(kernel: &Path) -> &str {
+    let file_name = kernel
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("");
+    file_name
+        .rsplit_once('-')
+        .map_or(file_name, |(name, _)| name)
+}
+
+/// Builds a UEFI disk image next to the test kernel, with the test filesystem images in its initrd
+fn create_test_image(kernel: &Path) -> PathBuf {
+    let image = kernel.with_extension("img");
+    let cmdline = env::var("KERNEL_TEST_CMDLINE").unwrap_or_default();
+
+    create_image(
+        kernel,
+        &cmdline,
+        Path::new(env!("INITRD_DIR")),
+        test_images::entries(),
+        Firmware::Uefi,
+        &image,
+    );
+
+    image
+}
+
+/// Builds a disk image the same way build.rs does, but with the given command line, directory and extra entries in
+/// the initrd
+fn create_image(
+    kernel: &Path,
+    cmdline: &str,
+    initrd_dir: &Path,
+    extra_entries: Vec<(String, initrd::Entry)>,
+    firmware: Firmware,
+    image: &Path,
+) {
+    let initrd_path = image.with_extension("initrd.tar");
+    let initrd_dir = Some(initrd_dir).filter(|dir| dir.exists());
+    initrd::write_kernel_initrd(kernel, cmdline, initrd_dir, extra_entries, &initrd_path);
+
+    let mut disk_builder = DiskImageBuilder::new(kernel.to_path_buf());
+    disk_builder.set_ramdisk(initrd_path);
+
+    match firmware {
+        Firmware::Uefi => disk_builder.create_uefi_image(image).unwrap(),
+        Firmware::Bios => disk_builder.create_bios_image(image).unwrap(),
+    }
 }
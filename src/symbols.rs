@@ -0,0 +1,53 @@
+//! Shared by build.rs and the runner, which both need to attach a symbol table to a kernel
+
+use object::{Object, ObjectSymbol, SymbolKind};
+use std::{fs, path::Path};
+
+/// Builds a table of the kernel's function symbols sorted by address in the format expected by `kernel::backtrace`:
+///
+/// ```text
+/// magic: b"BSYM", count: u32
+/// count * (address: u64, size: u64, name_offset: u32, name_len: u32)
+/// names: [u8]
+/// ```
+///
+/// All integers are little endian and name offsets are relative to the start of the names.
+pub fn symbol_table(kernel_path: &Path) -> Vec<u8> {
+    let kernel = fs::read(kernel_path).unwrap();
+    let kernel = object::File::parse(&*kernel).unwrap();
+
+    let mut symbols: Vec<(u64, u64, String)> = kernel
+        .symbols()
+        .filter(|symbol| symbol.kind() == SymbolKind::Text && symbol.address() != 0)
+        .filter_map(|symbol| {
+            let name = symbol.name().ok()?;
+            Some((
+                symbol.address(),
+                symbol.size(),
+                format!("{:#}", rustc_demangle::demangle(name)),
+            ))
+        })
+        .collect();
+
+    symbols.sort_by_key(|(address, _, _)| *address);
+    symbols.dedup_by_key(|(address, _, _)| *address);
+
+    let mut entries = Vec::new();
+    let mut names = Vec::new();
+
+    for (address, size, name) in &symbols {
+        entries.extend_from_slice(&address.to_le_bytes());
+        entries.extend_from_slice(&size.to_le_bytes());
+        entries.extend_from_slice(&(names.len() as u32).to_le_bytes());
+        entries.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        names.extend_from_slice(name.as_bytes());
+    }
+
+    let mut table = Vec::new();
+    table.extend_from_slice(b"BSYM");
+    table.extend_from_slice(&(symbols.len() as u32).to_le_bytes());
+    table.extend_from_slice(&entries);
+    table.extend_from_slice(&names);
+
+    table
+}
@@ -0,0 +1,247 @@
+//! Filesystem and partitioned disk images packed into the initrd of test kernels under `test/`, as there is no disk
+//! driver to attach them with yet. The kernel tests put them on a `RamDisk` of the size given here.
+
+use crate::initrd::Entry;
+use fatfs::{FatType, FileSystem, FormatVolumeOptions, FsOptions};
+use gpt::disk::LogicalBlockSize;
+use gpt::mbr::ProtectiveMBR;
+use gpt::GptConfig;
+use mbrman::{MBRPartitionEntry, BOOT_INACTIVE, CHS, MBR};
+use std::fs;
+use std::io::{Cursor, Seek, SeekFrom, Write};
+use std::os::unix::fs::symlink;
+use std::process::{Command, Stdio};
+
+/// Name in `test/`, FAT type and size. FAT32 needs at least 65525 clusters so is much bigger than the others, but
+/// most of it is zeros which aren't stored.
+const FAT_IMAGES: [(&str, FatType, usize); 3] = [
+    ("fat12.img", FatType::Fat12, 512 * 1024),
+    ("fat16.img", FatType::Fat16, 4 * 1024 * 1024),
+    ("fat32.img", FatType::Fat32, 40 * 1024 * 1024),
+];
+
+/// Size of the ext2 image in KiB, which is also its size in 1 KiB blocks
+const EXT2_SIZE: usize = 768;
+
+/// Size of the MBR and GPT images in sectors
+const PARTITIONED_SECTORS: usize = 384;
+/// Size of the FAT12 volume in the first partition of the MBR and GPT images
+const PARTITION_FAT_SIZE: usize = 64 * 1024;
+
+/// Every test image with its path in the initrd
+pub fn entries() -> Vec<(String, Entry)> {
+    let mut entries = vec![("test".to_string(), Entry::Directory)];
+
+    for (name, fat_type, size) in FAT_IMAGES {
+        entries.push((
+            format!("test/{}", name),
+            Entry::File(trim(fat_image(fat_type, size))),
+        ));
+    }
+
+    entries.push(("test/mbr.img".to_string(), Entry::File(trim(mbr_image()))));
+    entries.push(("test/gpt.img".to_string(), Entry::File(trim(gpt_image()))));
+
+    // Without mke2fs the runner skips the ext2 tests, but with it a failure is a bug
+    if mke2fs_installed() {
+        let image = ext2_image()
+            .unwrap_or_else(|error| panic!("building the ext2 test image failed: {}", error));
+        entries.push(("test/ext2.img".to_string(), Entry::File(image)));
+    }
+
+    entries
+}
+
+/// A formatted FAT volume holding a few files
+fn fat_image(fat_type: FatType, size: usize) -> Vec<u8> {
+    let mut image = Cursor::new(vec![0; size]);
+
+    // Small clusters so files span several of them
+    let options = FormatVolumeOptions::new()
+        .fat_type(fat_type)
+        .bytes_per_cluster(512)
+        .volume_label(*b"BENCH      ");
+    fatfs::format_volume(&mut image, options).unwrap();
+
+    {
+        let filesystem = FileSystem::new(&mut image, FsOptions::new()).unwrap();
+        let root = filesystem.root_dir();
+
+        let write_file = |path: &str, contents: &[u8]| {
+            root.create_file(path).unwrap().write_all(contents).unwrap();
+        };
+
+        write_file("HELLO.TXT", b"Hello from FAT!\n");
+        write_file("A long file name.txt", b"Long file names work too.\n");
+        root.create_dir("docs").unwrap();
+        write_file("docs/readme.md", b"# Docs\n");
+
+        let big: Vec<u8> = (0..5000).map(|index| (index % 251) as u8).collect();
+        write_file("big.bin", &big);
+    }
+
+    image.into_inner()
+}
+
+/// An MBR disk with a FAT12 volume in partition 1, an extended partition 2 holding logical partitions 5 and 6, and
+/// partition 4 at the end
+fn mbr_image() -> Vec<u8> {
+    let mut disk = Cursor::new(vec![0; PARTITIONED_SECTORS * 512]);
+
+    let mut mbr = MBR::new_from(&mut disk, 512, *b"BNCH").unwrap();
+    // Partitions can start on any sector rather than being lined up on 1 MiB
+    mbr.align = 1;
+
+    let primary = |sys, starting_lba, sectors| MBRPartitionEntry {
+        boot: BOOT_INACTIVE,
+        first_chs: CHS::empty(),
+        sys,
+        last_chs: CHS::empty(),
+        starting_lba,
+        sectors,
+    };
+    mbr[1] = primary(0x01, 16, (PARTITION_FAT_SIZE / 512) as u32);
+    mbr[2] = primary(0x05, 160, 160);
+    mbr[4] = primary(0x83, 320, 40);
+
+    // Each logical partition is a sector after its EBR, so 161-199 and 201-259
+    mbr.push(0x83, 160, 40).unwrap();
+    mbr.push(0x83, 200, 60).unwrap();
+    mbr.write_into(&mut disk).unwrap();
+
+    write_at(
+        &mut disk,
+        16,
+        &fat_image(FatType::Fat12, PARTITION_FAT_SIZE),
+    );
+    for (number, start) in [(4, 320), (5, 161), (6, 201)] {
+        write_at(&mut disk, start, partition_marker(number).as_bytes());
+    }
+
+    disk.into_inner()
+}
+
+/// A GPT disk with a FAT12 volume in partition 1 and a Linux partition 2, placed wherever the `gpt` crate puts them
+fn gpt_image() -> Vec<u8> {
+    let mut disk = Cursor::new(vec![0; PARTITIONED_SECTORS * 512]);
+
+    ProtectiveMBR::with_lb_size(PARTITIONED_SECTORS as u32 - 1)
+        .overwrite_lba0(&mut disk)
+        .unwrap();
+
+    let block_size = LogicalBlockSize::Lb512;
+    let mut gpt = GptConfig::new()
+        .writable(true)
+        .initialized(false)
+        .logical_block_size(block_size)
+        .create_from_device(Box::new(&mut disk), None)
+        .unwrap();
+    gpt.update_partitions(Default::default()).unwrap();
+
+    let partitions = [
+        ("bench fat", PARTITION_FAT_SIZE, gpt::partition_types::BASIC),
+        ("bench data", 32 * 1024, gpt::partition_types::LINUX_FS),
+    ];
+
+    let mut starts = Vec::new();
+    for (name, size, partition_type) in partitions {
+        let id = gpt
+            .add_partition(name, size as u64, partition_type, 0, None)
+            .unwrap();
+        starts.push(gpt.partitions()[&id].first_lba);
+    }
+    gpt.write().unwrap();
+
+    write_at(
+        &mut disk,
+        starts[0],
+        &fat_image(FatType::Fat12, PARTITION_FAT_SIZE),
+    );
+    write_at(&mut disk, starts[1], partition_marker(2).as_bytes());
+
+    disk.into_inner()
+}
+
+/// What the partitions without a filesystem start with, so the tests can tell they found the right sectors
+fn partition_marker(number: usize) -> String {
+    format!("partition {}", number)
+}
+
+fn write_at(disk: &mut Cursor<Vec<u8>>, sector: u64, data: &[u8]) {
+    disk.seek(SeekFrom::Start(sector * 512)).unwrap();
+    disk.write_all(data).unwrap();
+}
+
+/// Whether `mke2fs` is on the host to build the ext2 image with
+pub fn mke2fs_installed() -> bool {
+    Command::new("mke2fs")
+        .arg("-V")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok()
+}
+
+/// An ext2 volume made by `mke2fs` from a directory of files, with 1 KiB blocks and small groups so there are several
+/// of them
+fn ext2_image() -> std::io::Result<Vec<u8>> {
+    let work = std::env::temp_dir().join(format!("bench-ext2-{}", std::process::id()));
+    let contents = work.join("contents");
+    let image_path = work.join("ext2.img");
+
+    let _ = fs::remove_dir_all(&work);
+    fs::create_dir_all(contents.join("docs"))?;
+
+    fs::write(contents.join("hello.txt"), "Hello from ext2!\n")?;
+    fs::write(contents.join("docs/readme.md"), "# Docs\n")?;
+
+    // Past the 12 direct blocks, so it needs an indirect block
+    let big: Vec<u8> = (0..20000).map(|index| (index % 251) as u8).collect();
+    fs::write(contents.join("big.bin"), big)?;
+
+    // Targets under 60 bytes are stored in the inode, longer ones in a block
+    symlink("hello.txt", contents.join("link"))?;
+    symlink(
+        format!("docs/{}readme.md", "../docs/".repeat(8)),
+        contents.join("long-link"),
+    )?;
+
+    let status = Command::new("mke2fs")
+        .args([
+            "-q", "-F", "-t", "ext2", "-b", "1024", "-g", "256", "-N", "96",
+        ])
+        .args([
+            "-O",
+            "^resize_inode",
+            "-L",
+            "bench",
+            "-E",
+            "root_owner=0:0",
+            "-d",
+        ])
+        .arg(&contents)
+        .arg(&image_path)
+        .arg(EXT2_SIZE.to_string())
+        .stdout(Stdio::null())
+        .status();
+
+    let image = match status {
+        Ok(status) if status.success() => fs::read(&image_path),
+        Ok(status) => Err(std::io::Error::other(format!("exited with {}", status))),
+        Err(error) => Err(error),
+    };
+
+    let _ = fs::remove_dir_all(&work);
+    Ok(trim(image?))
+}
+
+/// Removes the zeros at the end of an image, the kernel tests put it on a disk that reads as zeros past it
+fn trim(mut image: Vec<u8>) -> Vec<u8> {
+    let end = image
+        .iter()
+        .rposition(|&byte| byte != 0)
+        .map_or(0, |end| end + 1);
+    image.truncate(end);
+
+    image
+}